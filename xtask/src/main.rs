@@ -1,7 +1,8 @@
 use clap::{Parser, Subcommand};
 use std::env;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::process::{self, Command};
+use std::process::{self, Command, Stdio};
 
 #[derive(Parser)]
 #[clap(name = "xtask")]
@@ -19,6 +20,8 @@ enum Commands {
     Qemu {},
     /// Emulate in QEMU under debug configuration
     Debug {},
+    /// Run in-kernel tests in QEMU and check that they pass
+    Test {},
     /// Run GDB debugger
     Gdb {},
 }
@@ -41,6 +44,11 @@ fn main() {
             xtask_build_zihai();
             xtask_debug_zihai();
         }
+        Commands::Test {} => {
+            println!("xtask: make hypervisor and run tests in QEMU");
+            xtask_build_zihai();
+            xtask_test_zihai();
+        }
         Commands::Gdb {} => {
             println!("xtask: debug hypervisor on GDB server localhost:3333");
             xtask_gdb_zihai();
@@ -109,6 +117,46 @@ fn xtask_debug_zihai() {
     }
 }
 
+// line the hypervisor prints once the wfi guest exits on a timer interrupt; any failed test panics
+const TEST_PASS_LINE: &str = "zihai > wfi guest run test passed";
+
+fn xtask_test_zihai() {
+    let mut command = Command::new("qemu-system-riscv64");
+    command.current_dir(project_root());
+    if detect_should_qemu_riscv_h_fix() {
+        // fix quirk on qemu 6
+        command.args(&["-cpu", "rv64,x-h=true"]); // enable hypervisor on qemu 6
+    }
+    command.args(&["-machine", "virt"]);
+    command.args(&["-bios", "bootloader/rustsbi-qemu.bin"]);
+    command.args(&["-kernel", "target/riscv64imac-unknown-none-elf/debug/zihai"]);
+    command.args(&["-smp", "8"]); // 8 cores
+    command.arg("-nographic");
+    command.stdout(Stdio::piped());
+
+    let mut child = command.spawn().expect("run program");
+    let stdout = child.stdout.take().expect("capture qemu output");
+    let (mut passed, mut panicked) = (false, false);
+    // hypervisor shuts down the machine after its tests, or on panic
+    for line in BufReader::new(stdout).lines() {
+        let line = line.expect("read qemu output");
+        println!("{}", line);
+        passed |= line.trim_end() == TEST_PASS_LINE;
+        panicked |= line.starts_with("panicked at");
+    }
+    let status = child.wait().expect("wait for qemu");
+
+    if !status.success() {
+        eprintln!("xtask: qemu failed with {}", status);
+        process::exit(status.code().unwrap_or(1));
+    }
+    if panicked || !passed {
+        eprintln!("xtask: tests failed, expected output '{}'", TEST_PASS_LINE);
+        process::exit(1);
+    }
+    println!("xtask: tests passed");
+}
+
 fn xtask_gdb_zihai() {
     let mut command = Command::new("riscv64-unknown-elf-gdb");
     command.current_dir(project_root());
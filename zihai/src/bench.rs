@@ -0,0 +1,115 @@
+//! Benchmark module
+//!
+//! Compares setup time and page table frames of fine and huge page mappings
+use riscv::register::time;
+
+use crate::mm::{self, PagedAddrSpace, Sv39, Sv39Flags, VirtAddr};
+
+const REGION_SIZE: usize = 1 << 30; // 1GiB
+const REGION_BASE: usize = 0x1_0000_0000; // aligned to 1GiB
+const FRAME_SIZE: usize = 1 << 12;
+const ROOT_TABLE_SPAN: usize = 1 << 39; // bytes mapped by Sv39 root page table
+
+// Mapping strategies to be compared
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Strategy {
+    Fine4K,
+    Huge2M,
+    Huge1G,
+}
+
+impl Strategy {
+    fn page_size(&self) -> usize {
+        match self {
+            Strategy::Fine4K => 4 * 1024,
+            Strategy::Huge2M => 2 * 1024 * 1024,
+            Strategy::Huge1G => 1024 * 1024 * 1024,
+        }
+    }
+    // Physical address to map the region onto.
+    //
+    // The mapping solver uses the largest page allowed by the alignment between
+    // virtual and physical addresses; offsetting the physical address by one
+    // page of the chosen size forbids larger pages.
+    fn phys_base(&self) -> usize {
+        match self {
+            Strategy::Huge1G => REGION_BASE,
+            _ => REGION_BASE + self.page_size(),
+        }
+    }
+}
+
+// Page table frames needed to map `size` bytes from an aligned base with pages
+// of `page_size` bytes under Sv39, not including the root page table
+fn expected_table_frames(size: usize, page_size: usize) -> usize {
+    let mut frames = 0;
+    // bytes mapped by one page table on current level
+    let mut table_span = page_size * 512;
+    while table_span < ROOT_TABLE_SPAN {
+        frames += size.div_ceil(table_span);
+        table_span *= 512;
+    }
+    frames
+}
+
+// Maps a 1GiB region with 4K, 2M and 1G pages, printing setup time and
+// page table frames consumed of each strategy
+pub fn run(frame_alloc: &mm::DefaultFrameAllocator) {
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    for strategy in [Strategy::Fine4K, Strategy::Huge2M, Strategy::Huge1G] {
+        let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+        let frames_before = frame_alloc.lock().frames_in_use();
+        let time_before = time::read();
+        let va = VirtAddr(REGION_BASE)..VirtAddr(REGION_BASE + REGION_SIZE);
+        space
+            .map_range(va, mm::PhysAddr(strategy.phys_base()), flags)
+            .expect("map benchmark region");
+        let time_after = time::read();
+        let frames = frame_alloc.lock().frames_in_use() - frames_before;
+        assert_eq!(
+            frames,
+            expected_table_frames(REGION_SIZE, strategy.page_size()),
+            "page table frames of {:?}",
+            strategy
+        );
+        println!(
+            "zihai > bench map 1GiB {:?}: {} ticks, {} page table frames",
+            strategy,
+            time_after - time_before,
+            frames
+        );
+        space.release();
+    }
+}
+
+pub(crate) fn test_bench_accounting() {
+    assert_eq!(
+        expected_table_frames(REGION_SIZE, 4 * 1024),
+        513,
+        "4K pages"
+    );
+    assert_eq!(
+        expected_table_frames(REGION_SIZE, 2 * 1024 * 1024),
+        1,
+        "2M pages"
+    );
+    assert_eq!(expected_table_frames(REGION_SIZE, 1 << 30), 0, "1G page");
+    assert_eq!(
+        expected_table_frames(3 << 21, 4 * 1024),
+        4,
+        "6M with 4K pages"
+    );
+    for strategy in [Strategy::Fine4K, Strategy::Huge2M, Strategy::Huge1G] {
+        let offset = strategy.phys_base() - REGION_BASE;
+        let page_frames = strategy.page_size() / FRAME_SIZE;
+        assert!(
+            offset.is_multiple_of(page_frames * FRAME_SIZE),
+            "allows chosen pages"
+        );
+        assert!(
+            strategy == Strategy::Huge1G || !offset.is_multiple_of(page_frames * 512 * FRAME_SIZE),
+            "forbids larger pages"
+        );
+    }
+    println!("zihai > bench accounting test passed");
+}
@@ -1,15 +1,14 @@
 // early console; should be replaced by serial console later.
 
-use crate::sbi::console_putchar;
+use crate::sbi::console_write_bytes;
 use core::fmt::{self, Write};
 
-struct Stdout;
+// Writer to the console, for dumps formatted piece by piece without a heap buffer
+pub struct Stdout;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
-        }
+        console_write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -31,3 +30,51 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+// Prints bytes in canonical hexdump format, 16 bytes per line, e.g.
+// `80200000  73 00 50 10 6f 00 00 00  41 42 43 44 45 46 47 48  |s.P.o...ABCDEFGH|`
+#[allow(unused)] // for debugging
+pub fn hexdump(bytes: &[u8], base_addr: usize) {
+    hexdump_to(&mut Stdout, bytes, base_addr).unwrap();
+}
+
+fn hexdump_to(w: &mut impl Write, bytes: &[u8], base_addr: usize) -> fmt::Result {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(w, "{:08x} ", base_addr + i * 16)?;
+        for j in 0..16 {
+            if j % 8 == 0 {
+                w.write_str(" ")?;
+            }
+            match line.get(j) {
+                Some(byte) => write!(w, "{:02x} ", byte)?,
+                None => w.write_str("   ")?,
+            }
+        }
+        w.write_str(" |")?;
+        for &byte in line {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            w.write_char(c)?;
+        }
+        w.write_str("|\n")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn test_hexdump() {
+    let mut ans = alloc::string::String::new();
+    let bytes = b"s\x00P\x10o\x00\x00\x00ABCDEFGHzihai\n";
+    hexdump_to(&mut ans, bytes, 0x80200000).unwrap();
+    let expected = concat!(
+        "80200000  73 00 50 10 6f 00 00 00  41 42 43 44 45 46 47 48  |s.P.o...ABCDEFGH|\n",
+        "80200010  7a 69 68 61 69 0a                                 |zihai.|\n",
+    );
+    assert_eq!(ans, expected, "hexdump of two lines");
+    ans.clear();
+    hexdump_to(&mut ans, &[], 0).unwrap();
+    assert_eq!(ans, "", "hexdump of nothing");
+    println!("zihai > hexdump test passed");
+}
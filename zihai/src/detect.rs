@@ -4,94 +4,693 @@
 // are all exceptions. Then, we filter out illegal instruction from exceptions.
 
 // use core::arch::riscv64;
+use crate::mm::{FrameAllocator, PagedAddrSpace, Sv39, Sv39Flags, VirtAddr};
 use core::arch::asm;
+use core::fmt;
 use riscv::register::{
     scause::{Exception, Scause, Trap},
     sstatus,
     stvec::{self, Stvec, TrapMode},
 };
 
+// Reads CSR number `CSR`, or returns None if the read raised illegal instruction
+//
+// The CSR number is encoded into the `csrr` instruction itself, so it is taken
+// as a const generic parameter rather than a runtime argument.
+pub fn detect_csr_read<const CSR: u16>() -> Option<usize> {
+    let mut value = 0;
+    // run detection by trap on csrr instruction.
+    let ans = with_detect_trap(|| unsafe {
+        asm!("csrr  {}, {csr}", out(reg) value, csr = const CSR, options(nomem, nostack));
+    });
+    ans.ok().map(|()| value)
+}
+
 // Detect if hypervisor extension exists on current hart environment
 //
 // This function tries to read hgatp and returns false if the read operation failed.
 pub fn detect_h_extension() -> bool {
-    // run detection by trap on csrr instruction.
-    let ans = with_detect_trap(0, || unsafe {
-        asm!("csrr  {}, 0x680", out(reg) _, options(nomem, nostack)); // 0x680 => hgatp
+    detect_csr_read::<0x680>().is_some() // 0x680 => hgatp
+}
+
+// Detect if supervisor timer compare extension Sstc exists on current hart environment
+//
+// This function tries to read stimecmp and returns false if the read operation failed.
+pub fn detect_sstc_extension() -> bool {
+    detect_csr_read::<0x14D>().is_some() // 0x14D => stimecmp
+}
+
+// Sets `mask` bits of CSR number `CSR` and returns those that stay set, or None
+// if the CSR does not exist; the CSR is restored to its previous value
+//
+// Write-any-read-legal fields read back as zero when the feature behind them
+// is not implemented.
+pub fn detect_csr_writable_bits<const CSR: u16>(mask: usize) -> Option<usize> {
+    let (mut stored, mut written) = (0, 0);
+    let ans = with_detect_trap(|| unsafe {
+        asm!("csrrs {}, {csr}, {}", out(reg) stored, in(reg) mask, csr = const CSR, options(nomem, nostack));
+        asm!("csrr  {}, {csr}", out(reg) written, csr = const CSR, options(nomem, nostack));
+        asm!("csrw  {csr}, {}", in(reg) stored, csr = const CSR, options(nomem, nostack));
+    });
+    ans.ok().map(|()| written & mask)
+}
+
+// Cache block management instruction used for detection, emitted as raw word as
+// the assembler may not support Zicbom mnemonics. Address operand is t0 (x5).
+const INSN_CBO_FLUSH_T0: u32 = 0x0022a00f; // Zicbom cbo.flush (t0)
+
+// Detect if cache block management extension Zicbom exists and is enabled for
+// supervisor mode on current hart environment
+pub fn detect_zicbom() -> bool {
+    let probe = 0_usize;
+    let ans = with_detect_trap(|| unsafe {
+        asm!(".word {}", const INSN_CBO_FLUSH_T0, in("t0") &probe, options(nostack));
+    });
+    probe_exists(ans)
+}
+
+// Optional features of a hart, detected once at boot
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct HartCaps {
+    // hypervisor extension, by reading hgatp
+    pub h: bool,
+    // supervisor timer compare, by reading stimecmp
+    pub sstc: bool,
+    // counter overflow and mode-based filtering, by reading scountovf
+    pub sscofpmf: bool,
+    // page-based memory types, by setting henvcfg.PBMTE; requires H extension
+    pub svpbmt: bool,
+    // cache block management instructions, by executing cbo.flush
+    pub zicbom: bool,
+    // address generation instructions, by executing sh1add
+    pub zba: bool,
+    // basic bit-manipulation instructions, by executing andn
+    pub zbb: bool,
+}
+
+// henvcfg.PBMTE, read-only zero if Svpbmt is not implemented
+const HENVCFG_PBMTE: usize = 1 << 62;
+
+// Detects optional features of current hart
+pub fn detect_caps() -> HartCaps {
+    let h = detect_csr_read::<0x680>().is_some(); // 0x680 => hgatp
+    let pbmte = detect_csr_writable_bits::<0x60A>(HENVCFG_PBMTE); // 0x60A => henvcfg
+    HartCaps {
+        h,
+        sstc: detect_csr_read::<0x14D>().is_some(), // 0x14D => stimecmp
+        sscofpmf: detect_csr_read::<0xDA0>().is_some(), // 0xDA0 => scountovf
+        svpbmt: h && pbmte == Some(HENVCFG_PBMTE),
+        zicbom: detect_zicbom(),
+        zba: detect_zba(),
+        zbb: detect_zbb(),
+    }
+}
+
+impl fmt::Display for HartCaps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let caps = [
+            (self.h, "h"),
+            (self.sstc, "sstc"),
+            (self.sscofpmf, "sscofpmf"),
+            (self.svpbmt, "svpbmt"),
+            (self.zicbom, "zicbom"),
+            (self.zba, "zba"),
+            (self.zbb, "zbb"),
+        ];
+        let mut first = true;
+        for (_, name) in caps.iter().filter(|(exists, _)| *exists) {
+            if !first {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn test_detect_caps() {
+    let caps = detect_caps();
+    assert_eq!(caps.h, detect_h_extension(), "h matches");
+    assert_eq!(caps.sstc, detect_sstc_extension(), "sstc matches");
+    assert_eq!(caps.zba, detect_zba(), "zba matches");
+    assert_eq!(caps.zbb, detect_zbb(), "zbb matches");
+    assert!(!caps.svpbmt || caps.h, "svpbmt probed through henvcfg");
+    assert_eq!(detect_caps(), caps, "detection is stable");
+    let some = HartCaps {
+        h: true,
+        svpbmt: true,
+        ..Default::default()
+    };
+    assert_eq!(alloc::format!("{}", some), "h svpbmt");
+    assert_eq!(alloc::format!("{}", HartCaps::default()), "none");
+    println!("zihai > hart capabilities test passed, current: {}", caps);
+}
+
+pub(crate) fn test_detect_csr_read() {
+    let time = detect_csr_read::<0xC01>().expect("time is always readable"); // 0xC01 => time
+    let later = detect_csr_read::<0xC01>().unwrap();
+    assert!(later >= time, "time does not go backwards");
+    assert_eq!(detect_csr_read::<0x680>().is_some(), detect_h_extension());
+    println!("zihai > csr read detect test passed");
+}
+
+// Bit-manipulation instructions used for detection, emitted as raw words as the
+// assembler may not support Zbb or Zba mnemonics. Register operands are all t0 (x5).
+const INSN_ANDN_T0: u32 = 0x4052f2b3; // Zbb andn t0, t0, t0
+const INSN_SH1ADD_T0: u32 = 0x2052a2b3; // Zba sh1add t0, t0, t0
+
+// Detect if basic bit-manipulation extension Zbb exists on current hart environment
+//
+// Routines that scan bitmaps or count zeros (dirty page bitmaps, page layout
+// computation) could use `ctz`, `clz` and `andn` from Zbb after runtime dispatch.
+pub fn detect_zbb() -> bool {
+    let ans = with_detect_trap(|| unsafe {
+        asm!(".word {}", const INSN_ANDN_T0, out("t0") _, options(nomem, nostack));
+    });
+    probe_exists(ans)
+}
+
+// Detect if address generation extension Zba exists on current hart environment
+//
+// Page table walks could use `sh3add` from Zba to compute the address of a page
+// table entry from its index after runtime dispatch.
+pub fn detect_zba() -> bool {
+    let ans = with_detect_trap(|| unsafe {
+        asm!(".word {}", const INSN_SH1ADD_T0, out("t0") _, options(nomem, nostack));
+    });
+    probe_exists(ans)
+}
+
+pub(crate) fn test_detect_bitmanip() {
+    use bit_field::BitField;
+    // opcode OP, rd = rs1 = rs2 = t0
+    for insn in [INSN_ANDN_T0, INSN_SH1ADD_T0] {
+        assert_eq!(insn.get_bits(0..7), 0b0110011, "opcode OP");
+        assert_eq!(insn.get_bits(7..12), 5, "rd t0");
+        assert_eq!(insn.get_bits(15..20), 5, "rs1 t0");
+        assert_eq!(insn.get_bits(20..25), 5, "rs2 t0");
+    }
+    assert_eq!(INSN_ANDN_T0.get_bits(12..15), 0b111, "andn funct3");
+    assert_eq!(INSN_ANDN_T0.get_bits(25..32), 0b0100000, "andn funct7");
+    assert_eq!(INSN_SH1ADD_T0.get_bits(12..15), 0b010, "sh1add funct3");
+    assert_eq!(INSN_SH1ADD_T0.get_bits(25..32), 0b0010000, "sh1add funct7");
+    // detection must be stable across calls
+    let (zbb, zba) = (detect_zbb(), detect_zba());
+    assert_eq!(detect_zbb(), zbb, "Zbb detection result");
+    assert_eq!(detect_zba(), zba, "Zba detection result");
+    println!(
+        "zihai > bit-manipulation detection test passed, Zbb: {}, Zba: {}",
+        zbb, zba
+    );
+}
+
+// ISA string of a hart, e.g. "rv64imach"
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct IsaString {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl IsaString {
+    pub const fn new() -> Self {
+        IsaString {
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+    // appends ASCII string to this ISA string; panics if it's too long
+    pub fn push_str(&mut self, s: &str) {
+        let end = self.len + s.len();
+        assert!(end <= self.buf.len(), "ISA string too long");
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+    }
+    pub fn as_str(&self) -> &str {
+        // note(unwrap): only ASCII strings are pushed
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Display for IsaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for IsaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+// Detect if single-precision floating point extension F exists on current hart environment
+//
+// Field `sstatus.FS` is read-only zero if there's no floating point unit;
+// this function tries to turn the unit on and checks if the write takes effect.
+pub fn detect_f_extension() -> bool {
+    let fs_mask = 0b11 << 13; // sstatus.FS
+    let stored_sstatus: usize;
+    let written_sstatus: usize;
+    unsafe {
+        asm!("csrrs {}, sstatus, {}", out(reg) stored_sstatus, in(reg) fs_mask, options(nomem, nostack));
+        asm!("csrr  {}, sstatus", out(reg) written_sstatus, options(nomem, nostack));
+        asm!("csrw  sstatus, {}", in(reg) stored_sstatus, options(nomem, nostack));
+    }
+    written_sstatus & fs_mask != 0
+}
+
+// Detect if hardware updates Accessed and Dirty bits of page table entries (Svadu)
+//
+// Must run after kernel paging is active. This function maps the root page table
+// of `space` read-only with A=0 at `probe_va`, then reads from it. Under Svadu,
+// hardware sets A; under Svade, the read raises a load page fault, which is
+// skipped by detection trap handler. The probe mapping is removed afterwards.
+//
+// The result steers dirty logging, see `vmm::dirty_tracking_strategy`.
+pub fn detect_hw_ad_update<A: FrameAllocator + Clone>(
+    space: &mut PagedAddrSpace<Sv39, A>,
+    probe_va: VirtAddr,
+) -> bool {
+    let vpn = probe_va.page_number::<Sv39>();
+    if space
+        .allocate_map(vpn, space.root_page_number(), 1, Sv39Flags::R)
+        .is_err()
+    {
+        return false;
+    }
+    unsafe { asm!("sfence.vma {}", in(reg) probe_va.0, options(nostack)) };
+    let ans = with_detect_trap(|| unsafe {
+        asm!("ld    {}, 0({})", out(reg) _, in(reg) probe_va.0, options(readonly, nostack));
     });
-    // return the answer from output flag. 0 => success, 2 => failed, illegal instruction
-    ans != 2
+    let mut accessed = false;
+    if let Ok((entry, _)) = space.find_entry_mut(vpn) {
+        accessed = entry.flags().contains(Sv39Flags::A);
+    }
+    // also frees the intermediate page tables created for the probe mapping
+    space.deallocate_map(vpn, 1).expect("remove probe mapping");
+    unsafe { asm!("sfence.vma {}", in(reg) probe_va.0, options(nostack)) };
+    ans.is_ok() && accessed
+}
+
+// Detect the number of implemented physical page number bits on current hart
+//
+// Field PPN of `hgatp` is WARL; this function writes all ones into it under
+// Sv39x4 mode, reads back the bits kept by hardware, then restores `hgatp`.
+// No guest runs on this hart meanwhile, so G-stage translation is not used.
+// Must be used only if hypervisor extension exists.
+pub fn detect_ppn_bits() -> usize {
+    let probe: usize = (8 << 60) | ((1 << 44) - 1); // MODE = Sv39x4, PPN = all ones
+    let (stored, ans): (usize, usize);
+    unsafe {
+        asm!("csrrw {}, 0x680, {}", out(reg) stored, in(reg) probe, options(nomem, nostack)); // 0x680 => hgatp
+        asm!("csrrw {}, 0x680, {}", out(reg) ans, in(reg) stored, options(nomem, nostack));
+        asm!(".word 0x62000073", options(nostack)); // hfence.gvma zero, zero
+    }
+    let ppn = ans & ((1 << 44) - 1);
+    (usize::BITS - ppn.leading_zeros()) as usize
+}
+
+// Paging modes of supervisor address translation in `satp`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum PagingMode {
+    Bare,
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+// Page aligned root table for probing paging modes, filled on each probe
+// as .bss is not cleared on boot
+#[repr(C, align(4096))]
+struct ProbeTable([usize; 512]);
+
+static mut PAGING_PROBE_TABLE: ProbeTable = ProbeTable([0; 512]);
+
+// Detect the widest paging mode supported by current hart
+//
+// Writing an unsupported mode into `satp` has no effect, so this function
+// writes Sv57, Sv48 and Sv39 in turn and reads back which one is kept, then
+// restores `satp`. While a mode is being probed translation goes through a
+// root table of 1 GiB identity leaves: under Sv48 and Sv57 its first entry
+// becomes a 512 GiB or 256 TiB identity leaf, so the running code stays mapped
+// in any mode. Requires the kernel image to be identity mapped, or paging off.
+pub fn detect_max_paging_mode() -> PagingMode {
+    // V | R | W | X | A | D, A and D set to avoid faults without Svadu
+    const LEAF_FLAGS: usize = 0xcf;
+    let table = unsafe { &mut *core::ptr::addr_of_mut!(PAGING_PROBE_TABLE) };
+    for (idx, entry) in table.0.iter_mut().enumerate() {
+        *entry = ((idx << 18) << 10) | LEAF_FLAGS; // PPN of idx GiB
+    }
+    let root_ppn = table.0.as_ptr() as usize >> 12;
+    let sie = sstatus::read().sie();
+    unsafe { sstatus::clear_sie() };
+    let mut ans = PagingMode::Bare;
+    for (mode, bits) in [
+        (PagingMode::Sv57, 10),
+        (PagingMode::Sv48, 9),
+        (PagingMode::Sv39, 8),
+    ] {
+        let probe: usize = (bits << 60) | root_ppn;
+        let read: usize;
+        unsafe {
+            asm!(
+                "csrrw  {stored}, satp, {probe}",
+                "sfence.vma",
+                "csrr   {read}, satp",
+                "csrw   satp, {stored}",
+                "sfence.vma",
+                stored = out(reg) _,
+                probe = in(reg) probe,
+                read = out(reg) read,
+                options(nostack)
+            )
+        };
+        if read >> 60 == bits {
+            ans = mode;
+            break;
+        }
+    }
+    if sie {
+        unsafe { sstatus::set_sie() };
+    }
+    ans
+}
+
+pub(crate) fn test_max_paging_mode() {
+    let mode = detect_max_paging_mode();
+    // QEMU virt machine supports at least Sv39
+    assert!(mode >= PagingMode::Sv39, "paging mode {:?}", mode);
+    assert!(PagingMode::Sv57 > PagingMode::Sv48 && PagingMode::Sv39 > PagingMode::Bare);
+    println!("zihai > max paging mode test passed, current: {:?}", mode);
+}
+
+// Double-precision instruction used for detection, emitted as raw word as the
+// compile target has no floating point support.
+const INSN_FMV_D_X_FT0: u32 = 0xf2000053; // D fmv.d.x ft0, zero
+
+// Detect if double-precision floating point extension D exists on current hart environment
+//
+// Must be used only if F extension exists; it turns floating point unit on
+// and moves an integer to a double-precision register.
+pub fn detect_d_extension() -> bool {
+    let fs_mask = 0b11 << 13; // sstatus.FS
+    let stored_sstatus: usize;
+    unsafe {
+        asm!("csrrs {}, sstatus, {}", out(reg) stored_sstatus, in(reg) fs_mask, options(nomem, nostack));
+    }
+    let ans = with_detect_trap(|| unsafe {
+        asm!(".word {}", const INSN_FMV_D_X_FT0, options(nomem, nostack));
+    });
+    unsafe { asm!("csrw  sstatus, {}", in(reg) stored_sstatus, options(nomem, nostack)) };
+    probe_exists(ans)
+}
+
+// ISA extensions of a hart, used to compose its ISA string.
+//
+// Register `misa` is only accessible in machine mode, so the hypervisor composes
+// the ISA string from detection results instead.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct IsaExtensions {
+    pub m: bool,
+    pub a: bool,
+    pub f: bool,
+    pub d: bool,
+    pub c: bool,
+    pub h: bool,
+    pub zba: bool,
+    pub zbb: bool,
+    pub sstc: bool,
+}
+
+impl IsaExtensions {
+    // Detects ISA extensions of current hart
+    //
+    // M, A and C extensions come from the compile target, as this hypervisor
+    // cannot run without them; other extensions are detected on current hart.
+    pub fn detect() -> Self {
+        let f = detect_f_extension();
+        IsaExtensions {
+            m: cfg!(target_feature = "m"),
+            a: cfg!(target_feature = "a"),
+            f,
+            d: f && detect_d_extension(),
+            c: cfg!(target_feature = "c"),
+            h: detect_h_extension(),
+            zba: detect_zba(),
+            zbb: detect_zbb(),
+            sstc: detect_sstc_extension(),
+        }
+    }
+    // Composes ISA string in canonical order, e.g. "rv64imafdch_zba_zbb_sstc"
+    pub fn to_isa_string(self) -> IsaString {
+        let mut ans = IsaString::new();
+        #[cfg(target_pointer_width = "64")]
+        ans.push_str("rv64i");
+        #[cfg(target_pointer_width = "32")]
+        ans.push_str("rv32i");
+        let single_letters = [
+            (self.m, "m"),
+            (self.a, "a"),
+            (self.f, "f"),
+            (self.d, "d"),
+            (self.c, "c"),
+            (self.h, "h"),
+        ];
+        // multi-letter extensions: Z extensions before S extensions
+        let multi_letters = [(self.zba, "_zba"), (self.zbb, "_zbb"), (self.sstc, "_sstc")];
+        for (exists, name) in single_letters.into_iter().chain(multi_letters) {
+            if exists {
+                ans.push_str(name);
+            }
+        }
+        ans
+    }
+}
+
+// Gets ISA string of current hart
+pub fn isa_string() -> IsaString {
+    IsaExtensions::detect().to_isa_string()
+}
+
+pub(crate) fn test_isa_string() {
+    let base = IsaExtensions {
+        m: true,
+        a: true,
+        c: true,
+        ..Default::default()
+    };
+    assert_eq!(base.to_isa_string().as_str(), "rv64imac", "base ISA");
+    let general = IsaExtensions {
+        f: true,
+        d: true,
+        h: true,
+        ..base
+    };
+    assert_eq!(
+        general.to_isa_string().as_str(),
+        "rv64imafdch",
+        "general ISA"
+    );
+    let extended = IsaExtensions {
+        zba: true,
+        zbb: true,
+        sstc: true,
+        ..general
+    };
+    assert_eq!(
+        extended.to_isa_string().as_str(),
+        "rv64imafdch_zba_zbb_sstc",
+        "multi-letter extensions"
+    );
+    let no_f = IsaExtensions {
+        h: true,
+        sstc: true,
+        ..base
+    };
+    assert_eq!(
+        no_f.to_isa_string().as_str(),
+        "rv64imach_sstc",
+        "without floating point"
+    );
+    println!("zihai > ISA string test passed, current: {}", isa_string());
 }
 
 // Tries to execute all instructions defined in clojure `f`.
-// If resulted in an exception, this function returns its exception id.
+// If resulted in an exception, this function returns the last exception and its `stval`.
 //
 // This function is useful to detect if an instruction exists on current environment.
+// Detections may nest, e.g. `f` may call `detect_csr_read`; each detection
+// records its answer into its own context on the stack.
 #[inline]
-fn with_detect_trap(param: usize, f: impl FnOnce()) -> usize {
+pub(crate) fn with_detect_trap(f: impl FnOnce()) -> Result<(), DetectTrap> {
+    let mut ctx = DetectContext { trap: None };
+    let ctx_ptr = &mut ctx as *mut DetectContext;
     // disable interrupts and handle exceptions only
-    let (sie, stvec, tp) = unsafe { init_detect_trap(param) };
+    let (sie, stvec, tp) = unsafe { init_detect_trap(ctx_ptr) };
     // run detection inner
     f();
     // restore trap handler and enable interrupts
-    let ans = unsafe { restore_detect_trap(sie, stvec, tp) };
-    // return the answer
-    ans
+    let current = unsafe { restore_detect_trap(sie, stvec, tp) };
+    debug_assert_eq!(current, ctx_ptr as usize, "detect context kept in tp");
+    // the trap handler writes the answer behind the compiler's back
+    match unsafe { core::ptr::read_volatile(ctx_ptr) }.trap {
+        Some(trap) => Err(trap),
+        None => Ok(()),
+    }
+}
+
+// Exception raised while running a detection
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DetectTrap {
+    pub exception: Exception,
+    pub stval: usize,
+}
+
+impl DetectTrap {
+    // The probed instruction or CSR does not exist, or is not accessible
+    pub fn is_illegal_instruction(&self) -> bool {
+        self.exception == Exception::IllegalInstruction
+    }
+}
+
+// Whether the probed instruction or CSR exists, i.e. it did not raise illegal instruction
+fn probe_exists(ans: Result<(), DetectTrap>) -> bool {
+    !matches!(ans, Err(trap) if trap.is_illegal_instruction())
+}
+
+// Per-detection context, pointed to by tp register while the detection runs
+//
+// The previous tp, which may point to the context of an outer detection, is
+// saved by `init_detect_trap` and restored afterwards.
+#[repr(C)]
+struct DetectContext {
+    // the last exception, if any
+    trap: Option<DetectTrap>,
 }
 
 // rust trap handler for detect exceptions
 extern "C" fn rust_detect_trap(trap_frame: &mut TrapFrame) {
-    // store returned exception id value into tp register
-    // specially: illegal instruction => 2
-    trap_frame.tp = trap_frame.scause.bits();
+    // store the exception into current detect context
+    let ctx = trap_frame.tp as *mut DetectContext;
+    if let Trap::Exception(exception) = trap_frame.scause.cause() {
+        let stval = trap_frame.stval;
+        unsafe { (*ctx).trap = Some(DetectTrap { exception, stval }) };
+    }
     // if illegal instruction, skip current instruction
     match trap_frame.scause.cause() {
         Trap::Exception(Exception::IllegalInstruction) => {
-            let mut insn_bits = riscv_illegal_insn_bits((trap_frame.stval & 0xFFFF) as u16);
-            if insn_bits == 0 {
-                let insn_half = unsafe { *(trap_frame.sepc as *const u16) };
-                insn_bits = riscv_illegal_insn_bits(insn_half);
-            }
+            // stval may hold the instruction bits; zero means either the hardware
+            // does not provide them, or the instruction is all-zero `c.unimp`.
+            // Either way the instruction at sepc is readable S-mode code.
+            let insn_half = match (trap_frame.stval & 0xFFFF) as u16 {
+                0 => unsafe { *(trap_frame.sepc as *const u16) },
+                stval_half => stval_half,
+            };
             // skip current instruction
-            trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_bits);
+            skip_insn(trap_frame, insn_half);
+        }
+        Trap::Exception(
+            Exception::InstructionMisaligned
+            | Exception::InstructionFault
+            | Exception::InstructionPageFault,
+        ) => {
+            // there is no instruction at sepc to skip
+            panic!(
+                "instruction fetch exception during detection, sepc: {:#x}",
+                trap_frame.sepc
+            )
+        }
+        Trap::Exception(_) => {
+            // e.g. the load probing hardware A/D bit update, or an unexpected
+            // data access exception; the caller sees the exception
+            let insn_half = unsafe { *(trap_frame.sepc as *const u16) };
+            skip_insn(trap_frame, insn_half);
         }
-        Trap::Exception(_) => unreachable!(), // FIXME: unexpected instruction errors
-        Trap::Interrupt(_) => unreachable!(), // filtered out for sie == false
+        Trap::Interrupt(_) => {} // filtered out for sie == false
     }
 }
 
-// Gets risc-v instruction bits from illegal instruction stval value, or 0 if unknown
-#[inline]
-fn riscv_illegal_insn_bits(insn: u16) -> usize {
-    if insn == 0 {
-        return 0; // stval[0..16] == 0, unknown
+// Moves sepc past the trapped instruction whose lowest 16 bits are `insn_half`
+//
+// An instruction of unknown length cannot be skipped; resuming at the same sepc
+// would trap forever, so this panics instead.
+fn skip_insn(trap_frame: &mut TrapFrame, insn_half: u16) {
+    match riscv_insn_bits(insn_half) {
+        Some(insn_bits) => trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_bits),
+        None => panic!(
+            "unknown instruction length during detection, sepc: {:#x}, instruction: {:#06x}",
+            trap_frame.sepc, insn_half
+        ),
     }
+}
+
+// Gets length in bytes of a risc-v instruction from its lowest 16 bits, or None
+// if it is a reserved encoding of 80 bits or longer
+#[inline]
+fn riscv_insn_bits(insn: u16) -> Option<usize> {
     if insn & 0b11 != 0b11 {
-        return 2; // 16-bit
+        return Some(2); // 16-bit, including all-zero c.unimp
     }
     if insn & 0b11100 != 0b11100 {
-        return 4; // 32-bit
+        return Some(4); // 32-bit
     }
-    // FIXME: add >= 48-bit instructions in the future if we need to detect such instrucions
-    return 0; // >= 48-bit, unknown from this function by now
+    if insn & 0b111111 == 0b011111 {
+        return Some(6); // 48-bit
+    }
+    if insn & 0b1111111 == 0b0111111 {
+        return Some(8); // 64-bit
+    }
+    None // >= 80-bit, reserved
+}
+
+pub(crate) fn test_detect_trap_insn_len() {
+    assert_eq!(riscv_insn_bits(0x0000), Some(2), "c.unimp");
+    assert_eq!(riscv_insn_bits(0x4285), Some(2), "c.li");
+    assert_eq!(riscv_insn_bits(0x0073), Some(4), "ecall");
+    assert_eq!(riscv_insn_bits(0x001f), Some(6), "48-bit");
+    assert_eq!(riscv_insn_bits(0x003f), Some(8), "64-bit");
+    assert_eq!(riscv_insn_bits(0x007f), None, "reserved");
+    // c.unimp is illegal with all-zero stval; only c.li t0, 1 right after it
+    // runs if the trap handler skips exactly 2 bytes
+    let mut t0 = 0;
+    let ans = with_detect_trap(|| unsafe {
+        asm!(
+            "li     t0, 0",
+            ".2byte 0x0000", // c.unimp
+            ".2byte 0x4285", // c.li t0, 1
+            out("t0") t0,
+            options(nomem, nostack),
+        );
+    });
+    assert!(
+        ans.unwrap_err().is_illegal_instruction(),
+        "illegal instruction trapped"
+    );
+    assert_eq!(t0, 1, "compressed illegal instruction skipped by 2 bytes");
+    println!("zihai > detect trap instruction length test passed");
 }
 
 // Initialize environment for trap detection and filter in exception only
 #[inline]
-unsafe fn init_detect_trap(param: usize) -> (bool, Stvec, usize) {
+unsafe fn init_detect_trap(ctx: *mut DetectContext) -> (bool, Stvec, usize) {
     // clear SIE to handle exception only
     let stored_sie = sstatus::read().sie();
     sstatus::clear_sie();
     // use detect trap handler to handle exceptions
     let stored_stvec = stvec::read();
-    let mut trap_addr = on_detect_trap as usize;
+    let mut trap_addr = on_detect_trap as *const () as usize;
     if trap_addr & 0b1 != 0 {
         trap_addr += 0b1;
     }
     stvec::write(trap_addr, TrapMode::Direct);
-    // store tp register. tp will be used to load parameter and store return value
+    // store tp register. tp points to detect context while detection runs;
+    // not `nomem`, as the context must be in memory before any trap
     let stored_tp: usize;
-    asm!("mv  {}, tp", "mv  tp, {}", out(reg) stored_tp, in(reg) param, options(nomem, nostack));
+    asm!("mv  {}, tp", "mv  tp, {}", out(reg) stored_tp, in(reg) ctx, options(nostack));
     // returns preserved previous hardware states
     (stored_sie, stored_stvec, stored_tp)
 }
@@ -99,18 +698,174 @@ unsafe fn init_detect_trap(param: usize) -> (bool, Stvec, usize) {
 // Restore previous hardware states before trap detection
 #[inline]
 unsafe fn restore_detect_trap(sie: bool, stvec: Stvec, tp: usize) -> usize {
-    // read the return value from tp register, and restore tp value
-    let ans: usize;
-    asm!("mv  {}, tp", "mv  tp, {}", out(reg) ans, in(reg) tp, options(nomem, nostack));
+    restore_detect_trap_with(&mut HartCsrs, sie, stvec.bits(), tp)
+}
+
+// Restores hardware states in order.
+//
+// Interrupts stay disabled while `tp` and `stvec` are being restored; `sstatus.SIE`
+// is re-enabled last, so a pending interrupt can only fire with the previous
+// trap vector fully restored, and never between restoring `tp` and reading the answer.
+#[inline]
+fn restore_detect_trap_with<C: DetectTrapCsrs>(
+    csrs: &mut C,
+    sie: bool,
+    stvec: usize,
+    tp: usize,
+) -> usize {
+    // read the detect context from tp register, and restore tp value
+    let ans = csrs.swap_tp(tp);
     // restore trap vector settings
-    asm!("csrw  stvec, {}", in(reg) stvec.bits(), options(nomem, nostack));
+    csrs.write_stvec(stvec);
     // enable interrupts
     if sie {
-        sstatus::set_sie();
-    };
+        csrs.set_sie();
+    }
     ans
 }
 
+// Registers modified when restoring states after trap detection
+trait DetectTrapCsrs {
+    // writes tp and returns its previous value
+    fn swap_tp(&mut self, tp: usize) -> usize;
+    fn write_stvec(&mut self, bits: usize);
+    fn set_sie(&mut self);
+}
+
+// Registers of current hart
+struct HartCsrs;
+
+impl DetectTrapCsrs for HartCsrs {
+    #[inline]
+    fn swap_tp(&mut self, tp: usize) -> usize {
+        let ans: usize;
+        // not `nomem`, as the answer in detect context is read afterwards
+        unsafe { asm!("mv  {}, tp", "mv  tp, {}", out(reg) ans, in(reg) tp, options(nostack)) };
+        ans
+    }
+    #[inline]
+    fn write_stvec(&mut self, bits: usize) {
+        unsafe { asm!("csrw  stvec, {}", in(reg) bits, options(nomem, nostack)) };
+    }
+    #[inline]
+    fn set_sie(&mut self) {
+        unsafe { sstatus::set_sie() };
+    }
+}
+
+pub(crate) fn test_detect_trap_result() {
+    assert_eq!(with_detect_trap(|| {}), Ok(()), "no exception");
+    let ans = with_detect_trap(|| unsafe {
+        asm!(".2byte 0x0000", options(nomem, nostack)); // c.unimp
+    });
+    // stval is zero both when it holds the all-zero instruction bits and
+    // when the hardware does not provide them
+    let expected = DetectTrap {
+        exception: Exception::IllegalInstruction,
+        stval: 0,
+    };
+    assert_eq!(ans, Err(expected), "illegal instruction with stval");
+    assert!(!probe_exists(ans), "illegal instruction means absent");
+    assert!(probe_exists(Ok(())), "no exception means present");
+    println!("zihai > detect trap result test passed");
+}
+
+pub(crate) fn test_nested_detect_trap() {
+    let mut inner = Ok(());
+    let outer = with_detect_trap(|| {
+        inner = with_detect_trap(|| unsafe {
+            asm!(".2byte 0x0000", options(nomem, nostack)); // c.unimp
+        });
+    });
+    assert!(
+        inner.unwrap_err().is_illegal_instruction(),
+        "inner detection trapped"
+    );
+    assert_eq!(outer, Ok(()), "outer detection unaffected");
+    let outer = with_detect_trap(|| {
+        inner = with_detect_trap(|| {});
+        unsafe { asm!(".2byte 0x0000", options(nomem, nostack)) }; // c.unimp
+    });
+    assert_eq!(inner, Ok(()), "inner detection without trap");
+    assert!(
+        outer.unwrap_err().is_illegal_instruction(),
+        "outer detection trapped after inner one"
+    );
+    let mut time = None;
+    let outer = with_detect_trap(|| time = detect_csr_read::<0xC01>()); // 0xC01 => time
+    assert!(time.is_some(), "csr read inside detection");
+    assert_eq!(outer, Ok(()), "outer detection unaffected by csr read");
+    // breakpoint is not expected by any detection; it is recorded and
+    // skipped instead of panicking in the trap handler
+    let ans = with_detect_trap(|| unsafe { asm!("ebreak", options(nomem, nostack)) });
+    let trap = ans.unwrap_err();
+    assert_eq!(
+        trap.exception,
+        Exception::Breakpoint,
+        "unexpected exception recorded"
+    );
+    assert!(!trap.is_illegal_instruction());
+    println!("zihai > nested detect trap test passed");
+}
+
+pub(crate) fn test_restore_detect_trap() {
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    enum Op {
+        SwapTp(usize),
+        WriteStvec(usize),
+        SetSie,
+    }
+    struct MockCsrs {
+        ops: [Option<Op>; 4],
+        len: usize,
+        tp: usize,
+    }
+    impl MockCsrs {
+        fn record(&mut self, op: Op) {
+            self.ops[self.len] = Some(op);
+            self.len += 1;
+        }
+    }
+    impl DetectTrapCsrs for MockCsrs {
+        fn swap_tp(&mut self, tp: usize) -> usize {
+            self.record(Op::SwapTp(tp));
+            core::mem::replace(&mut self.tp, tp)
+        }
+        fn write_stvec(&mut self, bits: usize) {
+            self.record(Op::WriteStvec(bits));
+        }
+        fn set_sie(&mut self) {
+            self.record(Op::SetSie);
+        }
+    }
+    let mut csrs = MockCsrs {
+        ops: [None; 4],
+        len: 0,
+        tp: 0x80201000, // detect context
+    };
+    let ans = restore_detect_trap_with(&mut csrs, true, 0x80200000, 0x1234);
+    assert_eq!(ans, 0x80201000, "context from tp");
+    assert_eq!(csrs.tp, 0x1234, "tp restored");
+    let expected = [
+        Some(Op::SwapTp(0x1234)),
+        Some(Op::WriteStvec(0x80200000)),
+        Some(Op::SetSie),
+        None,
+    ];
+    assert_eq!(
+        csrs.ops, expected,
+        "stvec restored before interrupts enabled"
+    );
+    let mut csrs = MockCsrs {
+        ops: [None; 4],
+        len: 0,
+        tp: 0,
+    };
+    restore_detect_trap_with(&mut csrs, false, 0x80200000, 0x1234);
+    assert_eq!(csrs.len, 2, "interrupts stay disabled");
+    println!("zihai > detect trap restore order test passed");
+}
+
 // Trap frame for instruction exception detection
 #[repr(C)]
 struct TrapFrame {
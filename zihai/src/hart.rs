@@ -0,0 +1,410 @@
+//! Hart module
+//!
+//! Includes per-hart states preserved across non-retentive suspension,
+//! and the roster of harts with their capabilities
+#![allow(unused)] // use in the future
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::detect::{self, IsaString};
+use crate::mm::{FrameAllocError, FrameAllocator, PhysPageNum, Sv39};
+use crate::sbi;
+use crate::trap::{self, HartMessage};
+
+/// Maximum number of harts supported by this hypervisor
+pub const MAX_HARTS: usize = 8;
+
+// Hart states to restore after non-retentive suspension.
+//
+// Non-retentive suspension loses all CSR states including `satp`, and the
+// resumed hart begins with an undefined stack pointer. The boot hart fills
+// these states in before other harts are woken up. The trap vector is not saved
+// here, as `trap::init` installs it on every hart.
+//
+// Field `stack_top` must be the first field; it's loaded by `resume_entry`.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HartSavedState {
+    /// Stack pointer `sp` of this hart on resume
+    pub stack_top: usize,
+    /// Kernel address space `satp` value
+    pub satp: usize,
+}
+
+impl HartSavedState {
+    // a state that has not been prepared by the boot hart
+    pub const fn empty() -> Self {
+        HartSavedState {
+            stack_top: 0,
+            satp: 0,
+        }
+    }
+    // computes the stack top the same way as the boot procedure `start` does
+    pub const fn new(hartid: usize, stack_base: usize, stack_size: usize, satp: usize) -> Self {
+        HartSavedState {
+            stack_top: stack_base + stack_size * (hartid + 1),
+            satp,
+        }
+    }
+    // uses a stack top prepared elsewhere, e.g. a stack backed by allocated frames
+    pub const fn with_stack_top(stack_top: usize, satp: usize) -> Self {
+        HartSavedState { stack_top, satp }
+    }
+}
+
+static mut HART_SAVED_STATES: [HartSavedState; MAX_HARTS] = [HartSavedState::empty(); MAX_HARTS];
+
+// Saves the state for a hart to use when it resumes.
+//
+// The caller must ensure the hart `hartid` is not running or resuming at the same time.
+pub unsafe fn save_for_resume(hartid: usize, state: HartSavedState) {
+    assert!(hartid < MAX_HARTS, "hart id {} out of range", hartid);
+    HART_SAVED_STATES[hartid] = state;
+}
+
+// Gets the saved state of a hart
+pub fn saved_state(hartid: usize) -> &'static HartSavedState {
+    assert!(hartid < MAX_HARTS, "hart id {} out of range", hartid);
+    unsafe { &*core::ptr::addr_of!(HART_SAVED_STATES[hartid]) }
+}
+
+// Claim of the boot hart role.
+//
+// SBI starts only one hart, chosen by firmware, and passes its id in `a0`;
+// it is not necessarily hart 0. Harts that enter `_start` otherwise, e.g. on
+// platforms that release all harts at once, lose the claim and are suspended.
+// The flag is a word so that `_start` can claim it with `amoswap.w`.
+#[repr(transparent)]
+pub struct BootClaim(AtomicU32);
+
+impl BootClaim {
+    pub const fn new() -> Self {
+        BootClaim(AtomicU32::new(0))
+    }
+    // Claims the boot hart role; only the first hart to arrive succeeds.
+    //
+    // Uses the same `amoswap.w.aq` as `_start` does on `BOOT_CLAIM`.
+    pub fn try_claim(&self) -> bool {
+        let prev: usize;
+        let flag = &self.0 as *const AtomicU32;
+        unsafe {
+            asm!("amoswap.w.aq {}, {}, ({})", out(reg) prev, in(reg) 1, in(reg) flag, options(nostack))
+        };
+        prev == 0
+    }
+    pub fn is_claimed(&self) -> bool {
+        self.0.load(Ordering::Relaxed) != 0
+    }
+}
+
+// Starts all harts other than the boot hart at `resume_entry`.
+//
+// States for the harts to resume with must be saved before. Hart ids not
+// present on this platform fail to start and are skipped. Waits until all
+// started harts have registered in the roster; returns the number of them.
+pub fn start_secondary_harts(boot_hartid: usize) -> usize {
+    let start_addr = resume_entry as *const () as usize;
+    let started = (0..MAX_HARTS)
+        .filter(|&hartid| hartid != boot_hartid)
+        .filter(|&hartid| sbi::hart_start(hartid, start_addr, 0).error == sbi::SBI_SUCCESS)
+        .count();
+    while roster().lock().len() < started + 1 {
+        core::hint::spin_loop();
+    }
+    started
+}
+
+// Calls `f` on every registered hart other than `current` through their mailboxes.
+//
+// Returns the number of harts called without waiting for them to finish.
+pub fn call_on_other_harts(current: usize, f: fn(usize)) -> usize {
+    let others: Vec<usize> = roster()
+        .lock()
+        .iter()
+        .map(|h| h.hartid)
+        .filter(|&hartid| hartid != current)
+        .collect();
+    let mut hart_mask = 0;
+    for &hartid in &others {
+        trap::mailbox(hartid).post(HartMessage::Call { f });
+        hart_mask |= 1 << hartid;
+    }
+    if hart_mask != 0 {
+        sbi::send_ipi(hart_mask, 0);
+    }
+    others.len()
+}
+
+// Calls `f` on hart `hartid` through its mailbox without waiting for it to finish
+pub fn call_on_hart(hartid: usize, f: fn(usize)) {
+    trap::mailbox(hartid).post(HartMessage::Call { f });
+    sbi::send_ipi(1 << hartid, 0);
+}
+
+// Claimed by the first hart entering `_start`; placed in `.data` since `.bss`
+// is not cleared before the claim
+#[link_section = ".data"]
+pub static BOOT_CLAIM: BootClaim = BootClaim::new();
+
+// Stack of a secondary hart backed by contiguous frames from frame allocator.
+//
+// Only the boot hart needs a static stack for the earliest code; stacks of
+// other harts are allocated by the boot hart before they are resumed. Frames
+// are identically mapped in kernel address space, so the stack top is the end
+// physical address of these frames.
+#[derive(Debug)]
+pub struct FrameStack<A: FrameAllocator> {
+    base: PhysPageNum,
+    frame_count: usize,
+    frame_alloc: A,
+}
+
+impl<A: FrameAllocator> FrameStack<A> {
+    // allocates a stack of at least `size` bytes, rounded up to whole frames
+    pub fn try_new_in(size: usize, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        let frame_size = 1 << <Sv39 as crate::mm::PageMode>::FRAME_SIZE_BITS;
+        let frame_count = size.div_ceil(frame_size);
+        let base = frame_alloc.allocate_frames(frame_count, 0)?;
+        Ok(FrameStack {
+            base,
+            frame_count,
+            frame_alloc,
+        })
+    }
+    // initial stack pointer of this stack; the stack grows downwards
+    pub fn stack_top(&self) -> usize {
+        self.base.addr_begin::<Sv39>().0 + self.size()
+    }
+    pub fn size(&self) -> usize {
+        self.frame_count << <Sv39 as crate::mm::PageMode>::FRAME_SIZE_BITS
+    }
+    // Keeps the frames for the whole lifetime of hypervisor, returning the stack top.
+    //
+    // A hart runs on its stack until the system resets, so its frames are never freed.
+    pub fn leak(self) -> usize {
+        let stack_top = self.stack_top();
+        core::mem::forget(self);
+        stack_top
+    }
+}
+
+impl<A: FrameAllocator> Drop for FrameStack<A> {
+    fn drop(&mut self) {
+        self.frame_alloc
+            .deallocate_frames(self.base, self.frame_count);
+    }
+}
+
+// Restores the hart state after non-retentive suspension.
+//
+// Stack pointer `sp` is already re-established by `resume_entry` before any
+// Rust code runs; this function re-activates the kernel address space. The
+// trap vector is re-installed by `trap::init` afterwards. Must be called first
+// thing in `rust_init_harts`.
+pub unsafe fn restore_after_suspend(saved: &HartSavedState) {
+    asm!("csrw  satp, {}", "sfence.vma", in(reg) saved.satp, options(nostack));
+}
+
+// Resume address of harts after non-retentive suspension.
+//
+// SBI HSM resumes the hart with a0 = hartid, a1 = opaque. We load the stack
+// pointer from the saved state of this hart before entering Rust code; if the
+// boot hart has not prepared the state, we halt this hart.
+#[naked]
+pub unsafe extern "C" fn resume_entry() -> ! {
+    asm!(
+        "li     t0, {max_harts}",
+        "bgeu   a0, t0, 1f",
+        "la     t1, {saved_states}",
+        "li     t2, {state_size}",
+        "mul    t2, t2, a0",
+        "add    t1, t1, t2",
+        "ld     sp, 0(t1)", // HartSavedState::stack_top
+        "beqz   sp, 1f",
+        "tail   {rust_init_harts}",
+        "1:",
+        "wfi",
+        "j      1b",
+        max_harts = const MAX_HARTS,
+        saved_states = sym HART_SAVED_STATES,
+        state_size = const core::mem::size_of::<HartSavedState>(),
+        rust_init_harts = sym crate::rust_init_harts,
+        options(noreturn)
+    )
+}
+
+// Running state of a hart
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HartState {
+    Started,
+    Suspended,
+    Stopped,
+}
+
+// Capabilities of a hart, filled during its initialization
+#[derive(Clone, Debug)]
+pub struct HartInfo {
+    pub hartid: usize,
+    pub has_h: bool,
+    pub has_sstc: bool,
+    pub isa_string: IsaString,
+    pub state: HartState,
+}
+
+impl HartInfo {
+    // detects capabilities of current hart
+    pub fn detect(hartid: usize, state: HartState) -> Self {
+        HartInfo {
+            hartid,
+            has_h: detect::detect_h_extension(),
+            has_sstc: detect::detect_sstc_extension(),
+            isa_string: detect::isa_string(),
+            state,
+        }
+    }
+}
+
+// Roster of harts, sorted by hart id.
+//
+// Harts register themselves concurrently during initialization. Harts capable
+// of hardware virtualization may host guests, while others may still be used
+// in supervisor level i/o, networking or monitoring procedures.
+pub struct HartRoster {
+    harts: spin::Mutex<Vec<HartInfo>>,
+}
+
+impl HartRoster {
+    pub const fn new() -> Self {
+        HartRoster {
+            harts: spin::Mutex::new(Vec::new()),
+        }
+    }
+    // adds or replaces information of a hart
+    pub fn register(&self, info: HartInfo) {
+        let mut harts = self.harts.lock();
+        match harts.binary_search_by_key(&info.hartid, |h| h.hartid) {
+            Ok(idx) => harts[idx] = info,
+            Err(idx) => harts.insert(idx, info),
+        }
+    }
+    // locks the roster to read information of all harts
+    pub fn lock(&self) -> spin::MutexGuard<'_, Vec<HartInfo>> {
+        self.harts.lock()
+    }
+    // ids of harts that are capable of hosting guests
+    pub fn guest_capable_harts(&self) -> Vec<usize> {
+        let harts = self.harts.lock();
+        harts.iter().filter(|h| h.has_h).map(|h| h.hartid).collect()
+    }
+}
+
+static HART_ROSTER: HartRoster = HartRoster::new();
+
+// Gets the roster of all harts in this system
+pub fn roster() -> &'static HartRoster {
+    &HART_ROSTER
+}
+
+pub(crate) fn test_roster() {
+    let mocked = |hartid, has_h| {
+        let mut isa_string = IsaString::new();
+        isa_string.push_str(if has_h { "rv64imach" } else { "rv64imac" });
+        HartInfo {
+            hartid,
+            has_h,
+            has_sstc: false,
+            isa_string,
+            state: HartState::Started,
+        }
+    };
+    let roster = HartRoster::new();
+    roster.register(mocked(2, true));
+    roster.register(mocked(0, true));
+    roster.register(mocked(1, false));
+    assert_eq!(roster.guest_capable_harts(), [0, 2], "H-capable harts");
+    roster.register(mocked(2, false));
+    assert_eq!(roster.guest_capable_harts(), [0], "hart 2 re-registered");
+    let harts = roster.lock();
+    assert_eq!(harts.len(), 3, "number of harts");
+    assert_eq!(harts[1].isa_string.as_str(), "rv64imac", "isa string");
+    drop(harts);
+    println!("zihai > hart roster test passed");
+}
+
+pub(crate) fn test_saved_state() {
+    let state = HartSavedState::new(0, 0x80210000, 64 * 1024, 0);
+    assert_eq!(state.stack_top, 0x80220000, "boot hart stack top");
+    let state = HartSavedState::new(3, 0x80210000, 64 * 1024, 0);
+    assert_eq!(state.stack_top, 0x80250000, "hart 3 stack top");
+    let satp = (8 << 60) | 0x80400; // Sv39, asid 0, root ppn 0x80400
+    let state = HartSavedState::new(1, 0x80210000, 64 * 1024, satp);
+    assert_eq!(state.satp, satp, "resumed hart kernel satp");
+    assert_eq!(
+        HartSavedState::empty().stack_top,
+        0,
+        "unprepared state halts the hart"
+    );
+    println!("zihai > hart saved state test passed");
+}
+
+pub(crate) fn test_frame_stack(frame_alloc: &crate::mm::DefaultFrameAllocator) {
+    let checkpoint = frame_alloc.lock().checkpoint();
+    let stack1 = FrameStack::try_new_in(64 * 1024, frame_alloc).unwrap();
+    let stack2 = FrameStack::try_new_in(64 * 1024 - 100, frame_alloc).unwrap();
+    assert_eq!(stack1.size(), 64 * 1024, "whole frames");
+    assert_eq!(stack2.size(), 64 * 1024, "rounded up to whole frames");
+    assert_eq!(stack1.stack_top() % 16, 0, "stack pointer alignment");
+    let base1 = stack1.stack_top() - stack1.size();
+    let base2 = stack2.stack_top() - stack2.size();
+    assert!(
+        stack1.stack_top() <= base2 || stack2.stack_top() <= base1,
+        "stacks do not overlap"
+    );
+    let satp = (8 << 60) | 0x80400; // Sv39, asid 0, root ppn 0x80400
+    let state = HartSavedState::with_stack_top(stack2.stack_top(), satp);
+    assert_eq!(state.stack_top, base2 + 64 * 1024, "resumed hart stack top");
+    drop((stack1, stack2));
+    frame_alloc.lock().assert_restored(checkpoint);
+    println!("zihai > frame backed stack test passed");
+}
+
+pub(crate) fn test_boot_claim(hartid: usize) {
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+    static CLAIM: BootClaim = BootClaim::new();
+    static READY: AtomicUsize = AtomicUsize::new(0);
+    static GO: AtomicBool = AtomicBool::new(false);
+    static WINS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    // all harts wait at the same line, then claim at once
+    fn race(_hartid: usize) {
+        READY.fetch_add(1, Ordering::AcqRel);
+        while !GO.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        if CLAIM.try_claim() {
+            WINS.fetch_add(1, Ordering::AcqRel);
+        }
+        DONE.fetch_add(1, Ordering::Release);
+    }
+    assert!(BOOT_CLAIM.is_claimed(), "claimed by current boot hart");
+    assert!(!CLAIM.is_claimed(), "not claimed before any hart arrives");
+    let others = call_on_other_harts(hartid, race);
+    while READY.load(Ordering::Acquire) < others {
+        core::hint::spin_loop();
+    }
+    GO.store(true, Ordering::Release);
+    race(hartid);
+    while DONE.load(Ordering::Acquire) < others + 1 {
+        core::hint::spin_loop();
+    }
+    assert_eq!(
+        WINS.load(Ordering::Acquire),
+        1,
+        "exactly one of {} racing harts wins",
+        others + 1
+    );
+    assert!(CLAIM.is_claimed(), "claimed after the race");
+    assert!(!CLAIM.try_claim(), "late arrival loses");
+    println!("zihai > boot hart claim test passed");
+}
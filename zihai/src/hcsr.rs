@@ -0,0 +1,130 @@
+//! Hypervisor CSR module
+//!
+//! Includes structured views of hypervisor extension CSRs
+#![allow(unused)] // use in the future
+use bit_field::BitField;
+use core::arch::asm;
+
+// Hypervisor status register `hstatus`.
+//
+// On a trap from a guest, these fields describe the context that trapped,
+// e.g. whether `stval` holds a guest virtual address.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct HStatus {
+    bits: usize,
+}
+
+impl HStatus {
+    pub const fn from_bits(bits: usize) -> Self {
+        HStatus { bits }
+    }
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+    // Supervisor Previous Virtualization mode; trap taken from a guest (VS or VU mode)
+    pub fn spv(&self) -> bool {
+        self.bits.get_bit(7)
+    }
+    // Supervisor Previous Virtual Privilege; guest was in VS mode rather than VU mode
+    pub fn spvp(&self) -> bool {
+        self.bits.get_bit(8)
+    }
+    // Guest Virtual Address; the trap wrote a guest virtual address into `stval`
+    pub fn gva(&self) -> bool {
+        self.bits.get_bit(6)
+    }
+    // XLEN of VS mode encoded as in `misa.MXL`: 1 => 32, 2 => 64, 3 => 128
+    pub fn vsxl(&self) -> usize {
+        self.bits.get_bits(32..34)
+    }
+}
+
+impl core::fmt::Debug for HStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HStatus")
+            .field("spv", &self.spv())
+            .field("spvp", &self.spvp())
+            .field("gva", &self.gva())
+            .field("vsxl", &self.vsxl())
+            .finish()
+    }
+}
+
+// Reads `hstatus` of current hart; requires hypervisor extension
+pub fn read_hstatus() -> HStatus {
+    let bits: usize;
+    unsafe { asm!("csrr  {}, 0x600", out(reg) bits, options(nomem, nostack)) }; // 0x600 => hstatus
+    HStatus::from_bits(bits)
+}
+
+// Writes `hstatus` of current hart
+pub unsafe fn write_hstatus(bits: usize) {
+    asm!("csrw  0x600, {}", in(reg) bits, options(nomem, nostack)); // 0x600 => hstatus
+}
+
+// Reads `htval` of current hart, the guest physical address shifted right by 2 on guest page faults
+pub fn read_htval() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr  {}, 0x643", out(reg) bits, options(nomem, nostack)) }; // 0x643 => htval
+    bits
+}
+
+// Reads `htinst` of current hart, the transformed trapping instruction or zero
+pub fn read_htinst() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr  {}, 0x64A", out(reg) bits, options(nomem, nostack)) }; // 0x64A => htinst
+    bits
+}
+
+// Reads `hvip` of current hart, interrupts injected into VS mode
+pub fn read_hvip() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr  {}, 0x645", out(reg) bits, options(nomem, nostack)) }; // 0x645 => hvip
+    bits
+}
+
+// Writes `hvip` of current hart
+pub unsafe fn write_hvip(bits: usize) {
+    asm!("csrw  0x645, {}", in(reg) bits, options(nomem, nostack)); // 0x645 => hvip
+}
+
+// Reads `hie` of current hart, enables of VS-level interrupts
+pub fn read_hie() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr  {}, 0x604", out(reg) bits, options(nomem, nostack)) }; // 0x604 => hie
+    bits
+}
+
+// Writes `hie` of current hart
+pub unsafe fn write_hie(bits: usize) {
+    asm!("csrw  0x604, {}", in(reg) bits, options(nomem, nostack)); // 0x604 => hie
+}
+
+// Writes `htimedelta` of current hart, added to `time` when read by a guest
+pub unsafe fn write_htimedelta(bits: usize) {
+    asm!("csrw  0x605, {}", in(reg) bits, options(nomem, nostack)); // 0x605 => htimedelta
+}
+
+// Writes `hgatp` of current hart, root of G-stage translation for the guest to run
+pub unsafe fn write_hgatp(bits: usize) {
+    asm!("csrw  0x680, {}", in(reg) bits, options(nomem, nostack)); // 0x680 => hgatp
+}
+
+// Writes `vsatp` of current hart, VS-stage translation of the guest to run
+pub unsafe fn write_vsatp(bits: usize) {
+    asm!("csrw  0x280, {}", in(reg) bits, options(nomem, nostack)); // 0x280 => vsatp
+}
+
+pub(crate) fn test_hstatus() {
+    let hstatus = HStatus::from_bits((2 << 32) | (1 << 8) | (1 << 7) | (1 << 6));
+    assert!(hstatus.spv(), "trapped from guest");
+    assert!(hstatus.spvp(), "trapped from VS mode");
+    assert!(hstatus.gva(), "guest virtual address in stval");
+    assert_eq!(hstatus.vsxl(), 2, "64-bit guest");
+    let hstatus = HStatus::from_bits(2 << 32);
+    assert!(
+        !hstatus.spv() && !hstatus.spvp() && !hstatus.gva(),
+        "no fields set"
+    );
+    println!("zihai > hstatus test passed");
+}
@@ -8,9 +8,18 @@ use core::mem::MaybeUninit;
 
 #[macro_use]
 mod console;
+#[cfg(feature = "bench")]
+mod bench;
 mod detect;
+mod hart;
+mod hcsr;
 mod mm;
+mod pmp;
 mod sbi;
+mod sync;
+mod tlb;
+mod trap;
+mod vmm;
 
 // boot hart start
 pub extern "C" fn rust_init(hartid: usize, opaque: usize) {
@@ -23,59 +32,207 @@ pub extern "C" fn rust_init(hartid: usize, opaque: usize) {
     }
     println!("zihai > init hart id: {}", hartid);
     println!("zihai > opaque register: {}", opaque);
+    sbi::test_spec_version();
+    sbi::test_console_path();
     println!("zihai > SBI HSM probe identifier: {}", hsm_version);
-    if !detect::detect_h_extension() {
+    let caps = detect::detect_caps();
+    println!("zihai > boot hart capabilities: {}", caps);
+    if !caps.h {
         panic!("no RISC-V hypervisor H extension on current environment");
     } // fixme: move this if statement to future join_hypervisor_work_hart function.
       // if current hart is not capable of hardware virtualization, it may still be used
       // in supervisor level i/o, networking or monitoring procedures.
     println!("zihai > running with hardware RISC-V H ISA acceleration");
+    let boot_hart_info = hart::HartInfo::detect(hartid, hart::HartState::Started);
+    println!("zihai > boot hart ISA: {}", boot_hart_info.isa_string);
     mm::heap_init();
+    // the last megabyte of the mapped range is reserved for growing the kernel heap
+    let heap_from = mm::PhysAddr(0x80700000).page_number::<mm::Sv39>();
+    let heap_to = mm::PhysAddr(0x80800000).page_number::<mm::Sv39>();
+    mm::heap_set_frame_source(heap_from..heap_to);
+    mm::test_heap_alignment();
+    console::test_hexdump();
     mm::test_frame_alloc();
+    mm::test_frame_compact();
+    mm::test_frame_dealloc_validity();
+    #[cfg(feature = "deterministic-alloc")]
+    mm::test_deterministic_alloc();
+    mm::test_alloc_checkpoint();
+    mm::test_frame_alloc_contiguous();
+    mm::test_buddy_frame_alloc();
+    mm::test_frame_alloc_stats();
+    mm::test_numa_frame_alloc();
+    #[cfg(feature = "frame-debug")]
+    mm::test_frame_ownership();
     // there's only one frame allocator no matter how much core the system have
     let from = mm::PhysAddr(0x80400000).page_number::<mm::Sv39>();
-    let to = mm::PhysAddr(0x80800000).page_number::<mm::Sv39>(); // fixed for qemu
-    let frame_alloc = spin::Mutex::new(mm::StackFrameAllocator::new(from, to));
+    let to = mm::PhysAddr(0x80700000).page_number::<mm::Sv39>(); // fixed for qemu
+    let frame_alloc = mm::DefaultFrameAllocator::new(mm::StackFrameAllocator::new(from, to));
+    hart::roster().register(boot_hart_info);
+    mm::test_heap_grow();
     let mut kernel_addr_space = mm::PagedAddrSpace::try_new_in(mm::Sv39, &frame_alloc)
         .expect("allocate page to create kernel paged address space");
     mm::test_map_solve();
-    kernel_addr_space
-        .allocate_map(
-            mm::VirtAddr(0x80000000).page_number::<mm::Sv39>(),
-            mm::PhysAddr(0x80000000).page_number::<mm::Sv39>(),
-            1024,
-            mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X,
-        )
-        .expect("allocate kernel and bootloader environment mapped space");
-    kernel_addr_space
-        .allocate_map(
-            mm::VirtAddr(0x80400000).page_number::<mm::Sv39>(),
-            mm::PhysAddr(0x80400000).page_number::<mm::Sv39>(),
-            1024,
-            mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X,
-        )
-        .expect("allocate remaining space");
+    mm::test_page_num_arith();
+    mm::test_frame_size();
+    mm::test_leaf_va_range();
+    mm::test_guest_pa_width();
+    mm::test_map_range(&frame_alloc);
+    mm::test_page_size_at(&frame_alloc);
+    mm::test_translate_addr(&frame_alloc);
+    mm::test_deallocate_map(&frame_alloc);
+    mm::test_protect(&frame_alloc);
+    mm::test_enforce_wx(&frame_alloc);
+    mm::test_snapshot_mappings(&frame_alloc);
+    mm::test_for_each_mapping(&frame_alloc);
+    mm::test_sv39x4_root_table(&frame_alloc);
+    mm::test_mem_type(&frame_alloc);
+    mm::test_page_level_display();
+    mm::test_find_free_region(&frame_alloc);
+    mm::test_allocate_map_overlap(&frame_alloc);
+    mm::test_napot(&frame_alloc);
+    mm::test_accessed_dirty(&frame_alloc);
+    mm::test_phys_to_virt_window(&frame_alloc);
+    tlb::test_shootdown_batch();
+    mm::test_migrate_page(&frame_alloc);
+    mm::test_cow_mapping(&frame_alloc);
+    mm::test_identity_mapped_tables(&frame_alloc);
+    mm::test_level_histogram(&frame_alloc);
+    mm::test_empty_translate(&frame_alloc);
+    mm::test_translate_frame_write(&frame_alloc);
+    mm::test_map_too_large(&frame_alloc);
+    mm::test_page_table_iter(&frame_alloc);
+    mm::test_scratch_page(&frame_alloc);
+    mm::test_zeroed_frame(&frame_alloc);
+    mm::test_zeroed_frame_pool(&frame_alloc);
+    mm::map_kernel_range(
+        &mut kernel_addr_space,
+        mm::VirtAddr(0x80000000)..mm::VirtAddr(0x80400000),
+        mm::PhysAddr(0x80000000),
+        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X,
+    )
+    .expect("allocate kernel and bootloader environment mapped space");
+    mm::map_kernel_range(
+        &mut kernel_addr_space,
+        mm::VirtAddr(0x80400000)..mm::VirtAddr(0x80800000),
+        mm::PhysAddr(0x80400000),
+        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X,
+    )
+    .expect("allocate remaining space");
+    mm::test_memory_map();
+    let scratch_frame = mm::FrameBox::try_new_in(&frame_alloc).expect("allocate scratch page");
+    mm::scratch_page().init(scratch_frame);
     mm::test_asid_alloc();
+    mm::test_asid_recycle(&frame_alloc);
+    mm::test_vmid_alloc();
+    mm::test_hfence();
     let max_asid = mm::max_asid();
     let mut asid_alloc = mm::StackAsidAllocator::new(max_asid);
     let kernel_asid = asid_alloc.allocate_asid().expect("alloc kernel asid");
-    let _kernel_satp = unsafe {
+    #[cfg(debug_assertions)]
+    kernel_addr_space
+        .assert_page_tables_identity_mapped()
+        .expect("kernel page tables are identity mapped");
+    let kernel_satp = unsafe {
         mm::activate_supervisor_paged_riscv_sv39(kernel_addr_space.root_page_number(), kernel_asid)
     };
     println!(
         "zihai > entered kernel virtual address space: {}",
         kernel_asid
     );
-
+    // paging is active, detect if hardware updates A/D bits
+    let hw_ad_update =
+        detect::detect_hw_ad_update(&mut kernel_addr_space, mm::VirtAddr(0x90000000));
+    println!("zihai > hardware A/D bit update: {}", hw_ad_update);
+    hart::test_saved_state();
+    hart::test_roster();
+    trap::test_trap_stack();
+    hart::test_frame_stack(&frame_alloc);
+    trap::test_dispatcher();
+    trap::test_mailbox();
+    trap::test_dump_frame();
+    unsafe { trap::init(hartid) };
+    trap::init_ipi();
+    // prepare states for other harts to start with, or resume from non-retentive suspension
+    #[cfg(not(feature = "frame-stacks"))]
+    for hartid in 0..hart::MAX_HARTS {
+        let boot_stack_base = core::ptr::addr_of!(BOOT_STACK) as usize;
+        let state =
+            hart::HartSavedState::new(hartid, boot_stack_base, BOOT_STACK_SIZE, kernel_satp.bits());
+        unsafe { hart::save_for_resume(hartid, state) };
+    }
+    #[cfg(feature = "frame-stacks")]
+    for hartid in (0..hart::MAX_HARTS).filter(|&id| id != hartid) {
+        let stack = hart::FrameStack::try_new_in(BOOT_STACK_SIZE, &frame_alloc)
+            .expect("allocate hart stack");
+        let state = hart::HartSavedState::with_stack_top(stack.leak(), kernel_satp.bits());
+        unsafe { hart::save_for_resume(hartid, state) };
+    }
+    let started = hart::start_secondary_harts(hartid);
+    println!("zihai > started {} secondary harts", started);
+    hart::test_boot_claim(hartid);
+    sync::test_ticket_mutex(hartid);
+    detect::test_detect_csr_read();
+    detect::test_detect_caps();
+    detect::test_detect_bitmanip();
+    detect::test_isa_string();
+    detect::test_restore_detect_trap();
+    detect::test_detect_trap_result();
+    detect::test_nested_detect_trap();
+    detect::test_detect_trap_insn_len();
+    detect::test_max_paging_mode();
+    pmp::test_count_implemented();
+    vmm::test_dirty_logging(&frame_alloc);
+    vmm::test_dirty_tracking_strategy(hw_ad_update);
+    vmm::test_create_guest(&frame_alloc);
+    vmm::test_minimal_fdt();
+    vmm::test_wfi_guest(&frame_alloc);
+    vmm::test_read_write_guest(&frame_alloc);
+    vmm::test_guest_frame_quota(&frame_alloc);
+    vmm::test_guest_satp_write(&frame_alloc);
+    vmm::test_setup_boot_args(&frame_alloc);
+    vmm::test_vs_ecall_reset();
+    vmm::test_forwarded_ecall();
+    vmm::test_inject_exception();
+    vmm::test_guest_interrupts();
+    vmm::test_guest_memory_layout(&frame_alloc);
+    vmm::test_decode_fault_access(&frame_alloc);
+    vmm::test_add_memory(&frame_alloc);
+    vmm::test_map_contiguous_ram(&frame_alloc);
+    vmm::test_guest_slice(&frame_alloc);
+    hcsr::test_hstatus();
+    vmm::test_decode_guest_page_fault();
+    vmm::test_decode_htinst();
+    vmm::test_event_log();
+    vmm::test_guest_timer();
+    vmm::test_scheduler();
+    mm::test_frame_bulk_dealloc(&frame_alloc);
+    mm::test_into_frames(&frame_alloc);
+    mm::test_frame_rc(&frame_alloc);
+    #[cfg(feature = "bench")]
+    {
+        bench::test_bench_accounting();
+        bench::run(&frame_alloc);
+    }
     // call sbi remote retentive suspension, use sbi 0.3 to wake other harts
 
     sbi::reset(0x00000000, 0x00000000); // shutdown // todo: remove
 }
 
-// FIXME: after hart suspension, stack pointer register `sp` remains an undefined state
-// set register `sp` before higher programming language procedure
-pub extern "C" fn rust_init_harts(_opaque: usize) {
-    // join working queue, ...
+// started by the boot hart, or resumed from non-retentive suspension; `sp` is set by `hart::resume_entry`
+pub extern "C" fn rust_init_harts(hartid: usize, _opaque: usize) -> ! {
+    unsafe { hart::restore_after_suspend(hart::saved_state(hartid)) };
+    let hart_info = hart::HartInfo::detect(hartid, hart::HartState::Started);
+    hart::roster().register(hart_info);
+    unsafe { trap::init(hartid) };
+    // wait for work from other harts in the mailbox of this hart
+    unsafe {
+        riscv::register::sie::set_ssoft();
+        riscv::register::sstatus::set_sie();
+    }
+    loop {
+        unsafe { asm!("wfi", options(nomem, nostack)) };
+    }
 }
 
 #[panic_handler]
@@ -85,34 +242,55 @@ fn on_panic(info: &core::panic::PanicInfo) -> ! {
 }
 
 const BOOT_STACK_SIZE: usize = 64 * 1024; // 64KB
-static BOOT_STACK: MaybeUninit<[u8; BOOT_STACK_SIZE]> = MaybeUninit::uninit();
+
+// number of harts with static stacks; with frame backed stacks only the boot hart has one
+#[cfg(not(feature = "frame-stacks"))]
+const STATIC_STACK_HARTS: usize = hart::MAX_HARTS;
+#[cfg(feature = "frame-stacks")]
+const STATIC_STACK_HARTS: usize = 1;
+// distance between stacks of adjacent hart ids; zero if only the boot hart has a static stack
+#[cfg(not(feature = "frame-stacks"))]
+const BOOT_STACK_STRIDE: usize = BOOT_STACK_SIZE;
+#[cfg(feature = "frame-stacks")]
+const BOOT_STACK_STRIDE: usize = 0;
+#[link_section = ".bss.uninit"]
+static mut BOOT_STACK: MaybeUninit<[u8; BOOT_STACK_SIZE * STATIC_STACK_HARTS]> =
+    MaybeUninit::uninit();
 
 #[link_section = ".text.entry"]
 #[export_name = "_start"]
 #[naked]
 pub unsafe extern "C" fn start() -> ! {
     asm!(
-        // prepare stack
-        "la     sp, {boot_stack}",
-        "li     t2, {boot_stack_size}",
-        "addi   t3, a0, 1",
-        "mul    t2, t2, t3",
-        "add    sp, sp, t2",
-        // start boot hart
-        "beqz   a0, 2f",
         "mv     t0, a0",
         "mv     t1, a1",
+        // SBI starts one hart of its choice, which is not always hart 0;
+        // the first hart to arrive claims to be the boot hart
+        "la     t2, {boot_claim}",
+        "li     t3, 1",
+        "amoswap.w.aq t3, t3, (t2)",
+        "beqz   t3, 3f",
         // stop other harts
         "li     a7, 0x48534D",
         "li     a6, 0x3", // hart suspend
         "li     a0, 0x80000000",  // suspend type: non retentive
-        "la     a1, {rust_init_harts}", // resume address
+        "la     a1, {resume_entry}", // resume address
         "mv     a2, t1", // a2: opaque parameter
         "ecall", // SBI hart syspend
         "1:",
         "wfi", // suspend failed, use WFI-loop halt instead
         "j      1b", // non-boot hart, halt
-        "2:",
+        "3:",
+        // prepare stack of boot hart; the first stack is lent to report an
+        // unsupported hart id, as no other hart runs yet
+        "la     sp, {boot_stack}",
+        "li     t2, {boot_stack_size}",
+        "add    sp, sp, t2",
+        "li     t2, {max_harts}",
+        "bgeu   t0, t2, 4f",
+        "li     t2, {boot_stack_stride}",
+        "mul    t2, t2, t0",
+        "add    sp, sp, t2",
         // detect SBI version
         "li     a7, 0x10", // function id
         "li     a6, 0x0", // get spec version
@@ -122,34 +300,51 @@ pub unsafe extern "C" fn start() -> ! {
         "j      2f",
         "1:",
         "mv     a0, a1",
-        "tail   {err_sbi_version}",
+        "tail   {report_sbi_version_error}",
         "unimp",
         "2:",
         "mv     a0, t0",
         "mv     a1, t1",
         "tail   {rust_init}",
         "unimp", // unreachable
+        "4:",
+        "mv     a0, t0",
+        "tail   {report_hartid_error}",
+        "unimp",
         boot_stack = sym BOOT_STACK,
         boot_stack_size = const BOOT_STACK_SIZE,
+        boot_stack_stride = const BOOT_STACK_STRIDE,
+        max_harts = const hart::MAX_HARTS,
+        boot_claim = sym hart::BOOT_CLAIM,
         rust_init = sym rust_init,
-        rust_init_harts = sym rust_init_harts,
-        err_sbi_version = sym err_sbi_version,
+        resume_entry = sym hart::resume_entry,
+        report_sbi_version_error = sym report_sbi_version_error,
+        report_hartid_error = sym report_hartid_error,
         options(noreturn)
     )
 }
 
-// fixme: better code format
-unsafe extern "C" fn err_sbi_version(wrong_version: usize) -> ! {
-    let string =
-        "zihai: this hypervisor software must run over SBI version >= 0.3, but we have version ";
-    for byte in string.bytes() {
-        asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) byte as usize);
-    }
-    asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) b'0' as usize + (wrong_version >> 24));
-    asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) b'.' as usize);
-    asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) b'0' as usize + (wrong_version & 0xFF));
-    asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) b'.' as usize);
-    asm!("li a7, 0x01", "mv a6, {}", "ecall", in(reg) b'\n' as usize);
-    asm!("li a7, 0x08", "ecall"); // shutdown
-    loop {}
+// Reports unsupported SBI version and shuts down.
+//
+// SBI system reset extension requires version 0.3, thus legacy shutdown is used.
+extern "C" fn report_sbi_version_error(version: usize) -> ! {
+    let (major, minor) = sbi::spec_version_parts(version);
+    println!(
+        "zihai: this hypervisor software must run over SBI version >= 0.3, but we have version {}.{}",
+        major, minor
+    );
+    sbi::legacy_shutdown()
+}
+
+// Reports a boot hart id this hypervisor has no per-hart states for, and shuts down.
+//
+// Hart ids must be less than `hart::MAX_HARTS`; such a hart would otherwise
+// share its stack and trap stack with another hart.
+extern "C" fn report_hartid_error(hartid: usize) -> ! {
+    println!(
+        "zihai: boot hart id {} is not supported, must be less than {}",
+        hartid,
+        hart::MAX_HARTS
+    );
+    sbi::legacy_shutdown()
 }
@@ -3,32 +3,132 @@
 //! Includes heap memory and virtual memory system
 #![allow(unused)] // use in the future
 
-use alloc::alloc::Layout;
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::arch::riscv64;
-use core::{fmt, ops::Range};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::{fmt, marker::PhantomData, ops::Range};
 
 use bit_field::BitField;
 use buddy_system_allocator::LockedHeap;
 use riscv::register::satp::{self, Mode, Satp};
 
 const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+// 堆空间起始地址的对齐；伙伴分配器按块大小对齐分配，起始地址对齐时不必浪费区间开头的空间
+pub const KERNEL_HEAP_ALIGN: usize = 4096;
 
-static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
+#[repr(C, align(4096))]
+struct HeapSpace([u8; KERNEL_HEAP_SIZE]);
+
+const _: () = assert!(core::mem::align_of::<HeapSpace>() == KERNEL_HEAP_ALIGN);
+
+static mut HEAP_SPACE: HeapSpace = HeapSpace([0; KERNEL_HEAP_SIZE]);
 
 #[global_allocator]
-static HEAP: LockedHeap<32> = LockedHeap::empty();
+static HEAP: GrowableHeap = GrowableHeap(LockedHeap::empty());
+
+// 堆空间用完时，从这个页帧区间的开头取得页帧扩充堆；要求内核对其中的页帧有恒等映射。
+// 取得页帧的过程不分配内存，扩充堆时不会再进入分配器
+static HEAP_FRAMES: crate::sync::Mutex<Option<Range<PhysPageNum>>> = crate::sync::Mutex::new(None);
+
+// 每次自动扩充堆的最少页数
+const HEAP_GROW_PAGES: usize = 16;
+
+// 分配失败时扩充堆，然后重试分配，直到没有页帧可以扩充
+struct GrowableHeap(LockedHeap<32>);
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let ptr = self.0.alloc(layout);
+            if !ptr.is_null() || !heap_grow(layout) {
+                return ptr;
+            }
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+impl core::ops::Deref for GrowableHeap {
+    type Target = LockedHeap<32>;
+    fn deref(&self) -> &LockedHeap<32> {
+        &self.0
+    }
+}
 
+// 分配器扩充一次堆并重试之后仍然失败，才会来到这里
 #[cfg_attr(not(test), alloc_error_handler)]
 #[allow(unused)]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("hypervisor alloc error for layout {:?}", layout)
 }
 
+// 设置扩充堆使用的页帧区间，区间中的页帧不能再由其它页帧分配器分配
+pub(crate) fn heap_set_frame_source(frames: Range<PhysPageNum>) {
+    *HEAP_FRAMES.lock() = Some(frames);
+}
+
+// 从扩充堆的页帧区间取得pages个页帧，起始页号按2^align_log2个页帧对齐；
+// 为了对齐跳过的页帧直接加入堆，不会浪费
+fn heap_take_frames(pages: usize, align_log2: usize) -> Option<PhysPageNum> {
+    let mut source = HEAP_FRAMES.lock();
+    let frames = source.as_mut()?;
+    let align = 1_usize.checked_shl(align_log2 as u32)?;
+    let start = frames.start.0.checked_next_multiple_of(align)?;
+    if frames.end.0 < start || frames.end.0 - start < pages {
+        return None;
+    }
+    let skipped = frames.start;
+    frames.start = PhysPageNum(start + pages);
+    drop(source);
+    if start > skipped.0 {
+        unsafe { heap_extend(skipped, start - skipped.0) };
+    }
+    Some(PhysPageNum(start))
+}
+
+// 把从from开始的pages个页帧加入内核堆。页帧需要先从页帧分配器中取得，加入之后不再归还；
+// 要求内核对这些页帧有恒等映射
+pub unsafe fn heap_extend(from: PhysPageNum, pages: usize) {
+    let start = from.addr_begin::<Sv39>().0;
+    let end = start + (pages << Sv39::FRAME_SIZE_BITS);
+    HEAP.lock().add_to_heap(start, end);
+}
+
+// 扩充一次堆，使它能满足layout的分配。伙伴分配器需要对齐的块，因此取得按自身大小对齐的页帧。
+// 同一时刻只有一个处理核扩充堆；其它处理核等待扩充完成后返回true，由调用者重试分配。
+// 返回false表示没有页帧可以扩充
+fn heap_grow(layout: Layout) -> bool {
+    static GROWING: AtomicBool = AtomicBool::new(false);
+    if GROWING.swap(true, Ordering::Acquire) {
+        while GROWING.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        return true;
+    }
+    let frame_size = 1 << Sv39::FRAME_SIZE_BITS;
+    let pages = layout.size().max(layout.align()).div_ceil(frame_size);
+    let pages = pages.max(HEAP_GROW_PAGES).next_power_of_two();
+    let grown = heap_take_frames(pages, pages.trailing_zeros() as usize);
+    if let Some(from) = grown {
+        unsafe { heap_extend(from, pages) };
+    }
+    GROWING.store(false, Ordering::Release);
+    grown.is_some()
+}
+
 pub(crate) fn heap_init() {
     unsafe {
-        HEAP.lock()
-            .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE)
+        HEAP.lock().init(
+            core::ptr::addr_of_mut!(HEAP_SPACE) as usize,
+            KERNEL_HEAP_SIZE,
+        )
     }
     let mut vec = Vec::new();
     for i in 0..5 {
@@ -41,6 +141,45 @@ pub(crate) fn heap_init() {
     }
 }
 
+pub(crate) fn test_heap_alignment() {
+    let base = unsafe { core::ptr::addr_of_mut!(HEAP_SPACE) as usize };
+    assert_eq!(base % KERNEL_HEAP_ALIGN, 0, "heap base aligned");
+    let total = HEAP.lock().stats_total_bytes();
+    assert_eq!(total, KERNEL_HEAP_SIZE, "whole heap space usable");
+    let layout = Layout::from_size_align(KERNEL_HEAP_ALIGN, KERNEL_HEAP_ALIGN).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!ptr.is_null(), "aligned block allocated");
+    let addr = ptr as usize;
+    assert!(addr >= base && addr + KERNEL_HEAP_ALIGN <= base + KERNEL_HEAP_SIZE);
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+    println!("zihai > heap alignment test passed");
+}
+
+pub(crate) fn test_heap_grow() {
+    let pages = 32;
+    let from = heap_take_frames(pages, 5).expect("frames from heap frame source");
+    // 为了对齐跳过的页帧已经加入堆
+    let before = HEAP.lock().stats_total_bytes();
+    unsafe { heap_extend(from, pages) };
+    let total = HEAP.lock().stats_total_bytes();
+    assert_eq!(total, before + (pages << 12), "heap extended");
+    let big = vec![0x5a_u8; KERNEL_HEAP_SIZE + 4096];
+    let addr = big.as_ptr() as usize;
+    let start = from.addr_begin::<Sv39>().0;
+    assert!(
+        addr >= start && addr < start + (pages << 12),
+        "in extended frames"
+    );
+    // 堆中已经没有足够的空间，分配时自动扩充
+    let bigger = vec![0xa5_u8; KERNEL_HEAP_SIZE + 4096];
+    assert!(
+        HEAP.lock().stats_total_bytes() > total,
+        "heap grown on demand"
+    );
+    assert!(big.iter().all(|&b| b == 0x5a) && bigger.iter().all(|&b| b == 0xa5));
+    println!("zihai > heap grow test passed");
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct PhysAddr(pub usize);
 
@@ -80,39 +219,77 @@ impl PhysPageNum {
             begin.0 <= self.0 || self.0 < end.0
         }
     }
+    // 向前或向后移动by个页，超出范围时回绕
+    pub fn offset(self, by: isize) -> Self {
+        PhysPageNum(self.0.wrapping_add_signed(by))
+    }
+    // 向后移动n个页，溢出时返回None
+    pub fn checked_add(self, n: usize) -> Option<Self> {
+        self.0.checked_add(n).map(PhysPageNum)
+    }
+}
+
+// 两个页号之间相差的页数；要求减数不大于被减数
+impl core::ops::Sub for PhysPageNum {
+    type Output = usize;
+    fn sub(self, rhs: PhysPageNum) -> usize {
+        self.0 - rhs.0
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct VirtPageNum(usize);
 
 impl VirtPageNum {
-    // pub fn addr_begin<M: PageMode>(&self) -> VirtAddr {
-    //     VirtAddr(self.0 << M::FRAME_SIZE_BITS)
-    // }
+    pub fn addr_begin<M: PageMode>(&self) -> VirtAddr {
+        VirtAddr(self.0 << M::FRAME_SIZE_BITS)
+    }
     pub fn next_page_by_level<M: PageMode>(&self, lvl: PageLevel) -> VirtPageNum {
         let step = M::get_layout_for_level(lvl).align_in_frames();
         VirtPageNum(self.0.wrapping_add(step))
     }
+    // 向前或向后移动by个页，超出范围时回绕
+    pub fn offset(self, by: isize) -> Self {
+        VirtPageNum(self.0.wrapping_add_signed(by))
+    }
+    // 向后移动n个页，溢出时返回None
+    pub fn checked_add(self, n: usize) -> Option<Self> {
+        self.0.checked_add(n).map(VirtPageNum)
+    }
+}
+
+// 两个页号之间相差的页数；要求减数不大于被减数
+impl core::ops::Sub for VirtPageNum {
+    type Output = usize;
+    fn sub(self, rhs: VirtPageNum) -> usize {
+        self.0 - rhs.0
+    }
 }
 
 // 页帧分配器。**对于物理空间的一个片段，只存在一个页帧分配器，无论有多少个处理核**
 #[derive(Debug)]
 pub struct StackFrameAllocator {
+    start: PhysPageNum,
     current: PhysPageNum,
     end: PhysPageNum,
     recycled: Vec<PhysPageNum>,
+    // 回收列表中的页帧，用于回收时快速检查重复释放
+    recycled_set: BTreeSet<usize>,
 }
 
 impl StackFrameAllocator {
     pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
         StackFrameAllocator {
+            start,
             current: start,
             end,
             recycled: Vec::new(),
+            recycled_set: BTreeSet::new(),
         }
     }
     pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
         if let Some(ppn) = self.recycled.pop() {
+            self.recycled_set.remove(&ppn.0);
             Ok(ppn)
         } else {
             if self.current == self.end {
@@ -126,14 +303,142 @@ impl StackFrameAllocator {
     }
     pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
         // validity check
-        if ppn.is_within_range(self.current, self.end)
-            || self.recycled.iter().find(|&v| *v == ppn).is_some()
-        {
+        if !self.is_allocated(ppn) {
             panic!("Frame ppn={:x?} has not been allocated!", ppn);
         }
         // recycle
+        self.recycle(ppn);
+        self.recycled_set.insert(ppn.0);
+    }
+    // 放入回收列表。确定性分配模式下回收列表按页号从大到小排列，总是先分配页号最小的回收页帧，
+    // 这样分配结果只取决于哪些页帧被回收，而不取决于回收的顺序
+    #[cfg(not(feature = "deterministic-alloc"))]
+    fn recycle(&mut self, ppn: PhysPageNum) {
         self.recycled.push(ppn);
     }
+    #[cfg(feature = "deterministic-alloc")]
+    fn recycle(&mut self, ppn: PhysPageNum) {
+        let idx = self.recycled.partition_point(|other| other.0 > ppn.0);
+        self.recycled.insert(idx, ppn);
+    }
+    // 页帧是否已经分配出去：位于已分配过的start..current区间中，并且不在回收列表中
+    pub fn is_allocated(&self, ppn: PhysPageNum) -> bool {
+        ppn.is_within_range(self.start, self.current) && !self.recycled_set.contains(&ppn.0)
+    }
+    // 已经分配出去的页帧数量
+    pub fn frames_in_use(&self) -> usize {
+        self.current.0 - self.start.0 - self.recycled.len()
+    }
+    // 页帧使用情况的统计
+    pub fn stats(&self) -> FrameAllocStats {
+        let total = self.end.0 - self.start.0;
+        let allocated = self.frames_in_use();
+        FrameAllocStats {
+            total,
+            allocated,
+            recycled: self.recycled.len(),
+            free: total - allocated,
+        }
+    }
+    // 记录分配器当前的状态，用于检查页帧泄漏
+    pub fn checkpoint(&self) -> AllocCheckpoint {
+        AllocCheckpoint {
+            current: self.current,
+            recycled_len: self.recycled.len(),
+        }
+    }
+    // 已分配的页帧数量是否和记录时相同
+    pub fn is_restored(&self, checkpoint: AllocCheckpoint) -> bool {
+        self.frames_in_use() == checkpoint.current.0 - self.start.0 - checkpoint.recycled_len
+    }
+    // 检查分配器恢复到记录时的状态；否则说明中间发生了页帧泄漏或重复释放
+    pub fn assert_restored(&self, checkpoint: AllocCheckpoint) {
+        if !self.is_restored(checkpoint) {
+            panic!(
+                "frame allocator not restored, checkpoint {:x?}, now {:x?}",
+                checkpoint,
+                self.checkpoint()
+            );
+        }
+    }
+    // 分配count个物理地址连续的页帧，起始页号按2^align_log2个页帧对齐，返回第一个页帧的页号。
+    // 回收的页帧不一定连续，因此只从未分配的区域中分配；为了对齐跳过的页帧放入回收列表
+    pub fn allocate_frames(
+        &mut self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        let align = 1_usize
+            .checked_shl(align_log2 as u32)
+            .ok_or(FrameAllocError)?;
+        let start = (self.current.0 + align - 1) & !(align - 1);
+        if count == 0 || self.end.0 < start || self.end.0 - start < count {
+            return Err(FrameAllocError);
+        }
+        for ppn in self.current.0..start {
+            self.recycle(PhysPageNum(ppn));
+        }
+        self.recycled_set.extend(self.current.0..start);
+        self.current = PhysPageNum(start + count);
+        Ok(PhysPageNum(start))
+    }
+    // 回收从ppn开始的count个物理地址连续的页帧
+    pub fn deallocate_frames(&mut self, ppn: PhysPageNum, count: usize) {
+        self.deallocate_frames_bulk((ppn.0..ppn.0 + count).map(PhysPageNum))
+    }
+    // 整理回收的页帧列表：紧接在current之下的连续回收页帧，重新并入未分配的区域
+    pub fn compact(&mut self) {
+        self.recycled.sort_unstable_by_key(|ppn| ppn.0);
+        while let Some(&last) = self.recycled.last() {
+            if last.next_page() != self.current {
+                break;
+            }
+            self.current = last;
+            self.recycled.pop();
+            self.recycled_set.remove(&last.0);
+        }
+        #[cfg(feature = "deterministic-alloc")]
+        self.recycled.reverse();
+        self.recycled.shrink_to_fit();
+    }
+    // 一次性回收多个页帧，合并进行有效性检查
+    pub fn deallocate_frames_bulk(&mut self, ppns: impl Iterator<Item = PhysPageNum>) {
+        for ppn in ppns {
+            // validity check; a frame repeated in ppns is found in the set
+            if !ppn.is_within_range(self.start, self.current) || !self.recycled_set.insert(ppn.0) {
+                panic!("Frame ppn={:x?} has not been allocated!", ppn);
+            }
+            self.recycle(ppn);
+        }
+    }
+}
+
+// 页帧分配器某一时刻的状态
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AllocCheckpoint {
+    current: PhysPageNum,
+    recycled_len: usize,
+}
+
+// 页帧分配器的使用情况；free包括回收的页帧和从未分配过的页帧
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct FrameAllocStats {
+    pub total: usize,
+    pub allocated: usize,
+    pub recycled: usize,
+    pub free: usize,
+}
+
+impl core::ops::Add for FrameAllocStats {
+    type Output = FrameAllocStats;
+    fn add(self, rhs: FrameAllocStats) -> FrameAllocStats {
+        FrameAllocStats {
+            total: self.total + rhs.total,
+            allocated: self.allocated + rhs.allocated,
+            recycled: self.recycled + rhs.recycled,
+            free: self.free + rhs.free,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -160,6 +465,492 @@ pub(crate) fn test_frame_alloc() {
     println!("zihai > frame allocator test passed");
 }
 
+pub(crate) fn test_alloc_checkpoint() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x100000);
+    let mut alloc = StackFrameAllocator::new(from, to);
+    let f0 = alloc.allocate_frame().unwrap();
+    let checkpoint = alloc.checkpoint();
+    let f1 = alloc.allocate_frame().unwrap();
+    let f2 = alloc.allocate_frame().unwrap();
+    alloc.deallocate_frame(f2);
+    alloc.deallocate_frame(f1);
+    assert!(alloc.is_restored(checkpoint), "balanced allocation");
+    alloc.assert_restored(checkpoint);
+    // 回收的页帧重新分配，状态不同但数量相同
+    alloc.deallocate_frame(f0);
+    let f0 = alloc.allocate_frame().unwrap();
+    assert!(
+        alloc.is_restored(checkpoint),
+        "recycled frame allocated again"
+    );
+    let leaked = alloc.allocate_frame().unwrap();
+    assert!(!alloc.is_restored(checkpoint), "frame leaked");
+    alloc.deallocate_frame(leaked);
+    alloc.deallocate_frame(f0);
+    assert!(
+        !alloc.is_restored(checkpoint),
+        "frame allocated before checkpoint freed"
+    );
+    println!("zihai > frame allocator checkpoint test passed");
+}
+
+pub(crate) fn test_frame_compact() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x100000);
+    let mut alloc = StackFrameAllocator::new(from, to);
+    for _ in 0..5 {
+        alloc.allocate_frame().unwrap();
+    }
+    alloc.deallocate_frame(PhysPageNum(0x80004));
+    alloc.deallocate_frame(PhysPageNum(0x80001));
+    alloc.deallocate_frame(PhysPageNum(0x80003));
+    alloc.compact();
+    assert_eq!(alloc.current, PhysPageNum(0x80003), "top frames reabsorbed");
+    assert_eq!(
+        alloc.recycled,
+        [PhysPageNum(0x80001)],
+        "recycle list shrinks"
+    );
+    let f1 = alloc.allocate_frame();
+    assert_eq!(f1, Ok(PhysPageNum(0x80001)), "allocate recycled frame");
+    let f2 = alloc.allocate_frame();
+    assert_eq!(f2, Ok(PhysPageNum(0x80003)), "allocate reabsorbed frame");
+    alloc.deallocate_frame(PhysPageNum(0x80000));
+    alloc.compact();
+    assert_eq!(alloc.current, PhysPageNum(0x80004), "not below current");
+    assert_eq!(
+        alloc.recycled,
+        [PhysPageNum(0x80000)],
+        "frame not reabsorbed"
+    );
+    println!("zihai > frame compact test passed");
+}
+
+#[cfg(feature = "deterministic-alloc")]
+pub(crate) fn test_deterministic_alloc() {
+    // 同样的分配和回收序列，只有回收的顺序不同
+    fn run(free_order: &[usize]) -> Vec<PhysPageNum> {
+        let mut alloc = StackFrameAllocator::new(PhysPageNum(0x80000), PhysPageNum(0x80020));
+        let frames: Vec<_> = (0..8).map(|_| alloc.allocate_frame().unwrap()).collect();
+        for &i in free_order {
+            alloc.deallocate_frame(frames[i]);
+        }
+        let mut ans: Vec<_> = (0..6).map(|_| alloc.allocate_frame().unwrap()).collect();
+        alloc.deallocate_frames_bulk([ans[4], ans[1], ans[2]].into_iter());
+        alloc.compact();
+        ans.extend((0..4).map(|_| alloc.allocate_frame().unwrap()));
+        ans.push(alloc.allocate_frames(2, 2).unwrap());
+        ans.extend((0..3).map(|_| alloc.allocate_frame().unwrap()));
+        ans
+    }
+    let expected = run(&[1, 5, 3, 6]);
+    assert_eq!(expected, run(&[1, 5, 3, 6]), "same sequence, same frames");
+    assert_eq!(expected, run(&[6, 3, 1, 5]), "free order does not matter");
+    assert_eq!(
+        &expected[..4],
+        &[0x80001, 0x80003, 0x80005, 0x80006].map(PhysPageNum),
+        "lowest recycled frame first"
+    );
+    println!("zihai > deterministic frame alloc test passed");
+}
+
+pub(crate) fn test_frame_dealloc_validity() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x80010);
+    let mut alloc = StackFrameAllocator::new(from, to);
+    let f1 = alloc.allocate_frame().unwrap();
+    let f2 = alloc.allocate_frame().unwrap();
+    assert!(alloc.is_allocated(f1), "allocated frame");
+    assert!(
+        !alloc.is_allocated(PhysPageNum(0x80002)),
+        "frame in unallocated region"
+    );
+    assert!(
+        !alloc.is_allocated(PhysPageNum(0x7ffff)),
+        "frame below start"
+    );
+    assert!(!alloc.is_allocated(to), "frame at end");
+    alloc.deallocate_frame(f1);
+    assert!(!alloc.is_allocated(f1), "recycled frame");
+    assert!(alloc.is_allocated(f2), "other frame still allocated");
+    assert_eq!(alloc.frames_in_use(), 1, "counted from start");
+    let f1 = alloc.allocate_frame().unwrap();
+    assert!(alloc.is_allocated(f1), "recycled frame allocated again");
+    alloc.deallocate_frames_bulk([f2, f1].into_iter());
+    assert!(
+        !alloc.is_allocated(f1) && !alloc.is_allocated(f2),
+        "frames freed in bulk"
+    );
+    let aligned = alloc.allocate_frames(4, 2).unwrap();
+    assert!(
+        !alloc.is_allocated(PhysPageNum(0x80002)),
+        "frame skipped for alignment is recycled"
+    );
+    alloc.deallocate_frames(aligned, 4);
+    alloc.compact();
+    assert_eq!(alloc.frames_in_use(), 0, "all frames freed");
+    assert!(!alloc.is_allocated(f1), "frame reabsorbed by compact");
+    let f3 = alloc.allocate_frame().unwrap();
+    assert_eq!(f3, from, "allocated again from start");
+    alloc.deallocate_frame(f3);
+    println!("zihai > frame deallocation validity test passed");
+}
+
+pub(crate) fn test_frame_alloc_stats() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x80010);
+    let frames = DefaultFrameAllocator::new(StackFrameAllocator::new(from, to));
+    let consistent = |s: FrameAllocStats| s.allocated + s.free == s.total && s.recycled <= s.free;
+    let stats = frames.stats();
+    assert_eq!(stats.total, 16, "total frames");
+    assert_eq!(stats.free, 16, "all frames free");
+    let f1 = frames.allocate_frame().unwrap();
+    let f2 = frames.allocate_frame().unwrap();
+    let run = frames.allocate_frames(4, 2).unwrap();
+    let stats = frames.stats();
+    assert!(consistent(stats), "consistent after allocation");
+    assert_eq!(stats.allocated, 6, "frames allocated");
+    assert_eq!(stats.recycled, 2, "frames skipped for alignment recycled");
+    frames.deallocate_frame(f1);
+    frames.deallocate_frames(run, 4);
+    let stats = frames.stats();
+    assert!(consistent(stats), "consistent after free");
+    assert_eq!(stats.allocated, 1, "one frame left allocated");
+    assert_eq!(stats.recycled, 7, "freed frames recycled");
+    assert_eq!(stats.free, 15, "free includes recycled frames");
+    frames.deallocate_frame(f2);
+    frames.lock().compact();
+    let stats = frames.stats();
+    assert_eq!(stats.recycled, 0, "recycled frames reabsorbed");
+    assert_eq!(stats.free, stats.total, "all frames free again");
+    let buddy = crate::sync::Mutex::new(BuddyFrameAllocator::new(from, to));
+    let run = buddy.allocate_frames(3, 0).unwrap();
+    let stats = buddy.stats();
+    assert!(consistent(stats), "buddy allocator consistent");
+    assert_eq!(stats.allocated, 3, "buddy frames allocated");
+    buddy.deallocate_frames(run, 3);
+    assert_eq!(buddy.stats().free, 16, "buddy frames freed");
+    println!("zihai > frame allocator stats test passed");
+}
+
+pub(crate) fn test_frame_alloc_contiguous() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x80010);
+    let mut alloc = StackFrameAllocator::new(from, to);
+    let f1 = alloc.allocate_frame().unwrap();
+    alloc.deallocate_frame(f1);
+    let base = alloc.allocate_frames(8, 0);
+    assert_eq!(base, Ok(PhysPageNum(0x80001)), "skip recycled frames");
+    assert_eq!(alloc.current, PhysPageNum(0x80009), "contiguous run taken");
+    assert_eq!(
+        alloc.allocate_frames(8, 0),
+        Err(FrameAllocError),
+        "not enough frames"
+    );
+    assert_eq!(
+        alloc.allocate_frames(0, 0),
+        Err(FrameAllocError),
+        "empty run"
+    );
+    let in_use = alloc.frames_in_use();
+    let aligned = alloc.allocate_frames(4, 2);
+    assert_eq!(aligned, Ok(PhysPageNum(0x8000c)), "aligned to 4 frames");
+    assert_eq!(alloc.frames_in_use(), in_use + 4, "skipped frames recycled");
+    assert_eq!(
+        alloc.allocate_frames(1, 0),
+        Err(FrameAllocError),
+        "bump space used up"
+    );
+    alloc.deallocate_frames(aligned.unwrap(), 4);
+    assert_eq!(alloc.frames_in_use(), in_use, "contiguous run freed");
+    assert_eq!(
+        alloc.allocate_frames(2, 2),
+        Err(FrameAllocError),
+        "freed frames not reused for runs"
+    );
+    let mut rest = Vec::new();
+    while let Ok(ppn) = alloc.allocate_frame() {
+        rest.push(ppn);
+    }
+    assert_eq!(rest.len(), 1 + 3 + 4, "recycled frames still available");
+    assert!(rest.contains(&f1), "frame freed before the runs");
+    println!("zihai > contiguous frame alloc test passed");
+}
+
+pub(crate) fn test_into_frames(frame_alloc: &DefaultFrameAllocator) {
+    let frames_in_use = || frame_alloc.lock().frames_in_use();
+    let before = frames_in_use();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let root = space.root_page_number();
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x90000), 1, Sv39Flags::R)
+        .unwrap();
+    let frames = space.into_frames();
+    assert_eq!(frames.len(), 3, "root and two intermediate page tables");
+    assert_eq!(frames[0].phys_page_num(), root, "root page table first");
+    assert_eq!(
+        frames_in_use(),
+        before + 3,
+        "frames not freed by into_frames"
+    );
+    drop(frames);
+    assert_eq!(frames_in_use(), before, "each frame freed once");
+    println!("zihai > address space into frames test passed");
+}
+
+pub(crate) fn test_frame_bulk_dealloc(frame_alloc: &DefaultFrameAllocator) {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x100000);
+    let mut alloc = StackFrameAllocator::new(from, to);
+    let frames = (0..4)
+        .map(|_| alloc.allocate_frame().unwrap())
+        .collect::<Vec<_>>();
+    alloc.deallocate_frames_bulk(frames.iter().copied());
+    assert_eq!(alloc.recycled.len(), 4, "all frames recycled");
+    let mut again = (0..4)
+        .map(|_| alloc.allocate_frame().unwrap())
+        .collect::<Vec<_>>();
+    again.sort_unstable_by_key(|ppn| ppn.0);
+    assert_eq!(again, frames, "recycled frames are allocated again");
+    assert_eq!(
+        alloc.allocate_frame(),
+        Ok(PhysPageNum(0x80004)),
+        "allocate after recycled frames used up"
+    );
+    // release address space: root page table and two intermediate page tables
+    let frames_in_use = || {
+        let a = frame_alloc.lock();
+        a.current.0 - a.recycled.len()
+    };
+    let before = frames_in_use();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x90000), 1, Sv39Flags::R)
+        .unwrap();
+    assert_eq!(frames_in_use(), before + 3, "frames of address space");
+    space.release();
+    assert_eq!(frames_in_use(), before, "frames released in bulk");
+    println!("zihai > frame bulk deallocation test passed");
+}
+
+// 伙伴系统页帧分配器的最大阶数；一个块最多包含2^(BUDDY_MAX_ORDER-1)个页帧
+pub const BUDDY_MAX_ORDER: usize = 32;
+
+// 伙伴系统页帧分配器
+//
+// 空闲页帧按2的幂大小分块，每个阶数一个空闲链表；大小为2^k的块起始页号按2^k对齐。
+// 分配时从最小的足够大的块中拆分，回收时和空闲的伙伴块合并，
+// 因此按对齐分配连续页帧和回收都只需要O(log n)的时间
+#[derive(Debug)]
+pub struct BuddyFrameAllocator {
+    start: PhysPageNum,
+    end: PhysPageNum,
+    free_lists: Vec<BTreeSet<usize>>,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
+        let mut ans = BuddyFrameAllocator {
+            start,
+            end,
+            free_lists: (0..BUDDY_MAX_ORDER).map(|_| BTreeSet::new()).collect(),
+            free_frames: 0,
+        };
+        ans.free_range(start.0, end.0);
+        ans
+    }
+    pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_block(0).map(PhysPageNum)
+    }
+    pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
+        self.deallocate_frames(ppn, 1)
+    }
+    // 分配count个物理地址连续的页帧，起始页号按2^align_log2个页帧对齐。
+    // 取出足够大的块，块尾部多余的页帧立即回收
+    pub fn allocate_frames(
+        &mut self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError);
+        }
+        let order = count
+            .checked_next_power_of_two()
+            .ok_or(FrameAllocError)?
+            .trailing_zeros() as usize;
+        let base = self.allocate_block(order.max(align_log2))?;
+        let block_end = base + (1 << order.max(align_log2));
+        self.free_range(base + count, block_end);
+        Ok(PhysPageNum(base))
+    }
+    // 回收从ppn开始的count个物理地址连续的页帧，不要求和分配时的块对应
+    pub fn deallocate_frames(&mut self, ppn: PhysPageNum, count: usize) {
+        // validity check
+        let end = PhysPageNum(ppn.0 + count);
+        if count == 0 || ppn.0 < self.start.0 || end.0 > self.end.0 {
+            panic!("Frame ppn={:x?} has not been allocated!", ppn);
+        }
+        self.free_range(ppn.0, end.0);
+    }
+    // 空闲页帧的数量
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+    // 已经分配出去的页帧数量
+    pub fn frames_in_use(&self) -> usize {
+        self.end.0 - self.start.0 - self.free_frames
+    }
+    // 页帧使用情况的统计；空闲页帧都在空闲链表中，没有单独的回收列表
+    pub fn stats(&self) -> FrameAllocStats {
+        let total = self.end.0 - self.start.0;
+        FrameAllocStats {
+            total,
+            allocated: total - self.free_frames,
+            recycled: 0,
+            free: self.free_frames,
+        }
+    }
+    // 最大空闲块的阶数，没有空闲页帧时返回None
+    pub fn max_free_order(&self) -> Option<usize> {
+        self.free_lists.iter().rposition(|list| !list.is_empty())
+    }
+    // 从不小于order的最小空闲块中拆分出大小为2^order的块
+    fn allocate_block(&mut self, order: usize) -> Result<usize, FrameAllocError> {
+        let found = (order..BUDDY_MAX_ORDER)
+            .find(|&o| !self.free_lists[o].is_empty())
+            .ok_or(FrameAllocError)?;
+        let base = *self.free_lists[found].iter().next().unwrap();
+        self.free_lists[found].remove(&base);
+        // 拆分后高半部分放回低一阶的空闲链表
+        for o in (order..found).rev() {
+            self.free_lists[o].insert(base + (1 << o));
+        }
+        self.free_frames -= 1 << order;
+        Ok(base)
+    }
+    // 把区间拆成尽量大的对齐块逐个回收
+    fn free_range(&mut self, mut begin: usize, end: usize) {
+        while begin < end {
+            let align_order = begin.trailing_zeros() as usize;
+            let size_order = (usize::BITS - 1 - (end - begin).leading_zeros()) as usize;
+            let order = align_order.min(size_order).min(BUDDY_MAX_ORDER - 1);
+            self.free_block(begin, order);
+            begin += 1 << order;
+        }
+    }
+    // 回收一个块，和空闲的伙伴块合并成更大的块
+    fn free_block(&mut self, mut base: usize, mut order: usize) {
+        // validity check: 块的任何部分都不能已经空闲
+        for o in 0..BUDDY_MAX_ORDER {
+            let block_begin = base & !((1 << o) - 1);
+            let overlaps_larger = self.free_lists[o].contains(&block_begin);
+            let overlaps_smaller = o < order
+                && self.free_lists[o]
+                    .range(base..base + (1 << order))
+                    .next()
+                    .is_some();
+            if overlaps_larger || overlaps_smaller {
+                panic!("Frame ppn={:x?} has not been allocated!", PhysPageNum(base));
+            }
+        }
+        self.free_frames += 1 << order;
+        while order < BUDDY_MAX_ORDER - 1 {
+            let buddy = base ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            base = base.min(buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(base);
+    }
+}
+
+impl FrameAllocator for crate::sync::Mutex<BuddyFrameAllocator> {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frame()
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.lock().deallocate_frame(ppn)
+    }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frames(count, align_log2)
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.lock().deallocate_frames(ppn, count)
+    }
+    fn stats(&self) -> FrameAllocStats {
+        self.lock().stats()
+    }
+}
+
+pub(crate) fn test_buddy_frame_alloc() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x80010);
+    let mut alloc = BuddyFrameAllocator::new(from, to);
+    assert_eq!(alloc.max_free_order(), Some(4), "whole range in one block");
+    let f1 = alloc.allocate_frame();
+    assert_eq!(f1, Ok(PhysPageNum(0x80000)), "first allocation");
+    assert_eq!(alloc.max_free_order(), Some(3), "largest block split");
+    let f2 = alloc.allocate_frames(4, 2).unwrap();
+    assert_eq!(f2, PhysPageNum(0x80004), "aligned to 4 frames");
+    let f3 = alloc.allocate_frames(2, 1).unwrap();
+    assert_eq!(f3, PhysPageNum(0x80002), "aligned to 2 frames");
+    let f4 = alloc.allocate_frames(3, 0).unwrap();
+    assert_eq!(f4, PhysPageNum(0x80008), "rounded up to a block of 4");
+    assert_eq!(alloc.frames_in_use(), 1 + 4 + 2 + 3, "tail of block freed");
+    assert_eq!(
+        alloc.allocate_frames(8, 3),
+        Err(FrameAllocError),
+        "no block of 8 remains"
+    );
+    assert_eq!(
+        alloc.allocate_frames(0, 0),
+        Err(FrameAllocError),
+        "empty run"
+    );
+    let f5 = alloc.allocate_frames(4, 2).unwrap();
+    assert_eq!(f5, PhysPageNum(0x8000c), "last block of 4");
+    assert_eq!(
+        alloc.allocate_frame(),
+        Ok(PhysPageNum(0x80001)),
+        "last frame"
+    );
+    assert_eq!(alloc.free_frames(), 1, "tail frame of run of 3 left");
+    assert_eq!(
+        alloc.allocate_frame(),
+        Ok(PhysPageNum(0x8000b)),
+        "tail frame"
+    );
+    assert_eq!(alloc.allocate_frame(), Err(FrameAllocError), "exhausted");
+    // 按任意顺序回收，伙伴块合并后恢复成整个区间
+    alloc.deallocate_frames(f2, 4);
+    alloc.deallocate_frames(f5, 4);
+    alloc.deallocate_frame(PhysPageNum(0x8000b));
+    alloc.deallocate_frame(PhysPageNum(0x80001));
+    alloc.deallocate_frames(f4, 3);
+    assert_eq!(alloc.max_free_order(), Some(3), "upper half coalesced");
+    alloc.deallocate_frame(f1.unwrap());
+    alloc.deallocate_frames(f3, 2);
+    assert_eq!(alloc.max_free_order(), Some(4), "coalesced into full order");
+    assert_eq!(alloc.free_lists[4].len(), 1, "one block of 16");
+    let fragments: usize = alloc.free_lists[..4].iter().map(|list| list.len()).sum();
+    assert_eq!(fragments, 0, "no fragments left");
+    assert_eq!(alloc.frames_in_use(), 0, "all frames freed");
+    // 区间起始不对齐时拆成多个对齐的块
+    let alloc = BuddyFrameAllocator::new(PhysPageNum(0x80003), PhysPageNum(0x80010));
+    assert_eq!(alloc.free_frames(), 13, "unaligned range");
+    assert_eq!(alloc.max_free_order(), Some(3), "block of 8 at 0x80008");
+    println!("zihai > buddy frame allocator test passed");
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct AddressSpaceId(u16);
 
@@ -249,59 +1040,273 @@ impl StackAsidAllocator {
         }
     }
 
-    fn deallocate_asid(&mut self, asid: AddressSpaceId) {
-        if asid.next_asid(self.max).is_none()
-            || self.recycled.iter().find(|&v| *v == asid).is_some()
-        {
+    // 还能分配的地址空间编号数量，包括回收的编号
+    pub fn remaining(&self) -> usize {
+        let unallocated = if self.exhausted {
+            0
+        } else {
+            (self.max.0 - self.current.0) as usize + 1
+        };
+        unallocated + self.recycled.len()
+    }
+
+    // 回收地址空间编号，比如地址空间销毁的时候
+    pub fn deallocate_asid(&mut self, asid: AddressSpaceId) {
+        let allocated = if self.exhausted {
+            asid.0 <= self.max.0
+        } else {
+            asid.0 < self.current.0
+        };
+        if !allocated || self.recycled.iter().find(|&v| *v == asid).is_some() {
             panic!("Asid {:x?} has not been allocated!", asid);
         }
         self.recycled.push(asid);
     }
 }
 
-pub(crate) fn test_asid_alloc() {
-    let max_asid = AddressSpaceId(0xffff);
-    let mut alloc = StackAsidAllocator::new(max_asid);
-    let a1 = alloc.allocate_asid();
-    assert_eq!(a1, Ok(AddressSpaceId(0)), "first allocation");
-    let a2 = alloc.allocate_asid();
-    assert_eq!(a2, Ok(AddressSpaceId(1)), "second allocation");
-    alloc.deallocate_asid(a1.unwrap());
-    let a3 = alloc.allocate_asid();
-    assert_eq!(
-        a3,
-        Ok(AddressSpaceId(0)),
-        "after free first one, third allocation"
-    );
-    for _ in 0..max_asid.0 - 2 {
-        alloc.allocate_asid().unwrap();
+// 客户机编号，用于区分不同客户机在G阶段地址翻译中的TLB条目
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VirtMachineId(u16);
+
+impl VirtMachineId {
+    fn next_vmid(&self, max_vmid: VirtMachineId) -> Option<VirtMachineId> {
+        if self.0 >= max_vmid.0 {
+            None
+        } else {
+            Some(VirtMachineId(self.0.wrapping_add(1)))
+        }
     }
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Ok(max_asid), "last asid");
-    let an = alloc.allocate_asid();
-    assert_eq!(
-        an,
-        Err(AsidAllocError),
-        "when asid exhausted, allocate next"
-    );
-    alloc.deallocate_asid(a2.unwrap());
-    let an = alloc.allocate_asid();
-    assert_eq!(
-        an,
-        Ok(AddressSpaceId(1)),
-        "after free second one, allocate next"
-    );
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Err(AsidAllocError), "no asid remains, allocate next");
+}
 
-    let mut alloc = StackAsidAllocator::new(DEFAULT_ASID); // asid not implemented
-    let a1 = alloc.allocate_asid();
-    assert_eq!(
-        a1,
-        Ok(AddressSpaceId(0)),
-        "asid not implemented, first allocation"
-    );
-    let a2 = alloc.allocate_asid();
+impl fmt::Display for VirtMachineId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+const DEFAULT_VMID: VirtMachineId = VirtMachineId(0); // 未实现客户机编号时只有0
+
+// 每个平台上是不一样的，需要通过读写hgatp寄存器获得；要求有H扩展
+pub fn max_vmid() -> VirtMachineId {
+    #[cfg(target_pointer_width = "64")]
+    let mut val: usize = ((1 << 14) - 1) << 44;
+    #[cfg(target_pointer_width = "32")]
+    let mut val: usize = ((1 << 7) - 1) << 22;
+    unsafe {
+        core::arch::asm!("
+        csrr    {tmp}, 0x680
+        or      {val}, {tmp}, {val}
+        csrw    0x680, {val}
+        csrrw   {val}, 0x680, {tmp}
+    ", tmp = out(reg) _, val = inlateout(reg) val)
+    }; // 0x680 => hgatp
+    #[cfg(target_pointer_width = "64")]
+    return VirtMachineId(((val >> 44) & ((1 << 14) - 1)) as u16);
+    #[cfg(target_pointer_width = "32")]
+    return VirtMachineId(((val >> 22) & ((1 << 7) - 1)) as u16);
+}
+
+// 客户机编号分配器，和地址空间编号分配器一样使用栈式回收的算法
+#[derive(Debug)]
+pub struct StackVmidAllocator {
+    current: VirtMachineId,
+    exhausted: bool,
+    max: VirtMachineId,
+    recycled: Vec<VirtMachineId>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VmidAllocError;
+
+impl StackVmidAllocator {
+    pub fn new(max_vmid: VirtMachineId) -> Self {
+        StackVmidAllocator {
+            current: DEFAULT_VMID,
+            exhausted: false,
+            max: max_vmid,
+            recycled: Vec::new(),
+        }
+    }
+
+    pub fn allocate_vmid(&mut self) -> Result<VirtMachineId, VmidAllocError> {
+        if let Some(vmid) = self.recycled.pop() {
+            return Ok(vmid);
+        }
+        if self.exhausted {
+            return Err(VmidAllocError);
+        }
+        if self.current == self.max {
+            self.exhausted = true;
+            return Ok(self.max);
+        }
+        if let Some(next) = self.current.next_vmid(self.max) {
+            let ans = self.current;
+            self.current = next;
+            Ok(ans)
+        } else {
+            Err(VmidAllocError)
+        }
+    }
+
+    // 还能分配的客户机编号数量，包括回收的编号
+    pub fn remaining(&self) -> usize {
+        let unallocated = if self.exhausted {
+            0
+        } else {
+            (self.max.0 - self.current.0) as usize + 1
+        };
+        unallocated + self.recycled.len()
+    }
+
+    // 客户机销毁后回收它的编号
+    pub fn deallocate_vmid(&mut self, vmid: VirtMachineId) {
+        let allocated = if self.exhausted {
+            vmid.0 <= self.max.0
+        } else {
+            vmid.0 < self.current.0
+        };
+        if !allocated || self.recycled.contains(&vmid) {
+            panic!("Vmid {:x?} has not been allocated!", vmid);
+        }
+        self.recycled.push(vmid);
+    }
+}
+
+pub(crate) fn test_vmid_alloc() {
+    let max_vmid = VirtMachineId(0x3fff);
+    let mut alloc = StackVmidAllocator::new(max_vmid);
+    assert_eq!(alloc.remaining(), 0x4000, "all vmids remain");
+    let v1 = alloc.allocate_vmid();
+    assert_eq!(v1, Ok(VirtMachineId(0)), "first allocation");
+    let v2 = alloc.allocate_vmid();
+    assert_eq!(v2, Ok(VirtMachineId(1)), "second allocation");
+    alloc.deallocate_vmid(v1.unwrap());
+    assert_eq!(alloc.remaining(), 0x3fff, "first one freed");
+    assert_eq!(
+        alloc.allocate_vmid(),
+        Ok(VirtMachineId(0)),
+        "freed vmid allocated again"
+    );
+    for _ in 0..max_vmid.0 - 2 {
+        alloc.allocate_vmid().unwrap();
+    }
+    assert_eq!(alloc.allocate_vmid(), Ok(max_vmid), "last vmid");
+    assert_eq!(alloc.remaining(), 0, "vmid exhausted");
+    assert_eq!(alloc.allocate_vmid(), Err(VmidAllocError));
+    alloc.deallocate_vmid(max_vmid);
+    alloc.deallocate_vmid(v2.unwrap());
+    assert_eq!(alloc.remaining(), 2, "two vmids freed");
+    assert_eq!(alloc.allocate_vmid(), Ok(VirtMachineId(1)));
+    assert_eq!(alloc.allocate_vmid(), Ok(max_vmid));
+    assert_eq!(alloc.allocate_vmid(), Err(VmidAllocError));
+
+    let mut alloc = StackVmidAllocator::new(DEFAULT_VMID); // vmid not implemented
+    assert_eq!(alloc.remaining(), 1, "vmid not implemented, one remains");
+    assert_eq!(alloc.allocate_vmid(), Ok(VirtMachineId(0)));
+    assert_eq!(alloc.allocate_vmid(), Err(VmidAllocError));
+    println!("zihai > vmid alloc test passed");
+}
+
+// 在有H扩展的环境中执行所有形式的刷新指令；编码错误会触发非法指令异常
+pub(crate) fn test_hfence() {
+    hfence_gvma(None, None);
+    hfence_gvma(Some(0x8000_1000), None);
+    hfence_gvma(None, Some(1));
+    hfence_gvma(Some(0x8000_1000), Some(1));
+    hfence_vvma(None, None);
+    hfence_vvma(Some(0x1000), None);
+    hfence_vvma(None, Some(1));
+    hfence_vvma(Some(0x1000), Some(1));
+    println!("zihai > hfence test passed");
+}
+
+pub(crate) fn test_asid_recycle(frame_alloc: &DefaultFrameAllocator) {
+    let mut asid_alloc = StackAsidAllocator::new(AddressSpaceId(3));
+    for _ in 0..16 {
+        let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+        let asid = space
+            .assign_asid(&mut asid_alloc)
+            .expect("asid not exhausted");
+        assert_eq!(
+            space.assign_asid(&mut asid_alloc),
+            Ok(asid),
+            "assigned once"
+        );
+        space.release_with_asid(&mut asid_alloc);
+    }
+    assert_eq!(asid_alloc.remaining(), 4, "create and drop cycles");
+    let mut spaces: Vec<_> = (0..4)
+        .map(|_| {
+            let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+            space.assign_asid(&mut asid_alloc).unwrap();
+            space
+        })
+        .collect();
+    let mut extra = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    assert_eq!(extra.assign_asid(&mut asid_alloc), Err(AsidAllocError));
+    let last = spaces.pop().unwrap();
+    let asid = last.asid();
+    assert_eq!(asid, Some(AddressSpaceId(3)), "maximum asid in use");
+    last.release_with_asid(&mut asid_alloc);
+    assert_eq!(extra.assign_asid(&mut asid_alloc).ok(), asid, "asid reused");
+    extra.release_with_asid(&mut asid_alloc);
+    for space in spaces {
+        space.release_with_asid(&mut asid_alloc);
+    }
+    assert_eq!(asid_alloc.remaining(), 4, "all asids returned");
+    println!("zihai > asid recycle test passed");
+}
+
+pub(crate) fn test_asid_alloc() {
+    let max_asid = AddressSpaceId(0xffff);
+    let mut alloc = StackAsidAllocator::new(max_asid);
+    assert_eq!(alloc.remaining(), 0x10000, "all asids remain");
+    let a1 = alloc.allocate_asid();
+    assert_eq!(a1, Ok(AddressSpaceId(0)), "first allocation");
+    let a2 = alloc.allocate_asid();
+    assert_eq!(a2, Ok(AddressSpaceId(1)), "second allocation");
+    assert_eq!(alloc.remaining(), 0xfffe, "two asids allocated");
+    alloc.deallocate_asid(a1.unwrap());
+    assert_eq!(alloc.remaining(), 0xffff, "first one freed");
+    let a3 = alloc.allocate_asid();
+    assert_eq!(
+        a3,
+        Ok(AddressSpaceId(0)),
+        "after free first one, third allocation"
+    );
+    for _ in 0..max_asid.0 - 2 {
+        alloc.allocate_asid().unwrap();
+    }
+    let an = alloc.allocate_asid();
+    assert_eq!(an, Ok(max_asid), "last asid");
+    assert_eq!(alloc.remaining(), 0, "asid exhausted");
+    let an = alloc.allocate_asid();
+    assert_eq!(
+        an,
+        Err(AsidAllocError),
+        "when asid exhausted, allocate next"
+    );
+    alloc.deallocate_asid(a2.unwrap());
+    assert_eq!(alloc.remaining(), 1, "after free second one");
+    let an = alloc.allocate_asid();
+    assert_eq!(
+        an,
+        Ok(AddressSpaceId(1)),
+        "after free second one, allocate next"
+    );
+    let an = alloc.allocate_asid();
+    assert_eq!(an, Err(AsidAllocError), "no asid remains, allocate next");
+
+    let mut alloc = StackAsidAllocator::new(DEFAULT_ASID); // asid not implemented
+    assert_eq!(alloc.remaining(), 1, "asid not implemented, one remains");
+    let a1 = alloc.allocate_asid();
+    assert_eq!(
+        a1,
+        Ok(AddressSpaceId(0)),
+        "asid not implemented, first allocation"
+    );
+    assert_eq!(alloc.remaining(), 0, "asid not implemented, none remains");
+    let a2 = alloc.allocate_asid();
     assert_eq!(
         a2,
         Err(AsidAllocError),
@@ -314,9 +1319,35 @@ pub(crate) fn test_asid_alloc() {
 pub trait FrameAllocator {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError>;
     fn deallocate_frame(&self, ppn: PhysPageNum);
+    // 分配物理地址连续的count个页帧，起始页号按2^align_log2个页帧对齐；
+    // 不支持连续分配的分配器只能分配单个页帧，其它情况返回错误
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        if count == 1 && align_log2 == 0 {
+            self.allocate_frame()
+        } else {
+            Err(FrameAllocError)
+        }
+    }
+    // 回收从ppn开始的count个物理地址连续的页帧
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        for i in 0..count {
+            self.deallocate_frame(PhysPageNum(ppn.0 + i))
+        }
+    }
+    fn deallocate_frames_bulk(&self, ppns: &[PhysPageNum]) {
+        for &ppn in ppns {
+            self.deallocate_frame(ppn)
+        }
+    }
+    // 页帧使用情况的统计
+    fn stats(&self) -> FrameAllocStats;
 }
 
-pub type DefaultFrameAllocator = spin::Mutex<StackFrameAllocator>;
+pub type DefaultFrameAllocator = crate::sync::Mutex<StackFrameAllocator>;
 
 impl FrameAllocator for DefaultFrameAllocator {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
@@ -325,6 +1356,22 @@ impl FrameAllocator for DefaultFrameAllocator {
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         self.lock().deallocate_frame(ppn)
     }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frames(count, align_log2)
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.lock().deallocate_frames(ppn, count)
+    }
+    fn deallocate_frames_bulk(&self, ppns: &[PhysPageNum]) {
+        self.lock().deallocate_frames_bulk(ppns.iter().copied())
+    }
+    fn stats(&self) -> FrameAllocStats {
+        self.lock().stats()
+    }
 }
 
 impl<A: FrameAllocator + ?Sized> FrameAllocator for &A {
@@ -334,6 +1381,286 @@ impl<A: FrameAllocator + ?Sized> FrameAllocator for &A {
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         (**self).deallocate_frame(ppn)
     }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        (**self).allocate_frames(count, align_log2)
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        (**self).deallocate_frames(ppn, count)
+    }
+    fn deallocate_frames_bulk(&self, ppns: &[PhysPageNum]) {
+        (**self).deallocate_frames_bulk(ppns)
+    }
+    fn stats(&self) -> FrameAllocStats {
+        (**self).stats()
+    }
+}
+
+// 限制页帧数量的分配器
+//
+// 包装一个共享的页帧分配器，限制通过它分配出去的页帧总数，比如用于客户机的内存配额。
+// 克隆得到的分配器共享同一个配额
+#[derive(Debug)]
+pub struct LimitedFrameAllocator<A: FrameAllocator> {
+    inner: A,
+    quota: Arc<FrameQuota>,
+}
+
+#[derive(Debug)]
+struct FrameQuota {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl<A: FrameAllocator> LimitedFrameAllocator<A> {
+    pub fn new(inner: A, limit: usize) -> Self {
+        let quota = FrameQuota {
+            limit,
+            used: AtomicUsize::new(0),
+        };
+        LimitedFrameAllocator {
+            inner,
+            quota: Arc::new(quota),
+        }
+    }
+    // 已经分配出去的页帧数量
+    pub fn used(&self) -> usize {
+        self.quota.used.load(Ordering::Relaxed)
+    }
+    // 页帧数量的上限
+    pub fn limit(&self) -> usize {
+        self.quota.limit
+    }
+    // 先占用配额，再从内部的分配器分配；分配失败时归还配额
+    fn allocate_counted(
+        &self,
+        count: usize,
+        f: impl FnOnce(&A) -> Result<PhysPageNum, FrameAllocError>,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        let limit = self.quota.limit;
+        self.quota
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(count).filter(|&n| n <= limit)
+            })
+            .map_err(|_| FrameAllocError)?;
+        f(&self.inner).inspect_err(|_| {
+            self.quota.used.fetch_sub(count, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<A: FrameAllocator + Clone> Clone for LimitedFrameAllocator<A> {
+    fn clone(&self) -> Self {
+        LimitedFrameAllocator {
+            inner: self.inner.clone(),
+            quota: self.quota.clone(),
+        }
+    }
+}
+
+impl<A: FrameAllocator> FrameAllocator for LimitedFrameAllocator<A> {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_counted(1, |a| a.allocate_frame())
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.inner.deallocate_frame(ppn);
+        self.quota.used.fetch_sub(1, Ordering::Relaxed);
+    }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_counted(count, |a| a.allocate_frames(count, align_log2))
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.inner.deallocate_frames(ppn, count);
+        self.quota.used.fetch_sub(count, Ordering::Relaxed);
+    }
+    fn deallocate_frames_bulk(&self, ppns: &[PhysPageNum]) {
+        self.inner.deallocate_frames_bulk(ppns);
+        self.quota.used.fetch_sub(ppns.len(), Ordering::Relaxed);
+    }
+    // 统计的是共享的内层分配器；配额的使用情况见used
+    fn stats(&self) -> FrameAllocStats {
+        self.inner.stats()
+    }
+}
+
+// 一个NUMA节点的物理内存
+#[derive(Debug)]
+struct NumaNode {
+    node_id: usize,
+    start: PhysPageNum,
+    end: PhysPageNum,
+    frames: DefaultFrameAllocator,
+}
+
+// 按NUMA节点划分物理内存的页帧分配器
+//
+// 多路系统中，从运行客户机的处理核所在节点分配页帧，访存更快。节点的范围和处理核的归属
+// 来自设备树的numa-node-id属性。优先从本地节点分配，本地节点耗尽时再从远程节点分配
+#[derive(Debug)]
+pub struct NumaFrameAllocator {
+    nodes: Vec<NumaNode>,
+    hart_nodes: [Option<usize>; crate::hart::MAX_HARTS],
+}
+
+impl NumaFrameAllocator {
+    pub fn new() -> Self {
+        NumaFrameAllocator {
+            nodes: Vec::new(),
+            hart_nodes: [None; crate::hart::MAX_HARTS],
+        }
+    }
+    // 添加一个节点的物理页帧范围
+    pub fn add_node(&mut self, node_id: usize, start: PhysPageNum, end: PhysPageNum) {
+        let overlaps = |n: &NumaNode| start.0 < n.end.0 && n.start.0 < end.0;
+        assert!(
+            !self.nodes.iter().any(overlaps),
+            "numa node {} overlaps existing nodes",
+            node_id
+        );
+        let frames = DefaultFrameAllocator::new(StackFrameAllocator::new(start, end));
+        self.nodes.push(NumaNode {
+            node_id,
+            start,
+            end,
+            frames,
+        });
+    }
+    // 设置处理核所在的节点
+    pub fn assign_hart(&mut self, hartid: usize, node_id: usize) {
+        assert!(
+            self.nodes.iter().any(|n| n.node_id == node_id),
+            "numa node {} not exist",
+            node_id
+        );
+        self.hart_nodes[hartid] = Some(node_id);
+    }
+    // 得到优先从处理核所在节点分配的分配器；没有设置节点的处理核不区分节点
+    pub fn local(&self, hartid: usize) -> NumaLocalAllocator<'_> {
+        NumaLocalAllocator {
+            numa: self,
+            node_id: self.hart_nodes[hartid],
+        }
+    }
+    // 先尝试本地节点，再按顺序尝试其它节点
+    fn allocate_with(
+        &self,
+        node_id: Option<usize>,
+        f: impl Fn(&DefaultFrameAllocator) -> Result<PhysPageNum, FrameAllocError>,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        let is_local = |n: &&NumaNode| Some(n.node_id) == node_id;
+        let local = self.nodes.iter().filter(is_local);
+        let remote = self.nodes.iter().filter(|n| !is_local(n));
+        local
+            .chain(remote)
+            .find_map(|n| f(&n.frames).ok())
+            .ok_or(FrameAllocError)
+    }
+    fn node_of(&self, ppn: PhysPageNum) -> &NumaNode {
+        let node = self
+            .nodes
+            .iter()
+            .find(|n| ppn.is_within_range(n.start, n.end));
+        node.unwrap_or_else(|| panic!("Frame ppn={:x?} is not in any numa node!", ppn))
+    }
+}
+
+impl FrameAllocator for NumaFrameAllocator {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_with(None, |a| a.allocate_frame())
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.node_of(ppn).frames.deallocate_frame(ppn)
+    }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_with(None, |a| a.allocate_frames(count, align_log2))
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.node_of(ppn).frames.deallocate_frames(ppn, count)
+    }
+    // 所有节点的统计之和
+    fn stats(&self) -> FrameAllocStats {
+        self.nodes
+            .iter()
+            .map(|n| n.frames.stats())
+            .fold(FrameAllocStats::default(), |acc, s| acc + s)
+    }
+}
+
+// 优先从某个节点分配页帧的分配器
+#[derive(Copy, Clone, Debug)]
+pub struct NumaLocalAllocator<'a> {
+    numa: &'a NumaFrameAllocator,
+    node_id: Option<usize>,
+}
+
+impl FrameAllocator for NumaLocalAllocator<'_> {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.numa
+            .allocate_with(self.node_id, |a| a.allocate_frame())
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.numa.deallocate_frame(ppn)
+    }
+    fn allocate_frames(
+        &self,
+        count: usize,
+        align_log2: usize,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        self.numa
+            .allocate_with(self.node_id, |a| a.allocate_frames(count, align_log2))
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.numa.deallocate_frames(ppn, count)
+    }
+    fn stats(&self) -> FrameAllocStats {
+        self.numa.stats()
+    }
+}
+
+pub(crate) fn test_numa_frame_alloc() {
+    let mut numa = NumaFrameAllocator::new();
+    numa.add_node(0, PhysPageNum(0x80000), PhysPageNum(0x80004));
+    numa.add_node(1, PhysPageNum(0x90000), PhysPageNum(0x90002));
+    numa.assign_hart(0, 0);
+    numa.assign_hart(1, 1);
+    let in_node = |ppn: PhysPageNum, node: usize| {
+        ppn.is_within_range(numa.nodes[node].start, numa.nodes[node].end)
+    };
+    let hart1 = numa.local(1);
+    let a = hart1.allocate_frame().unwrap();
+    let b = hart1.allocate_frame().unwrap();
+    assert!(in_node(a, 1) && in_node(b, 1), "local node preferred");
+    let c = hart1.allocate_frame().unwrap();
+    assert!(in_node(c, 0), "spill to remote node when local exhausted");
+    hart1.deallocate_frame(a);
+    let d = hart1.allocate_frame().unwrap();
+    assert_eq!(d, a, "local frame freed and reused");
+    let hart0 = numa.local(0);
+    let e = hart0.allocate_frame().unwrap();
+    assert!(in_node(e, 0), "hart 0 allocates from its own node");
+    assert_eq!(
+        hart0.allocate_frames(4, 0),
+        Err(FrameAllocError),
+        "no node has 4 contiguous frames left"
+    );
+    let f = hart0.allocate_frames(2, 0).unwrap();
+    assert!(in_node(f, 0), "contiguous frames from local node");
+    for ppn in [b, c, d, e, f, f.next_page()] {
+        numa.deallocate_frame(ppn);
+    }
+    println!("zihai > numa frame allocator test passed");
 }
 
 // 表示整个页帧内存的所有权
@@ -347,80 +1674,485 @@ impl<A: FrameAllocator> FrameBox<A> {
     // 分配页帧并创建FrameBox
     pub fn try_new_in(frame_alloc: A) -> Result<FrameBox<A>, FrameAllocError> {
         let ppn = frame_alloc.allocate_frame()?;
+        #[cfg(feature = "frame-debug")]
+        debug_acquire_frame(ppn);
         Ok(FrameBox { ppn, frame_alloc })
     }
-    // // unsafe说明。调用者必须保证以下约定：
-    // // 1. ppn只被一个FrameBox拥有，也就是不能破坏所有权约定
-    // // 2. 这个ppn是由frame_alloc分配的
-    // unsafe fn from_ppn(ppn: PhysPageNum, frame_alloc: A) -> Self {
-    //     Self { ppn, frame_alloc }
-    // }
+    // 分配页帧并清零整个页帧，避免回收的页帧中残留上一个客户机的数据；要求内核对页帧有恒等映射
+    pub fn try_new_zeroed_in(frame_alloc: A) -> Result<FrameBox<A>, FrameAllocError> {
+        let frame = Self::try_new_in(frame_alloc)?;
+        zero_frame(&frame);
+        Ok(frame)
+    }
+    // unsafe说明。调用者必须保证以下约定：
+    // 1. ppn只被一个FrameBox拥有，也就是不能破坏所有权约定
+    // 2. 这个ppn是由frame_alloc分配的
+    // 启用frame-debug功能时，违反第1条约定会导致panic
+    pub unsafe fn from_raw(ppn: PhysPageNum, frame_alloc: A) -> Self {
+        #[cfg(feature = "frame-debug")]
+        debug_acquire_frame(ppn);
+        Self { ppn, frame_alloc }
+    }
 
     // 得到本页帧内存的页号
     pub fn phys_page_num(&self) -> PhysPageNum {
         self.ppn
     }
+    // 放弃页帧的所有权，不释放页帧，返回它的页号
+    pub fn into_raw(self) -> PhysPageNum {
+        let this = core::mem::ManuallyDrop::new(self);
+        #[cfg(feature = "frame-debug")]
+        debug_release_frame(this.ppn);
+        // note(unsafe): this.frame_alloc is never used after read
+        drop(unsafe { core::ptr::read(&this.frame_alloc) });
+        this.ppn
+    }
 }
 
 impl<A: FrameAllocator> Drop for FrameBox<A> {
     fn drop(&mut self) {
+        #[cfg(feature = "frame-debug")]
+        debug_release_frame(self.ppn);
         // 释放所占有的页帧
         self.frame_alloc.deallocate_frame(self.ppn);
     }
 }
 
-// 分页模式
-//
-// 在每个页式管理模式下，我们认为分页系统分为不同的等级，每一级如果存在大页页表，都应当有相应的对齐要求。
-// 然后当前的页式管理模式，一定有一个固定的最大等级。
+// 共享页帧内存的所有权，用引用计数管理，比如写时复制的客户机内存
 //
-// 如果虚拟内存的模式是直接映射或者线性映射，这将不属于分页模式的范围。应当混合使用其它的地址空间，综合成为更大的地址空间。
-pub trait PageMode: Copy {
-    /// Number of binary bits of the number in bytes each *frame* would contain under current page mode.
-    ///
-    /// *Frames* are defined as memory blocks with the length of the greatest common divisor of all
-    /// possible page table sizes under current page mode.
-    /// Each page under current mode should contain integer multiples of frames.
-    ///
-    /// Simple page modes may contain only one frame for page table of any levels.
-    /// If such mode contains only 4KiB sized pages, value of frame size bits would be 12.
-    const FRAME_SIZE_BITS: usize;
-    // 当前分页模式下，物理页号的位数
-    const PPN_BITS: usize;
-    /// Number of maximum page levels this mode supports
-    const MAX_PAGE_LEVELS: u8;
+// 计数和FrameBox一起放在堆上的头部中；克隆时增加计数，最后一个FrameRc释放时才释放页帧
+pub struct FrameRc<A: FrameAllocator = DefaultFrameAllocator> {
+    header: core::ptr::NonNull<FrameRcHeader<A>>,
+}
 
-    const PAGE_ENTRIES_BITS: u8;
-    fn get_layout_for_level(level: PageLevel) -> PageLayout {
-        // lowest possible leaf level alignment
-        let mut align_in_frames = 1_usize;
-        // for every higher level, physical address alignment would multiply by
-        for _ in 0..level.0 {
-            if align_in_frames.leading_zeros() < Self::PAGE_ENTRIES_BITS as u32 {
-                panic!("too much page levels")
-            }
-            align_in_frames <<= Self::PAGE_ENTRIES_BITS;
+struct FrameRcHeader<A: FrameAllocator> {
+    count: AtomicUsize,
+    frame: FrameBox<A>,
+}
+
+unsafe impl<A: FrameAllocator + Send + Sync> Send for FrameRc<A> {}
+unsafe impl<A: FrameAllocator + Send + Sync> Sync for FrameRc<A> {}
+
+impl<A: FrameAllocator> FrameRc<A> {
+    // 分配页帧并创建FrameRc，引用计数为1
+    pub fn try_new_in(frame_alloc: A) -> Result<FrameRc<A>, FrameAllocError> {
+        Ok(FrameRc::from_box(FrameBox::try_new_in(frame_alloc)?))
+    }
+    // 把独占的页帧转为共享的页帧
+    pub fn from_box(frame: FrameBox<A>) -> FrameRc<A> {
+        let header = Box::new(FrameRcHeader {
+            count: AtomicUsize::new(1),
+            frame,
+        });
+        FrameRc {
+            header: core::ptr::NonNull::from(Box::leak(header)),
         }
-        unsafe { PageLayout::new_unchecked(align_in_frames) }
     }
-    // 得到从高到低的页表等级
-    fn visit_levels_until(level: PageLevel) -> LevelIter {
-        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
-        LevelIter::falling_includes(Self::MAX_PAGE_LEVELS - 1, level.0)
+    // 得到本页帧内存的页号
+    pub fn phys_page_num(&self) -> PhysPageNum {
+        self.header().frame.phys_page_num()
     }
-    // 得到从高到低的页表等级，不包括level
-    fn visit_levels_before(level: PageLevel) -> LevelIter {
-        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
-        LevelIter::falling_excludes(Self::MAX_PAGE_LEVELS - 1, level.0)
+    // 共享本页帧的FrameRc数量
+    pub fn strong_count(&self) -> usize {
+        self.header().count.load(Ordering::Acquire)
     }
-    // 得到从高到低的页表等级
-    fn visit_levels_from(level: PageLevel) -> LevelIter {
-        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
-        LevelIter::falling_includes(level.0, 0)
+    // 是否只有这一个FrameRc；写时复制时不必复制，直接改为可写即可
+    pub fn is_unique(&self) -> bool {
+        self.strong_count() == 1
     }
-    // 得到一个虚拟页号对应等级的索引
-    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize;
-    // 得到一段虚拟页号对应该等级索引的区间；如果超过此段最大的索引，返回索引的结束值为索引的最大值
+    // 只有这一个FrameRc时，取回独占的FrameBox；否则原样返回
+    pub fn try_unwrap(self) -> Result<FrameBox<A>, FrameRc<A>> {
+        if !self.is_unique() {
+            return Err(self);
+        }
+        let this = core::mem::ManuallyDrop::new(self);
+        // note(unsafe): the only reference owns the header
+        let header = unsafe { Box::from_raw(this.header.as_ptr()) };
+        Ok(header.frame)
+    }
+    fn header(&self) -> &FrameRcHeader<A> {
+        // note(unsafe): header lives until the last FrameRc drops
+        unsafe { self.header.as_ref() }
+    }
+}
+
+impl<A: FrameAllocator> Clone for FrameRc<A> {
+    fn clone(&self) -> Self {
+        self.header().count.fetch_add(1, Ordering::Relaxed);
+        FrameRc {
+            header: self.header,
+        }
+    }
+}
+
+impl<A: FrameAllocator> Drop for FrameRc<A> {
+    fn drop(&mut self) {
+        if self.header().count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        core::sync::atomic::fence(Ordering::Acquire);
+        // 最后一个FrameRc，释放头部，其中的FrameBox释放页帧
+        drop(unsafe { Box::from_raw(self.header.as_ptr()) });
+    }
+}
+
+impl<A: FrameAllocator> fmt::Debug for FrameRc<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameRc")
+            .field("ppn", &self.phys_page_num())
+            .field("count", &self.strong_count())
+            .finish()
+    }
+}
+
+pub(crate) fn test_frame_rc(frame_alloc: &DefaultFrameAllocator) {
+    let frames_in_use = || frame_alloc.lock().frames_in_use();
+    let before = frames_in_use();
+    let rc1 = FrameRc::try_new_in(frame_alloc).unwrap();
+    let ppn = rc1.phys_page_num();
+    assert!(rc1.is_unique(), "new frame is unique");
+    let rc2 = rc1.clone();
+    let rc3 = rc2.clone();
+    assert_eq!(rc1.strong_count(), 3, "two clones");
+    assert_eq!(rc3.phys_page_num(), ppn, "clones share the frame");
+    assert_eq!(frames_in_use(), before + 1, "one frame shared");
+    let rc2 = rc2.try_unwrap().unwrap_err();
+    drop(rc1);
+    drop(rc3);
+    assert_eq!(frames_in_use(), before + 1, "frame alive with one owner");
+    assert!(rc2.is_unique(), "last owner");
+    drop(rc2);
+    assert_eq!(frames_in_use(), before, "last drop frees the frame");
+    let rc = FrameRc::try_new_in(frame_alloc).unwrap();
+    let frame = rc.try_unwrap().expect("unique frame unwraps");
+    assert_eq!(frames_in_use(), before + 1, "unwrapped frame kept");
+    drop(frame);
+    assert_eq!(frames_in_use(), before, "unwrapped frame freed");
+    println!("zihai > reference counted frame test passed");
+}
+
+// 调试模式下，记录所有被FrameBox拥有的页帧，用来检查所有权约定
+#[cfg(feature = "frame-debug")]
+struct FrameOwners {
+    owned: Vec<PhysPageNum>,
+}
+
+#[cfg(feature = "frame-debug")]
+impl FrameOwners {
+    const fn new() -> Self {
+        FrameOwners { owned: Vec::new() }
+    }
+    // 记录页帧被拥有；如果已经被拥有，返回false
+    fn acquire(&mut self, ppn: PhysPageNum) -> bool {
+        if self.owned.contains(&ppn) {
+            return false;
+        }
+        self.owned.push(ppn);
+        true
+    }
+    // 记录页帧不再被拥有；如果本来就没有被拥有，返回false
+    fn release(&mut self, ppn: PhysPageNum) -> bool {
+        match self.owned.iter().position(|&v| v == ppn) {
+            Some(idx) => {
+                self.owned.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(feature = "frame-debug")]
+static FRAME_OWNERS: spin::Mutex<FrameOwners> = spin::Mutex::new(FrameOwners::new());
+
+#[cfg(feature = "frame-debug")]
+fn debug_acquire_frame(ppn: PhysPageNum) {
+    if !FRAME_OWNERS.lock().acquire(ppn) {
+        panic!("Frame ppn={:x?} is owned by more than one FrameBox!", ppn);
+    }
+}
+
+#[cfg(feature = "frame-debug")]
+fn debug_release_frame(ppn: PhysPageNum) {
+    if !FRAME_OWNERS.lock().release(ppn) {
+        panic!("Frame ppn={:x?} is freed while not owned!", ppn);
+    }
+}
+
+#[cfg(feature = "frame-debug")]
+pub(crate) fn test_frame_ownership() {
+    let mut owners = FrameOwners::new();
+    let ppn = PhysPageNum(0x80000);
+    assert!(owners.acquire(ppn), "first owner");
+    assert!(
+        !owners.acquire(ppn),
+        "second owner over the same ppn panics"
+    );
+    assert!(owners.release(ppn), "owner frees");
+    assert!(!owners.release(ppn), "free while not owned panics");
+    // FrameBox registers its frame in global owner set
+    let frame_alloc = DefaultFrameAllocator::new(StackFrameAllocator::new(
+        PhysPageNum(0x80000),
+        PhysPageNum(0x100000),
+    ));
+    let owned = |ppn| FRAME_OWNERS.lock().owned.contains(&ppn);
+    let frame_box = FrameBox::try_new_in(&frame_alloc).unwrap();
+    let ppn = frame_box.phys_page_num();
+    assert!(owned(ppn), "allocated frame is owned");
+    let ppn = frame_box.into_raw();
+    assert!(!owned(ppn), "frame is not owned after into_raw");
+    let frame_box = unsafe { FrameBox::from_raw(ppn, &frame_alloc) };
+    assert!(owned(ppn), "frame is owned after from_raw");
+    drop(frame_box);
+    assert!(!owned(ppn), "frame is not owned after drop");
+    println!("zihai > frame ownership debug test passed");
+}
+
+// 可重复使用的临时页帧，只在初始化时分配一次
+//
+// 临时映射、非对齐访问的暂存、构造设备树等操作都需要短暂使用一个页帧，
+// 使用同一个页帧可以避免反复分配和释放。使用时需要上锁，因此同一个核上不能嵌套使用。
+pub struct ScratchPage {
+    ppn: spin::Mutex<Option<PhysPageNum>>,
+}
+
+impl ScratchPage {
+    pub const fn new() -> Self {
+        ScratchPage {
+            ppn: spin::Mutex::new(None),
+        }
+    }
+    // 放入临时页帧；此后页帧由ScratchPage独占，不会归还给分配器
+    pub fn init<A: FrameAllocator>(&self, frame: FrameBox<A>) {
+        let mut ppn = self.ppn.lock();
+        assert!(ppn.is_none(), "scratch page already initialized");
+        *ppn = Some(frame.into_raw());
+    }
+    // 取出临时页帧，调用者负责释放它
+    pub fn take(&self) -> Option<PhysPageNum> {
+        self.ppn.lock().take()
+    }
+    // 上锁并使用临时页帧；每次使用前，页帧的内容都会被清零
+    pub fn with_scratch<R>(&self, f: impl FnOnce(&mut [u8; 4096]) -> R) -> R {
+        let ppn = self.ppn.lock();
+        let ppn = ppn.expect("scratch page not initialized");
+        // 注意: 要求内核对页帧有恒等映射；持有锁期间，页帧只被当前调用者使用
+        let page = unsafe { &mut *(ppn.addr_begin::<Sv39>().0 as *mut [u8; 4096]) };
+        page.fill(0);
+        f(page)
+    }
+}
+
+static SCRATCH_PAGE: ScratchPage = ScratchPage::new();
+
+// 得到全局的临时页帧
+pub fn scratch_page() -> &'static ScratchPage {
+    &SCRATCH_PAGE
+}
+
+// 使用全局的临时页帧
+pub fn with_scratch<R>(f: impl FnOnce(&mut [u8; 4096]) -> R) -> R {
+    SCRATCH_PAGE.with_scratch(f)
+}
+
+pub(crate) fn test_scratch_page(frame_alloc: &DefaultFrameAllocator) {
+    let scratch = ScratchPage::new();
+    scratch.init(FrameBox::try_new_in(frame_alloc).unwrap());
+    let first = scratch.with_scratch(|page| {
+        page[0] = 0x5a;
+        page[4095] = 0xa5;
+        page.as_ptr() as usize
+    });
+    let second = scratch.with_scratch(|page| {
+        assert!(page.iter().all(|&b| b == 0), "zeroed before each use");
+        page.as_ptr() as usize
+    });
+    assert_eq!(first, second, "the same frame is reused");
+    let ppn = scratch.take().expect("take scratch frame");
+    drop(unsafe { FrameBox::from_raw(ppn, frame_alloc) });
+    assert_eq!(scratch.take(), None, "scratch frame taken");
+    println!("zihai > scratch page test passed");
+}
+
+// 预先清零的页帧池
+//
+// 按需分页时，缺页处理要分配并清零页帧，清零会增加缺页的延迟。页帧池保存若干已清零的页帧，
+// 缺页时直接取出；低于低水位时，由空闲的处理核或者运行循环的末尾调用refill补充
+#[derive(Debug)]
+pub struct ZeroedFramePool<A: FrameAllocator> {
+    frames: Vec<FrameBox<A>>,
+    capacity: usize,
+    low_water: usize,
+    frame_alloc: A,
+}
+
+impl<A: FrameAllocator + Clone> ZeroedFramePool<A> {
+    // 创建空的页帧池；需要调用refill才会填入页帧
+    pub fn new_in(capacity: usize, low_water: usize, frame_alloc: A) -> Self {
+        assert!(low_water <= capacity, "low water mark exceeds capacity");
+        ZeroedFramePool {
+            frames: Vec::with_capacity(capacity),
+            capacity,
+            low_water,
+            frame_alloc,
+        }
+    }
+    // 取出一个已清零的页帧，不做清零操作；池为空时返回None
+    pub fn take(&mut self) -> Option<FrameBox<A>> {
+        self.frames.pop()
+    }
+    // 池中的页帧数量
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    // 页帧数量低于低水位，需要补充
+    pub fn needs_refill(&self) -> bool {
+        self.frames.len() < self.low_water
+    }
+    // 分配并清零页帧，直到填满页帧池；返回补充的页帧数量。
+    // 分配失败时，已经补充的页帧保留在池中
+    pub fn refill(&mut self) -> Result<usize, FrameAllocError> {
+        let mut count = 0;
+        while self.frames.len() < self.capacity {
+            let frame = FrameBox::try_new_in(self.frame_alloc.clone())?;
+            zero_frame(&frame);
+            self.frames.push(frame);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+// 清零一个页帧；要求内核对页帧有恒等映射
+pub fn zero_frame<A: FrameAllocator>(frame: &FrameBox<A>) {
+    let addr = frame.phys_page_num().addr_begin::<Sv39>().0;
+    // 注意: 页帧由FrameBox独占
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, 1 << Sv39::FRAME_SIZE_BITS) };
+}
+
+pub(crate) fn test_zeroed_frame(frame_alloc: &DefaultFrameAllocator) {
+    let frame_size = 1 << Sv39::FRAME_SIZE_BITS;
+    let dirty = FrameBox::try_new_in(frame_alloc).unwrap();
+    let dirty_ppn = dirty.phys_page_num();
+    let addr = dirty_ppn.addr_begin::<Sv39>().0;
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0xa5, frame_size) };
+    drop(dirty);
+    let frame = FrameBox::try_new_zeroed_in(frame_alloc).unwrap();
+    assert_eq!(frame.phys_page_num(), dirty_ppn, "recycled frame");
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, frame_size) };
+    assert!(bytes.iter().all(|&b| b == 0), "recycled frame zeroed");
+    drop(frame);
+    // 页表帧同样经过清零
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    space.set_zero_tables(true);
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x80400), 1, flags)
+        .unwrap();
+    assert_eq!(
+        space.translate_addr(VirtAddr(0x9000_0123)),
+        Ok(PhysAddr(0x8040_0123)),
+        "mapped through zeroed tables"
+    );
+    println!("zihai > zeroed frame test passed");
+}
+
+pub(crate) fn test_zeroed_frame_pool(frame_alloc: &DefaultFrameAllocator) {
+    let checkpoint = frame_alloc.lock().checkpoint();
+    let mut pool = ZeroedFramePool::new_in(4, 2, frame_alloc);
+    assert!(pool.take().is_none(), "empty before refill");
+    assert!(pool.needs_refill(), "empty pool needs refill");
+    assert_eq!(pool.refill(), Ok(4), "refilled to capacity");
+    assert!(!pool.needs_refill(), "full pool");
+    let page = |frame: &FrameBox<&DefaultFrameAllocator>| {
+        let addr = frame.phys_page_num().addr_begin::<Sv39>().0;
+        unsafe { &mut *(addr as *mut [u8; 4096]) }
+    };
+    let frame = pool.take().unwrap();
+    assert!(page(&frame).iter().all(|&b| b == 0), "zeroed frame");
+    // a mark left in a pooled frame survives taking it, so it's not zeroed on the hot path
+    page(pool.frames.last().unwrap())[0] = 0x5a;
+    let marked = pool.take().unwrap();
+    assert_eq!(page(&marked)[0], 0x5a, "no zeroing on take");
+    assert_eq!(pool.len(), 2, "two frames left");
+    assert!(!pool.needs_refill(), "at low water mark");
+    drop(pool.take());
+    assert!(pool.needs_refill(), "below low water mark");
+    assert_eq!(pool.refill(), Ok(3), "refilled to capacity");
+    assert_eq!(pool.len(), 4, "full again");
+    drop((frame, marked, pool));
+    frame_alloc.lock().assert_restored(checkpoint);
+    println!("zihai > zeroed frame pool test passed");
+}
+
+// 分页模式
+//
+// 在每个页式管理模式下，我们认为分页系统分为不同的等级，每一级如果存在大页页表，都应当有相应的对齐要求。
+// 然后当前的页式管理模式，一定有一个固定的最大等级。
+//
+// 如果虚拟内存的模式是直接映射或者线性映射，这将不属于分页模式的范围。应当混合使用其它的地址空间，综合成为更大的地址空间。
+pub trait PageMode: Copy {
+    /// Number of binary bits of the number in bytes each *frame* would contain under current page mode.
+    ///
+    /// *Frames* are defined as memory blocks with the length of the greatest common divisor of all
+    /// possible page table sizes under current page mode.
+    /// Each page under current mode should contain integer multiples of frames.
+    ///
+    /// Simple page modes may contain only one frame for page table of any levels.
+    /// If such mode contains only 4KiB sized pages, value of frame size bits would be 12.
+    const FRAME_SIZE_BITS: usize;
+    // 当前分页模式下，物理页号的位数
+    const PPN_BITS: usize;
+    /// Number of maximum page levels this mode supports
+    const MAX_PAGE_LEVELS: u8;
+
+    const PAGE_ENTRIES_BITS: u8;
+    fn get_layout_for_level(level: PageLevel) -> PageLayout {
+        // lowest possible leaf level alignment
+        let mut align_in_frames = 1_usize;
+        // for every higher level, physical address alignment would multiply by
+        for _ in 0..level.0 {
+            if align_in_frames.leading_zeros() < Self::PAGE_ENTRIES_BITS as u32 {
+                panic!("too much page levels")
+            }
+            align_in_frames <<= Self::PAGE_ENTRIES_BITS;
+        }
+        unsafe { PageLayout::new_unchecked(align_in_frames) }
+    }
+    // 得到该等级的叶子页表项覆盖的整个虚拟页号区间；页号向下对齐到该等级的页
+    fn leaf_va_range(base_vpn: VirtPageNum, level: PageLevel) -> Range<VirtPageNum> {
+        let span = Self::get_layout_for_level(level).align_in_frames();
+        let start = base_vpn.0 - base_vpn.0 % span;
+        VirtPageNum(start)..VirtPageNum(start + span)
+    }
+    // 得到叶子页表项实际覆盖的虚拟页号区间；通常和leaf_va_range相同，
+    // 连续页编码（比如Svnapot）的页表项覆盖多个相邻的同级页表项
+    fn entry_leaf_va_range(
+        entry: &Self::Entry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        let _ = entry;
+        Self::leaf_va_range(base_vpn, level)
+    }
+    // 得到从高到低的页表等级
+    fn visit_levels_until(level: PageLevel) -> LevelIter {
+        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
+        LevelIter::falling_includes(Self::MAX_PAGE_LEVELS - 1, level.0)
+    }
+    // 得到从高到低的页表等级，不包括level
+    fn visit_levels_before(level: PageLevel) -> LevelIter {
+        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
+        LevelIter::falling_excludes(Self::MAX_PAGE_LEVELS - 1, level.0)
+    }
+    // 得到从高到低的页表等级
+    fn visit_levels_from(level: PageLevel) -> LevelIter {
+        assert!(level.0 < Self::MAX_PAGE_LEVELS, "page level doesn't exist");
+        LevelIter::falling_includes(level.0, 0)
+    }
+    // 得到一个虚拟页号对应等级的索引
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize;
+    // 得到一段虚拟页号对应该等级索引的区间；如果超过此段最大的索引，返回索引的结束值为索引的最大值
     fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize>;
     // 得到虚拟页号在当前等级下重新索引得到的页号
     fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum;
@@ -428,6 +2160,19 @@ pub trait PageMode: Copy {
     type PageTable: core::ops::Index<usize, Output = Self::Slot> + core::ops::IndexMut<usize>;
     // 创建页表时，把它的所有条目设置为无效条目
     fn init_page_table(table: &mut Self::PageTable);
+    // 根页表占用的页帧数量，根页表需要按这个数量的页帧对齐
+    const ROOT_TABLE_FRAMES: usize = 1;
+    // 把该等级页表的索引分解为(页帧序号, 页帧中页表的索引)；根页表占用多个页帧时需要覆盖，
+    // 每个页帧都按PageTable访问，不会越过页帧的边界
+    fn split_table_index(level: PageLevel, idx: usize) -> (usize, usize) {
+        let _ = level;
+        (0, idx)
+    }
+    // 该等级的页表中条目的数量
+    fn table_entries(level: PageLevel) -> usize {
+        let _ = level;
+        1 << Self::PAGE_ENTRIES_BITS
+    }
     // 页式管理模式，可能有效也可能无效的页表项类型
     type Slot;
     // 页式管理模式，有效的页表项类型
@@ -440,12 +2185,65 @@ pub trait PageMode: Copy {
     fn slot_set_child(slot: &mut Self::Slot, ppn: PhysPageNum);
     // 写数据，建立一个到内存地址的页表项
     fn slot_set_mapping(slot: &mut Self::Slot, ppn: PhysPageNum, flags: Self::Flags);
+    // 写数据，建立一个指定内存类型的页表项，比如设备寄存器使用Io类型；
+    // 不支持页面内存类型的分页模式只能使用Pma类型
+    fn slot_set_mapping_typed(
+        slot: &mut Self::Slot,
+        ppn: PhysPageNum,
+        flags: Self::Flags,
+        mem_type: MemType,
+    ) {
+        assert!(
+            mem_type == MemType::Pma,
+            "memory type {:?} not supported in this paging mode",
+            mem_type
+        );
+        Self::slot_set_mapping(slot, ppn, flags)
+    }
+    // 清除页表项，使它成为无效的页表项
+    fn slot_clear(slot: &mut Self::Slot);
     // 判断页表项目是否是一个叶子节点
     fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool;
     // 写数据到页表项目，说明这是一个叶子节点
     fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags);
     // 得到一个页表项目包含的物理页号
     fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum;
+    // 得到一个页表项目的设置
+    fn entry_get_flags(entry: &Self::Entry) -> Self::Flags;
+    // 设置是否同时允许写和执行，违反W^X原则
+    fn flags_write_execute(flags: &Self::Flags) -> bool;
+    // 刷新一个虚拟地址的地址翻译缓存；地址空间需要处于激活状态才有意义
+    unsafe fn flush_page(va: VirtAddr) {
+        core::arch::asm!("sfence.vma {}, zero", in(reg) va.0, options(nostack));
+    }
+    // 按索引从低到高，遍历页表的所有条目
+    fn page_table_iter(table: &Self::PageTable) -> PageTableIter<'_, Self>
+    where
+        Self: Sized,
+    {
+        PageTableIter { table, idx: 0 }
+    }
+}
+
+// 页表条目的迭代器，得到条目的索引和条目
+pub struct PageTableIter<'a, M: PageMode> {
+    table: &'a M::PageTable,
+    idx: usize,
+}
+
+impl<'a, M: PageMode> Iterator for PageTableIter<'a, M>
+where
+    M::Slot: 'a,
+{
+    type Item = (usize, &'a M::Slot);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= 1 << M::PAGE_ENTRIES_BITS {
+            return None;
+        }
+        let idx = self.idx;
+        self.idx += 1;
+        Some((idx, &self.table[idx]))
+    }
 }
 
 /// Levels of paged memory systems
@@ -460,6 +2258,33 @@ impl PageLevel {
     pub const fn leaf_level() -> Self {
         Self(0)
     }
+    /// Display adapter rendering the page size of this level under paging mode `M`,
+    /// e.g. "2MiB" for level one of Sv39
+    pub fn display<M: PageMode>(self) -> PageLevelFmt<M> {
+        PageLevelFmt {
+            level: self,
+            _mode: PhantomData,
+        }
+    }
+}
+
+/// Page size of a page level under paging mode `M`, created by `PageLevel::display`.
+pub struct PageLevelFmt<M> {
+    level: PageLevel,
+    _mode: PhantomData<M>,
+}
+
+impl<M: PageMode> fmt::Display for PageLevelFmt<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+        let mut size = M::get_layout_for_level(self.level).page_size::<M>();
+        let mut unit = 0;
+        while unit + 1 < UNITS.len() && size >= 1024 && size % 1024 == 0 {
+            size /= 1024;
+            unit += 1;
+        }
+        write!(f, "{}{}", size, UNITS[unit])
+    }
 }
 
 /// Iterator of page levels, can be forward or backward.
@@ -572,6 +2397,29 @@ impl PageLayout {
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Sv39;
 
+// 与Sv39结构相同的页表项覆盖的虚拟页号区间；最低一级的连续页页表项覆盖整个连续区间
+fn napot_leaf_va_range<M: PageMode>(
+    entry: &Sv39PageEntry,
+    base_vpn: VirtPageNum,
+    level: PageLevel,
+) -> Range<VirtPageNum> {
+    if level.0 == 0 && entry.is_napot() {
+        let start = base_vpn.0 & !(NAPOT_FRAMES - 1);
+        VirtPageNum(start)..VirtPageNum(start + NAPOT_FRAMES)
+    } else {
+        M::leaf_va_range(base_vpn, level)
+    }
+}
+
+impl Sv39 {
+    // 写数据，建立一个Svnapot连续页的页表项。连续区间的16个页表项都要这样写入，
+    // 每一项的页号都是区间起始的页号；ppn需要按16个页帧对齐
+    pub fn slot_set_napot(slot: &mut Sv39PageSlot, ppn: PhysPageNum, flags: Sv39Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv39PageEntry) };
+        ans.write_napot(ppn, Sv39Flags::V | flags);
+    }
+}
+
 impl PageMode for Sv39 {
     const FRAME_SIZE_BITS: usize = 12;
     const PPN_BITS: usize = 44;
@@ -627,6 +2475,19 @@ impl PageMode for Sv39 {
         let ans = unsafe { &mut *(slot as *mut _ as *mut Sv39PageEntry) };
         ans.write_ppn_flags(ppn, Sv39Flags::V | flags);
     }
+    fn slot_set_mapping_typed(
+        slot: &mut Sv39PageSlot,
+        ppn: PhysPageNum,
+        flags: Sv39Flags,
+        mem_type: MemType,
+    ) {
+        Sv39::slot_set_mapping(slot, ppn, flags);
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv39PageEntry) };
+        ans.write_pbmt(mem_type);
+    }
+    fn slot_clear(slot: &mut Sv39PageSlot) {
+        slot.bits = 0;
+    }
     fn entry_is_leaf_page(entry: &mut Sv39PageEntry) -> bool {
         // 如果包含R、W或X项，就是叶子节点。
         entry
@@ -639,6 +2500,19 @@ impl PageMode for Sv39 {
     fn entry_get_ppn(entry: &Sv39PageEntry) -> PhysPageNum {
         entry.ppn()
     }
+    fn entry_leaf_va_range(
+        entry: &Sv39PageEntry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        napot_leaf_va_range::<Sv39>(entry, base_vpn, level)
+    }
+    fn entry_get_flags(entry: &Sv39PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn flags_write_execute(flags: &Sv39Flags) -> bool {
+        flags.contains(Sv39Flags::W | Sv39Flags::X)
+    }
 }
 
 #[repr(C)]
@@ -669,19 +2543,71 @@ pub struct Sv39PageEntry {
     bits: usize,
 }
 
+// Svnapot连续页：一个NAPOT页表项代表16个按64KiB对齐的4KiB页
+pub const NAPOT_FRAMES: usize = 16;
+
 impl Sv39PageEntry {
+    // 连续页的页表项，页号的低4位编码为0b1000，解码得到连续区间起始的页号
     #[inline]
     pub fn ppn(&self) -> PhysPageNum {
-        PhysPageNum(self.bits.get_bits(10..54))
+        let ppn = self.bits.get_bits(10..54);
+        if self.is_napot() {
+            PhysPageNum(ppn & !(NAPOT_FRAMES - 1))
+        } else {
+            PhysPageNum(ppn)
+        }
+    }
+    // 是否是Svnapot连续页的页表项，第63位
+    #[inline]
+    pub fn is_napot(&self) -> bool {
+        self.bits.get_bit(63)
     }
     #[inline]
     pub fn flags(&self) -> Sv39Flags {
         Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
     }
+    // 写入页号和设置；高位的内存类型和连续页等字段保持不变，修改权限时不会丢失内存类型
     #[inline]
     pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
-        self.bits = (ppn.0 << 10) | flags.bits() as usize
+        let ppn_bits = if self.is_napot() {
+            (ppn.0 & !(NAPOT_FRAMES - 1)) | (NAPOT_FRAMES >> 1)
+        } else {
+            ppn.0
+        };
+        self.bits = (self.bits & !((1 << 61) - 1)) | (ppn_bits << 10) | flags.bits() as usize
     }
+    // 写入连续页的页表项，ppn是连续区间起始的页号，需要按16个页帧对齐
+    #[inline]
+    pub fn write_napot(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
+        debug_assert_eq!(ppn.0 % NAPOT_FRAMES, 0, "napot range aligned");
+        self.bits = 1 << 63;
+        self.write_ppn_flags(ppn, flags);
+    }
+    // Svpbmt扩展的页面内存类型，在第61到62位；保留的编码返回None
+    #[inline]
+    pub fn pbmt(&self) -> Option<MemType> {
+        match self.bits.get_bits(61..63) {
+            0 => Some(MemType::Pma),
+            1 => Some(MemType::Nc),
+            2 => Some(MemType::Io),
+            _ => None,
+        }
+    }
+    #[inline]
+    pub fn write_pbmt(&mut self, mem_type: MemType) {
+        self.bits.set_bits(61..63, mem_type as usize);
+    }
+}
+
+// 页面内存类型（Svpbmt），覆盖物理内存属性（PMA）中的缓存和访存顺序属性
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MemType {
+    // 使用物理内存属性规定的类型
+    Pma = 0,
+    // 不可缓存、幂等、弱顺序的主存
+    Nc = 1,
+    // 不可缓存、非幂等、强顺序的输入输出，比如设备寄存器
+    Io = 2,
 }
 
 bitflags::bitflags! {
@@ -702,6 +2628,8 @@ bitflags::bitflags! {
 pub struct Sv39x4;
 
 impl Sv39x4 {
+    // width of guest physical address under Sv39x4
+    pub const GPA_BITS: usize = 41;
     // returns vpn mask of Sv39x4 by page level
     #[inline]
     fn vpn_mask_by_level(level: PageLevel) -> usize {
@@ -715,20 +2643,46 @@ impl Sv39x4 {
     }
 }
 
-// todo: To accommodate the 2 extra bits, the root page table (only)
-// is expanded by a factor of four to be 16 KiB instead of the usual 4 KiB.
-// Matching its larger size, the root page table also must be aligned to a 16 KiB
-// boundary instead of the usual 4 KiB page boundary.
+// To accommodate the 2 extra bits, the root page table (only) is expanded by a
+// factor of four to be 16 KiB instead of the usual 4 KiB. Matching its larger
+// size, the root page table also must be aligned to a 16 KiB boundary instead
+// of the usual 4 KiB page boundary.
+
+// 客户机物理地址的位数：Sv39x4规定的位数，同时不能超过硬件实现的物理地址位数。
+// 客户机内存布局不应放在这个宽度之上，否则G阶段翻译无法得到这样的地址
+pub fn guest_pa_width() -> usize {
+    guest_pa_width_for(crate::detect::detect_ppn_bits())
+}
+
+// 根据硬件实现的物理页号位数，计算客户机物理地址的位数
+pub fn guest_pa_width_for(ppn_bits: usize) -> usize {
+    usize::min(Sv39x4::GPA_BITS, ppn_bits + Sv39x4::FRAME_SIZE_BITS)
+}
+
+pub(crate) fn test_guest_pa_width() {
+    assert_eq!(guest_pa_width_for(44), 41, "Sv39x4 width");
+    assert_eq!(guest_pa_width_for(29), 41, "exactly Sv39x4 width");
+    assert_eq!(guest_pa_width_for(28), 40, "bounded by hardware");
+    assert_eq!(guest_pa_width_for(20), 32, "32-bit physical address");
+    println!(
+        "zihai > guest physical width test passed, current: {}",
+        guest_pa_width()
+    );
+}
 
 // Under Sv39x4, virtual address bits would be 41 other than 39;
 // other attributes would be the same as Sv39.
-// todo: incomplete design considering 16-KiB root page
 impl PageMode for Sv39x4 {
     const FRAME_SIZE_BITS: usize = 12;
     const PPN_BITS: usize = 44;
     // Sv39x4 page levels are the same as Sv39 except that they are with bigger root pages
     const MAX_PAGE_LEVELS: u8 = 3;
     const PAGE_ENTRIES_BITS: u8 = 9;
+    // G-stage translation is flushed by guest physical address shifted right by 2
+    unsafe fn flush_page(va: VirtAddr) {
+        // hfence.gvma a0, zero; emitted as raw word as the assembler may not support H extension
+        core::arch::asm!(".word 0x62050073", in("a0") va.0 >> 2, options(nostack));
+    }
     // In Sv39x4 vpn[2] would be 11 bits, vpn[0..=1] would be 9 bits
     fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
         // `vpn_mask_by_level` will panic if `level` does not exist on Sv39x4
@@ -747,15 +2701,32 @@ impl PageMode for Sv39x4 {
         }
         start..end
     }
+    // Replaces index of `level` in `vpn` by `idx`; idx of level 2 would be 11 bits
     fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
-        Sv39::vpn_level_index(vpn, level, idx) // todo: figure out what is this
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 29) - 1)) + (idx << 18),
+            _ => unimplemented!("this level does not exist on Sv39x4"),
+        })
     }
     // Other than root table being 16-KiB, Sv39x4 has the same page table design as Sv39
     type PageTable = Sv39PageTable;
-    // todo: 16-KiB root page table
     fn init_page_table(table: &mut Self::PageTable) {
         Sv39::init_page_table(table)
     }
+    const ROOT_TABLE_FRAMES: usize = 4;
+    // The 16-KiB root table is four consecutive 4-KiB tables; the upper 2 bits of
+    // the 11-bit VPN[2] select the frame and the lower 9 bits index into it
+    fn split_table_index(level: PageLevel, idx: usize) -> (usize, usize) {
+        match level.0 {
+            2 => (idx >> 9, idx & 511),
+            _ => (0, idx),
+        }
+    }
+    fn table_entries(level: PageLevel) -> usize {
+        Sv39x4::vpn_mask_by_level(level) + 1
+    }
     // Sv39x4 has same page table entry structure as Sv39
     type Slot = Sv39PageSlot;
     type Entry = Sv39PageEntry;
@@ -769,6 +2740,17 @@ impl PageMode for Sv39x4 {
     fn slot_set_mapping(slot: &mut Self::Slot, ppn: PhysPageNum, flags: Self::Flags) {
         Sv39::slot_set_mapping(slot, ppn, flags)
     }
+    fn slot_set_mapping_typed(
+        slot: &mut Self::Slot,
+        ppn: PhysPageNum,
+        flags: Self::Flags,
+        mem_type: MemType,
+    ) {
+        Sv39::slot_set_mapping_typed(slot, ppn, flags, mem_type)
+    }
+    fn slot_clear(slot: &mut Self::Slot) {
+        Sv39::slot_clear(slot)
+    }
     fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool {
         Sv39::entry_is_leaf_page(entry)
     }
@@ -778,288 +2760,2742 @@ impl PageMode for Sv39x4 {
     fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum {
         Sv39::entry_get_ppn(entry)
     }
+    fn entry_leaf_va_range(
+        entry: &Self::Entry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        napot_leaf_va_range::<Self>(entry, base_vpn, level)
+    }
+    fn entry_get_flags(entry: &Self::Entry) -> Self::Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn flags_write_execute(flags: &Self::Flags) -> bool {
+        Sv39::flags_write_execute(flags)
+    }
 }
 
-// 表示一个分页系统实现的地址空间
+// Sv48分页系统模式；RISC-V RV64下有效
 //
-// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
-#[derive(Debug)]
-pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
-    root_frame: FrameBox<A>,
-    frames: Vec<FrameBox<A>>,
-    frame_alloc: A,
-    page_mode: M,
-}
+// 在Sv39的基础上增加一级页表，虚拟地址为48位，第3级叶子页表项是512GiB的大页；页表项的结构与Sv39相同。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv48;
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
-    // 创建一个空的分页地址空间。一定会产生内存的写操作
-    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
-        // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
-        let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
-        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
-        // 向帧里填入一个空的根页表
-        unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
-        Ok(Self {
-            root_frame,
-            frames: Vec::new(),
-            frame_alloc,
-            page_mode,
-        })
+impl PageMode for Sv48 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    const MAX_PAGE_LEVELS: u8 = 4;
+    const PAGE_ENTRIES_BITS: u8 = 9;
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
     }
-    // 得到根页表的地址
-    pub fn root_page_number(&self) -> PhysPageNum {
-        self.root_frame.phys_page_num()
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        if level.0 <= 2 {
+            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 9);
+            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 9);
+            if end_idx1 > start_idx1 {
+                end = 512;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 44) - 1)) + (idx << 27),
+            _ => unimplemented!("this level does not exist on Sv48"),
+        })
+    }
+    type PageTable = Sv39PageTable;
+    fn init_page_table(table: &mut Self::PageTable) {
+        Sv39::init_page_table(table)
+    }
+    type Slot = Sv39PageSlot;
+    type Entry = Sv39PageEntry;
+    fn slot_try_get_entry(slot: &mut Self::Slot) -> Result<&mut Self::Entry, &mut Self::Slot> {
+        Sv39::slot_try_get_entry(slot)
+    }
+    type Flags = Sv39Flags;
+    fn slot_set_child(slot: &mut Self::Slot, ppn: PhysPageNum) {
+        Sv39::slot_set_child(slot, ppn)
+    }
+    fn slot_set_mapping(slot: &mut Self::Slot, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::slot_set_mapping(slot, ppn, flags)
+    }
+    fn slot_set_mapping_typed(
+        slot: &mut Self::Slot,
+        ppn: PhysPageNum,
+        flags: Self::Flags,
+        mem_type: MemType,
+    ) {
+        Sv39::slot_set_mapping_typed(slot, ppn, flags, mem_type)
+    }
+    fn slot_clear(slot: &mut Self::Slot) {
+        Sv39::slot_clear(slot)
+    }
+    fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool {
+        Sv39::entry_is_leaf_page(entry)
+    }
+    fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::entry_write_ppn_flags(entry, ppn, flags)
+    }
+    fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum {
+        Sv39::entry_get_ppn(entry)
+    }
+    fn entry_leaf_va_range(
+        entry: &Self::Entry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        napot_leaf_va_range::<Self>(entry, base_vpn, level)
+    }
+    fn entry_get_flags(entry: &Self::Entry) -> Self::Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn flags_write_execute(flags: &Self::Flags) -> bool {
+        Sv39::flags_write_execute(flags)
     }
 }
 
-#[inline]
-unsafe fn unref_ppn_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
-    let pa = ppn.addr_begin::<M>();
-    &mut *(pa.0 as *mut M::PageTable)
-}
-
-// note: kernel identical mapping only
-#[inline]
-unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(
-    b: &mut FrameBox<A>,
-) {
-    let a = &mut *(b.ppn.addr_begin::<M>().0 as *mut M::PageTable);
-    M::init_page_table(a);
-}
+// Sv57分页系统模式；RISC-V RV64下有效
+//
+// 在Sv48的基础上再增加一级页表，虚拟地址为57位，第4级叶子页表项是256TiB的大页；页表项的结构与Sv39相同。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv57;
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
-    pub fn allocate_map(
-        &mut self,
-        vpn: VirtPageNum,
-        ppn: PhysPageNum,
-        n: usize,
-        flags: M::Flags,
-    ) -> Result<(), FrameAllocError> {
-        for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
-            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
-            let table = unsafe { self.alloc_get_table(page_level, vpn_range.start) }?;
-            let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
-            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
-            for vidx in idx_range {
-                let this_ppn = PhysPageNum(
-                    ppn.0 + M::vpn_level_index(vpn_range.start, page_level, vidx).0 - vpn.0,
-                );
-                // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
-                match M::slot_try_get_entry(&mut table[vidx]) {
-                    Ok(_entry) => panic!("already allocated"),
-                    Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone()),
-                }
+impl PageMode for Sv57 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    const MAX_PAGE_LEVELS: u8 = 5;
+    const PAGE_ENTRIES_BITS: u8 = 9;
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        if level.0 <= 3 {
+            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 9);
+            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 9);
+            if end_idx1 > start_idx1 {
+                end = 512;
             }
         }
-        Ok(())
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 36) - 1)) + (idx << 27),
+            4 => (vpn.0 & !((1 << 45) - 1)) + (idx << 36),
+            _ => unimplemented!("this level does not exist on Sv57"),
+        })
+    }
+    type PageTable = Sv39PageTable;
+    fn init_page_table(table: &mut Self::PageTable) {
+        Sv39::init_page_table(table)
+    }
+    type Slot = Sv39PageSlot;
+    type Entry = Sv39PageEntry;
+    fn slot_try_get_entry(slot: &mut Self::Slot) -> Result<&mut Self::Entry, &mut Self::Slot> {
+        Sv39::slot_try_get_entry(slot)
+    }
+    type Flags = Sv39Flags;
+    fn slot_set_child(slot: &mut Self::Slot, ppn: PhysPageNum) {
+        Sv39::slot_set_child(slot, ppn)
+    }
+    fn slot_set_mapping(slot: &mut Self::Slot, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::slot_set_mapping(slot, ppn, flags)
+    }
+    fn slot_set_mapping_typed(
+        slot: &mut Self::Slot,
+        ppn: PhysPageNum,
+        flags: Self::Flags,
+        mem_type: MemType,
+    ) {
+        Sv39::slot_set_mapping_typed(slot, ppn, flags, mem_type)
+    }
+    fn slot_clear(slot: &mut Self::Slot) {
+        Sv39::slot_clear(slot)
+    }
+    fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool {
+        Sv39::entry_is_leaf_page(entry)
+    }
+    fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::entry_write_ppn_flags(entry, ppn, flags)
+    }
+    fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum {
+        Sv39::entry_get_ppn(entry)
+    }
+    fn entry_leaf_va_range(
+        entry: &Self::Entry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        napot_leaf_va_range::<Self>(entry, base_vpn, level)
+    }
+    fn entry_get_flags(entry: &Self::Entry) -> Self::Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn flags_write_execute(flags: &Self::Flags) -> bool {
+        Sv39::flags_write_execute(flags)
     }
 }
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
-    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
-    // should run on identical mapping (ppn == vpn) or paged mapping disabled
-    unsafe fn alloc_get_table(
-        &mut self,
-        entry_level: PageLevel,
-        vpn_start: VirtPageNum,
-    ) -> Result<&mut M::PageTable, FrameAllocError> {
-        let mut ppn = self.root_frame.phys_page_num();
-        for level in M::visit_levels_before(entry_level) {
-            // println!("[] BEFORE PPN = {:x?}", ppn);
-            let page_table = unref_ppn_mut::<M>(ppn);
-            let vidx = M::vpn_index(vpn_start, level);
-            match M::slot_try_get_entry(&mut page_table[vidx]) {
-                Ok(entry) => ppn = M::entry_get_ppn(entry),
-                Err(mut slot) => {
-                    // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
-                    let mut frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
-                    fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
-                    M::slot_set_child(&mut slot, frame_box.phys_page_num());
-                    // println!("[] Created a new frame box");
-                    ppn = frame_box.phys_page_num();
-                    self.frames.push(frame_box);
-                }
+// 以16KiB为基本页帧的分页模式；目前没有硬件实现此模式，用于检验代码中没有写死4KiB页帧的假设
+//
+// 每一级页表有2048项，共三级，虚拟地址为47位；页表项的结构与Sv39相同。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Page16K;
+
+impl PageMode for Page16K {
+    const FRAME_SIZE_BITS: usize = 14;
+    const PPN_BITS: usize = 44;
+    const MAX_PAGE_LEVELS: u8 = 3;
+    const PAGE_ENTRIES_BITS: u8 = 11;
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 11)) & 2047
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 11)) & 2047;
+        let mut end = (vpn_range.end.0 >> (level.0 * 11)) & 2047;
+        if level.0 <= 1 {
+            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 11);
+            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 11);
+            if end_idx1 > start_idx1 {
+                end = 2048;
             }
         }
-        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
-        let page_table = unref_ppn_mut::<M>(ppn); // 此时ppn是当前所需要修改的页表
-                                                  // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
-        Ok(&mut *(page_table as *mut _))
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 11) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 22) - 1)) + (idx << 11),
+            2 => (vpn.0 & !((1 << 33) - 1)) + (idx << 22),
+            _ => unimplemented!("this level does not exist on Page16K"),
+        })
+    }
+    type PageTable = Page16KPageTable;
+    fn init_page_table(table: &mut Self::PageTable) {
+        // Zero init
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+    }
+    // 页表项的结构与Sv39相同
+    type Slot = Sv39PageSlot;
+    type Entry = Sv39PageEntry;
+    fn slot_try_get_entry(slot: &mut Self::Slot) -> Result<&mut Self::Entry, &mut Self::Slot> {
+        Sv39::slot_try_get_entry(slot)
+    }
+    type Flags = Sv39Flags;
+    fn slot_set_child(slot: &mut Self::Slot, ppn: PhysPageNum) {
+        Sv39::slot_set_child(slot, ppn)
+    }
+    fn slot_set_mapping(slot: &mut Self::Slot, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::slot_set_mapping(slot, ppn, flags)
+    }
+    fn slot_clear(slot: &mut Self::Slot) {
+        Sv39::slot_clear(slot)
+    }
+    fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool {
+        Sv39::entry_is_leaf_page(entry)
+    }
+    fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags) {
+        Sv39::entry_write_ppn_flags(entry, ppn, flags)
+    }
+    fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum {
+        Sv39::entry_get_ppn(entry)
+    }
+    fn entry_leaf_va_range(
+        entry: &Self::Entry,
+        base_vpn: VirtPageNum,
+        level: PageLevel,
+    ) -> Range<VirtPageNum> {
+        napot_leaf_va_range::<Self>(entry, base_vpn, level)
+    }
+    fn entry_get_flags(entry: &Self::Entry) -> Self::Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn flags_write_execute(flags: &Self::Flags) -> bool {
+        Sv39::flags_write_execute(flags)
     }
-    // pub fn unmap(&mut self, vpn: VirtPageNum) {
-    //     todo!()
-    // }
+}
 
-    /// 根据虚拟页号查询物理页号，可能出错。
-    pub fn find_ppn(&self, vpn: VirtPageNum) -> Result<(&M::Entry, PageLevel), PageError> {
-        let mut ppn = self.root_frame.phys_page_num();
-        for lvl in M::visit_levels_until(PageLevel::leaf_level()) {
-            // 注意: 要求内核对页表空间有恒等映射，可以直接解释物理地址
-            let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
-            let vidx = M::vpn_index(vpn, lvl);
-            match M::slot_try_get_entry(&mut page_table[vidx]) {
-                Ok(entry) => {
-                    if M::entry_is_leaf_page(entry) {
-                        return Ok((entry, lvl));
-                    } else {
-                        ppn = M::entry_get_ppn(entry)
-                    }
-                }
-                Err(_slot) => return Err(PageError::InvalidEntry),
+#[repr(C)]
+pub struct Page16KPageTable {
+    entries: [Sv39PageSlot; 2048],
+}
+
+impl core::ops::Index<usize> for Page16KPageTable {
+    type Output = Sv39PageSlot;
+    fn index(&self, idx: usize) -> &Sv39PageSlot {
+        &self.entries[idx]
+    }
+}
+
+impl core::ops::IndexMut<usize> for Page16KPageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv39PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+// Sv32分页系统模式；RISC-V RV32下有效
+//
+// 每一级页表有1024项，共两级，虚拟地址为32位，第1级叶子页表项是4MiB的大页；
+// 页表项为32位，物理页号有22位，可以访问34位物理地址。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv32;
+
+impl PageMode for Sv32 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 22;
+    const MAX_PAGE_LEVELS: u8 = 2;
+    const PAGE_ENTRIES_BITS: u8 = 10;
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 10)) & 1023
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 10)) & 1023;
+        let mut end = (vpn_range.end.0 >> (level.0 * 10)) & 1023;
+        if level.0 == 0 {
+            let start_idx1 = vpn_range.start.0 >> 10;
+            let end_idx1 = vpn_range.end.0 >> 10;
+            if end_idx1 > start_idx1 {
+                end = 1024;
             }
         }
-        Err(PageError::NotLeafInLowestPage)
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 10) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 20) - 1)) + (idx << 10),
+            _ => unimplemented!("this level does not exist on Sv32"),
+        })
+    }
+    type PageTable = Sv32PageTable;
+    fn init_page_table(table: &mut Self::PageTable) {
+        // Zero init
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+    }
+    type Slot = Sv32PageSlot;
+    type Entry = Sv32PageEntry;
+    fn slot_try_get_entry(
+        slot: &mut Sv32PageSlot,
+    ) -> Result<&mut Sv32PageEntry, &mut Sv32PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        if ans.flags().contains(Sv39Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    // 页表项低8位的设置与Sv39相同
+    type Flags = Sv39Flags;
+    fn slot_set_child(slot: &mut Sv32PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv32PageSlot, ppn: PhysPageNum, flags: Sv39Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V | flags);
+    }
+    fn slot_clear(slot: &mut Sv32PageSlot) {
+        slot.bits = 0;
+    }
+    fn entry_is_leaf_page(entry: &mut Sv32PageEntry) -> bool {
+        // 如果包含R、W或X项，就是叶子节点。
+        entry
+            .flags()
+            .intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv32PageEntry, ppn: PhysPageNum, flags: Sv39Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &Sv32PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_get_flags(entry: &Sv32PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn flags_write_execute(flags: &Sv39Flags) -> bool {
+        Sv39::flags_write_execute(flags)
+    }
+}
+
+#[repr(C)]
+pub struct Sv32PageTable {
+    entries: [Sv32PageSlot; 1024],
+}
+
+impl core::ops::Index<usize> for Sv32PageTable {
+    type Output = Sv32PageSlot;
+    fn index(&self, idx: usize) -> &Sv32PageSlot {
+        &self.entries[idx]
+    }
+}
+
+impl core::ops::IndexMut<usize> for Sv32PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv32PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+#[repr(C)]
+pub struct Sv32PageSlot {
+    bits: u32,
+}
+
+#[repr(C)]
+pub struct Sv32PageEntry {
+    bits: u32,
+}
+
+impl Sv32PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..32) as usize)
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv39Flags {
+        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
+        self.bits = ((ppn.0 as u32) << 10) | flags.bits() as u32
+    }
+}
+
+pub(crate) fn test_page_num_arith() {
+    let ppn = PhysPageNum(0x80400);
+    assert_eq!(ppn.offset(3), PhysPageNum(0x80403), "forward");
+    assert_eq!(ppn.offset(-0x400), PhysPageNum(0x80000), "backward");
+    assert_eq!(
+        PhysPageNum(0).offset(-1),
+        PhysPageNum(usize::MAX),
+        "wraps below"
+    );
+    assert_eq!(ppn.checked_add(0x10), Some(PhysPageNum(0x80410)));
+    assert_eq!(PhysPageNum(usize::MAX).checked_add(1), None, "overflow");
+    assert_eq!(PhysPageNum(0x80410) - ppn, 0x10, "physical distance");
+    let vpn = VirtPageNum(0x90000);
+    assert_eq!(
+        VirtPageNum(usize::MAX).offset(1),
+        VirtPageNum(0),
+        "wraps above"
+    );
+    assert_eq!(vpn.offset(-1), VirtPageNum(0x8ffff), "backward");
+    assert_eq!(vpn.checked_add(usize::MAX), None, "overflow");
+    assert_eq!(vpn.checked_add(0x200), Some(VirtPageNum(0x90200)));
+    assert_eq!(VirtPageNum(0x90200) - vpn, 0x200, "virtual distance");
+    assert_eq!(vpn.offset(3) - vpn, 3, "offset round trip");
+    assert_eq!(vpn.addr_begin::<Sv39>(), VirtAddr(0x9000_0000));
+    println!("zihai > page number arithmetic test passed");
+}
+
+pub(crate) fn test_frame_size() {
+    let pa = PhysAddr(0x8000_4000);
+    assert_eq!(
+        pa.page_number::<Page16K>(),
+        PhysPageNum(0x20001),
+        "16K page number"
+    );
+    assert_eq!(
+        pa.page_number::<Sv39>(),
+        PhysPageNum(0x80004),
+        "4K page number"
+    );
+    assert_eq!(
+        pa.page_number::<Page16K>().addr_begin::<Page16K>(),
+        pa,
+        "16K physical round trip"
+    );
+    let va = VirtAddr(0x1234_5678);
+    let vpn = va.page_number::<Page16K>();
+    assert_eq!(vpn, VirtPageNum(0x48d1), "16K virtual page number");
+    assert_eq!(
+        vpn.addr_begin::<Page16K>(),
+        VirtAddr(0x1234_4000),
+        "16K virtual round trip"
+    );
+    assert_eq!(
+        va.page_offset::<Page16K>(PageLevel(0)),
+        0x1678,
+        "16K offset"
+    );
+    let page_sizes_16k = [
+        (PageLevel(0), 16 * 1024),
+        (PageLevel(1), 32 * 1024 * 1024),
+        (PageLevel(2), 64 * 1024 * 1024 * 1024),
+    ];
+    for (level, page_size) in page_sizes_16k {
+        assert_eq!(
+            Page16K::get_layout_for_level(level).page_size::<Page16K>(),
+            page_size
+        );
+    }
+    let pairs =
+        MapPairs::solve(VirtPageNum(0), PhysPageNum(0), 2048 + 3, Page16K).collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(1), VirtPageNum(0)..VirtPageNum(2048)),
+            (PageLevel(0), VirtPageNum(2048)..VirtPageNum(2051))
+        ]
+    );
+    println!("zihai > configurable frame size test passed");
+}
+
+// 表示一个分页系统实现的地址空间
+//
+// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
+#[derive(Debug)]
+pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
+    root_frame: FrameBox<A>,
+    frames: Vec<FrameBox<A>>,
+    // 写时复制映射的页帧，按虚拟页号索引；和其它地址空间共享，或者复制后由本地址空间独占
+    cow_frames: BTreeMap<usize, FrameRc<A>>,
+    // 严格W^X模式下，拒绝同时可写和可执行的映射
+    strict_wx: bool,
+    // 新建页表时先清零整个页帧
+    zero_tables: bool,
+    // 分配给地址空间的地址空间编号；分配器是每个处理核一个的，不保存在这里，归还时由调用者提供
+    asid: Option<AddressSpaceId>,
+    // 页表所在页帧在内核中的虚拟页号，用于遍历和修改页表
+    phys_to_virt: fn(PhysPageNum) -> VirtPageNum,
+    frame_alloc: A,
+    page_mode: M,
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 创建一个空的分页地址空间。一定会产生内存的写操作
+    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        Self::try_new_in_with(page_mode, frame_alloc, identity_phys_to_virt)
+    }
+    // 创建一个空的分页地址空间，页表经过phys_to_virt访问，比如内核把物理内存线性映射到固定偏移的窗口时
+    pub fn try_new_in_with(
+        page_mode: M,
+        frame_alloc: A,
+        phys_to_virt: fn(PhysPageNum) -> VirtPageNum,
+    ) -> Result<Self, FrameAllocError> {
+        // 新建满足根页表对齐要求的帧；通常根页表只占一个帧，对齐要求是1
+        let (root_frame, frames) = if M::ROOT_TABLE_FRAMES == 1 {
+            (FrameBox::try_new_in(frame_alloc.clone())?, Vec::new())
+        } else {
+            let count = M::ROOT_TABLE_FRAMES;
+            let base = frame_alloc.allocate_frames(count, count.trailing_zeros() as usize)?;
+            // note(unsafe): 页帧刚从frame_alloc分配，每个页帧只由一个FrameBox拥有
+            let mut boxes = (0..count).map(|i| unsafe {
+                let pa = PhysAddr(base.addr_begin::<M>().0 + (i << M::FRAME_SIZE_BITS));
+                FrameBox::from_raw(pa.page_number::<M>(), frame_alloc.clone())
+            });
+            let root_frame = boxes.next().unwrap();
+            // 根页表其余的帧和其它页表帧一起由地址空间拥有，不会因为清空而被释放
+            (root_frame, boxes.collect())
+        };
+        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
+        // 向帧里填入一个空的根页表；根页表占用多个页帧时，逐个页帧填入
+        for i in 0..M::ROOT_TABLE_FRAMES {
+            let ppn = root_frame.phys_page_num().offset(i as isize);
+            unsafe { M::init_page_table(window_as_table_mut::<M>(phys_to_virt, ppn)) };
+        }
+        Ok(Self {
+            root_frame,
+            frames,
+            cow_frames: BTreeMap::new(),
+            strict_wx: false,
+            zero_tables: false,
+            asid: None,
+            phys_to_virt,
+            frame_alloc,
+            page_mode,
+        })
+    }
+    // 设置严格W^X模式；之后allocate_map和protect拒绝同时可写和可执行的映射，返回WxViolation。
+    // 已有的映射不受影响，可以用enforce_wx检查
+    pub fn set_strict_wx(&mut self, strict: bool) {
+        self.strict_wx = strict;
+    }
+    // 设置之后新建的页表先经过phys_to_virt清零整个页帧，再初始化页表，
+    // 页表没有覆盖的部分不会残留回收页帧中的数据
+    pub fn set_zero_tables(&mut self, zero: bool) {
+        self.zero_tables = zero;
+    }
+    // 得到根页表的地址
+    pub fn root_page_number(&self) -> PhysPageNum {
+        self.root_frame.phys_page_num()
+    }
+    // 销毁地址空间，把所有页表占有的页帧一次性归还给页帧分配器
+    pub fn release(self) {
+        let PagedAddrSpace {
+            root_frame,
+            frames,
+            frame_alloc,
+            ..
+        } = self;
+        let mut ppns = Vec::with_capacity(frames.len() + 1);
+        ppns.push(root_frame.into_raw());
+        ppns.extend(frames.into_iter().map(FrameBox::into_raw));
+        frame_alloc.deallocate_frames_bulk(&ppns);
+    }
+    // 分配给地址空间的地址空间编号
+    pub fn asid(&self) -> Option<AddressSpaceId> {
+        self.asid
+    }
+    // 从分配器为地址空间分配编号；已经有编号时直接返回
+    pub fn assign_asid(
+        &mut self,
+        asid_alloc: &mut StackAsidAllocator,
+    ) -> Result<AddressSpaceId, AsidAllocError> {
+        if let Some(asid) = self.asid {
+            return Ok(asid);
+        }
+        let asid = asid_alloc.allocate_asid()?;
+        self.asid = Some(asid);
+        Ok(asid)
+    }
+    // 把地址空间编号归还给分配它的分配器
+    pub fn release_asid(&mut self, asid_alloc: &mut StackAsidAllocator) {
+        if let Some(asid) = self.asid.take() {
+            asid_alloc.deallocate_asid(asid);
+        }
+    }
+    // 销毁地址空间，归还页帧和地址空间编号。有编号的地址空间应当用这个函数销毁，直接丢弃会泄漏编号
+    pub fn release_with_asid(mut self, asid_alloc: &mut StackAsidAllocator) {
+        self.release_asid(asid_alloc);
+        self.release();
+    }
+    // 迁移一个已映射页的内容到新的页帧，返回旧页帧的页号，由调用者释放。
+    // 先复制内容，再修改页表项指向新页帧并保留原有设置，最后刷新地址翻译缓存，
+    // 因此切换前后访问到的内容相同。新页帧需要满足该页的对齐要求。
+    // 连续页（比如Svnapot）的页表项覆盖多个同级的页，整个连续区间一起迁移，返回区间起始的旧页号
+    pub fn migrate_page(
+        &mut self,
+        vpn: VirtPageNum,
+        new_ppn: PhysPageNum,
+    ) -> Result<PhysPageNum, PageError> {
+        let (entry, lvl) = self.find_entry_mut(vpn)?;
+        let range = M::entry_leaf_va_range(entry, vpn, lvl);
+        let frames = range.end - range.start;
+        if !new_ppn.0.is_multiple_of(frames) {
+            return Err(PageError::MisalignedAddress);
+        }
+        let old_ppn = M::entry_get_ppn(entry);
+        let flags = M::entry_get_flags(entry);
+        // 注意: 要求内核对新旧页帧有恒等映射
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                old_ppn.addr_begin::<M>().0 as *const u8,
+                new_ppn.addr_begin::<M>().0 as *mut u8,
+                frames << M::FRAME_SIZE_BITS,
+            )
+        };
+        // 连续区间中每个页表项都指向区间起始的页号
+        let mut cur = range.start;
+        while cur.0 < range.end.0 {
+            let (entry, _) = self.find_entry_mut(cur)?;
+            M::entry_write_ppn_flags(entry, new_ppn, flags.clone());
+            unsafe { M::flush_page(cur.addr_begin::<M>()) };
+            cur = M::leaf_va_range(cur, lvl).end;
+        }
+        Ok(old_ppn)
+    }
+    // 拆解地址空间，返回页表占有的所有页帧，根页表在最前。
+    // 调用者决定页帧的去向，比如先清空再归还给指定的分配器。
+    // 叶子页表项映射的页帧不属于地址空间，不会被返回
+    pub fn into_frames(self) -> Vec<FrameBox<A>> {
+        let PagedAddrSpace {
+            root_frame, frames, ..
+        } = self;
+        let mut ans = Vec::with_capacity(frames.len() + 1);
+        ans.push(root_frame);
+        ans.extend(frames);
+        ans
+    }
+}
+
+impl<A: FrameAllocator + Clone> PagedAddrSpace<Sv39, A> {
+    // 检查地址空间对自己所有的页表帧（根页表和中间页表）都有可读写的恒等映射。
+    // 激活后修改页表的代码直接解释物理地址，不满足这个前提时会访问野指针；
+    // 在激活之前检查，就能得到明确的错误
+    pub fn assert_page_tables_identity_mapped(&self) -> Result<(), PageError> {
+        let table_frames = core::iter::once(&self.root_frame).chain(self.frames.iter());
+        for frame in table_frames {
+            let ppn = frame.phys_page_num();
+            let vpn = VirtPageNum(ppn.0);
+            let (entry, lvl) = match self.find_ppn(vpn) {
+                Ok(ans) => ans,
+                Err(_) => return Err(PageError::TableNotIdentityMapped),
+            };
+            // 大页和连续页中的页帧，需要加上区间中的偏移才得到对应的物理页号
+            let rw = Sv39Flags::R | Sv39Flags::W;
+            if leaf_ppn::<Sv39>(entry, vpn, lvl) != ppn || !entry.flags().contains(rw) {
+                return Err(PageError::TableNotIdentityMapped);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 从vpn开始，以写时复制的方式逐页映射共享的页帧。
+    // 映射去掉W位，第一次写入产生页异常，由handle_cow_fault处理；地址空间持有每个页帧的一份引用。
+    // 比如复制客户机时，两个地址空间以同样的页帧调用本函数，之后各自的写入互不可见
+    pub fn map_cow(
+        &mut self,
+        vpn: VirtPageNum,
+        frames: &[FrameRc<A>],
+        flags: Sv39Flags,
+    ) -> Result<(), PageError> {
+        let flags = flags - Sv39Flags::W;
+        for (i, frame) in frames.iter().enumerate() {
+            let cur = VirtPageNum(vpn.0 + i);
+            self.allocate_map(cur, frame.phys_page_num(), 1, flags)?;
+            self.cow_frames.insert(cur.0, frame.clone());
+        }
+        Ok(())
+    }
+    // 是否为写时复制映射、尚未复制的页
+    pub fn is_cow(&self, vpn: VirtPageNum) -> bool {
+        let writable = match self.find_ppn(vpn) {
+            Ok((entry, _)) => M::entry_get_flags(entry).contains(Sv39Flags::W),
+            Err(_) => return false,
+        };
+        self.cow_frames.contains_key(&vpn.0) && !writable
+    }
+    // 处理写时复制页上的写入异常，返回之后映射的页帧。
+    // 页帧仍然被其它地址空间共享时，分配新的页帧复制内容，重新映射为可写；
+    // 只剩本地址空间持有时不必复制，直接改为可写。不是写时复制的页返回InvalidEntry
+    pub fn handle_cow_fault(&mut self, vpn: VirtPageNum) -> Result<PhysPageNum, PageError> {
+        let shared = self.cow_frames.get(&vpn.0).ok_or(PageError::InvalidEntry)?;
+        let old_ppn = shared.phys_page_num();
+        let new_frame = if shared.is_unique() {
+            None
+        } else {
+            let frame = FrameBox::try_new_in(self.frame_alloc.clone())?;
+            // 注意: 要求内核对新旧页帧有恒等映射
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    old_ppn.addr_begin::<M>().0 as *const u8,
+                    frame.phys_page_num().addr_begin::<M>().0 as *mut u8,
+                    1 << M::FRAME_SIZE_BITS,
+                )
+            };
+            Some(frame)
+        };
+        let (entry, _) = self.find_entry_mut(vpn)?;
+        let flags = M::entry_get_flags(entry) | Sv39Flags::W;
+        let ppn = match new_frame {
+            Some(frame) => {
+                let ppn = frame.phys_page_num();
+                M::entry_write_ppn_flags(entry, ppn, flags);
+                // 放弃对共享页帧的引用
+                self.cow_frames.insert(vpn.0, FrameRc::from_box(frame));
+                ppn
+            }
+            None => {
+                M::entry_write_ppn_flags(entry, old_ppn, flags);
+                old_ppn
+            }
+        };
+        unsafe { M::flush_page(vpn.addr_begin::<M>()) };
+        Ok(ppn)
+    }
+    // 读取vpn所在页的A位并清除，返回清除之前的值；用于客户机页面的换出策略
+    pub fn test_and_clear_accessed(&mut self, vpn: VirtPageNum) -> Result<bool, PageError> {
+        self.test_and_clear_flag(vpn, Sv39Flags::A)
+    }
+    // 读取vpn所在页的D位并清除，返回清除之前的值
+    pub fn test_and_clear_dirty(&mut self, vpn: VirtPageNum) -> Result<bool, PageError> {
+        self.test_and_clear_flag(vpn, Sv39Flags::D)
+    }
+    // 连续页的硬件可能只在其中一个页表项上设置A和D位，因此检查并清除区间中的每一项
+    fn test_and_clear_flag(
+        &mut self,
+        vpn: VirtPageNum,
+        flag: Sv39Flags,
+    ) -> Result<bool, PageError> {
+        let (entry, lvl) = self.find_ppn(vpn)?;
+        let range = M::entry_leaf_va_range(entry, vpn, lvl);
+        let mut was_set = false;
+        let mut cur = range.start;
+        while cur.0 < range.end.0 {
+            let slot = unsafe { self.leaf_slot_mut(cur, lvl) };
+            let entry = M::slot_try_get_entry(slot)
+                .ok()
+                .expect("checked mapped leaf");
+            let flags = M::entry_get_flags(entry);
+            if flags.contains(flag) {
+                was_set = true;
+                let ppn = M::entry_get_ppn(entry);
+                M::entry_write_ppn_flags(entry, ppn, flags - flag);
+            }
+            cur = M::leaf_va_range(cur, lvl).end;
+        }
+        if was_set {
+            unsafe { M::flush_page(range.start.addr_begin::<M>()) };
+        }
+        Ok(was_set)
+    }
+}
+
+// 把页帧解释为当前分页模式下的页表
+//
+// unsafe说明。调用者必须保证以下约定：
+// 1. 内核对页帧有恒等映射，或者没有开启分页，可以直接解释物理地址
+// 2. 页帧中存放的是当前分页模式的页表
+// 3. 返回的引用存在期间，没有这个页帧的可变引用
+//
+// 注意: 不能用递归映射（根页表映射自己）代替恒等映射。RISC-V分页中，页表项要么是叶子要么指向下一级页表，
+// 硬件在最低一级必须读到叶子页表项，经过自映射的页表项走到最低一级时会得到非叶子项，产生页异常；
+// 而把自映射页表项设为叶子，又会在高层成为未对齐的大页。需要不依赖恒等映射时，应当使用临时映射窗口。
+#[inline]
+pub unsafe fn frame_as_table<'a, M: PageMode>(ppn: PhysPageNum) -> &'a M::PageTable {
+    let pa = ppn.addr_begin::<M>();
+    &*(pa.0 as *const M::PageTable)
+}
+
+// 把页帧解释为当前分页模式下可修改的页表；约定同frame_as_table，
+// 并且返回的引用存在期间，没有这个页帧的其它引用
+#[inline]
+pub unsafe fn frame_as_table_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
+    let pa = ppn.addr_begin::<M>();
+    &mut *(pa.0 as *mut M::PageTable)
+}
+
+// 通过线性映射窗口把页帧解释为可修改的页表；约定同frame_as_table_mut，只是页帧在phys_to_virt给出的虚拟页
+#[inline]
+unsafe fn window_as_table_mut<'a, M: PageMode>(
+    phys_to_virt: fn(PhysPageNum) -> VirtPageNum,
+    ppn: PhysPageNum,
+) -> &'a mut M::PageTable {
+    let va = phys_to_virt(ppn).addr_begin::<M>();
+    &mut *(va.0 as *mut M::PageTable)
+}
+
+// 恒等映射：页表所在页帧的虚拟页号等于物理页号，或者没有开启分页
+pub fn identity_phys_to_virt(ppn: PhysPageNum) -> VirtPageNum {
+    VirtPageNum(ppn.0)
+}
+
+// 一次映射最多的页帧数量。以4K页帧计算为512G，足够映射以大页构成的客户机内存
+pub const MAX_MAP_PAGES: usize = 1 << 27;
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    pub fn allocate_map(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        n: usize,
+        flags: M::Flags,
+    ) -> Result<(), PageError> {
+        self.allocate_map_typed(vpn, ppn, n, flags, MemType::Pma)
+    }
+    // 建立指定内存类型的映射，比如把设备寄存器以Io类型映射给客户机。
+    // 区间中已经有映射时返回AlreadyMapped；中途失败时撤销已经写入的页表项和新建的页表，地址空间保持不变
+    pub fn allocate_map_typed(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        n: usize,
+        flags: M::Flags,
+        mem_type: MemType,
+    ) -> Result<(), PageError> {
+        // 拒绝过大的映射请求，避免求解和填写页表的过程耗时过长
+        if n > MAX_MAP_PAGES {
+            return Err(PageError::MappingTooLarge);
+        }
+        if self.strict_wx && M::flags_write_execute(&flags) {
+            return Err(PageError::WxViolation);
+        }
+        if let Some(mapped) = self.first_mapped(vpn, n) {
+            return Err(PageError::AlreadyMapped { vpn: mapped });
+        }
+        let pairs: Vec<_> = MapPairs::solve(vpn, ppn, n, self.page_mode).collect();
+        let mut written = Vec::new();
+        let ans = unsafe { self.write_mappings(&pairs, vpn, ppn, flags, mem_type, &mut written) };
+        if ans.is_err() {
+            // 回滚：清除已经写入的页表项，再释放这次新建、仍然为空的页表
+            unsafe {
+                for (vpn, level) in written.into_iter().rev() {
+                    self.clear_leaf(vpn, level);
+                }
+                for (_, vpn_range) in &pairs {
+                    self.prune_tables(vpn_range.start);
+                }
+            }
+        }
+        ans
+    }
+    // 按分解好的区间填写叶子页表项，写入的页表项记录在written中，用于失败时回滚
+    unsafe fn write_mappings(
+        &mut self,
+        pairs: &[(PageLevel, Range<VirtPageNum>)],
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: M::Flags,
+        mem_type: MemType,
+        written: &mut Vec<(VirtPageNum, PageLevel)>,
+    ) -> Result<(), PageError> {
+        for (page_level, vpn_range) in pairs.iter().cloned() {
+            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
+            let table_ppn = self.alloc_get_table(page_level, vpn_range.start)?;
+            let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
+            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
+            for vidx in idx_range {
+                let this_vpn = M::vpn_level_index(vpn_range.start, page_level, vidx);
+                let this_ppn = ppn.offset((this_vpn - vpn) as isize);
+                // println!("[kernel-alloc-map-test] Table: {:x?} Vidx {} -> Ppn {:x?}", table_ppn, vidx, this_ppn);
+                match M::slot_try_get_entry(self.slot_mut(table_ppn, page_level, vidx)) {
+                    Ok(_entry) => return Err(PageError::AlreadyMapped { vpn: this_vpn }),
+                    Err(slot) => M::slot_set_mapping_typed(slot, this_ppn, flags.clone(), mem_type),
+                }
+                written.push((this_vpn, page_level));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M: PageMode<Slot = Sv39PageSlot, Flags = Sv39Flags>, A: FrameAllocator + Clone>
+    PagedAddrSpace<M, A>
+{
+    // 以Svnapot连续页映射从vpn开始的64K区间，填写最低一级页表中连续的16个页表项。
+    // vpn和ppn都需要按16个页帧对齐，否则返回MisalignedNapot
+    pub fn allocate_map_napot(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: Sv39Flags,
+    ) -> Result<(), PageError> {
+        if !vpn.0.is_multiple_of(NAPOT_FRAMES) || !ppn.0.is_multiple_of(NAPOT_FRAMES) {
+            return Err(PageError::MisalignedNapot);
+        }
+        if self.strict_wx && M::flags_write_execute(&flags) {
+            return Err(PageError::WxViolation);
+        }
+        if let Some(mapped) = self.first_mapped(vpn, NAPOT_FRAMES) {
+            return Err(PageError::AlreadyMapped { vpn: mapped });
+        }
+        let table_ppn = match unsafe { self.alloc_get_table(PageLevel(0), vpn) } {
+            Ok(ppn) => ppn,
+            Err(e) => {
+                // 释放这次新建的页表
+                unsafe { self.prune_tables(vpn) };
+                return Err(e.into());
+            }
+        };
+        let start = M::vpn_index(vpn, PageLevel(0));
+        for vidx in start..start + NAPOT_FRAMES {
+            Sv39::slot_set_napot(
+                unsafe { self.slot_mut(table_ppn, PageLevel(0), vidx) },
+                ppn,
+                flags,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 把虚拟地址区间映射到从pa_base开始的物理地址；地址必须按页帧对齐
+    pub fn map_range(
+        &mut self,
+        va: Range<VirtAddr>,
+        pa_base: PhysAddr,
+        flags: M::Flags,
+    ) -> Result<(), PageError> {
+        let frame_mask = (1 << M::FRAME_SIZE_BITS) - 1;
+        if va.start.0 & frame_mask != 0 || va.end.0 & frame_mask != 0 || pa_base.0 & frame_mask != 0
+        {
+            return Err(PageError::MisalignedAddress);
+        }
+        if va.end.0 < va.start.0 {
+            return Err(PageError::InvalidRange);
+        }
+        let n = (va.end.0 - va.start.0) >> M::FRAME_SIZE_BITS;
+        // 物理地址区间不能超过地址空间的上界
+        if pa_base.0.checked_add(va.end.0 - va.start.0).is_none() {
+            return Err(PageError::InvalidRange);
+        }
+        let vpn = va.start.page_number::<M>();
+        let ppn = pa_base.page_number::<M>();
+        self.allocate_map(vpn, ppn, n, flags)?;
+        Ok(())
+    }
+    // 把页表所在的页帧解释为页表，经过地址空间的phys_to_virt转换
+    unsafe fn table_mut<'a>(&self, ppn: PhysPageNum) -> &'a mut M::PageTable {
+        window_as_table_mut::<M>(self.phys_to_virt, ppn)
+    }
+    // 得到页号为ppn、等级为level的页表中第idx个槽位；根页表可能占用多个页帧
+    unsafe fn slot_mut<'a>(
+        &self,
+        ppn: PhysPageNum,
+        level: PageLevel,
+        idx: usize,
+    ) -> &'a mut M::Slot {
+        window_slot_mut::<M>(self.phys_to_virt, ppn, level, idx)
+    }
+    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
+    // 页表经过phys_to_virt访问，要求内核对页表所在的页帧有恒等映射或者固定偏移的线性映射
+    unsafe fn alloc_get_table(
+        &mut self,
+        entry_level: PageLevel,
+        vpn_start: VirtPageNum,
+    ) -> Result<PhysPageNum, FrameAllocError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for level in M::visit_levels_before(entry_level) {
+            // println!("[] BEFORE PPN = {:x?}", ppn);
+            let vidx = M::vpn_index(vpn_start, level);
+            match M::slot_try_get_entry(self.slot_mut(ppn, level, vidx)) {
+                Ok(entry) => ppn = M::entry_get_ppn(entry),
+                Err(slot) => {
+                    // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
+                    let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+                    if self.zero_tables {
+                        let table = self.table_mut(frame_box.phys_page_num());
+                        let len = 1 << M::FRAME_SIZE_BITS;
+                        core::ptr::write_bytes(table as *mut _ as *mut u8, 0, len);
+                    }
+                    M::init_page_table(self.table_mut(frame_box.phys_page_num()));
+                    M::slot_set_child(slot, frame_box.phys_page_num());
+                    // println!("[] Created a new frame box");
+                    ppn = frame_box.phys_page_num();
+                    self.frames.push(frame_box);
+                }
+            }
+        }
+        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
+        Ok(ppn) // 此时ppn是当前所需要修改的页表
+    }
+    // 取消从vpn开始的n个页的映射，不会归还叶子页表项映射的页帧。
+    // 按和allocate_map相同的方式分解区间，逐个清除叶子页表项；子页表的页表项全部无效时，
+    // 释放子页表的页帧。区间中有未映射的页，或者只覆盖大页的一部分时返回错误，此时不修改页表。
+    // 地址空间处于激活状态时，调用者负责刷新地址翻译缓存
+    pub fn deallocate_map(&mut self, vpn: VirtPageNum, n: usize) -> Result<(), PageError> {
+        if n > MAX_MAP_PAGES {
+            return Err(PageError::MappingTooLarge);
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        // 映射时，区间的分解由虚拟页号和物理页号的对齐情况决定
+        let (entry, lvl) = self.find_ppn(vpn)?;
+        let offset = vpn - M::entry_leaf_va_range(entry, vpn, lvl).start;
+        let ppn = M::entry_get_ppn(entry).offset(offset as isize);
+        let pairs: Vec<_> = MapPairs::solve(vpn, ppn, n, self.page_mode).collect();
+        // 先检查所有的叶子页表项，再修改页表
+        let mut leaves = Vec::new();
+        for (_page_level, vpn_range) in pairs {
+            let mut cur = vpn_range.start;
+            while cur.0 < vpn_range.end.0 {
+                let (entry, lvl) = self.find_ppn(cur)?;
+                let leaf_range = M::entry_leaf_va_range(entry, cur, lvl);
+                if leaf_range.start != cur || leaf_range.end.0 > vpn_range.end.0 {
+                    return Err(PageError::MisalignedAddress);
+                }
+                leaves.push((leaf_range.clone(), lvl));
+                cur = leaf_range.end;
+            }
+        }
+        for (range, lvl) in leaves {
+            // 连续页的每个页表项都需要清除
+            let mut cur = range.start;
+            while cur.0 < range.end.0 {
+                unsafe { self.clear_leaf(cur, lvl) };
+                cur = M::leaf_va_range(cur, lvl).end;
+            }
+        }
+        // 写时复制的页帧随映射一起放弃
+        let cow_vpns: Vec<usize> = self
+            .cow_frames
+            .range(vpn.0..vpn.0 + n)
+            .map(|(&v, _)| v)
+            .collect();
+        for v in cow_vpns {
+            self.cow_frames.remove(&v);
+        }
+        Ok(())
+    }
+    // 修改一段已映射区间的页表项设置，保留原有的物理页号；设置的含义与allocate_map相同。
+    // 区间可以跨越不同等级的叶子页表项（比如大页之后接着小页），但必须完整覆盖每个叶子页表项，
+    // 否则需要先拆分大页，返回MisalignedAddress；区间内有未映射的页时返回InvalidEntry。
+    // 出错时不修改页表
+    pub fn protect(
+        &mut self,
+        vpn: VirtPageNum,
+        n: usize,
+        new_flags: M::Flags,
+    ) -> Result<(), PageError> {
+        if self.strict_wx && M::flags_write_execute(&new_flags) {
+            return Err(PageError::WxViolation);
+        }
+        let end = VirtPageNum(vpn.0 + n);
+        // 先检查所有的叶子页表项，再修改页表
+        let mut leaves = Vec::new();
+        let mut cur = vpn;
+        while cur.0 < end.0 {
+            let (entry, lvl) = self.find_entry_mut(cur)?;
+            debug_assert!(M::entry_is_leaf_page(entry));
+            let ppn = M::entry_get_ppn(entry);
+            let leaf_range = M::entry_leaf_va_range(entry, cur, lvl);
+            if leaf_range.start != cur || leaf_range.end.0 > end.0 {
+                return Err(PageError::MisalignedAddress);
+            }
+            leaves.push((leaf_range.clone(), lvl, ppn));
+            cur = leaf_range.end;
+        }
+        for (range, lvl, ppn) in leaves {
+            // 连续页的每个页表项都需要相同的设置
+            let mut vpn = range.start;
+            while vpn.0 < range.end.0 {
+                unsafe {
+                    let slot = self.leaf_slot_mut(vpn, lvl);
+                    M::slot_set_mapping(slot, ppn, new_flags.clone());
+                    M::flush_page(vpn.addr_begin::<M>());
+                }
+                vpn = M::leaf_va_range(vpn, lvl).end;
+            }
+        }
+        Ok(())
+    }
+    // 得到一个叶子页表项所在的槽位。要求叶子页表项存在
+    unsafe fn leaf_slot_mut(&mut self, vpn: VirtPageNum, leaf_level: PageLevel) -> &mut M::Slot {
+        let mut ppn = self.root_frame.phys_page_num();
+        for level in M::visit_levels_before(leaf_level) {
+            let entry = M::slot_try_get_entry(self.slot_mut(ppn, level, M::vpn_index(vpn, level)));
+            ppn = M::entry_get_ppn(entry.ok().expect("checked mapped leaf"));
+        }
+        self.slot_mut(ppn, leaf_level, M::vpn_index(vpn, leaf_level))
+    }
+    // 清除一个叶子页表项，然后自底向上释放变为空的子页表。要求叶子页表项存在
+    unsafe fn clear_leaf(&mut self, vpn: VirtPageNum, leaf_level: PageLevel) {
+        // 从根页表到叶子页表项所在页表的路径
+        let mut path = vec![(
+            self.root_frame.phys_page_num(),
+            PageLevel(M::MAX_PAGE_LEVELS - 1),
+        )];
+        let mut ppn = self.root_frame.phys_page_num();
+        for level in M::visit_levels_before(leaf_level) {
+            let entry = M::slot_try_get_entry(self.slot_mut(ppn, level, M::vpn_index(vpn, level)));
+            ppn = M::entry_get_ppn(entry.ok().expect("checked mapped leaf"));
+            path.push((ppn, PageLevel(level.0 - 1)));
+        }
+        M::slot_clear(self.slot_mut(ppn, leaf_level, M::vpn_index(vpn, leaf_level)));
+        self.release_empty_tables(vpn, &path);
+    }
+    // 沿vpn经过的页表路径，自底向上释放所有条目都无效的子页表
+    unsafe fn prune_tables(&mut self, vpn: VirtPageNum) {
+        let mut path = vec![(
+            self.root_frame.phys_page_num(),
+            PageLevel(M::MAX_PAGE_LEVELS - 1),
+        )];
+        let mut ppn = self.root_frame.phys_page_num();
+        for level in (1..M::MAX_PAGE_LEVELS).rev().map(PageLevel) {
+            match M::slot_try_get_entry(self.slot_mut(ppn, level, M::vpn_index(vpn, level))) {
+                Ok(entry) => {
+                    if M::entry_is_leaf_page(entry) {
+                        break;
+                    }
+                    ppn = M::entry_get_ppn(entry)
+                }
+                Err(_slot) => break,
+            }
+            path.push((ppn, PageLevel(level.0 - 1)));
+        }
+        self.release_empty_tables(vpn, &path);
+    }
+    // path是从根页表开始的(页表页号, 等级)路径；从最后一个页表开始释放空的页表，遇到非空的页表为止
+    unsafe fn release_empty_tables(&mut self, vpn: VirtPageNum, path: &[(PhysPageNum, PageLevel)]) {
+        for i in (1..path.len()).rev() {
+            let page_table = self.table_mut(path[i].0);
+            let is_empty = (0..1 << M::PAGE_ENTRIES_BITS)
+                .all(|idx| M::slot_try_get_entry(&mut page_table[idx]).is_err());
+            if !is_empty {
+                break;
+            }
+            let (parent_ppn, parent_level) = path[i - 1];
+            M::slot_clear(self.slot_mut(parent_ppn, parent_level, M::vpn_index(vpn, parent_level)));
+            let pos = self
+                .frames
+                .iter()
+                .position(|f| f.phys_page_num() == path[i].0);
+            // 页表的页帧由地址空间所有，丢弃FrameBox即归还给分配器
+            self.frames
+                .swap_remove(pos.expect("page table frame owned by address space"));
+        }
+    }
+
+    /// 根据虚拟页号查询物理页号，可能出错。
+    pub fn find_ppn(&self, vpn: VirtPageNum) -> Result<(&M::Entry, PageLevel), PageError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for lvl in M::visit_levels_until(PageLevel::leaf_level()) {
+            // 注意: 要求内核能经过phys_to_virt访问页表空间
+            let vidx = M::vpn_index(vpn, lvl);
+            match M::slot_try_get_entry(unsafe { self.slot_mut(ppn, lvl, vidx) }) {
+                Ok(entry) => {
+                    if M::entry_is_leaf_page(entry) {
+                        return Ok((entry, lvl));
+                    } else {
+                        ppn = M::entry_get_ppn(entry)
+                    }
+                }
+                Err(_slot) => return Err(PageError::InvalidEntry),
+            }
+        }
+        Err(PageError::NotLeafInLowestPage)
+    }
+
+    /// 查询映射虚拟地址的页的大小，以字节为单位，可能出错。
+    pub fn page_size_at(&self, va: VirtAddr) -> Result<usize, PageError> {
+        let vpn = va.page_number::<M>();
+        let (entry, lvl) = self.find_ppn(vpn)?;
+        let range = M::entry_leaf_va_range(entry, vpn, lvl);
+        Ok((range.end.0 - range.start.0) << M::FRAME_SIZE_BITS)
+    }
+
+    /// 寻找hint开始、最低的连续n个页都没有映射的虚拟页区间，返回区间起始的虚拟页号。
+    /// 沿页表查找，无效的页表项和大页整体跳过，不逐个检查其中的页；地址空间中没有足够的区间时返回None
+    pub fn find_free_region(&self, hint: VirtPageNum, n: usize) -> Option<VirtPageNum> {
+        let root_level = PageLevel(M::MAX_PAGE_LEVELS - 1);
+        let root_pages = M::get_layout_for_level(root_level).page_size::<M>() >> M::FRAME_SIZE_BITS;
+        let limit = M::table_entries(root_level).checked_mul(root_pages)?;
+        let (mut start, mut cur) = (hint, hint);
+        while cur.0 - start.0 < n {
+            if cur.0 >= limit {
+                return None;
+            }
+            let (mapped, end) = self.probe_region(cur);
+            if mapped {
+                start = end;
+            }
+            cur = end;
+        }
+        Some(start)
+    }
+    // 从vpn开始的n个页中第一个有映射的页，跳过无效页表项覆盖的区间
+    fn first_mapped(&self, vpn: VirtPageNum, n: usize) -> Option<VirtPageNum> {
+        let mut cur = vpn;
+        while cur.0 < vpn.0 + n {
+            let (mapped, end) = self.probe_region(cur);
+            if mapped {
+                return Some(cur);
+            }
+            cur = end;
+        }
+        None
+    }
+    // 查找vpn所在的区间：映射的叶子页返回(true, 叶子页的结尾)；
+    // 没有映射时返回(false, 无效页表项覆盖区间的结尾)，这个区间中的页都没有映射
+    fn probe_region(&self, vpn: VirtPageNum) -> (bool, VirtPageNum) {
+        let mut ppn = self.root_frame.phys_page_num();
+        for level in (0..M::MAX_PAGE_LEVELS).rev().map(PageLevel) {
+            let slot = unsafe { self.slot_mut(ppn, level, M::vpn_index(vpn, level)) };
+            match M::slot_try_get_entry(slot) {
+                Err(_slot) => return (false, M::leaf_va_range(vpn, level).end),
+                Ok(entry) => {
+                    if M::entry_is_leaf_page(entry) {
+                        return (true, M::entry_leaf_va_range(entry, vpn, level).end);
+                    }
+                    ppn = M::entry_get_ppn(entry)
+                }
+            }
+        }
+        // 第0层页表不能是内部节点，当作已经占用
+        (true, VirtPageNum(vpn.0 + 1))
+    }
+
+    /// 把虚拟地址翻译为物理地址，可能出错。
+    /// 页内偏移按叶子页表项覆盖的区间计算，大页和连续页中的偏移可以超过一个页帧
+    pub fn translate_addr(&self, va: VirtAddr) -> Result<PhysAddr, PageError> {
+        let vpn = va.page_number::<M>();
+        let (entry, lvl) = self.find_ppn(vpn)?;
+        let start = M::entry_leaf_va_range(entry, vpn, lvl)
+            .start
+            .addr_begin::<M>();
+        let base = M::entry_get_ppn(entry).addr_begin::<M>();
+        Ok(PhysAddr(base.0 + (va.0 - start.0)))
+    }
+
+    // 根据虚拟页号查询可修改的叶子页表项
+    pub(crate) fn find_entry_mut(
+        &mut self,
+        vpn: VirtPageNum,
+    ) -> Result<(&mut M::Entry, PageLevel), PageError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for lvl in M::visit_levels_until(PageLevel::leaf_level()) {
+            let vidx = M::vpn_index(vpn, lvl);
+            match M::slot_try_get_entry(unsafe { self.slot_mut(ppn, lvl, vidx) }) {
+                Ok(entry) => {
+                    if M::entry_is_leaf_page(entry) {
+                        return Ok((entry, lvl));
+                    } else {
+                        ppn = M::entry_get_ppn(entry)
+                    }
+                }
+                Err(_slot) => return Err(PageError::InvalidEntry),
+            }
+        }
+        Err(PageError::NotLeafInLowestPage)
+    }
+
+    // 按虚拟页号从低到高，遍历所有的叶子页表项
+    pub(crate) fn visit_leaves_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(VirtPageNum, PageLevel, &mut M::Entry),
+    {
+        let root_ppn = self.root_frame.phys_page_num();
+        let root_level = PageLevel(M::MAX_PAGE_LEVELS - 1);
+        let phys_to_virt = self.phys_to_virt;
+        unsafe {
+            visit_table_leaves_mut::<M, F>(
+                phys_to_virt,
+                root_ppn,
+                root_level,
+                VirtPageNum(0),
+                &mut f,
+            )
+        }
+    }
+    // 按虚拟页号从低到高，只读地遍历所有的叶子页表项
+    pub(crate) fn visit_leaves<F>(&self, mut f: F)
+    where
+        F: FnMut(VirtPageNum, PageLevel, &M::Entry),
+    {
+        let root_ppn = self.root_frame.phys_page_num();
+        let root_level = PageLevel(M::MAX_PAGE_LEVELS - 1);
+        let mut g = |vpn, level, entry: &mut M::Entry| f(vpn, level, entry);
+        // note(unsafe): 页表项只被只读地访问
+        unsafe {
+            visit_table_leaves_mut::<M, _>(
+                self.phys_to_virt,
+                root_ppn,
+                root_level,
+                VirtPageNum(0),
+                &mut g,
+            )
+        }
+    }
+    // 从根页表开始遍历整个翻译树，按虚拟页号从低到高，对每个叶子映射调用f(虚拟页号, 物理页号, 等级, 设置)
+    pub fn for_each_mapping<F>(&self, mut f: F)
+    where
+        F: FnMut(VirtPageNum, PhysPageNum, PageLevel, M::Flags),
+    {
+        self.visit_leaves(|vpn, level, entry| {
+            f(
+                vpn,
+                leaf_ppn::<M>(entry, vpn, level),
+                level,
+                M::entry_get_flags(entry),
+            )
+        });
+    }
+    // 打印所有的叶子映射和页的大小，用于调试错误的映射
+    pub fn dump(&self)
+    where
+        M::Flags: core::fmt::Debug,
+    {
+        println!("zihai > address space, root {:x?}", self.root_page_number());
+        self.for_each_mapping(|vpn, ppn, level, flags| {
+            let page_size = M::get_layout_for_level(level).page_size::<M>();
+            println!(
+                "  {:#x} -> {:#x}, size {:#x}, level {}, {:?}",
+                vpn.addr_begin::<M>().0,
+                ppn.addr_begin::<M>().0,
+                page_size,
+                level.0,
+                flags
+            );
+        });
+    }
+    // 统计每一级页表的叶子页表项数量，按页表等级索引。
+    // 可以看出大页的使用情况，以及4K页的碎片程度
+    pub fn level_histogram(&self) -> Vec<usize> {
+        let mut ans = vec![0; M::MAX_PAGE_LEVELS as usize];
+        self.visit_leaves(|_, level, _| ans[level.0 as usize] += 1);
+        ans
+    }
+    // 检查所有叶子映射是否满足W^X原则，返回同时可写和可执行的映射的起始虚拟页号。
+    // 策略层可以据此拒绝这样的地址空间，比如在激活或运行客户机之前
+    pub fn enforce_wx(&self) -> Result<(), Vec<VirtPageNum>> {
+        let mut violations = Vec::new();
+        self.visit_leaves(|vpn, _, entry| {
+            if M::flags_write_execute(&M::entry_get_flags(entry)) {
+                violations.push(vpn);
+            }
+        });
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+    // 复制当前所有的叶子映射，按虚拟页号从低到高排列。
+    // 快照归调用者所有，与地址空间之后的修改无关；监视器核只需要在复制期间持有地址空间的锁，
+    // 之后读取快照不会阻塞正在运行的核
+    pub fn snapshot_mappings(&self) -> Box<[MappingRecord<M::Flags>]> {
+        let mut ans = Vec::new();
+        self.visit_leaves(|vpn, level, entry| {
+            ans.push(MappingRecord {
+                vpn,
+                level,
+                ppn: leaf_ppn::<M>(entry, vpn, level),
+                flags: M::entry_get_flags(entry),
+            })
+        });
+        ans.into_boxed_slice()
+    }
+}
+
+// 一个叶子映射的副本
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MappingRecord<F> {
+    // 映射的起始虚拟页号
+    pub vpn: VirtPageNum,
+    // 叶子页表项的等级，决定映射的大小
+    pub level: PageLevel,
+    pub ppn: PhysPageNum,
+    pub flags: F,
+}
+
+// 叶子页表项中虚拟页号对应的物理页号；连续页的页表项记录的是连续区间起始的页号
+fn leaf_ppn<M: PageMode>(entry: &M::Entry, vpn: VirtPageNum, level: PageLevel) -> PhysPageNum {
+    let start = M::entry_leaf_va_range(entry, vpn, level).start;
+    M::entry_get_ppn(entry).offset((vpn - start) as isize)
+}
+
+// 得到页表中第idx个槽位，页表所在的帧经过phys_to_virt访问；约定同window_as_table_mut。
+// 占用多个页帧的根页表按split_table_index找到槽位所在的页帧
+unsafe fn window_slot_mut<'a, M: PageMode>(
+    phys_to_virt: fn(PhysPageNum) -> VirtPageNum,
+    ppn: PhysPageNum,
+    level: PageLevel,
+    idx: usize,
+) -> &'a mut M::Slot {
+    let (frame, idx) = M::split_table_index(level, idx);
+    let table = window_as_table_mut::<M>(phys_to_virt, ppn.offset(frame as isize));
+    &mut *(&mut table[idx] as *mut M::Slot)
+}
+
+// 遍历一个页表及其子页表的所有叶子页表项；页表所在的帧经过phys_to_virt访问
+unsafe fn visit_table_leaves_mut<M: PageMode, F>(
+    phys_to_virt: fn(PhysPageNum) -> VirtPageNum,
+    ppn: PhysPageNum,
+    level: PageLevel,
+    vpn_prefix: VirtPageNum,
+    f: &mut F,
+) where
+    F: FnMut(VirtPageNum, PageLevel, &mut M::Entry),
+{
+    for vidx in 0..M::table_entries(level) {
+        let slot = window_slot_mut::<M>(phys_to_virt, ppn, level, vidx);
+        if let Ok(entry) = M::slot_try_get_entry(slot) {
+            let vpn = M::vpn_level_index(vpn_prefix, level, vidx);
+            if M::entry_is_leaf_page(entry) {
+                f(vpn, level, entry);
+            } else if level.0 > 0 {
+                let child_ppn = M::entry_get_ppn(entry);
+                let child_level = PageLevel(level.0 - 1);
+                visit_table_leaves_mut::<M, F>(phys_to_virt, child_ppn, child_level, vpn, f);
+            }
+        }
+    }
+}
+
+/// 页表操作可能出现的错误
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageError {
+    /// 节点不具有有效位
+    InvalidEntry,
+    /// 第0层页表不能是内部节点
+    NotLeafInLowestPage,
+    /// 地址没有按页帧对齐
+    MisalignedAddress,
+    /// 地址区间的结束地址小于开始地址
+    InvalidRange,
+    /// 没有页帧可以分配
+    FrameAllocFailed,
+    /// 映射的页帧数量超过上限
+    MappingTooLarge,
+    /// 页表所在的页帧没有可读写的恒等映射
+    TableNotIdentityMapped,
+    /// 严格W^X模式下，映射同时可写和可执行
+    WxViolation,
+    /// 写入的页没有可写权限
+    NotWritable,
+    /// 连续页的虚拟页号或物理页号没有按连续区间对齐
+    MisalignedNapot,
+    /// 虚拟页已经有映射，vpn是区间中第一个有映射的页
+    AlreadyMapped { vpn: VirtPageNum },
+}
+
+impl From<FrameAllocError> for PageError {
+    fn from(_: FrameAllocError) -> Self {
+        PageError::FrameAllocFailed
+    }
+}
+
+#[derive(Debug)]
+pub struct MapPairs<M> {
+    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
+    mode: M,
+}
+
+impl<M: PageMode> MapPairs<M> {
+    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Self {
+        let mut ans = Vec::new();
+        for i in M::visit_levels_until(PageLevel::leaf_level()) {
+            let align = M::get_layout_for_level(i).align_in_frames();
+            if usize::wrapping_sub(vpn.0, ppn.0) % align != 0 || n < align {
+                continue;
+            }
+            let (mut ve_prev, mut vs_prev) = (None, None);
+            for j in M::visit_levels_from(i) {
+                let align_cur = M::get_layout_for_level(j).align_in_frames();
+                let ve_cur = align_cur * ((vpn.0 + align_cur - 1) / align_cur); // a * roundup(v / a)
+                let vs_cur = align_cur * ((vpn.0 + n) / align_cur); // a * rounddown((v+n) / a)
+                if let (Some(ve_prev), Some(vs_prev)) = (ve_prev, vs_prev) {
+                    if ve_cur != ve_prev {
+                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(ve_prev)));
+                    }
+                    if vs_prev != vs_cur {
+                        ans.push((j, VirtPageNum(vs_prev)..VirtPageNum(vs_cur)));
+                    }
+                } else {
+                    if ve_cur != vs_cur {
+                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(vs_cur)));
+                    }
+                }
+                (ve_prev, vs_prev) = (Some(ve_cur), Some(vs_cur));
+            }
+            break;
+        }
+        // println!("[SOLVE] Ans = {:x?}", ans);
+        Self {
+            ans_iter: ans.into_iter(),
+            mode,
+        }
+    }
+}
+
+impl<M> Iterator for MapPairs<M> {
+    type Item = (PageLevel, Range<VirtPageNum>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.ans_iter.next()
+    }
+}
+
+pub(crate) fn test_map_solve() {
+    let layout_frames_sv39 = [
+        (PageLevel(0), 1),
+        (PageLevel(1), 512),
+        (PageLevel(2), 512 * 512),
+    ];
+    for (level, align_in_frames) in layout_frames_sv39 {
+        assert_eq!(
+            Sv39::get_layout_for_level(level).align_in_frames(),
+            align_in_frames
+        );
+    }
+    let visit_levels_until_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
+        (PageLevel(0), &[PageLevel(2), PageLevel(1), PageLevel(0)]),
+        (PageLevel(1), &[PageLevel(2), PageLevel(1)]),
+        (PageLevel(2), &[PageLevel(2)]),
+    ];
+    for (level, iter_result) in visit_levels_until_sv39 {
+        assert_eq!(
+            Sv39::visit_levels_until(level).collect::<Vec<_>>(),
+            iter_result,
+        );
+    }
+    let visit_levels_before_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
+        (PageLevel(0), &[PageLevel(2), PageLevel(1)]),
+        (PageLevel(1), &[PageLevel(2)]),
+        (PageLevel(2), &[]),
+    ];
+    for (level, iter_result) in visit_levels_before_sv39 {
+        assert_eq!(
+            Sv39::visit_levels_before(level).collect::<Vec<_>>(),
+            iter_result,
+        );
+    }
+    let visit_levels_from_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
+        (PageLevel(0), &[PageLevel(0)]),
+        (PageLevel(1), &[PageLevel(1), PageLevel(0)]),
+        (PageLevel(2), &[PageLevel(2), PageLevel(1), PageLevel(0)]),
+    ];
+    for (level, iter_result) in visit_levels_from_sv39 {
+        assert_eq!(
+            Sv39::visit_levels_from(level).collect::<Vec<_>>(),
+            iter_result,
+        );
+    }
+
+    let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(2), VirtPageNum(786432)..VirtPageNum(1048576)),
+            (PageLevel(1), VirtPageNum(589824)..VirtPageNum(786432)),
+            (PageLevel(1), VirtPageNum(1048576)..VirtPageNum(1256448)),
+            (PageLevel(0), VirtPageNum(1256448)..VirtPageNum(1256490))
+        ]
+    );
+    let pairs = MapPairs::solve(VirtPageNum(0x90_001), PhysPageNum(0x50_001), 77777, Sv39)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(1), VirtPageNum(590336)..VirtPageNum(667136)),
+            (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)),
+            (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
+        ]
+    );
+    let pairs = MapPairs::solve(VirtPageNum(0x12_345), PhysPageNum(0x22_345), 888888, Sv39x4)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(1), VirtPageNum(74752)..VirtPageNum(963072)),
+            (PageLevel(0), VirtPageNum(74565)..VirtPageNum(74752)),
+            (PageLevel(0), VirtPageNum(963072)..VirtPageNum(963453))
+        ]
+    );
+    let pairs = MapPairs::solve(
+        VirtPageNum(0x400000),
+        PhysPageNum(0x200000),
+        0x40000000,
+        Sv39x4,
+    )
+    .collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [(PageLevel(2), VirtPageNum(4194304)..VirtPageNum(1077936128))]
+    );
+    assert_eq!(
+        Sv48::get_layout_for_level(PageLevel(3)).align_in_frames(),
+        512 * 512 * 512
+    );
+    assert_eq!(
+        Sv48::visit_levels_until(PageLevel(0)).collect::<Vec<_>>(),
+        [PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)]
+    );
+    // 从一个512GiB边界前的2MiB开始，映射一个512GiB大页和之后的几个页
+    let vpn = VirtPageNum((1 << 27) - 512);
+    let ppn = PhysPageNum(vpn.0 + (1 << 28));
+    let pairs = MapPairs::solve(vpn, ppn, 512 + (1 << 27) + 1024 + 1, Sv48).collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(3), VirtPageNum(134217728)..VirtPageNum(268435456)),
+            (PageLevel(1), VirtPageNum(134217216)..VirtPageNum(134217728)),
+            (PageLevel(1), VirtPageNum(268435456)..VirtPageNum(268436480)),
+            (PageLevel(0), VirtPageNum(268436480)..VirtPageNum(268436481))
+        ]
+    );
+    assert_eq!(
+        Sv57::get_layout_for_level(PageLevel(4)).align_in_frames(),
+        512 * 512 * 512 * 512
+    );
+    // 从一个256TiB边界前的512GiB开始，映射两个256TiB大页和之后的一个1GiB大页
+    let vpn = VirtPageNum((1 << 36) - (1 << 27));
+    let ppn = PhysPageNum(vpn.0 + (3 << 36));
+    let n = (1 << 27) + (2 << 36) + (1 << 18);
+    let pairs = MapPairs::solve(vpn, ppn, n, Sv57).collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (
+                PageLevel(4),
+                VirtPageNum(68719476736)..VirtPageNum(206158430208)
+            ),
+            (
+                PageLevel(3),
+                VirtPageNum(68585259008)..VirtPageNum(68719476736)
+            ),
+            (
+                PageLevel(2),
+                VirtPageNum(206158430208)..VirtPageNum(206158692352)
+            )
+        ]
+    );
+    let layout_frames_sv32 = [(PageLevel(0), 1), (PageLevel(1), 1024)];
+    for (level, align_in_frames) in layout_frames_sv32 {
+        assert_eq!(
+            Sv32::get_layout_for_level(level).align_in_frames(),
+            align_in_frames
+        );
+    }
+    assert_eq!(
+        Sv32::get_layout_for_level(PageLevel(1)).page_size::<Sv32>(),
+        4 * 1024 * 1024,
+        "Sv32 megapage"
+    );
+    // 4MiB大页前后各有几个4KiB页
+    let vpn = VirtPageNum(0x3fe);
+    let pairs = MapPairs::solve(vpn, PhysPageNum(0x13fe), 2 + 2048 + 3, Sv32).collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [
+            (PageLevel(1), VirtPageNum(0x400)..VirtPageNum(0xc00)),
+            (PageLevel(0), VirtPageNum(0x3fe)..VirtPageNum(0x400)),
+            (PageLevel(0), VirtPageNum(0xc00)..VirtPageNum(0xc03))
+        ]
+    );
+    let pairs = MapPairs::solve(vpn, PhysPageNum(0x13ff), 2 + 2048 + 3, Sv32).collect::<Vec<_>>();
+    assert_eq!(
+        pairs,
+        [(PageLevel(0), VirtPageNum(0x3fe)..VirtPageNum(0xc03))],
+        "misaligned physical pages"
+    );
+    println!("zihai > address map solver test passed");
+}
+
+pub(crate) fn test_map_range(frame_alloc: &DefaultFrameAllocator) {
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let mut by_range = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    by_range
+        .map_range(
+            VirtAddr(0x9000_0000)..VirtAddr(0x9020_3000),
+            PhysAddr(0x8800_0000),
+            flags,
+        )
+        .expect("map byte range");
+    let mut by_count = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    by_count
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x88000), 0x203, flags)
+        .expect("map page number and count");
+    for vpn in [0x90000, 0x901ff, 0x90200, 0x90202] {
+        let (entry1, lvl1) = by_range.find_ppn(VirtPageNum(vpn)).unwrap();
+        let (entry2, lvl2) = by_count.find_ppn(VirtPageNum(vpn)).unwrap();
+        assert_eq!(entry1.ppn(), entry2.ppn(), "same physical page");
+        assert_eq!(lvl1, lvl2, "same page level");
+    }
+    assert_eq!(
+        by_range.find_ppn(VirtPageNum(0x90203)).map(|_| ()),
+        Err(PageError::InvalidEntry),
+        "end of range is not mapped"
+    );
+    assert_eq!(
+        by_range.map_range(
+            VirtAddr(0xa000_0800)..VirtAddr(0xa000_2000),
+            PhysAddr(0x8800_0000),
+            flags
+        ),
+        Err(PageError::MisalignedAddress),
+        "misaligned virtual address"
+    );
+    assert_eq!(
+        by_range.map_range(
+            VirtAddr(0xa000_2000)..VirtAddr(0xa000_1000),
+            PhysAddr(0x8800_0000),
+            flags
+        ),
+        Err(PageError::InvalidRange),
+        "reversed range"
+    );
+    println!("zihai > map byte range test passed");
+}
+
+pub(crate) fn test_page_table_iter(frame_alloc: &DefaultFrameAllocator) {
+    let frame = FrameBox::try_new_in(frame_alloc).unwrap();
+    let ppn = frame.phys_page_num();
+    let table = unsafe { frame_as_table_mut::<Sv39>(ppn) };
+    Sv39::init_page_table(table);
+    Sv39::slot_set_child(&mut table[3], PhysPageNum(0x80400));
+    Sv39::slot_set_mapping(&mut table[7], PhysPageNum(0x80000), Sv39Flags::R);
+    let table = unsafe { frame_as_table::<Sv39>(ppn) };
+    assert_eq!(
+        Sv39::page_table_iter(table).count(),
+        512,
+        "all slots visited"
+    );
+    let valid: Vec<usize> = Sv39::page_table_iter(table)
+        .filter(|(_, slot)| slot.bits & Sv39Flags::V.bits() as usize != 0)
+        .map(|(idx, _)| idx)
+        .collect();
+    assert_eq!(valid, [3, 7], "valid slots with their indices");
+    let (idx, slot) = Sv39::page_table_iter(table).nth(7).unwrap();
+    assert!(core::ptr::eq(slot, &table[idx]), "slot at its index");
+    drop(frame);
+    println!("zihai > page table iterator test passed");
+}
+
+pub(crate) fn test_map_too_large(frame_alloc: &DefaultFrameAllocator) {
+    let frames_in_use = || {
+        let a = frame_alloc.lock();
+        a.current.0 - a.recycled.len()
+    };
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let before = frames_in_use();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    assert_eq!(
+        space.allocate_map(VirtPageNum(0), PhysPageNum(0), MAX_MAP_PAGES + 1, flags),
+        Err(PageError::MappingTooLarge),
+        "mapping over limit"
+    );
+    assert_eq!(
+        space.allocate_map(VirtPageNum(0), PhysPageNum(0), usize::MAX, flags),
+        Err(PageError::MappingTooLarge),
+        "absurd mapping"
+    );
+    assert_eq!(frames_in_use(), before, "no page table allocated");
+    // 512G gigapage-backed mapping only needs one page table
+    space
+        .map_range(
+            VirtAddr(0)..VirtAddr(MAX_MAP_PAGES << 12),
+            PhysAddr(0),
+            flags,
+        )
+        .expect("mapping at limit");
+    assert_eq!(frames_in_use(), before, "gigapages only");
+    println!("zihai > mapping limit test passed");
+}
+
+pub(crate) fn test_empty_translate(frame_alloc: &DefaultFrameAllocator) {
+    let space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    // 只有根页表，没有任何映射
+    for vpn in [0, 0x4_0000, 0x7ff_ffff] {
+        assert_eq!(
+            space.find_ppn(VirtPageNum(vpn)).map(|_| ()),
+            Err(PageError::InvalidEntry),
+            "find in empty space"
+        );
+    }
+    let called = core::cell::Cell::new(false);
+    for (va, len) in [
+        (0, 0),
+        (0, 1),
+        (0x8000_0123, 0x3000),
+        (0x7f_ffff_f000, 0x1000),
+    ] {
+        let ans = translate_frame_read(&space, VirtAddr(va), len, |_, _, _| called.set(true));
+        assert_eq!(
+            ans,
+            Err(PageError::InvalidEntry),
+            "translate in empty space"
+        );
+    }
+    assert!(!called.get(), "callback never invoked");
+    println!("zihai > empty address space test passed");
+}
+
+pub(crate) fn test_translate_frame_write(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let frame1 = FrameBox::try_new_in(frame_alloc).unwrap();
+    let frame2 = FrameBox::try_new_in(frame_alloc).unwrap();
+    let frame3 = FrameBox::try_new_in(frame_alloc).unwrap();
+    let rw = Sv39Flags::R | Sv39Flags::W;
+    let vpn = VirtPageNum(0x90000);
+    // 相邻的虚拟页映射到不相邻的页帧，第三个页只读
+    space
+        .allocate_map(vpn, frame2.phys_page_num(), 1, rw)
+        .unwrap();
+    space
+        .allocate_map(VirtPageNum(0x90001), frame1.phys_page_num(), 1, rw)
+        .unwrap();
+    space
+        .allocate_map(
+            VirtPageNum(0x90002),
+            frame3.phys_page_num(),
+            1,
+            Sv39Flags::R,
+        )
+        .unwrap();
+    let data = b"zihai hypervisor";
+    let va = VirtAddr(0x9000_0ffa); // 跨越页边界
+    let mut written = 0;
+    translate_frame_write(&space, va, data.len(), |ppn, offset, len| {
+        let dst = (ppn.addr_begin::<Sv39>().0 + offset) as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(data[written..].as_ptr(), dst, len) };
+        written += len;
+    })
+    .expect("write across page boundary");
+    assert_eq!(written, data.len(), "whole buffer written");
+    let read_back = core::cell::RefCell::new(Vec::new());
+    translate_frame_read(&space, va, data.len(), |ppn, offset, len| {
+        let src = (ppn.addr_begin::<Sv39>().0 + offset) as *const u8;
+        let bytes = unsafe { core::slice::from_raw_parts(src, len) };
+        read_back.borrow_mut().extend_from_slice(bytes);
+    })
+    .unwrap();
+    assert_eq!(
+        &read_back.borrow()[..],
+        &data[..],
+        "read back across page boundary"
+    );
+    let page1 = frame1.phys_page_num().addr_begin::<Sv39>().0 as *const u8;
+    assert_eq!(unsafe { page1.read() }, b'a', "second part on second frame");
+    let mut called = false;
+    let ans = translate_frame_write(&space, VirtAddr(0x9000_1ff0), 0x20, |_, _, _| called = true);
+    assert_eq!(ans, Err(PageError::NotWritable), "write to read-only page");
+    assert!(!called, "nothing written when a page is read-only");
+    drop(space);
+    drop((frame1, frame2, frame3));
+    println!("zihai > translate frame write test passed");
+}
+
+pub(crate) fn test_level_histogram(frame_alloc: &DefaultFrameAllocator) {
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    assert_eq!(space.level_histogram(), [0, 0, 0], "empty space");
+    // 1个1G页，2个2M页，3个4K页
+    let ranges = [
+        (0x4000_0000, 0x8000_0000),
+        (0x8000_0000, 0x8040_0000),
+        (0x8040_0000, 0x8040_3000),
+    ];
+    for (start, end) in ranges {
+        space
+            .map_range(VirtAddr(start)..VirtAddr(end), PhysAddr(start), flags)
+            .unwrap();
+    }
+    let histogram = space.level_histogram();
+    assert_eq!(histogram, [3, 2, 1], "leaves on each level");
+    let total_pages: usize = (0..3)
+        .map(|lvl| {
+            histogram[lvl] * Sv39::get_layout_for_level(PageLevel(lvl as u8)).align_in_frames()
+        })
+        .sum();
+    let mapped_bytes: usize = ranges.iter().map(|(start, end)| end - start).sum();
+    assert_eq!(total_pages << 12, mapped_bytes, "sum of page spans");
+    println!("zihai > page level histogram test passed");
+}
+
+pub(crate) fn test_identity_mapped_tables(frame_alloc: &DefaultFrameAllocator) {
+    let rw = Sv39Flags::R | Sv39Flags::W;
+    let (from, to) = (VirtAddr(0x80000000), VirtAddr(0x80800000)); // covers all frames of `frame_alloc`
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    space
+        .map_range(from..to, PhysAddr(0x80000000), rw | Sv39Flags::X)
+        .unwrap();
+    assert_eq!(
+        space.assert_page_tables_identity_mapped(),
+        Ok(()),
+        "all table frames identity mapped"
+    );
+    drop(space);
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let vpn = VirtPageNum(0x90000);
+    space
+        .allocate_map(vpn, PhysPageNum(0x80000), 1, rw)
+        .unwrap();
+    assert_eq!(
+        space.assert_page_tables_identity_mapped(),
+        Err(PageError::TableNotIdentityMapped),
+        "table frames not mapped"
+    );
+    drop(space);
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    space
+        .map_range(from..to, PhysAddr(0x80000000), Sv39Flags::R)
+        .unwrap();
+    assert_eq!(
+        space.assert_page_tables_identity_mapped(),
+        Err(PageError::TableNotIdentityMapped),
+        "table frames mapped read only"
+    );
+    drop(space);
+    println!("zihai > identity mapped page tables test passed");
+}
+
+pub(crate) fn test_leaf_va_range() {
+    let vpn = VirtPageNum(0x12345);
+    assert_eq!(
+        Sv39::leaf_va_range(vpn, PageLevel(0)),
+        VirtPageNum(0x12345)..VirtPageNum(0x12346),
+        "level 0 covers one page"
+    );
+    assert_eq!(
+        Sv39::leaf_va_range(vpn, PageLevel(1)),
+        VirtPageNum(0x12200)..VirtPageNum(0x12400),
+        "level 1 covers 512 pages"
+    );
+    assert_eq!(
+        Sv39::leaf_va_range(vpn, PageLevel(2)),
+        VirtPageNum(0x00000)..VirtPageNum(0x40000),
+        "level 2 covers 512*512 pages"
+    );
+    assert_eq!(
+        Sv39::leaf_va_range(VirtPageNum(0x40000), PageLevel(2)),
+        VirtPageNum(0x40000)..VirtPageNum(0x80000),
+        "aligned base"
+    );
+    println!("zihai > leaf virtual range test passed");
+}
+
+pub(crate) fn test_deallocate_map(frame_alloc: &DefaultFrameAllocator) {
+    let checkpoint = frame_alloc.lock().checkpoint();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let (vpn, ppn) = (VirtPageNum(0x90000), PhysPageNum(0x80000));
+    space.allocate_map(vpn, ppn, 16, flags).unwrap();
+    let table_frames = space.frames.len();
+    space.deallocate_map(VirtPageNum(0x90004), 4).unwrap();
+    for i in 4..8 {
+        assert_eq!(
+            space.find_ppn(VirtPageNum(0x90000 + i)).map(|_| ()),
+            Err(PageError::InvalidEntry),
+            "unmapped page"
+        );
+    }
+    assert!(space.find_ppn(VirtPageNum(0x90003)).is_ok(), "before range");
+    assert!(space.find_ppn(VirtPageNum(0x90008)).is_ok(), "after range");
+    assert_eq!(space.frames.len(), table_frames, "table still in use");
+    assert_eq!(
+        space.deallocate_map(VirtPageNum(0x90004), 2),
+        Err(PageError::InvalidEntry),
+        "range never mapped"
+    );
+    assert_eq!(
+        space.deallocate_map(VirtPageNum(0x9000e), 4),
+        Err(PageError::InvalidEntry),
+        "range partly mapped"
+    );
+    assert!(
+        space.find_ppn(VirtPageNum(0x9000e)).is_ok(),
+        "unchanged on error"
+    );
+    // 2MiB huge page
+    let (huge_vpn, huge_ppn) = (VirtPageNum(0x40000), PhysPageNum(0x80200));
+    space.allocate_map(huge_vpn, huge_ppn, 512, flags).unwrap();
+    assert_eq!(
+        space.deallocate_map(huge_vpn, 1),
+        Err(PageError::MisalignedAddress),
+        "part of a huge page"
+    );
+    space.deallocate_map(huge_vpn, 512).unwrap();
+    space.deallocate_map(vpn, 4).unwrap();
+    space.deallocate_map(VirtPageNum(0x90008), 8).unwrap();
+    assert_eq!(space.frames.len(), 0, "empty tables freed");
+    assert_eq!(space.level_histogram(), [0, 0, 0], "no leaves left");
+    drop(space);
+    frame_alloc.lock().assert_restored(checkpoint);
+    println!("zihai > deallocate map test passed");
+}
+
+pub(crate) fn test_protect(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X;
+    // 2MiB大页之后接着4KiB小页
+    let (huge_vpn, huge_ppn) = (VirtPageNum(0x40000), PhysPageNum(0x80200));
+    space.allocate_map(huge_vpn, huge_ppn, 512, flags).unwrap();
+    let (small_vpn, small_ppn) = (VirtPageNum(0x40200), PhysPageNum(0x80401));
+    space.allocate_map(small_vpn, small_ppn, 4, flags).unwrap();
+    let rx = Sv39Flags::R | Sv39Flags::X;
+    space
+        .protect(huge_vpn, 512 + 4, rx)
+        .expect("mixed-level range");
+    let (entry, lvl) = space.find_ppn(huge_vpn).unwrap();
+    assert_eq!(lvl, PageLevel(1), "still a huge page");
+    assert_eq!(entry.ppn(), huge_ppn, "huge page number kept");
+    assert_eq!(entry.flags(), Sv39Flags::V | rx, "huge page protected");
+    for i in 0..4 {
+        let (entry, lvl) = space.find_ppn(VirtPageNum(0x40200 + i)).unwrap();
+        assert_eq!(lvl, PageLevel(0), "small page");
+        assert_eq!(entry.ppn(), PhysPageNum(0x80401 + i), "page number kept");
+        assert_eq!(entry.flags(), Sv39Flags::V | rx, "small page protected");
+    }
+    assert_eq!(
+        space.protect(VirtPageNum(0x40202), 4, Sv39Flags::R),
+        Err(PageError::InvalidEntry),
+        "range partly unmapped"
+    );
+    let (entry, _) = space.find_ppn(VirtPageNum(0x40202)).unwrap();
+    assert_eq!(entry.flags(), Sv39Flags::V | rx, "unchanged on error");
+    assert_eq!(
+        space.protect(VirtPageNum(0x40100), 0x104, Sv39Flags::R),
+        Err(PageError::MisalignedAddress),
+        "part of a huge page"
+    );
+    assert_eq!(
+        space.protect(small_vpn, 0, Sv39Flags::R),
+        Ok(()),
+        "empty range"
+    );
+    println!("zihai > protect test passed");
+}
+
+pub(crate) fn test_snapshot_mappings(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let (huge_vpn, huge_ppn) = (VirtPageNum(0x40000), PhysPageNum(0x80200));
+    space.allocate_map(huge_vpn, huge_ppn, 512, flags).unwrap();
+    let (vpn, ppn) = (VirtPageNum(0x90000), PhysPageNum(0x80001));
+    space.allocate_map(vpn, ppn, 2, flags).unwrap();
+    let snapshot = space.snapshot_mappings();
+    let expected = [
+        MappingRecord {
+            vpn: huge_vpn,
+            level: PageLevel(1),
+            ppn: huge_ppn,
+            flags: Sv39Flags::V | flags,
+        },
+        MappingRecord {
+            vpn,
+            level: PageLevel(0),
+            ppn,
+            flags: Sv39Flags::V | flags,
+        },
+        MappingRecord {
+            vpn: VirtPageNum(0x90001),
+            level: PageLevel(0),
+            ppn: PhysPageNum(0x80002),
+            flags: Sv39Flags::V | flags,
+        },
+    ];
+    assert_eq!(&snapshot[..], &expected[..], "leaf mappings captured");
+    space.protect(huge_vpn, 512, Sv39Flags::R).unwrap();
+    space.deallocate_map(vpn, 2).unwrap();
+    space
+        .allocate_map(VirtPageNum(0xa0000), ppn, 1, flags)
+        .unwrap();
+    assert_eq!(space.snapshot_mappings().len(), 2, "source space changed");
+    assert_eq!(&snapshot[..], &expected[..], "snapshot unchanged");
+    println!("zihai > snapshot mappings test passed");
+}
+
+pub(crate) fn test_for_each_mapping(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let mut leaves = Vec::new();
+    space.for_each_mapping(|vpn, ppn, level, flags| leaves.push((vpn, ppn, level, flags)));
+    assert!(leaves.is_empty(), "empty address space");
+    let (rw, rx) = (Sv39Flags::R | Sv39Flags::W, Sv39Flags::R | Sv39Flags::X);
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x80010), 2, rw)
+        .unwrap();
+    space
+        .allocate_map(VirtPageNum(0x40000), PhysPageNum(0x80200), 512, rx)
+        .unwrap();
+    space.for_each_mapping(|vpn, ppn, level, flags| leaves.push((vpn, ppn, level, flags)));
+    let expected = [
+        (
+            VirtPageNum(0x40000),
+            PhysPageNum(0x80200),
+            PageLevel(1),
+            Sv39Flags::V | rx,
+        ),
+        (
+            VirtPageNum(0x90000),
+            PhysPageNum(0x80010),
+            PageLevel(0),
+            Sv39Flags::V | rw,
+        ),
+        (
+            VirtPageNum(0x90001),
+            PhysPageNum(0x80011),
+            PageLevel(0),
+            Sv39Flags::V | rw,
+        ),
+    ];
+    assert_eq!(
+        &leaves[..],
+        &expected[..],
+        "leaves in virtual address order"
+    );
+    space.dump();
+    println!("zihai > for each mapping test passed");
+}
+
+pub(crate) fn test_phys_to_virt_window(frame_alloc: &DefaultFrameAllocator) {
+    // 页表帧的“物理页号”比真实页号小一个偏移，只有经过窗口才能访问到真正的页表
+    const WINDOW_OFFSET: usize = 0x40000;
+    fn window(ppn: PhysPageNum) -> VirtPageNum {
+        VirtPageNum(ppn.0 + WINDOW_OFFSET)
+    }
+    #[derive(Clone)]
+    struct OffsetFrames<'a>(&'a DefaultFrameAllocator);
+    impl FrameAllocator for OffsetFrames<'_> {
+        fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+            let ppn = self.0.allocate_frame()?;
+            Ok(PhysPageNum(ppn.0 - WINDOW_OFFSET))
+        }
+        fn deallocate_frame(&self, ppn: PhysPageNum) {
+            self.0.deallocate_frame(PhysPageNum(ppn.0 + WINDOW_OFFSET))
+        }
+        fn stats(&self) -> FrameAllocStats {
+            self.0.stats()
+        }
+    }
+    let checkpoint = frame_alloc.lock().checkpoint();
+    let offset_alloc = OffsetFrames(frame_alloc);
+    let mut space = PagedAddrSpace::try_new_in_with(Sv39, offset_alloc, window).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let (huge_vpn, huge_ppn) = (VirtPageNum(0x40000), PhysPageNum(0x80200));
+    space.allocate_map(huge_vpn, huge_ppn, 512, flags).unwrap();
+    let (vpn, ppn) = (VirtPageNum(0x90000), PhysPageNum(0x80001));
+    space.allocate_map(vpn, ppn, 2, flags).unwrap();
+    assert_eq!(
+        space.translate_addr(VirtAddr(0x9000_1008)),
+        Ok(PhysAddr(0x8000_2008)),
+        "walk through window"
+    );
+    assert_eq!(space.level_histogram(), vec![2, 1, 0], "leaves visited");
+    space.protect(vpn, 2, Sv39Flags::R).unwrap();
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert_eq!(
+        Sv39::entry_get_flags(entry),
+        Sv39Flags::V | Sv39Flags::R,
+        "protected"
+    );
+    let root = space.root_page_number();
+    let root_table = unsafe { window_as_table_mut::<Sv39>(window, root) };
+    let valid = (0..512)
+        .filter(|&idx| Sv39::slot_try_get_entry(&mut root_table[idx]).is_ok())
+        .count();
+    assert_eq!(valid, 2, "root table written through window");
+    space.deallocate_map(vpn, 2).unwrap();
+    assert_eq!(space.find_ppn(vpn).err(), Some(PageError::InvalidEntry));
+    drop(space);
+    frame_alloc.lock().assert_restored(checkpoint);
+    println!("zihai > phys to virt window test passed");
+}
+
+pub(crate) fn test_mem_type(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::U;
+    let (uart_vpn, uart_ppn) = (VirtPageNum(0x10000), PhysPageNum(0x10000));
+    space
+        .allocate_map_typed(uart_vpn, uart_ppn, 1, flags, MemType::Io)
+        .unwrap();
+    let (ram_vpn, ram_ppn) = (VirtPageNum(0x80000), PhysPageNum(0x80400));
+    space.allocate_map(ram_vpn, ram_ppn, 1, flags).unwrap();
+    let (entry, _) = space.find_ppn(uart_vpn).unwrap();
+    assert_eq!(entry.pbmt(), Some(MemType::Io), "device mapped as io");
+    assert_eq!(entry.ppn(), uart_ppn, "page number kept apart from type");
+    assert_eq!(
+        entry.flags(),
+        Sv39Flags::V | flags,
+        "flags kept apart from type"
+    );
+    let (entry, _) = space.find_ppn(ram_vpn).unwrap();
+    assert_eq!(
+        entry.pbmt(),
+        Some(MemType::Pma),
+        "memory mapped by attributes"
+    );
+    space
+        .protect(uart_vpn, 1, Sv39Flags::R | Sv39Flags::U)
+        .unwrap();
+    let (entry, _) = space.find_ppn(uart_vpn).unwrap();
+    assert_eq!(entry.pbmt(), Some(MemType::Io), "type kept on protect");
+    println!("zihai > memory type test passed");
+}
+
+pub(crate) fn test_page_level_display() {
+    let show = |level| alloc::format!("{}", PageLevel(level).display::<Sv39>());
+    assert_eq!(show(0), "4KiB");
+    assert_eq!(show(1), "2MiB");
+    assert_eq!(show(2), "1GiB");
+    assert_eq!(
+        alloc::format!("{:?}", PageLevel(2)),
+        "PageLevel(2)",
+        "numeric debug kept"
+    );
+    println!("zihai > page level display test passed");
+}
+
+pub(crate) fn test_find_free_region(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x80000), 1, flags)
+        .unwrap();
+    space
+        .allocate_map(VirtPageNum(0x90200), PhysPageNum(0x80200), 0x200, flags)
+        .unwrap();
+    assert_eq!(
+        space.page_size_at(VirtAddr(0x9020_0000)),
+        Ok(0x20_0000),
+        "2MiB page"
+    );
+    space
+        .allocate_map(VirtPageNum(0x90405), PhysPageNum(0x80405), 1, flags)
+        .unwrap();
+    let is_free = |start: VirtPageNum, n: usize| {
+        (start.0..start.0 + n).all(|vpn| space.find_ppn(VirtPageNum(vpn)).is_err())
+    };
+    let cases = [
+        (0x90000, 1, 0x90001),
+        (0x90001, 4, 0x90001),
+        (0x90001, 0x200, 0x90406),
+        (0x90300, 5, 0x90400),
+        (0x90400, 6, 0x90406),
+        (0x8ff00, 0x200, 0x90406),
+    ];
+    for (hint, n, expected) in cases {
+        let found = space.find_free_region(VirtPageNum(hint), n);
+        assert_eq!(found, Some(VirtPageNum(expected)), "lowest gap");
+        assert!(is_free(found.unwrap(), n), "gap is free");
     }
+    assert_eq!(
+        space.find_free_region(VirtPageNum(0), (1 << 27) + 1),
+        None,
+        "larger than address space"
+    );
+    println!("zihai > find free region test passed");
 }
 
-/// 查询物理页号可能出现的错误
-#[derive(Debug)]
-pub enum PageError {
-    /// 节点不具有有效位
-    InvalidEntry,
-    /// 第0层页表不能是内部节点
-    NotLeafInLowestPage,
+pub(crate) fn test_allocate_map_overlap(frame_alloc: &DefaultFrameAllocator) {
+    let quota = LimitedFrameAllocator::new(frame_alloc, 3);
+    let mut space = PagedAddrSpace::try_new_in(Sv39, quota.clone()).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    space
+        .allocate_map(VirtPageNum(0x90004), PhysPageNum(0x80004), 4, flags)
+        .unwrap();
+    let snapshot = |space: &PagedAddrSpace<Sv39, _>| {
+        let mut ans = Vec::new();
+        space.for_each_mapping(|vpn, ppn, level, flags| ans.push((vpn, ppn, level, flags)));
+        let found: Vec<_> = (0x90000..0x90400)
+            .map(|vpn| space.find_ppn(VirtPageNum(vpn)).map(|(e, l)| (e.ppn(), l)))
+            .collect();
+        (ans, found, space.frames.len())
+    };
+    let before = snapshot(&space);
+    assert_eq!(
+        space.allocate_map(VirtPageNum(0x90000), PhysPageNum(0x80100), 8, flags),
+        Err(PageError::AlreadyMapped {
+            vpn: VirtPageNum(0x90004)
+        }),
+        "partially overlapping map"
+    );
+    assert!(before == snapshot(&space), "unchanged after overlap");
+    // 第二个页需要新的页表，超过配额；已经写入的第一个页被撤销
+    assert_eq!(
+        space.allocate_map(VirtPageNum(0x901ff), PhysPageNum(0x801ff), 2, flags),
+        Err(PageError::FrameAllocFailed),
+        "out of table frames"
+    );
+    assert!(
+        before == snapshot(&space),
+        "unchanged after failed allocation"
+    );
+    assert_eq!(quota.used(), 3, "no table frames leaked");
+    println!("zihai > allocate map overlap test passed");
 }
 
-#[derive(Debug)]
-pub struct MapPairs<M> {
-    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
-    mode: M,
+pub(crate) fn test_napot(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let (vpn, ppn) = (VirtPageNum(0x90010), PhysPageNum(0x80410));
+    space.allocate_map_napot(vpn, ppn, flags).unwrap();
+    let mid = VirtAddr(0x9001_8123);
+    assert_eq!(
+        space.translate_addr(mid).unwrap(),
+        PhysAddr(0x8041_8123),
+        "middle of napot range"
+    );
+    let (entry, lvl) = space.find_ppn(mid.page_number::<Sv39>()).unwrap();
+    assert!(entry.is_napot(), "napot bit set");
+    assert_eq!(entry.ppn(), ppn, "base of napot range");
+    assert_eq!(
+        Sv39::entry_leaf_va_range(entry, mid.page_number::<Sv39>(), lvl),
+        vpn..VirtPageNum(vpn.0 + NAPOT_FRAMES),
+        "napot span"
+    );
+    assert_eq!(space.page_size_at(mid).unwrap(), 0x10000, "64K page");
+    assert_eq!(
+        space.allocate_map_napot(VirtPageNum(0x90028), PhysPageNum(0x80420), flags),
+        Err(PageError::MisalignedNapot),
+        "misaligned virtual page"
+    );
+    assert_eq!(
+        space.allocate_map_napot(VirtPageNum(0x90020), PhysPageNum(0x80428), flags),
+        Err(PageError::MisalignedNapot),
+        "misaligned physical page"
+    );
+    space.deallocate_map(vpn, NAPOT_FRAMES).unwrap();
+    assert!(space.find_ppn(vpn).is_err(), "napot range cleared");
+    println!("zihai > napot test passed");
 }
 
-impl<M: PageMode> MapPairs<M> {
-    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Self {
-        let mut ans = Vec::new();
-        for i in M::visit_levels_until(PageLevel::leaf_level()) {
-            let align = M::get_layout_for_level(i).align_in_frames();
-            if usize::wrapping_sub(vpn.0, ppn.0) % align != 0 || n < align {
-                continue;
-            }
-            let (mut ve_prev, mut vs_prev) = (None, None);
-            for j in M::visit_levels_from(i) {
-                let align_cur = M::get_layout_for_level(j).align_in_frames();
-                let ve_cur = align_cur * ((vpn.0 + align_cur - 1) / align_cur); // a * roundup(v / a)
-                let vs_cur = align_cur * ((vpn.0 + n) / align_cur); // a * rounddown((v+n) / a)
-                if let (Some(ve_prev), Some(vs_prev)) = (ve_prev, vs_prev) {
-                    if ve_cur != ve_prev {
-                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(ve_prev)));
-                    }
-                    if vs_prev != vs_cur {
-                        ans.push((j, VirtPageNum(vs_prev)..VirtPageNum(vs_cur)));
-                    }
-                } else {
-                    if ve_cur != vs_cur {
-                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(vs_cur)));
-                    }
-                }
-                (ve_prev, vs_prev) = (Some(ve_cur), Some(vs_cur));
-            }
-            break;
-        }
-        // println!("[SOLVE] Ans = {:x?}", ans);
-        Self {
-            ans_iter: ans.into_iter(),
-            mode,
-        }
-    }
+pub(crate) fn test_accessed_dirty(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let (vpn, ppn) = (VirtPageNum(0x90000), PhysPageNum(0x80400));
+    space.allocate_map(vpn, ppn, 1, flags).unwrap();
+    assert_eq!(
+        space.test_and_clear_accessed(vpn),
+        Ok(false),
+        "not accessed"
+    );
+    assert_eq!(space.test_and_clear_dirty(vpn), Ok(false), "not dirty");
+    let (entry, _) = space.find_entry_mut(vpn).unwrap();
+    entry.write_ppn_flags(ppn, Sv39Flags::V | flags | Sv39Flags::A | Sv39Flags::D);
+    assert_eq!(space.test_and_clear_accessed(vpn), Ok(true), "was accessed");
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert!(!entry.flags().contains(Sv39Flags::A), "accessed cleared");
+    assert!(entry.flags().contains(Sv39Flags::D), "dirty kept");
+    assert_eq!(entry.ppn(), ppn, "page number kept");
+    assert_eq!(
+        space.test_and_clear_accessed(vpn),
+        Ok(false),
+        "cleared once"
+    );
+    assert_eq!(space.test_and_clear_dirty(vpn), Ok(true), "was dirty");
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert_eq!(entry.flags(), Sv39Flags::V | flags, "both bits cleared");
+    // 连续页的A位只设置在最后一个页表项上
+    let (napot_vpn, napot_ppn) = (VirtPageNum(0x90010), PhysPageNum(0x80410));
+    space
+        .allocate_map_napot(napot_vpn, napot_ppn, flags)
+        .unwrap();
+    let last = VirtPageNum(napot_vpn.0 + NAPOT_FRAMES - 1);
+    let (entry, _) = space.find_entry_mut(last).unwrap();
+    entry.write_ppn_flags(napot_ppn, Sv39Flags::V | flags | Sv39Flags::A);
+    assert_eq!(
+        space.test_and_clear_accessed(napot_vpn),
+        Ok(true),
+        "napot accessed"
+    );
+    let (entry, _) = space.find_ppn(last).unwrap();
+    assert!(
+        !entry.flags().contains(Sv39Flags::A),
+        "napot accessed cleared"
+    );
+    assert!(entry.is_napot(), "napot kept");
+    assert_eq!(
+        space.test_and_clear_accessed(VirtPageNum(0x90100)),
+        Err(PageError::InvalidEntry),
+        "unmapped page"
+    );
+    println!("zihai > accessed dirty test passed");
 }
 
-impl<M> Iterator for MapPairs<M> {
-    type Item = (PageLevel, Range<VirtPageNum>);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.ans_iter.next()
-    }
+pub(crate) fn test_sv39x4_root_table(frame_alloc: &DefaultFrameAllocator) {
+    let checkpoint = frame_alloc.lock().checkpoint();
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc).unwrap();
+    assert_eq!(space.root_page_number().0 % 4, 0, "16KiB aligned root");
+    assert_eq!(space.frames.len(), 3, "root spans four frames");
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::U;
+    // VPN[2] = 1024 uses the third frame of the root table
+    let high_gpa = VirtAddr(1 << 40);
+    let high_vpn = high_gpa.page_number::<Sv39x4>();
+    assert_eq!(Sv39x4::vpn_index(high_vpn, PageLevel(2)), 1024);
+    let low_vpn = VirtAddr(0).page_number::<Sv39x4>();
+    let (high_ppn, low_ppn) = (PhysPageNum(0x80001), PhysPageNum(0x80002));
+    space.allocate_map(high_vpn, high_ppn, 1, flags).unwrap();
+    space.allocate_map(low_vpn, low_ppn, 1, flags).unwrap();
+    let (entry, _) = space.find_ppn(high_vpn).unwrap();
+    assert_eq!(entry.ppn(), high_ppn, "VPN[2] above 511 translated");
+    let (entry, _) = space.find_ppn(low_vpn).unwrap();
+    assert_eq!(entry.ppn(), low_ppn, "no aliasing into lower indices");
+    let third = unsafe { frame_as_table::<Sv39x4>(space.root_page_number().offset(2)) };
+    let valid: Vec<usize> = Sv39x4::page_table_iter(third)
+        .filter(|(_, slot)| slot.bits & Sv39Flags::V.bits() as usize != 0)
+        .map(|(idx, _)| idx)
+        .collect();
+    assert_eq!(valid, [0], "VPN[2] 1024 in third root frame");
+    let vpns: Vec<_> = space.snapshot_mappings().iter().map(|r| r.vpn).collect();
+    assert_eq!(vpns, [low_vpn, high_vpn], "leaves visited over whole root");
+    drop(space);
+    frame_alloc.lock().assert_restored(checkpoint);
+    println!("zihai > Sv39x4 root table test passed");
 }
 
-pub(crate) fn test_map_solve() {
-    let layout_frames_sv39 = [
-        (PageLevel(0), 1),
-        (PageLevel(1), 512),
-        (PageLevel(2), 512 * 512),
-    ];
-    for (level, align_in_frames) in layout_frames_sv39 {
-        assert_eq!(
-            Sv39::get_layout_for_level(level).align_in_frames(),
-            align_in_frames
-        );
+pub(crate) fn test_migrate_page(frame_alloc: &DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let old_frame = FrameBox::try_new_in(frame_alloc).unwrap();
+    let new_frame = FrameBox::try_new_in(frame_alloc).unwrap();
+    let (old_ppn, new_ppn) = (old_frame.phys_page_num(), new_frame.phys_page_num());
+    let old_page = old_ppn.addr_begin::<Sv39>().0 as *mut u8;
+    for i in 0..4096 {
+        unsafe { old_page.add(i).write(i as u8) };
     }
-    let visit_levels_until_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
-        (PageLevel(0), &[PageLevel(2), PageLevel(1), PageLevel(0)]),
-        (PageLevel(1), &[PageLevel(2), PageLevel(1)]),
-        (PageLevel(2), &[PageLevel(2)]),
-    ];
-    for (level, iter_result) in visit_levels_until_sv39 {
-        assert_eq!(
-            Sv39::visit_levels_until(level).collect::<Vec<_>>(),
-            iter_result,
-        );
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::A;
+    let vpn = VirtPageNum(0x90000);
+    space.allocate_map(vpn, old_ppn, 1, flags).unwrap();
+    let ans = space.migrate_page(vpn, new_ppn);
+    assert_eq!(ans, Ok(old_ppn), "old page number returned");
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert_eq!(entry.ppn(), new_ppn, "resolves to new frame");
+    assert_eq!(entry.flags(), Sv39Flags::V | flags, "flags preserved");
+    let new_page = new_ppn.addr_begin::<Sv39>().0 as *const u8;
+    let same = (0..4096).all(|i| unsafe { new_page.add(i).read() } == i as u8);
+    assert!(same, "contents copied");
+    assert_eq!(
+        space.migrate_page(VirtPageNum(0x90001), old_ppn),
+        Err(PageError::InvalidEntry),
+        "migrate unmapped page"
+    );
+    let napot_old = frame_alloc.allocate_frames(NAPOT_FRAMES, 4).unwrap();
+    let napot_new = frame_alloc.allocate_frames(NAPOT_FRAMES, 4).unwrap();
+    let napot_vpn = VirtPageNum(0x90010);
+    space
+        .allocate_map_napot(napot_vpn, napot_old, flags)
+        .unwrap();
+    let last_page = |ppn: PhysPageNum| ppn.offset(15).addr_begin::<Sv39>().0 as *mut u8;
+    unsafe { last_page(napot_old).write(0x5a) };
+    let ans = space.migrate_page(VirtPageNum(0x90013), napot_new);
+    assert_eq!(ans, Ok(napot_old), "base of napot range returned");
+    for i in 0..NAPOT_FRAMES {
+        let (entry, _) = space.find_ppn(napot_vpn.offset(i as isize)).unwrap();
+        assert!(entry.is_napot(), "napot bit kept");
+        assert_eq!(entry.ppn(), napot_new, "whole napot range moved");
     }
-    let visit_levels_before_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
-        (PageLevel(0), &[PageLevel(2), PageLevel(1)]),
-        (PageLevel(1), &[PageLevel(2)]),
-        (PageLevel(2), &[]),
-    ];
-    for (level, iter_result) in visit_levels_before_sv39 {
-        assert_eq!(
-            Sv39::visit_levels_before(level).collect::<Vec<_>>(),
-            iter_result,
-        );
+    assert_eq!(
+        unsafe { last_page(napot_new).read() },
+        0x5a,
+        "napot range copied"
+    );
+    drop(space);
+    frame_alloc.deallocate_frames(napot_old, NAPOT_FRAMES);
+    frame_alloc.deallocate_frames(napot_new, NAPOT_FRAMES);
+    drop((old_frame, new_frame));
+    println!("zihai > page migration test passed");
+}
+
+pub(crate) fn test_cow_mapping(frame_alloc: &DefaultFrameAllocator) {
+    let frames_in_use = || frame_alloc.lock().frames_in_use();
+    let mut parent = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let mut child = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    let shared = FrameRc::try_new_in(frame_alloc).unwrap();
+    let shared_ppn = shared.phys_page_num();
+    let page = shared_ppn.addr_begin::<Sv39>().0 as *mut u8;
+    for i in 0..4096 {
+        unsafe { page.add(i).write(i as u8) };
     }
-    let visit_levels_from_sv39: [(PageLevel, &'static [PageLevel]); 3] = [
-        (PageLevel(0), &[PageLevel(0)]),
-        (PageLevel(1), &[PageLevel(1), PageLevel(0)]),
-        (PageLevel(2), &[PageLevel(2), PageLevel(1), PageLevel(0)]),
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let vpn = VirtPageNum(0x90000);
+    parent
+        .map_cow(vpn, core::slice::from_ref(&shared), flags)
+        .unwrap();
+    child.map_cow(vpn, &[shared], flags).unwrap();
+    let (entry, _) = child.find_ppn(vpn).unwrap();
+    assert!(!entry.flags().contains(Sv39Flags::W), "mapped read-only");
+    assert!(child.is_cow(vpn), "copy on write page");
+    assert!(!child.is_cow(VirtPageNum(0x90001)), "unmapped page");
+    let before = frames_in_use();
+    // 页帧被两个地址空间共享，写入时复制
+    let copied = child.handle_cow_fault(vpn).unwrap();
+    assert_ne!(copied, shared_ppn, "new frame when shared");
+    assert_eq!(frames_in_use(), before + 1, "one frame copied");
+    let (entry, _) = child.find_ppn(vpn).unwrap();
+    assert_eq!(entry.ppn(), copied, "remapped to the copy");
+    assert!(entry.flags().contains(flags), "writable after copy");
+    assert!(!child.is_cow(vpn), "no longer copy on write");
+    let copy = copied.addr_begin::<Sv39>().0 as *const u8;
+    let same = (0..4096).all(|i| unsafe { copy.add(i).read() } == i as u8);
+    assert!(same, "contents copied");
+    // 只剩父地址空间持有原页帧，直接改为可写
+    let reused = parent.handle_cow_fault(vpn).unwrap();
+    assert_eq!(reused, shared_ppn, "same frame when not shared");
+    assert_eq!(frames_in_use(), before + 1, "no frame copied");
+    let (entry, _) = parent.find_ppn(vpn).unwrap();
+    assert!(entry.flags().contains(flags), "made writable in place");
+    assert_eq!(
+        parent.handle_cow_fault(VirtPageNum(0x90001)),
+        Err(PageError::InvalidEntry),
+        "not a copy on write page"
+    );
+    let is_allocated = |ppn| frame_alloc.lock().is_allocated(ppn);
+    drop(child);
+    assert!(!is_allocated(copied), "copy freed with its space");
+    assert!(is_allocated(shared_ppn), "original kept by parent");
+    parent.deallocate_map(vpn, 1).unwrap();
+    assert!(!is_allocated(shared_ppn), "original freed on unmap");
+    drop(parent);
+    println!("zihai > copy on write mapping test passed");
+}
+
+pub(crate) fn test_translate_addr(frame_alloc: &DefaultFrameAllocator) {
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    // 1G + 2M + 4K，分别以三种大小的页映射到偏移4G的物理地址
+    space
+        .map_range(
+            VirtAddr(0x4000_0000)..VirtAddr(0x8020_1000),
+            PhysAddr(0x1_4000_0000),
+            flags,
+        )
+        .unwrap();
+    let cases = [
+        (0x4123_4567, 0x1_4123_4567, "offset within 1G page"),
+        (0x8012_3456, 0x1_8012_3456, "offset within 2M page"),
+        (0x8020_0abc, 0x1_8020_0abc, "offset within 4K page"),
     ];
-    for (level, iter_result) in visit_levels_from_sv39 {
+    for (va, pa, msg) in cases {
         assert_eq!(
-            Sv39::visit_levels_from(level).collect::<Vec<_>>(),
-            iter_result,
+            space.translate_addr(VirtAddr(va)),
+            Ok(PhysAddr(pa)),
+            "{}",
+            msg
         );
     }
+    assert_eq!(
+        space.translate_addr(VirtAddr(0x8020_1000)),
+        Err(PageError::InvalidEntry),
+        "unmapped address"
+    );
+    println!("zihai > address translation test passed");
+}
 
-    let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39)
-        .collect::<Vec<_>>();
+pub(crate) fn test_enforce_wx(frame_alloc: &DefaultFrameAllocator) {
+    let rw = Sv39Flags::R | Sv39Flags::W;
+    let rx = Sv39Flags::R | Sv39Flags::X;
+    let rwx = rw | Sv39Flags::X;
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    space
+        .allocate_map(VirtPageNum(0x90000), PhysPageNum(0x90000), 1, rw)
+        .unwrap();
+    space
+        .allocate_map(VirtPageNum(0x90001), PhysPageNum(0x90001), 1, rx)
+        .unwrap();
+    assert_eq!(space.enforce_wx(), Ok(()), "no W+X mapping");
+    space
+        .allocate_map(VirtPageNum(0x90003), PhysPageNum(0x90003), 1, rwx)
+        .unwrap();
     assert_eq!(
-        pairs,
-        [
-            (PageLevel(2), VirtPageNum(786432)..VirtPageNum(1048576)),
-            (PageLevel(1), VirtPageNum(589824)..VirtPageNum(786432)),
-            (PageLevel(1), VirtPageNum(1048576)..VirtPageNum(1256448)),
-            (PageLevel(0), VirtPageNum(1256448)..VirtPageNum(1256490))
-        ]
+        space.enforce_wx(),
+        Err(vec![VirtPageNum(0x90003)]),
+        "W+X mapping detected"
     );
-    let pairs = MapPairs::solve(VirtPageNum(0x90_001), PhysPageNum(0x50_001), 77777, Sv39)
-        .collect::<Vec<_>>();
+    space.set_strict_wx(true);
     assert_eq!(
-        pairs,
-        [
-            (PageLevel(1), VirtPageNum(590336)..VirtPageNum(667136)),
-            (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)),
-            (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
-        ]
+        space.allocate_map(VirtPageNum(0x90004), PhysPageNum(0x90004), 1, rwx),
+        Err(PageError::WxViolation),
+        "strict mode rejects W+X mapping"
+    );
+    assert!(
+        space.find_ppn(VirtPageNum(0x90004)).is_err(),
+        "rejected mapping not created"
     );
-    let pairs = MapPairs::solve(VirtPageNum(0x12_345), PhysPageNum(0x22_345), 888888, Sv39x4)
-        .collect::<Vec<_>>();
     assert_eq!(
-        pairs,
-        [
-            (PageLevel(1), VirtPageNum(74752)..VirtPageNum(963072)),
-            (PageLevel(0), VirtPageNum(74565)..VirtPageNum(74752)),
-            (PageLevel(0), VirtPageNum(963072)..VirtPageNum(963453))
-        ]
+        space.protect(VirtPageNum(0x90000), 1, rwx),
+        Err(PageError::WxViolation),
+        "strict mode rejects W+X protection"
     );
-    let pairs = MapPairs::solve(
-        VirtPageNum(0x400000),
-        PhysPageNum(0x200000),
-        0x40000000,
-        Sv39x4,
-    )
-    .collect::<Vec<_>>();
+    space
+        .allocate_map(VirtPageNum(0x90004), PhysPageNum(0x90004), 1, rx)
+        .expect("strict mode allows R+X mapping");
+    space.protect(VirtPageNum(0x90003), 1, rx).unwrap();
+    assert_eq!(space.enforce_wx(), Ok(()), "W+X mapping fixed");
+    drop(space);
+    println!("zihai > W^X enforcement test passed");
+}
+
+pub(crate) fn test_page_size_at(frame_alloc: &DefaultFrameAllocator) {
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).unwrap();
+    // 1G + 2M + 4K，分别以三种大小的页映射
+    space
+        .map_range(
+            VirtAddr(0x4000_0000)..VirtAddr(0x8020_1000),
+            PhysAddr(0x4000_0000),
+            flags,
+        )
+        .unwrap();
     assert_eq!(
-        pairs,
-        [(PageLevel(2), VirtPageNum(4194304)..VirtPageNum(1077936128))]
+        space.page_size_at(VirtAddr(0x4123_4567)),
+        Ok(0x4000_0000),
+        "1G page"
     );
-    println!("zihai > address map solver test passed");
+    assert_eq!(
+        space.page_size_at(VirtAddr(0x8012_3456)),
+        Ok(0x20_0000),
+        "2M page"
+    );
+    assert_eq!(
+        space.page_size_at(VirtAddr(0x8020_0abc)),
+        Ok(0x1000),
+        "4K page"
+    );
+    assert_eq!(
+        space.page_size_at(VirtAddr(0x8020_1000)),
+        Err(PageError::InvalidEntry),
+        "unmapped address"
+    );
+    println!("zihai > page size query test passed");
+}
+
+// 内核地址空间中映射的一段区域
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MappedRegion {
+    pub virt: usize,
+    pub phys: usize,
+    pub size: usize,
+    pub flags: usize,
+}
+
+pub const MAX_MAPPED_REGIONS: usize = 16;
+
+// 内核地址空间的映射表，布局固定，供调试器脚本读取
+#[repr(C)]
+pub struct MemoryMap {
+    pub len: usize,
+    pub regions: [MappedRegion; MAX_MAPPED_REGIONS],
+}
+
+// 调试器中可以用`p HYPERVISOR_MEMORY_MAP`查看内核映射的所有区域。
+// 只由启动核在初始化时写入
+#[no_mangle]
+pub static mut HYPERVISOR_MEMORY_MAP: MemoryMap = MemoryMap {
+    len: 0,
+    regions: [MappedRegion {
+        virt: 0,
+        phys: 0,
+        size: 0,
+        flags: 0,
+    }; MAX_MAPPED_REGIONS],
+};
+
+// 在内核地址空间中映射一段区域，并记录到HYPERVISOR_MEMORY_MAP中。
+// 只能在启动核初始化时使用
+pub fn map_kernel_range<A: FrameAllocator + Clone>(
+    space: &mut PagedAddrSpace<Sv39, A>,
+    va: Range<VirtAddr>,
+    pa_base: PhysAddr,
+    flags: Sv39Flags,
+) -> Result<(), PageError> {
+    let region = MappedRegion {
+        virt: va.start.0,
+        phys: pa_base.0,
+        size: va.end.0.wrapping_sub(va.start.0),
+        flags: flags.bits() as usize,
+    };
+    space.map_range(va, pa_base, flags)?;
+    // note(unsafe): 只有启动核在初始化时写入
+    let map = unsafe { &mut *core::ptr::addr_of_mut!(HYPERVISOR_MEMORY_MAP) };
+    if map.len < MAX_MAPPED_REGIONS {
+        map.regions[map.len] = region;
+        map.len += 1;
+    }
+    Ok(())
+}
+
+// 得到内核映射的所有区域
+pub fn kernel_mapped_regions() -> &'static [MappedRegion] {
+    let map = unsafe { &*core::ptr::addr_of!(HYPERVISOR_MEMORY_MAP) };
+    &map.regions[..map.len]
+}
+
+pub(crate) fn test_memory_map() {
+    let rwx = (Sv39Flags::R | Sv39Flags::W | Sv39Flags::X).bits() as usize;
+    let expected = [
+        MappedRegion {
+            virt: 0x80000000,
+            phys: 0x80000000,
+            size: 0x400000,
+            flags: rwx,
+        },
+        MappedRegion {
+            virt: 0x80400000,
+            phys: 0x80400000,
+            size: 0x400000,
+            flags: rwx,
+        },
+    ];
+    assert_eq!(kernel_mapped_regions(), expected, "kernel identity maps");
+    println!("zihai > hypervisor memory map test passed");
 }
 
 // activate Sv39 HS-mode supervisor translation
@@ -1072,6 +5508,96 @@ pub unsafe fn activate_supervisor_paged_riscv_sv39(
     satp::read()
 }
 
+// activate Sv48 HS-mode supervisor translation
+pub unsafe fn activate_supervisor_paged_riscv_sv48(
+    root_ppn: PhysPageNum,
+    asid: AddressSpaceId,
+) -> Satp {
+    satp::set(Mode::Sv48, asid.0 as usize, root_ppn.0);
+    riscv64::sfence_vma_asid(asid.0 as usize);
+    satp::read()
+}
+
+// activate Sv57 HS-mode supervisor translation
+pub unsafe fn activate_supervisor_paged_riscv_sv57(
+    root_ppn: PhysPageNum,
+    asid: AddressSpaceId,
+) -> Satp {
+    satp::set(Mode::Sv57, asid.0 as usize, root_ppn.0);
+    riscv64::sfence_vma_asid(asid.0 as usize);
+    satp::read()
+}
+
+// activate Sv32 supervisor translation
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn activate_supervisor_paged_riscv_sv32(
+    root_ppn: PhysPageNum,
+    asid: AddressSpaceId,
+) -> Satp {
+    satp::set(Mode::Sv32, asid.0 as usize, root_ppn.0);
+    core::arch::asm!("sfence.vma zero, {}", in(reg) asid.0 as usize);
+    satp::read()
+}
+
+// activate Sv39x4 G-stage translation of a guest, returns value of hgatp
+pub unsafe fn activate_paged_riscv_sv39x4(root_ppn: PhysPageNum, vmid: VirtMachineId) -> usize {
+    let bits = (8 << 60) | ((vmid.0 as usize) << 44) | root_ppn.0; // mode 8 => Sv39x4
+    core::arch::asm!("csrw  0x680, {}", in(reg) bits, options(nomem, nostack)); // 0x680 => hgatp
+    hfence_gvma(None, Some(vmid.0));
+    let hgatp: usize;
+    core::arch::asm!("csrr  {}, 0x680", out(reg) hgatp, options(nomem, nostack));
+    hgatp
+}
+
+// 刷新客户机G阶段地址翻译的TLB条目，对应hfence.gvma指令。
+// gpa为客户机物理地址，None表示所有地址；vmid为客户机编号，None表示所有客户机。
+// 汇编器不一定认识H扩展指令，这里直接写出编码：funct7=0b0110001，rs2为编号，rs1为地址，opcode=SYSTEM；
+// 地址放在t0(x5)中，编号放在t1(x6)中，对应rs1=5和rs2=6。按规范rs1中的地址需要右移2位
+#[inline]
+pub fn hfence_gvma(gpa: Option<usize>, vmid: Option<u16>) {
+    // note(unsafe): 只丢弃TLB中缓存的翻译结果
+    unsafe {
+        match (gpa, vmid) {
+            // hfence.gvma zero, zero
+            (None, None) => core::arch::asm!(".word 0x62000073"),
+            // hfence.gvma t0, zero
+            (Some(gpa), None) => core::arch::asm!(".word 0x62028073", in("t0") gpa >> 2),
+            // hfence.gvma zero, t1
+            (None, Some(vmid)) => core::arch::asm!(".word 0x62600073", in("t1") vmid as usize),
+            // hfence.gvma t0, t1
+            (Some(gpa), Some(vmid)) => core::arch::asm!(
+                ".word 0x62628073",
+                in("t0") gpa >> 2,
+                in("t1") vmid as usize
+            ),
+        }
+    }
+}
+
+// 刷新当前客户机（hgatp中编号对应的客户机）VS阶段地址翻译的TLB条目，对应hfence.vvma指令。
+// va为客户机虚拟地址，None表示所有地址；asid为客户机的地址空间编号，None表示所有地址空间。
+// 编码同hfence_gvma，只是funct7=0b0010001，rs1中的地址不需要移位
+#[inline]
+pub fn hfence_vvma(va: Option<usize>, asid: Option<u16>) {
+    // note(unsafe): 只丢弃TLB中缓存的翻译结果
+    unsafe {
+        match (va, asid) {
+            // hfence.vvma zero, zero
+            (None, None) => core::arch::asm!(".word 0x22000073"),
+            // hfence.vvma t0, zero
+            (Some(va), None) => core::arch::asm!(".word 0x22028073", in("t0") va),
+            // hfence.vvma zero, t1
+            (None, Some(asid)) => core::arch::asm!(".word 0x22600073", in("t1") asid as usize),
+            // hfence.vvma t0, t1
+            (Some(va), Some(asid)) => core::arch::asm!(
+                ".word 0x22628073",
+                in("t0") va,
+                in("t1") asid as usize
+            ),
+        }
+    }
+}
+
 // 得到satp的值
 pub fn get_satp_sv39(asid: AddressSpaceId, ppn: PhysPageNum) -> Satp {
     let bits = (8 << 60) | ((asid.0 as usize) << 44) | ppn.0;
@@ -1093,29 +5619,74 @@ where
     A2: FrameAllocator + Clone,
     F: Fn(PhysPageNum, usize, usize), // 按顺序返回空间1中的帧
 {
-    // println!("vaddr2 = {:x?}, len_bytes2 = {}", vaddr2, len_bytes2);
+    for (ppn, offset, len, _flags) in translate_frames(as2, vaddr2, len_bytes2)? {
+        f(ppn, offset, len);
+    }
+    Ok(())
+}
+
+// 帧翻译：在空间1中写入空间2的帧，比如装载initrd或者写回模拟设备的结果。要求空间1具有恒等映射特性。
+// 区间内所有的叶子页表项都必须可写，否则返回NotWritable；出错时不会调用f
+pub fn translate_frame_write<M2, A2, F>(
+    as2: &PagedAddrSpace<M2, A2>,
+    vaddr2: VirtAddr,
+    len_bytes2: usize,
+    mut f: F,
+) -> Result<(), PageError>
+where
+    M2: PageMode<Flags = Sv39Flags>,
+    A2: FrameAllocator + Clone,
+    F: FnMut(PhysPageNum, usize, usize), // 按顺序返回空间1中的帧，由f写入
+{
+    let frames = translate_frames(as2, vaddr2, len_bytes2)?;
+    if frames
+        .iter()
+        .any(|(_, _, _, flags)| !flags.contains(Sv39Flags::W))
+    {
+        return Err(PageError::NotWritable);
+    }
+    for (ppn, offset, len, _flags) in frames {
+        f(ppn, offset, len);
+    }
+    Ok(())
+}
+
+// 区间落在一个叶子页表项中的部分：(页号, 页内偏移, 长度, 设置)
+type FrameRun<F> = (PhysPageNum, usize, usize, F);
+
+// 把空间2中的区间分解到各个叶子页表项，得到(页号, 页内偏移, 长度, 设置)；大页和连续页的页内偏移可以超过一个页帧。
+// 先检查区间内所有的页都已映射，空区间也要求起始地址已映射
+fn translate_frames<M2, A2>(
+    as2: &PagedAddrSpace<M2, A2>,
+    vaddr2: VirtAddr,
+    len_bytes2: usize,
+) -> Result<Vec<FrameRun<M2::Flags>>, PageError>
+where
+    M2: PageMode,
+    A2: FrameAllocator + Clone,
+{
+    let mut ans = Vec::new();
     let mut vpn2 = vaddr2.page_number::<M2>();
     let mut remaining_len = len_bytes2;
     let (mut entry, mut lvl) = as2.find_ppn(vpn2)?;
-    let mut cur_offset = vaddr2.page_offset::<M2>(lvl);
-    while remaining_len > 0 {
-        let ppn = M2::entry_get_ppn(entry);
-        let cur_frame_layout = M2::get_layout_for_level(lvl);
-        let cur_len = if remaining_len <= cur_frame_layout.page_size::<M2>() {
-            remaining_len
-        } else {
-            cur_frame_layout.page_size::<M2>()
-        };
-        f(ppn, cur_offset, cur_len);
-        // println!("[] {} {} {}", cur_frame_layout.page_size::<M2>(), cur_offset, cur_len);
+    let mut leaf_range = M2::entry_leaf_va_range(entry, vpn2, lvl);
+    let mut cur_offset = vaddr2.0 - leaf_range.start.addr_begin::<M2>().0;
+    loop {
+        let page_size = (leaf_range.end.0 - leaf_range.start.0) << M2::FRAME_SIZE_BITS;
+        // 第一个帧从区间起始地址的页内偏移开始，不能超过这个页的结尾
+        let cur_len = usize::min(remaining_len, page_size - cur_offset);
+        if cur_len > 0 {
+            let flags = M2::entry_get_flags(entry);
+            ans.push((M2::entry_get_ppn(entry), cur_offset, cur_len, flags));
+        }
         remaining_len -= cur_len;
         if remaining_len == 0 {
-            return Ok(());
+            return Ok(ans);
         }
-        cur_offset = 0; // 下一个帧从头开始
-        vpn2 = vpn2.next_page_by_level::<M2>(lvl);
+        // 下一个帧从头开始；vpn2可能在大页中间，下一个页从这个叶子页表项的结尾开始
+        cur_offset = 0;
+        vpn2 = leaf_range.end;
         (entry, lvl) = as2.find_ppn(vpn2)?;
-        // println!("[] {}", remaining_len);
+        leaf_range = M2::entry_leaf_va_range(entry, vpn2, lvl);
     }
-    Ok(())
 }
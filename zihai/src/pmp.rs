@@ -0,0 +1,88 @@
+//! Physical memory protection module
+//!
+//! Includes diagnostics of PMP entries implemented by hardware
+#![allow(unused)] // use in the future
+use core::arch::asm;
+
+use crate::detect;
+
+/// Maximum number of PMP entries defined by the privileged specification
+pub const MAX_PMP_ENTRIES: usize = 64;
+
+// Writes all ones into `pmpaddrN` and returns the bits kept by hardware,
+// restoring the previous value afterwards. Zero means the entry is not implemented.
+macro_rules! probe_pmpaddr {
+    ($n:literal) => {{
+        let (stored, kept): (usize, usize);
+        asm!(
+            "csrrw {stored}, {csr}, {ones}",
+            "csrrw {kept}, {csr}, {stored}",
+            csr = const 0x3B0 + $n, // 0x3B0 => pmpaddr0
+            ones = in(reg) usize::MAX,
+            stored = out(reg) stored,
+            kept = out(reg) kept,
+            options(nomem, nostack)
+        );
+        kept
+    }};
+}
+
+// CSR numbers are immediates, so each entry has its own instruction
+unsafe fn probe_pmpaddr(index: usize) -> usize {
+    macro_rules! by_index {
+        ($($n:literal),*) => {
+            match index {
+                $($n => probe_pmpaddr!($n),)*
+                _ => panic!("pmp entry {} out of range", index),
+            }
+        };
+    }
+    by_index!(
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+        48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+    )
+}
+
+// Counts implemented PMP entries on current hart.
+//
+// Implemented entries are the lowest-numbered ones, and a write of all ones
+// into `pmpaddrN` sticks only if entry N is implemented. PMP registers are
+// machine-level CSRs, so reading them in HS mode, where this hypervisor runs,
+// raises illegal instruction exception; the count is then reported as 0.
+// PMP introspection requires M mode, e.g. through the SBI firmware.
+pub fn count_implemented() -> usize {
+    count_implemented_with(|index| {
+        let mut kept = 0;
+        let ans = detect::with_detect_trap(|| kept = unsafe { probe_pmpaddr(index) });
+        match ans {
+            // illegal instruction, the CSR is not accessible
+            Err(trap) if trap.is_illegal_instruction() => None,
+            _ => Some(kept != 0),
+        }
+    })
+}
+
+// Counts implemented PMP entries from results of `probe`, which returns `None`
+// if entry cannot be accessed at all, or whether the write sticks
+fn count_implemented_with(mut probe: impl FnMut(usize) -> Option<bool>) -> usize {
+    (0..MAX_PMP_ENTRIES)
+        .take_while(|&index| probe(index) == Some(true))
+        .count()
+}
+
+pub(crate) fn test_count_implemented() {
+    let probed = |implemented: usize| count_implemented_with(|index| Some(index < implemented));
+    assert_eq!(probed(0), 0, "no pmp");
+    assert_eq!(probed(16), 16, "16 entries");
+    assert_eq!(probed(64), 64, "all entries");
+    let mut accessed = 0;
+    let hs_mode = count_implemented_with(|_| {
+        accessed += 1;
+        None
+    });
+    assert_eq!(hs_mode, 0, "inaccessible in HS mode");
+    assert_eq!(accessed, 1, "stops after first fault");
+    assert_eq!(count_implemented(), 0, "current hart runs in HS mode");
+    println!("zihai > pmp entry count test passed");
+}
@@ -1,5 +1,6 @@
 #![allow(unused)]
 use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 pub const EXTENSION_BASE: usize = 0x10;
 pub const EXTENSION_TIMER: usize = 0x54494D45;
@@ -7,6 +8,7 @@ pub const EXTENSION_IPI: usize = 0x735049;
 pub const EXTENSION_RFENCE: usize = 0x52464E43;
 pub const EXTENSION_HSM: usize = 0x48534D;
 pub const EXTENSION_SRST: usize = 0x53525354;
+pub const EXTENSION_DBCN: usize = 0x4442434E;
 
 const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0x0;
 const FUNCTION_BASE_GET_SBI_IMPL_ID: usize = 0x1;
@@ -18,7 +20,25 @@ const FUNCTION_BASE_GET_MIMPID: usize = 0x6;
 
 const FUNCTION_SYSTEM_RESET: usize = 0x0;
 
+const FUNCTION_IPI_SEND_IPI: usize = 0x0;
+
+const FUNCTION_HSM_HART_START: usize = 0x0;
+
+const FUNCTION_DBCN_CONSOLE_WRITE: usize = 0x0;
+const FUNCTION_DBCN_CONSOLE_WRITE_BYTE: usize = 0x2;
+
+pub const FUNCTION_RFENCE_REMOTE_FENCE_I: usize = 0x0;
+pub const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 0x1;
+pub const FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 0x2;
+pub const FUNCTION_RFENCE_REMOTE_HFENCE_VVMA_ASID: usize = 0x5;
+pub const FUNCTION_RFENCE_REMOTE_HFENCE_VVMA: usize = 0x6;
+
+pub const SBI_SUCCESS: usize = 0;
+pub const SBI_ERR_FAILED: usize = -1_isize as usize;
+pub const SBI_ERR_NOT_SUPPORTED: usize = -2_isize as usize;
+
 #[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct SbiRet {
     /// Error number
     pub error: usize,
@@ -48,11 +68,101 @@ fn sbi_call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: u
     SbiRet { error, value }
 }
 
+// Calls host SBI with all six argument registers, e.g. to forward a call on behalf of a guest
+#[inline]
+pub fn forward_call(extension: usize, function: usize, args: [usize; 6]) -> SbiRet {
+    let (error, value);
+    match () {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        () => unsafe {
+            asm!(
+                "ecall",
+                in("a0") args[0], in("a1") args[1], in("a2") args[2],
+                in("a3") args[3], in("a4") args[4], in("a5") args[5],
+                in("a6") function, in("a7") extension,
+                lateout("a0") error, lateout("a1") value,
+            )
+        },
+        #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+        () => {
+            drop((extension, function, args));
+            unimplemented!("not RISC-V instruction set architecture")
+        }
+    };
+    SbiRet { error, value }
+}
+
+// Instructs remote harts to execute SFENCE.VMA over a virtual address range of an ASID.
+//
+// A full flush is requested if both `start_addr` and `size` are 0, or `size` is `usize::MAX`.
+#[inline]
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+    asid: usize,
+) -> SbiRet {
+    let args = [hart_mask, hart_mask_base, start_addr, size, asid, 0];
+    forward_call(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID,
+        args,
+    )
+}
+
+// Instructs remote harts to execute SFENCE.VMA over a virtual address range of all ASIDs
+#[inline]
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+) -> SbiRet {
+    let args = [hart_mask, hart_mask_base, start_addr, size, 0, 0];
+    forward_call(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA, args)
+}
+
+// Raises supervisor software interrupts on the harts in `hart_mask` from `hart_mask_base`
+#[inline]
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    sbi_call(
+        EXTENSION_IPI,
+        FUNCTION_IPI_SEND_IPI,
+        hart_mask,
+        hart_mask_base,
+        0,
+    )
+}
+
+// Starts a stopped hart at physical address `start_addr` in supervisor mode.
+//
+// The hart begins with paging off, `a0` = its hart id and `a1` = `opaque`.
+#[inline]
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    sbi_call(
+        EXTENSION_HSM,
+        FUNCTION_HSM_HART_START,
+        hartid,
+        start_addr,
+        opaque,
+    )
+}
+
 #[inline]
 pub fn get_spec_version() -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_SPEC_VERSION, 0, 0, 0).value
 }
 
+// Decodes SBI specification version into (major, minor).
+//
+// Major number is in bits [30:24] and minor number in bits [23:0]; bit 31 is
+// reserved and must be 0.
+#[inline]
+pub fn spec_version_parts(version: usize) -> (usize, usize) {
+    ((version >> 24) & 0x7F, version & 0xFF_FFFF)
+}
+
 #[inline]
 pub fn get_sbi_impl_id() -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_SBI_IMPL_ID, 0, 0, 0).value
@@ -145,3 +255,163 @@ pub fn console_getchar() -> usize {
 pub fn set_timer(time: usize) {
     sbi_call_legacy(SBI_SET_TIMER, time, 0, 0);
 }
+
+// Shuts down using legacy extension, available before SBI system reset extension exists
+pub fn legacy_shutdown() -> ! {
+    sbi_call_legacy(SBI_SHUTDOWN, 0, 0, 0);
+    unreachable!()
+}
+
+// Way to write bytes to the debug console, from the best to the worst
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ConsolePath {
+    /// DBCN `console_write`, a whole buffer per call
+    DbcnWrite = 1,
+    /// DBCN `console_write_byte`, one byte per call
+    DbcnWriteByte = 2,
+    /// Legacy `console_putchar`
+    Legacy = 3,
+}
+
+impl ConsolePath {
+    // the next path to try once this path turns out not supported
+    pub fn fallback(self) -> ConsolePath {
+        match self {
+            ConsolePath::DbcnWrite => ConsolePath::DbcnWriteByte,
+            ConsolePath::DbcnWriteByte | ConsolePath::Legacy => ConsolePath::Legacy,
+        }
+    }
+}
+
+// Selects the best console path from available DBCN functions.
+//
+// Some firmware implements only one of `console_write` and `console_write_byte`,
+// so a present DBCN extension does not imply that any function of it exists.
+pub fn select_console_path(dbcn_present: bool, write: bool, write_byte: bool) -> ConsolePath {
+    match (dbcn_present, write, write_byte) {
+        (true, true, _) => ConsolePath::DbcnWrite,
+        (true, false, true) => ConsolePath::DbcnWriteByte,
+        _ => ConsolePath::Legacy,
+    }
+}
+
+// selected console path; 0 before probing
+static CONSOLE_PATH: AtomicU8 = AtomicU8::new(0);
+
+// Probes the console path on first use.
+//
+// A zero-length `console_write` has no output, so it is probed directly;
+// `console_write_byte` would print something, so it is assumed available and
+// checked on the first write, falling back if it is not supported.
+fn console_path() -> ConsolePath {
+    let path = match CONSOLE_PATH.load(Ordering::Relaxed) {
+        1 => return ConsolePath::DbcnWrite,
+        2 => return ConsolePath::DbcnWriteByte,
+        3 => return ConsolePath::Legacy,
+        _ => {
+            let dbcn_present = probe_extension(EXTENSION_DBCN) != 0;
+            let write = dbcn_present && {
+                let ret = sbi_call(EXTENSION_DBCN, FUNCTION_DBCN_CONSOLE_WRITE, 0, 0, 0);
+                ret.error != SBI_ERR_NOT_SUPPORTED
+            };
+            select_console_path(dbcn_present, write, true)
+        }
+    };
+    CONSOLE_PATH.store(path as u8, Ordering::Relaxed);
+    path
+}
+
+// Writes bytes to the debug console through the best available path.
+//
+// Hypervisor memory is identically mapped, so the buffer address is also its
+// physical address as DBCN requires.
+pub fn console_write_bytes(bytes: &[u8]) {
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let path = console_path();
+        let ret = match path {
+            ConsolePath::DbcnWrite => {
+                let (addr, len) = (rest.as_ptr() as usize, rest.len());
+                let ret = sbi_call(EXTENSION_DBCN, FUNCTION_DBCN_CONSOLE_WRITE, len, addr, 0);
+                if ret.error == SBI_SUCCESS {
+                    rest = &rest[ret.value.min(rest.len())..]; // may write partially
+                }
+                ret
+            }
+            ConsolePath::DbcnWriteByte => {
+                let ret = sbi_call(
+                    EXTENSION_DBCN,
+                    FUNCTION_DBCN_CONSOLE_WRITE_BYTE,
+                    rest[0] as usize,
+                    0,
+                    0,
+                );
+                if ret.error == SBI_SUCCESS {
+                    rest = &rest[1..];
+                }
+                ret
+            }
+            ConsolePath::Legacy => {
+                rest.iter().for_each(|&b| console_putchar(b as usize));
+                return;
+            }
+        };
+        match ret.error {
+            SBI_SUCCESS => {}
+            SBI_ERR_NOT_SUPPORTED => {
+                CONSOLE_PATH.store(path.fallback() as u8, Ordering::Relaxed);
+            }
+            // console failed; drop the output rather than retrying forever
+            _ => return,
+        }
+    }
+}
+
+pub(crate) fn test_console_path() {
+    use ConsolePath::*;
+    assert_eq!(
+        select_console_path(true, true, true),
+        DbcnWrite,
+        "full DBCN"
+    );
+    assert_eq!(
+        select_console_path(true, true, false),
+        DbcnWrite,
+        "write only"
+    );
+    assert_eq!(
+        select_console_path(true, false, true),
+        DbcnWriteByte,
+        "write byte only"
+    );
+    assert_eq!(
+        select_console_path(true, false, false),
+        Legacy,
+        "DBCN without functions"
+    );
+    assert_eq!(
+        select_console_path(false, true, true),
+        Legacy,
+        "no DBCN extension"
+    );
+    assert_eq!(DbcnWrite.fallback(), DbcnWriteByte, "write falls back");
+    assert_eq!(DbcnWriteByte.fallback(), Legacy, "write byte falls back");
+    assert_eq!(Legacy.fallback(), Legacy, "legacy always available");
+    println!(
+        "zihai > console path test passed, current: {:?}",
+        console_path()
+    );
+}
+
+pub(crate) fn test_spec_version() {
+    assert_eq!(spec_version_parts(0x0000_0003), (0, 3), "version 0.3");
+    assert_eq!(spec_version_parts(0x0100_0000), (1, 0), "version 1.0");
+    assert_eq!(spec_version_parts(0x0200_000F), (2, 15), "version 2.15");
+    assert_eq!(
+        spec_version_parts(0x0000_0100),
+        (0, 256),
+        "minor version over 255"
+    );
+    println!("zihai > sbi spec version test passed");
+}
@@ -0,0 +1,166 @@
+//! Synchronization primitives
+//!
+//! Includes a fair ticket lock and the lock type selected for shared hypervisor states
+#![allow(unused)] // use in the future
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Lock used for frame allocator and other shared states.
+//
+// Spin lock acquires in no particular order, so one hart may starve under
+// contention; ticket lock serves harts in the order they arrive.
+#[cfg(not(feature = "ticket-lock"))]
+pub type Mutex<T> = spin::Mutex<T>;
+#[cfg(feature = "ticket-lock")]
+pub type Mutex<T> = TicketMutex<T>;
+
+// A mutual exclusion lock that grants access in first-come, first-served order.
+//
+// Each locker takes a ticket from `next` and spins until `owner` reaches its
+// ticket; unlocking passes ownership to the next ticket.
+pub struct TicketMutex<T: ?Sized> {
+    next: AtomicUsize,
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for TicketMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for TicketMutex<T> {}
+
+// Guard of a locked ticket mutex, unlocks when dropped
+pub struct TicketMutexGuard<'a, T: ?Sized> {
+    lock: &'a TicketMutex<T>,
+}
+
+impl<T> TicketMutex<T> {
+    pub const fn new(data: T) -> Self {
+        TicketMutex {
+            next: AtomicUsize::new(0),
+            owner: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketMutex<T> {
+    pub fn lock(&self) -> TicketMutexGuard<'_, T> {
+        let ticket = self.take_ticket();
+        while !self.is_serving(ticket) {
+            core::hint::spin_loop();
+        }
+        TicketMutexGuard { lock: self }
+    }
+    // locks only if no one holds or waits for the lock
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T>> {
+        let owner = self.owner.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(owner, owner + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| TicketMutexGuard { lock: self })
+    }
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.owner.load(Ordering::Relaxed)
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+    fn take_ticket(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+    fn is_serving(&self, ticket: usize) -> bool {
+        self.owner.load(Ordering::Acquire) == ticket
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for TicketMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "TicketMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "TicketMutex {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for TicketMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.fetch_add(1, Ordering::Release);
+    }
+}
+
+pub(crate) fn test_ticket_mutex(hartid: usize) {
+    let mutex = TicketMutex::new(0usize);
+    let mut guard = mutex.lock();
+    *guard += 1;
+    assert!(mutex.is_locked(), "locked while guard is held");
+    assert!(mutex.try_lock().is_none(), "try lock while locked");
+    // other harts arrive in order while the lock is held
+    let first = mutex.take_ticket();
+    let second = mutex.take_ticket();
+    let third = mutex.take_ticket();
+    assert!(!mutex.is_serving(first), "waits while locked");
+    drop(guard);
+    assert!(mutex.is_serving(first), "first arrival served first");
+    assert!(!mutex.is_serving(second), "later arrival keeps waiting");
+    mutex.owner.fetch_add(1, Ordering::Release); // first unlocks
+    assert!(mutex.is_serving(second), "second arrival served next");
+    assert!(!mutex.is_serving(third), "third arrival keeps waiting");
+    mutex.owner.fetch_add(1, Ordering::Release); // second unlocks
+    assert!(mutex.is_serving(third), "third arrival served last");
+    assert!(
+        mutex.try_lock().is_none(),
+        "try lock does not jump the queue"
+    );
+    mutex.owner.fetch_add(1, Ordering::Release); // third unlocks
+    assert!(!mutex.is_locked(), "unlocked after all arrivals");
+    assert_eq!(*mutex.try_lock().unwrap(), 1, "data kept");
+    // other harts contend for a held lock, released to them one at a time
+    static CONTENDED: TicketMutex<Vec<usize>> = TicketMutex::new(Vec::new());
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    fn contend(hartid: usize) {
+        CONTENDED.lock().push(hartid);
+        DONE.fetch_add(1, Ordering::Release);
+    }
+    let others: Vec<usize> = crate::hart::roster()
+        .lock()
+        .iter()
+        .map(|h| h.hartid)
+        .filter(|&id| id != hartid)
+        .collect();
+    let guard = CONTENDED.lock();
+    for (i, &other) in others.iter().enumerate() {
+        crate::hart::call_on_hart(other, contend);
+        // once `next` moves on, this hart holds a ticket and spins in `lock`
+        while CONTENDED.next.load(Ordering::Relaxed) < i + 2 {
+            core::hint::spin_loop();
+        }
+    }
+    drop(guard);
+    while DONE.load(Ordering::Acquire) < others.len() {
+        core::hint::spin_loop();
+    }
+    assert_eq!(
+        *CONTENDED.lock(),
+        others,
+        "{} contending harts served in arrival order",
+        others.len()
+    );
+    println!("zihai > ticket mutex test passed");
+}
@@ -0,0 +1,154 @@
+//! TLB module
+//!
+//! Includes batched TLB shootdown over remote harts
+#![allow(unused)] // use in the future
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::mm::{PageMode, Sv39, VirtAddr, VirtPageNum};
+use crate::sbi;
+
+// Batches with more pages than this are flushed with one full fence; fencing
+// page by page costs more than refilling the whole TLB beyond this size.
+pub const FULL_FENCE_PAGES: usize = 64;
+
+const PAGE_SIZE: usize = 1 << Sv39::FRAME_SIZE_BITS;
+
+// One remote fence to issue
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Fence {
+    /// Fences a virtual address range of one address space
+    Range { asid: usize, va: Range<VirtAddr> },
+    /// Fences all addresses of all address spaces
+    Full,
+}
+
+// Invalidations collected during bulk unmapping, to fence remote harts at once.
+//
+// Issuing an SBI RFENCE call for every unmapped page is expensive, as each
+// call interrupts all target harts. Adjacent or overlapping ranges of the same
+// address space are coalesced when added.
+#[derive(Debug)]
+pub struct ShootdownBatch {
+    hart_mask: usize,
+    hart_mask_base: usize,
+    // (asid, address range), sorted by asid and then by start address
+    ranges: Vec<(usize, Range<VirtAddr>)>,
+}
+
+impl ShootdownBatch {
+    // a batch fencing harts in `hart_mask`, counted from hart `hart_mask_base`
+    pub fn new(hart_mask: usize, hart_mask_base: usize) -> Self {
+        ShootdownBatch {
+            hart_mask,
+            hart_mask_base,
+            ranges: Vec::new(),
+        }
+    }
+    // Adds pages of an address space to invalidate
+    pub fn add(&mut self, asid: usize, vpns: Range<VirtPageNum>) {
+        let mut start = vpns.start.addr_begin::<Sv39>();
+        let mut end = vpns.end.addr_begin::<Sv39>();
+        if end.0 <= start.0 {
+            return;
+        }
+        // merge every range of this asid that overlaps or touches the new one
+        while let Some(pos) = self
+            .ranges
+            .iter()
+            .position(|(a, va)| *a == asid && va.start.0 <= end.0 && start.0 <= va.end.0)
+        {
+            let (_, va) = self.ranges.remove(pos);
+            start = VirtAddr(usize::min(start.0, va.start.0));
+            end = VirtAddr(usize::max(end.0, va.end.0));
+        }
+        let pos = self
+            .ranges
+            .partition_point(|(a, va)| (*a, va.start.0) < (asid, start.0));
+        self.ranges.insert(pos, (asid, start..end));
+    }
+    // number of pages to invalidate
+    pub fn page_count(&self) -> usize {
+        let pages = |(_, va): &(usize, Range<VirtAddr>)| (va.end.0 - va.start.0) / PAGE_SIZE;
+        self.ranges.iter().map(pages).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+    // Fences needed to invalidate all pages in this batch
+    pub fn fences(&self) -> Vec<Fence> {
+        if self.page_count() > FULL_FENCE_PAGES {
+            return alloc::vec![Fence::Full];
+        }
+        let fence = |(asid, va): &(usize, Range<VirtAddr>)| Fence::Range {
+            asid: *asid,
+            va: va.clone(),
+        };
+        self.ranges.iter().map(fence).collect()
+    }
+    // Issues remote fences to target harts and empties the batch.
+    //
+    // On the first failed fence its SBI return value is returned and the batch
+    // is kept unchanged, so the caller may retry the flush; fences already
+    // issued are issued again then, which is harmless.
+    pub fn flush(&mut self) -> Result<(), sbi::SbiRet> {
+        for fence in self.fences() {
+            let (hart_mask, base) = (self.hart_mask, self.hart_mask_base);
+            let ret = match fence {
+                Fence::Range { asid, va } => {
+                    let size = va.end.0 - va.start.0;
+                    sbi::remote_sfence_vma_asid(hart_mask, base, va.start.0, size, asid)
+                }
+                Fence::Full => sbi::remote_sfence_vma(hart_mask, base, 0, usize::MAX),
+            };
+            if ret.error != sbi::SBI_SUCCESS {
+                return Err(ret);
+            }
+        }
+        self.ranges.clear();
+        Ok(())
+    }
+}
+
+pub(crate) fn test_shootdown_batch() {
+    let vpn = |va: usize| VirtAddr(va).page_number::<Sv39>();
+    let mut batch = ShootdownBatch::new(0b1110, 0);
+    for i in 0..8 {
+        let page = 0x9000_0000 + i * PAGE_SIZE;
+        batch.add(1, vpn(page)..vpn(page + PAGE_SIZE));
+    }
+    let expected = Fence::Range {
+        asid: 1,
+        va: VirtAddr(0x9000_0000)..VirtAddr(0x9000_8000),
+    };
+    assert_eq!(
+        batch.fences(),
+        core::slice::from_ref(&expected),
+        "adjacent pages coalesced"
+    );
+    batch.add(2, vpn(0x9000_8000)..vpn(0x9000_9000));
+    batch.add(1, vpn(0x8000_0000)..vpn(0x8000_1000));
+    batch.add(1, vpn(0x9000_4000)..vpn(0x9000_a000)); // overlapping
+    let fences = batch.fences();
+    assert_eq!(fences.len(), 3, "different asids or apart ranges");
+    assert_eq!(
+        fences[1],
+        Fence::Range {
+            asid: 1,
+            va: VirtAddr(0x9000_0000)..VirtAddr(0x9000_a000),
+        },
+        "overlapping range coalesced"
+    );
+    assert_eq!(batch.page_count(), 1 + 10 + 1, "pages to invalidate");
+    for i in 0..FULL_FENCE_PAGES {
+        let page = 0xa000_0000 + i * 2 * PAGE_SIZE; // never adjacent
+        batch.add(3, vpn(page)..vpn(page + PAGE_SIZE));
+    }
+    assert_eq!(batch.fences(), [Fence::Full], "large batch falls back");
+    // no hart 0x1000 exists, so the fence fails and the batch is kept for a retry
+    let mut batch = ShootdownBatch::new(0b1, 0x1000);
+    batch.add(1, vpn(0x9000_0000)..vpn(0x9000_1000));
+    assert!(batch.flush().is_err(), "fence on missing hart fails");
+    assert_eq!(batch.page_count(), 1, "failed batch kept");
+    println!("zihai > tlb shootdown batch test passed");
+}
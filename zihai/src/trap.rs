@@ -0,0 +1,529 @@
+//! Trap module
+//!
+//! Includes the persistent trap handler of hypervisor and per-hart trap stacks
+#![allow(unused)] // use in the future
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+
+use riscv::register::{
+    scause::{Scause, Trap},
+    sstatus,
+    stvec::{self, TrapMode},
+};
+
+use crate::hart::MAX_HARTS;
+
+const TRAP_STACK_SIZE: usize = 16 * 1024; // 16KB
+
+#[link_section = ".bss.uninit"]
+static mut TRAP_STACKS: MaybeUninit<[u8; TRAP_STACK_SIZE * MAX_HARTS]> = MaybeUninit::uninit();
+
+// Gets the initial trap stack top of a hart.
+//
+// Each hart has its own trap stack, so traps on different harts never share
+// trap frames. Nested traps on the same hart allocate their frames below the
+// frame of the trap they interrupted.
+pub fn trap_stack_top(stacks_base: usize, hartid: usize) -> usize {
+    assert!(hartid < MAX_HARTS, "hart id {} out of range", hartid);
+    stacks_base + TRAP_STACK_SIZE * (hartid + 1)
+}
+
+// Installs the persistent trap handler on current hart.
+//
+// Register `sscratch` holds the trap stack position of current hart whenever
+// the hart is not saving or restoring a trap frame.
+pub unsafe fn init(hartid: usize) {
+    let stacks_base = core::ptr::addr_of!(TRAP_STACKS) as usize;
+    let stack_top = trap_stack_top(stacks_base, hartid);
+    asm!("csrw  sscratch, {}", in(reg) stack_top, options(nomem, nostack));
+    stvec::write(on_trap as *const () as usize, TrapMode::Direct);
+}
+
+// Saved states of the interrupted context
+#[repr(C)]
+pub struct TrapFrame {
+    // general purpose registers, indexed by register number; x[0] is unused
+    pub x: [usize; 32],
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub scause: Scause,
+    pub stval: usize,
+}
+
+// Allows nested traps while handling current trap.
+//
+// Trap handlers run with interrupts disabled. Once the interrupted context is
+// saved onto the per-hart trap stack, a handler may call this function to
+// re-enable interrupts during long procedures (e.g. instruction emulation).
+// Nested traps save their frames below current frame and never clobber it.
+//
+// Re-entrancy contract: a handler that calls this function must not hold any
+// lock that nested trap handlers may acquire, and must call
+// `disable_nested_traps` before it returns. Interrupts are disabled again when
+// the trap frame is restored in any case.
+pub unsafe fn enable_nested_traps() {
+    sstatus::set_sie();
+}
+
+// Disallows nested traps before returning from current trap handler
+pub unsafe fn disable_nested_traps() {
+    sstatus::clear_sie();
+}
+
+// Handler of one trap cause, called with the saved frame of interrupted context
+pub type TrapHandler = fn(&mut TrapFrame);
+
+// number of cause codes for exceptions or interrupts that can be registered
+const MAX_TRAP_CAUSES: usize = 64;
+
+// Routing table from trap causes to their handlers.
+//
+// Subsystems (e.g. supervisor ecalls from guests, guest page faults, timer)
+// register their handlers on initialization; the persistent trap handler then
+// looks up and calls the handler by `scause`. Handlers are copied out of the
+// table before being called, so nested traps never wait on its lock.
+pub struct Dispatcher {
+    exceptions: spin::Mutex<[Option<TrapHandler>; MAX_TRAP_CAUSES]>,
+    interrupts: spin::Mutex<[Option<TrapHandler>; MAX_TRAP_CAUSES]>,
+}
+
+impl Dispatcher {
+    pub const fn new() -> Self {
+        Dispatcher {
+            exceptions: spin::Mutex::new([None; MAX_TRAP_CAUSES]),
+            interrupts: spin::Mutex::new([None; MAX_TRAP_CAUSES]),
+        }
+    }
+    // registers or replaces the handler of an exception cause code
+    pub fn register_exception(&self, code: usize, handler: TrapHandler) {
+        assert!(
+            code < MAX_TRAP_CAUSES,
+            "exception code {} out of range",
+            code
+        );
+        self.exceptions.lock()[code] = Some(handler);
+    }
+    // registers or replaces the handler of an interrupt cause code
+    pub fn register_interrupt(&self, code: usize, handler: TrapHandler) {
+        assert!(
+            code < MAX_TRAP_CAUSES,
+            "interrupt code {} out of range",
+            code
+        );
+        self.interrupts.lock()[code] = Some(handler);
+    }
+    // finds the registered handler of a trap cause
+    pub fn handler_for(&self, scause: Scause) -> Option<TrapHandler> {
+        let code = scause.code();
+        if code >= MAX_TRAP_CAUSES {
+            return None;
+        }
+        if scause.is_interrupt() {
+            self.interrupts.lock()[code]
+        } else {
+            self.exceptions.lock()[code]
+        }
+    }
+    // Calls the registered handler of the trap cause in `frame`.
+    //
+    // Returns false if no handler is registered for this cause.
+    pub fn dispatch(&self, frame: &mut TrapFrame) -> bool {
+        match self.handler_for(frame.scause) {
+            Some(handler) => {
+                handler(frame);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+static DISPATCHER: Dispatcher = Dispatcher::new();
+
+// Gets the trap dispatcher shared by all harts
+pub fn dispatcher() -> &'static Dispatcher {
+    &DISPATCHER
+}
+
+// Gets the hart a trap frame belongs to, found by which trap stack holds the frame
+fn frame_hartid(frame: &TrapFrame) -> usize {
+    let stacks_base = unsafe { core::ptr::addr_of!(TRAP_STACKS) } as usize;
+    (frame as *const TrapFrame as usize - stacks_base) / TRAP_STACK_SIZE
+}
+
+// Work requested by another hart, delivered with an inter-processor interrupt
+#[derive(Debug, Clone, Copy)]
+pub enum HartMessage {
+    // flushes local address translation caches of this address space id
+    FlushTlb { asid: usize },
+    // forces the guest running on this hart to exit, so that its pending states are checked
+    KickGuest { vmid: usize },
+    // calls a function with the id of this hart, e.g. a test running on all harts
+    Call { f: fn(usize) },
+}
+
+// Pending-work mailbox of one hart.
+//
+// Senders post messages and then raise a supervisor software interrupt on the
+// receiving hart through SBI. Messages are taken out of the queue before being
+// processed, so the lock is never held while handling them.
+pub struct Mailbox {
+    queue: spin::Mutex<VecDeque<HartMessage>>,
+}
+
+impl Mailbox {
+    pub const fn new() -> Self {
+        Mailbox {
+            queue: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+    // queues a message for the receiving hart
+    pub fn post(&self, message: HartMessage) {
+        self.queue.lock().push_back(message);
+    }
+    // Processes all pending messages in the order they were posted.
+    //
+    // Returns the number of messages processed.
+    pub fn drain(&self, mut handle: impl FnMut(HartMessage)) -> usize {
+        let pending = core::mem::take(&mut *self.queue.lock());
+        let count = pending.len();
+        pending.into_iter().for_each(&mut handle);
+        count
+    }
+}
+
+static MAILBOXES: [Mailbox; MAX_HARTS] = [const { Mailbox::new() }; MAX_HARTS];
+
+// Gets the pending-work mailbox of a hart
+pub fn mailbox(hartid: usize) -> &'static Mailbox {
+    assert!(hartid < MAX_HARTS, "hart id {} out of range", hartid);
+    &MAILBOXES[hartid]
+}
+
+// performs one message on hart `hartid`, the current hart
+fn handle_message(hartid: usize, message: HartMessage) {
+    match message {
+        HartMessage::FlushTlb { asid } => unsafe {
+            asm!("sfence.vma zero, {}", in(reg) asid, options(nostack))
+        },
+        // interrupting the guest is the kick itself; the guest exit path checks
+        // its pending states before resuming
+        HartMessage::KickGuest { vmid: _ } => {}
+        HartMessage::Call { f } => f(hartid),
+    }
+}
+
+// supervisor software interrupt code in `scause`
+const SUPERVISOR_SOFTWARE_INTERRUPT: usize = 1;
+
+// Handles supervisor software interrupts raised by other harts
+fn on_software_interrupt(frame: &mut TrapFrame) {
+    // clear sip.SSIP before draining, so messages posted after this are not missed
+    unsafe { asm!("csrc  sip, {}", in(reg) 1 << 1, options(nomem, nostack)) };
+    let hartid = frame_hartid(frame);
+    mailbox(hartid).drain(|message| handle_message(hartid, message));
+}
+
+// Registers trap handlers of inter-processor interrupts
+pub fn init_ipi() {
+    DISPATCHER.register_interrupt(SUPERVISOR_SOFTWARE_INTERRUPT, on_software_interrupt);
+}
+
+extern "C" fn rust_trap_handler(frame: &mut TrapFrame) {
+    if !DISPATCHER.dispatch(frame) {
+        unhandled_trap(frame)
+    }
+}
+
+// default handler of trap causes without registered handlers
+fn unhandled_trap(frame: &TrapFrame) -> ! {
+    dump_frame(frame);
+    match frame.scause.cause() {
+        Trap::Exception(e) => panic!(
+            "unhandled exception {:?}, sepc: {:#x}, stval: {:#x}",
+            e, frame.sepc, frame.stval
+        ),
+        Trap::Interrupt(i) => panic!("unhandled interrupt {:?}, sepc: {:#x}", i, frame.sepc),
+    }
+}
+
+// ABI names of general purpose registers, indexed by register number
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// Prints the complete saved register state of a trap for debugging.
+//
+// For guest page faults, `htval` and `htinst` are read from current hart and
+// printed as well. The dump is written straight to the console without heap
+// allocation, as the heap may be what went wrong.
+pub fn dump_frame(frame: &TrapFrame) {
+    let guest_csrs = if is_guest_page_fault(frame.scause) {
+        Some((crate::hcsr::read_htval(), crate::hcsr::read_htinst()))
+    } else {
+        None
+    };
+    dump_frame_to(&mut crate::console::Stdout, frame, guest_csrs).unwrap();
+}
+
+// instruction, load or store guest page fault
+fn is_guest_page_fault(scause: Scause) -> bool {
+    !scause.is_interrupt() && matches!(scause.code(), 20 | 21 | 23)
+}
+
+fn dump_frame_to(
+    w: &mut impl Write,
+    frame: &TrapFrame,
+    guest_csrs: Option<(usize, usize)>,
+) -> fmt::Result {
+    writeln!(
+        w,
+        "sepc: {:#018x} scause: {:#018x} stval: {:#018x} sstatus: {:#018x}",
+        frame.sepc,
+        frame.scause.bits(),
+        frame.stval,
+        frame.sstatus
+    )?;
+    if let Some((htval, htinst)) = guest_csrs {
+        writeln!(w, "htval: {:#018x} htinst: {:#018x}", htval, htinst)?;
+    }
+    // x0 is not saved
+    let rows = REGISTER_NAMES[1..].chunks(4).zip(frame.x[1..].chunks(4));
+    for (names, values) in rows {
+        for (col, (name, value)) in names.iter().zip(values).enumerate() {
+            if col != 0 {
+                w.write_str(" ")?;
+            }
+            write!(w, "{:>4}: {:#018x}", name, value)?;
+        }
+        w.write_str("\n")?;
+    }
+    Ok(())
+}
+
+// Assembly persistent trap handler.
+//
+// Swaps to the trap stack in `sscratch`, saves all registers into a trap frame
+// and points `sscratch` to this frame, so that nested traps allocate frames below it.
+// On return, `sstatus` is restored first to disable interrupts before `sscratch`
+// gets back its value at entry.
+#[naked]
+unsafe extern "C" fn on_trap() -> ! {
+    asm!(
+        ".p2align 2",
+        "csrrw  sp, sscratch, sp",
+        "addi   sp, sp, -{frame_size}",
+        "sd     x1, 1*8(sp)",
+        "sd     x3, 3*8(sp)",
+        "sd     x4, 4*8(sp)",
+        "sd     x5, 5*8(sp)",
+        "sd     x6, 6*8(sp)",
+        "sd     x7, 7*8(sp)",
+        "sd     x8, 8*8(sp)",
+        "sd     x9, 9*8(sp)",
+        "sd     x10, 10*8(sp)",
+        "sd     x11, 11*8(sp)",
+        "sd     x12, 12*8(sp)",
+        "sd     x13, 13*8(sp)",
+        "sd     x14, 14*8(sp)",
+        "sd     x15, 15*8(sp)",
+        "sd     x16, 16*8(sp)",
+        "sd     x17, 17*8(sp)",
+        "sd     x18, 18*8(sp)",
+        "sd     x19, 19*8(sp)",
+        "sd     x20, 20*8(sp)",
+        "sd     x21, 21*8(sp)",
+        "sd     x22, 22*8(sp)",
+        "sd     x23, 23*8(sp)",
+        "sd     x24, 24*8(sp)",
+        "sd     x25, 25*8(sp)",
+        "sd     x26, 26*8(sp)",
+        "sd     x27, 27*8(sp)",
+        "sd     x28, 28*8(sp)",
+        "sd     x29, 29*8(sp)",
+        "sd     x30, 30*8(sp)",
+        "sd     x31, 31*8(sp)",
+        "csrr   t0, sscratch",
+        "sd     t0, 2*8(sp)", // interrupted sp
+        "csrw   sscratch, sp",
+        "csrr   t0, sstatus",
+        "sd     t0, 32*8(sp)",
+        "csrr   t1, sepc",
+        "sd     t1, 33*8(sp)",
+        "csrr   t2, scause",
+        "sd     t2, 34*8(sp)",
+        "csrr   t3, stval",
+        "sd     t3, 35*8(sp)",
+        "mv     a0, sp",
+        "call   {rust_trap_handler}",
+        "ld     t0, 32*8(sp)",
+        "csrw   sstatus, t0",
+        "ld     t1, 33*8(sp)",
+        "csrw   sepc, t1",
+        "addi   t0, sp, {frame_size}",
+        "csrw   sscratch, t0",
+        "ld     x1, 1*8(sp)",
+        "ld     x3, 3*8(sp)",
+        "ld     x4, 4*8(sp)",
+        "ld     x5, 5*8(sp)",
+        "ld     x6, 6*8(sp)",
+        "ld     x7, 7*8(sp)",
+        "ld     x8, 8*8(sp)",
+        "ld     x9, 9*8(sp)",
+        "ld     x10, 10*8(sp)",
+        "ld     x11, 11*8(sp)",
+        "ld     x12, 12*8(sp)",
+        "ld     x13, 13*8(sp)",
+        "ld     x14, 14*8(sp)",
+        "ld     x15, 15*8(sp)",
+        "ld     x16, 16*8(sp)",
+        "ld     x17, 17*8(sp)",
+        "ld     x18, 18*8(sp)",
+        "ld     x19, 19*8(sp)",
+        "ld     x20, 20*8(sp)",
+        "ld     x21, 21*8(sp)",
+        "ld     x22, 22*8(sp)",
+        "ld     x23, 23*8(sp)",
+        "ld     x24, 24*8(sp)",
+        "ld     x25, 25*8(sp)",
+        "ld     x26, 26*8(sp)",
+        "ld     x27, 27*8(sp)",
+        "ld     x28, 28*8(sp)",
+        "ld     x29, 29*8(sp)",
+        "ld     x30, 30*8(sp)",
+        "ld     x31, 31*8(sp)",
+        "ld     sp, 2*8(sp)",
+        "sret",
+        frame_size = const core::mem::size_of::<TrapFrame>(),
+        rust_trap_handler = sym rust_trap_handler,
+        options(noreturn),
+    )
+}
+
+pub(crate) fn test_trap_stack() {
+    let base = 0x80300000;
+    assert_eq!(trap_stack_top(base, 0), 0x80304000, "boot hart trap stack");
+    assert_eq!(trap_stack_top(base, 5), 0x80318000, "hart 5 trap stack");
+    for hartid in 1..MAX_HARTS {
+        assert_eq!(
+            trap_stack_top(base, hartid) - trap_stack_top(base, hartid - 1),
+            TRAP_STACK_SIZE,
+            "trap stacks do not overlap"
+        );
+    }
+    assert_eq!(
+        trap_stack_top(base, MAX_HARTS - 1),
+        base + TRAP_STACK_SIZE * MAX_HARTS,
+        "last trap stack ends with static stacks"
+    );
+    assert_eq!(core::mem::size_of::<TrapFrame>() % 16, 0, "aligned frame");
+    println!("zihai > trap stack test passed");
+}
+
+pub(crate) fn test_dispatcher() {
+    const INTERRUPT_BIT: usize = 1 << (usize::BITS - 1);
+    let mocked = |scause: usize| {
+        let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+        frame.scause = unsafe { core::mem::transmute::<usize, Scause>(scause) };
+        frame
+    };
+    fn on_ecall(frame: &mut TrapFrame) {
+        frame.x[10] = 0x1234; // a0
+        frame.sepc += 4;
+    }
+    fn on_timer(frame: &mut TrapFrame) {
+        frame.x[10] = 0x5678;
+    }
+    let dispatcher = Dispatcher::new();
+    dispatcher.register_exception(8, on_ecall); // environment call from U or VU mode
+    dispatcher.register_interrupt(5, on_timer); // supervisor timer interrupt
+    let mut frame = mocked(8);
+    frame.sepc = 0x80200000;
+    assert!(dispatcher.dispatch(&mut frame), "registered exception");
+    assert_eq!(frame.x[10], 0x1234, "exception handler invoked");
+    assert_eq!(frame.sepc, 0x80200004, "exception handler skips ecall");
+    let mut frame = mocked(INTERRUPT_BIT | 5);
+    assert!(dispatcher.dispatch(&mut frame), "registered interrupt");
+    assert_eq!(frame.x[10], 0x5678, "interrupt handler invoked");
+    let mut frame = mocked(5); // load access fault, same code as timer interrupt
+    assert!(!dispatcher.dispatch(&mut frame), "unregistered exception");
+    assert_eq!(frame.x[10], 0, "no handler invoked");
+    let mut frame = mocked(INTERRUPT_BIT | 9);
+    assert!(!dispatcher.dispatch(&mut frame), "unregistered interrupt");
+    println!("zihai > trap dispatcher test passed");
+}
+
+pub(crate) fn test_mailbox() {
+    let mailbox = Mailbox::new();
+    mailbox.post(HartMessage::FlushTlb { asid: 3 });
+    mailbox.post(HartMessage::KickGuest { vmid: 1 });
+    mailbox.post(HartMessage::FlushTlb { asid: 5 });
+    let mut flushed = alloc::vec::Vec::new();
+    let mut kicked = alloc::vec::Vec::new();
+    let count = mailbox.drain(|message| match message {
+        HartMessage::FlushTlb { asid } => flushed.push(asid),
+        HartMessage::KickGuest { vmid } => kicked.push(vmid),
+        HartMessage::Call { .. } => panic!("not posted"),
+    });
+    assert_eq!(count, 3, "all messages drained");
+    assert_eq!(flushed, [3, 5], "flushes in posted order");
+    assert_eq!(kicked, [1], "guest kicked");
+    assert_eq!(mailbox.drain(|_| panic!("empty")), 0, "mailbox emptied");
+    mailbox.post(HartMessage::FlushTlb { asid: 0 });
+    assert_eq!(
+        mailbox.drain(|message| handle_message(0, message)),
+        1,
+        "flush on current hart"
+    );
+    // build a zeroed frame inside the trap stack of hart 2, which is not started yet,
+    // so that the reference points to initialized memory
+    let base = unsafe { core::ptr::addr_of_mut!(TRAP_STACKS) } as usize;
+    let frame_ptr = (base + TRAP_STACK_SIZE * 2 + 0x100) as *mut TrapFrame;
+    unsafe { frame_ptr.write(core::mem::zeroed()) };
+    let frame = unsafe { &*frame_ptr };
+    assert_eq!(frame_hartid(frame), 2, "hart of trap frame");
+    println!("zihai > hart mailbox test passed");
+}
+
+pub(crate) fn test_dump_frame() {
+    let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+    for (idx, reg) in frame.x.iter_mut().enumerate().skip(1) {
+        *reg = 0x1000 + idx;
+    }
+    frame.sepc = 0x80200000;
+    frame.scause = unsafe { core::mem::transmute::<usize, Scause>(21) }; // load guest page fault
+    frame.stval = 0x1234;
+    frame.sstatus = 0x8000000200006000;
+    let mut ans = alloc::string::String::new();
+    dump_frame_to(&mut ans, &frame, Some((0x20000480, 0x3003))).unwrap();
+    let lines: alloc::vec::Vec<_> = ans.lines().collect();
+    assert_eq!(lines.len(), 10, "cause, guest CSRs and 8 register lines");
+    assert_eq!(
+        lines[0],
+        "sepc: 0x0000000080200000 scause: 0x0000000000000015 stval: 0x0000000000001234 sstatus: 0x8000000200006000",
+        "trap CSRs"
+    );
+    assert_eq!(
+        lines[1], "htval: 0x0000000020000480 htinst: 0x0000000000003003",
+        "guest fault CSRs"
+    );
+    assert_eq!(
+        lines[2],
+        "  ra: 0x0000000000001001   sp: 0x0000000000001002   gp: 0x0000000000001003   tp: 0x0000000000001004",
+        "first register line"
+    );
+    assert_eq!(
+        lines[9], "  t4: 0x000000000000101d   t5: 0x000000000000101e   t6: 0x000000000000101f",
+        "last register line"
+    );
+    assert!(is_guest_page_fault(frame.scause), "guest page fault");
+    ans.clear();
+    dump_frame_to(&mut ans, &frame, None).unwrap();
+    assert_eq!(ans.lines().count(), 9, "no guest CSRs");
+    assert!(ans.contains(" s11: 0x000000000000101b"), "saved registers");
+    println!("zihai > trap frame dump test passed");
+}
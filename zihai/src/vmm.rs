@@ -0,0 +1,3027 @@
+//! Virtual machine monitor module
+//!
+//! Includes guest memory management for hardware-assisted virtual machines
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::cell::Cell;
+
+use crate::hcsr::{self, HStatus};
+use crate::mm::{
+    self, FrameAllocError, FrameAllocator, FrameBox, LimitedFrameAllocator, PageError, PageMode,
+    PagedAddrSpace, PhysPageNum, Sv39Flags, Sv39x4, VirtAddr, VirtPageNum, ZeroedFramePool,
+};
+use crate::sbi;
+
+/// Guest physical address
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct GuestPhysAddr(pub usize);
+
+impl GuestPhysAddr {
+    // page number of this address under G-stage translation
+    pub fn page_number<M: PageMode>(&self) -> VirtPageNum {
+        VirtAddr(self.0).page_number::<M>()
+    }
+}
+
+const GUEST_FRAME_SIZE: usize = 1 << Sv39x4::FRAME_SIZE_BITS;
+
+// Kind of guest access that caused a guest page fault
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GuestFaultKind {
+    Fetch,
+    Load,
+    Store,
+}
+
+// Guest page fault decoded from trap CSRs
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GuestPageFault {
+    pub kind: GuestFaultKind,
+    /// Faulting guest physical address
+    pub gpa: GuestPhysAddr,
+    /// Faulting guest virtual address, if `stval` holds one
+    pub gva: Option<usize>,
+    /// Transformed trapping instruction from `htinst`, zero if not provided
+    pub htinst: usize,
+}
+
+// Decodes a guest page fault from `scause` exception code, `hstatus`, `htval`,
+// `stval` and `htinst`.
+//
+// Register `htval` holds bits [MXLEN-1:2] of the faulting guest physical
+// address. If `hstatus.GVA` is set, `stval` holds the guest virtual address,
+// and bits [1:0] of the guest physical address are the same as those of it,
+// as translation never changes the page offset. Otherwise `stval` is not an
+// address, and these bits are left zero. The address is masked to the guest
+// physical width of Sv39x4 G-stage translation.
+pub fn decode_guest_page_fault(
+    scause_code: usize,
+    hstatus: HStatus,
+    htval: usize,
+    stval: usize,
+    htinst: usize,
+) -> Option<GuestPageFault> {
+    let kind = match scause_code {
+        20 => GuestFaultKind::Fetch,
+        21 => GuestFaultKind::Load,
+        23 => GuestFaultKind::Store,
+        _ => return None,
+    };
+    let gpa_mask = (1 << Sv39x4::GPA_BITS) - 1;
+    let gva = if hstatus.gva() { Some(stval) } else { None };
+    let low_bits = gva.map_or(0, |gva| gva & 0b11);
+    let gpa = ((htval << 2) | low_bits) & gpa_mask;
+    Some(GuestPageFault {
+        kind,
+        gpa: GuestPhysAddr(gpa),
+        gva,
+        htinst,
+    })
+}
+
+// Guest load or store decoded for emulation, e.g. of MMIO devices
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DecodedAccess {
+    /// True for stores, false for loads
+    pub is_store: bool,
+    /// Access width in bytes
+    pub width: usize,
+    /// Loaded value is sign-extended into the register
+    pub signed: bool,
+    /// Destination register of loads, or source register of stores
+    pub reg: usize,
+    /// Length of the trapping instruction in bytes, to advance `sepc`
+    pub insn_len: usize,
+}
+
+// Pseudo-instructions in `htinst` for implicit accesses of VS-stage page table walks
+const HTINST_PSEUDO_READ_32: usize = 0x0000_2000;
+const HTINST_PSEUDO_WRITE_32: usize = 0x0000_2020;
+const HTINST_PSEUDO_READ_64: usize = 0x0000_3000;
+const HTINST_PSEUDO_WRITE_64: usize = 0x0000_3020;
+
+// Decodes the transformed instruction in `htinst` of a load or store fault.
+//
+// The transformed instruction is the trapping load or store with its address
+// fields (rs1 and immediate) zeroed; bit 1 is cleared if the trapping
+// instruction is a compressed one, with the transformed one in its 32-bit form.
+// Returns None if `htinst` is zero, a pseudo-instruction of a page table walk,
+// or not a load or store; the caller then fetches the guest instruction instead.
+pub fn decode_htinst(htinst: usize) -> Option<DecodedAccess> {
+    use bit_field::BitField;
+    match htinst {
+        0 | HTINST_PSEUDO_READ_32 | HTINST_PSEUDO_WRITE_32 => return None,
+        HTINST_PSEUDO_READ_64 | HTINST_PSEUDO_WRITE_64 => return None,
+        _ => {}
+    }
+    if !htinst.get_bit(0) {
+        return None;
+    }
+    let insn_len = if htinst.get_bit(1) { 4 } else { 2 };
+    let funct3 = htinst.get_bits(12..15);
+    let (is_store, width, signed, reg) = match htinst.get_bits(0..7) | 0b10 {
+        0b000_0011 => match funct3 {
+            0b000 => (false, 1, true, htinst.get_bits(7..12)), // lb
+            0b001 => (false, 2, true, htinst.get_bits(7..12)), // lh
+            0b010 => (false, 4, true, htinst.get_bits(7..12)), // lw
+            0b011 => (false, 8, true, htinst.get_bits(7..12)), // ld
+            0b100 => (false, 1, false, htinst.get_bits(7..12)), // lbu
+            0b101 => (false, 2, false, htinst.get_bits(7..12)), // lhu
+            0b110 => (false, 4, false, htinst.get_bits(7..12)), // lwu
+            _ => return None,
+        },
+        0b010_0011 => match funct3 {
+            0b000 => (true, 1, false, htinst.get_bits(20..25)), // sb
+            0b001 => (true, 2, false, htinst.get_bits(20..25)), // sh
+            0b010 => (true, 4, false, htinst.get_bits(20..25)), // sw
+            0b011 => (true, 8, false, htinst.get_bits(20..25)), // sd
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(DecodedAccess {
+        is_store,
+        width,
+        signed,
+        reg,
+        insn_len,
+    })
+}
+
+// Transforms a guest instruction fetched from memory into the form of `htinst`.
+//
+// Instructions of 32 bits are decoded as is, as `decode_htinst` skips the
+// address fields. Compressed loads and stores of integer registers are
+// expanded into their 32-bit form with bit 1 cleared; other compressed
+// instructions give None.
+fn transform_fetched_insn(insn: usize) -> Option<usize> {
+    use bit_field::BitField;
+    if insn.get_bits(0..2) == 0b11 {
+        return Some(insn);
+    }
+    let funct3 = insn.get_bits(13..16);
+    let (is_store, width_funct3, reg) = match (insn.get_bits(0..2), funct3) {
+        // c.lw, c.ld, c.sw and c.sd use registers x8 to x15
+        (0b00, 0b010) | (0b00, 0b011) => (false, funct3, 8 + insn.get_bits(2..5)),
+        (0b00, 0b110) | (0b00, 0b111) => (true, funct3 & 0b11, 8 + insn.get_bits(2..5)),
+        // c.lwsp and c.ldsp; rd of zero is reserved
+        (0b10, 0b010) | (0b10, 0b011) if insn.get_bits(7..12) != 0 => {
+            (false, funct3, insn.get_bits(7..12))
+        }
+        // c.swsp and c.sdsp
+        (0b10, 0b110) | (0b10, 0b111) => (true, funct3 & 0b11, insn.get_bits(2..7)),
+        _ => return None,
+    };
+    Some(if is_store {
+        (reg << 20) | (width_funct3 << 12) | 0b010_0001
+    } else {
+        (reg << 7) | (width_funct3 << 12) | 0b000_0001
+    })
+}
+
+// Configuration of a guest
+#[derive(Copy, Clone, Debug)]
+pub struct GuestConfig<'a> {
+    /// Start address of guest RAM
+    pub ram_base: GuestPhysAddr,
+    /// Size of guest RAM in bytes
+    pub ram_size: usize,
+    /// Number of virtual harts
+    pub cpu_count: usize,
+    /// Raw binary kernel image, loaded at start of guest RAM
+    pub kernel_image: &'a [u8],
+    /// Flattened device tree blob, loaded at the last page(s) of guest RAM;
+    /// a minimal one describing guest RAM and harts is generated if None
+    pub dtb: Option<&'a [u8]>,
+    /// Map guest RAM pages on first access rather than on creation
+    pub lazy_ram: bool,
+    /// Maximum number of host frames for this guest, including G-stage page tables
+    pub frame_quota: usize,
+}
+
+// Errors on creating or modifying a guest
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CreateError {
+    /// Guest RAM base or size is not aligned to guest frames
+    MisalignedRam,
+    /// Number of virtual harts is zero
+    InvalidCpuCount,
+    /// Kernel image and device tree do not fit into guest RAM
+    ImageTooLarge,
+    /// Guest physical address is outside guest RAM
+    OutOfRam,
+    /// No frame to back guest RAM or page tables
+    FrameAllocFailed,
+    /// Guest memory region overlaps with an existing region
+    OverlappingRegion,
+    /// G-stage page table error
+    Page(PageError),
+}
+
+impl From<FrameAllocError> for CreateError {
+    fn from(_: FrameAllocError) -> Self {
+        CreateError::FrameAllocFailed
+    }
+}
+
+impl From<PageError> for CreateError {
+    fn from(e: PageError) -> Self {
+        match e {
+            PageError::FrameAllocFailed => CreateError::FrameAllocFailed,
+            e => CreateError::Page(e),
+        }
+    }
+}
+
+// Type of a guest physical memory region
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    /// Guest RAM, backed by host frames on demand
+    Ram,
+    /// Emulated device registers
+    Mmio { device: usize },
+    /// Read-only memory, mapped read-only and executable when added; its
+    /// contents are written with `Vm::write_guest`
+    Rom,
+    /// No memory or device; any access is an access fault
+    Hole,
+}
+
+// A region of guest physical address space
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GuestRegion {
+    pub start: GuestPhysAddr,
+    pub size: usize,
+    pub kind: RegionKind,
+}
+
+impl GuestRegion {
+    fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        gpa.0 >= self.start.0 && gpa.0 - self.start.0 < self.size
+    }
+}
+
+// Typed regions over guest physical address space.
+//
+// Addresses not covered by any region are holes.
+#[derive(Clone, Debug)]
+pub struct GuestMemoryLayout {
+    // sorted by start address, never overlapping
+    regions: Vec<GuestRegion>,
+}
+
+impl GuestMemoryLayout {
+    pub const fn new() -> Self {
+        GuestMemoryLayout {
+            regions: Vec::new(),
+        }
+    }
+    // adds a region that does not overlap with existing regions
+    pub fn add_region(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        kind: RegionKind,
+    ) -> Result<(), CreateError> {
+        let end = start
+            .0
+            .checked_add(size)
+            .ok_or(CreateError::OverlappingRegion)?;
+        let idx = self.regions.partition_point(|r| r.start.0 < start.0);
+        let overlaps_prev = idx > 0 && {
+            let prev = &self.regions[idx - 1];
+            prev.start.0 + prev.size > start.0
+        };
+        let overlaps_next = idx < self.regions.len() && self.regions[idx].start.0 < end;
+        if overlaps_prev || overlaps_next {
+            return Err(CreateError::OverlappingRegion);
+        }
+        let region = GuestRegion { start, size, kind };
+        self.regions.insert(idx, region);
+        Ok(())
+    }
+    // removes the region starting at `start`, if any
+    fn remove_region(&mut self, start: GuestPhysAddr) {
+        self.regions.retain(|r| r.start != start);
+    }
+    // type of the region containing guest physical address
+    pub fn region_kind(&self, gpa: GuestPhysAddr) -> RegionKind {
+        self.region_at(gpa)
+            .map(|r| r.kind)
+            .unwrap_or(RegionKind::Hole)
+    }
+    // the region containing guest physical address, if any
+    pub fn region_at(&self, gpa: GuestPhysAddr) -> Option<&GuestRegion> {
+        let idx = self.regions.partition_point(|r| r.start.0 <= gpa.0);
+        self.regions[..idx].last().filter(|r| r.contains(gpa))
+    }
+}
+
+// How a guest page fault has been handled
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FaultAction {
+    /// A host frame now backs the faulting guest RAM page; resume the guest
+    MappedRam,
+    /// The access should be emulated by a device
+    EmulateMmio {
+        device: usize,
+        offset: usize,
+        access: DecodedAccess,
+    },
+    /// An access fault should be injected into the guest
+    InjectAccessFault,
+    /// The guest has used up its frame quota; report out of memory to the guest
+    OutOfMemory,
+}
+
+// A virtual machine backed by G-stage address translation
+#[derive(Debug)]
+pub struct Vm<A: FrameAllocator + Clone> {
+    space: PagedAddrSpace<Sv39x4, A>,
+    // host frames backing guest RAM
+    ram_frames: Vec<FrameBox<A>>,
+    cpu_count: usize,
+    entry: GuestPhysAddr,
+    dtb: Option<GuestPhysAddr>,
+    layout: GuestMemoryLayout,
+    // pre-zeroed frames for guest page faults on lazily mapped RAM
+    zero_pool: ZeroedFramePool<A>,
+    frame_alloc: A,
+}
+
+// number of pre-zeroed frames kept for guest page faults, and the mark to refill them
+const ZERO_POOL_CAPACITY: usize = 16;
+const ZERO_POOL_LOW_WATER: usize = 4;
+
+// G-stage translation mode in field MODE of `hgatp`, and width of field VMID
+const HGATP_MODE_SV39X4: usize = 8;
+const HGATP_VMID_MASK: usize = (1 << 14) - 1;
+
+// Flattened device tree tokens and header fields
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+// header and one empty memory reservation entry
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_RSVMAP_SIZE: usize = 16;
+
+// timebase frequency told to guests, the same as QEMU virt machine
+const GUEST_TIMEBASE_FREQ: u32 = 10_000_000;
+
+// Writes the structure and strings blocks of a flattened device tree
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    fn new() -> Self {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+    fn align(&mut self) {
+        let len = self.structure.len().next_multiple_of(4);
+        self.structure.resize(len, 0);
+    }
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name_offset as u32);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+    fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+    fn property_str(&mut self, name: &str, value: &str) {
+        let mut bytes = Vec::from(value.as_bytes());
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+    // header, empty memory reservation block, structure block and strings block
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        let off_struct = FDT_HEADER_SIZE + FDT_RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            FDT_HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut ans = Vec::with_capacity(total);
+        for field in header {
+            ans.extend_from_slice(&field.to_be_bytes());
+        }
+        ans.resize(off_struct, 0);
+        ans.extend_from_slice(&self.structure);
+        ans.extend_from_slice(&self.strings);
+        ans
+    }
+}
+
+// Builds a minimal device tree for a guest without one: its harts and RAM.
+fn minimal_fdt(config: &GuestConfig) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "zihai,virt");
+    fdt.begin_node("chosen");
+    fdt.end_node();
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", GUEST_TIMEBASE_FREQ);
+    for hart_id in 0..config.cpu_count {
+        fdt.begin_node(&alloc::format!("cpu@{:x}", hart_id));
+        fdt.property_str("device_type", "cpu");
+        fdt.property_u32("reg", hart_id as u32);
+        fdt.property_str("compatible", "riscv");
+        fdt.property_str("status", "okay");
+        fdt.end_node();
+    }
+    fdt.end_node();
+    fdt.begin_node(&alloc::format!("memory@{:x}", config.ram_base.0));
+    fdt.property_str("device_type", "memory");
+    let mut reg = Vec::with_capacity(16);
+    reg.extend_from_slice(&(config.ram_base.0 as u64).to_be_bytes());
+    reg.extend_from_slice(&(config.ram_size as u64).to_be_bytes());
+    fdt.property("reg", &reg);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+// Creates a guest ready to run from its configuration.
+//
+// The kernel image is loaded at the start of guest RAM, which is also the entry
+// point; the device tree is loaded at the end of guest RAM, generated from the
+// configuration if not provided.
+// Host frames of the guest come from `frame_alloc` within the guest's quota.
+pub fn create_guest<A: FrameAllocator + Clone>(
+    config: GuestConfig,
+    frame_alloc: A,
+) -> Result<Vm<LimitedFrameAllocator<A>>, CreateError> {
+    let frame_alloc = LimitedFrameAllocator::new(frame_alloc, config.frame_quota);
+    let frame_mask = GUEST_FRAME_SIZE - 1;
+    if config.ram_base.0 & frame_mask != 0 || config.ram_size & frame_mask != 0 {
+        return Err(CreateError::MisalignedRam);
+    }
+    if config.cpu_count == 0 {
+        return Err(CreateError::InvalidCpuCount);
+    }
+    let generated;
+    let dtb = match config.dtb {
+        Some(dtb) => dtb,
+        None => {
+            generated = minimal_fdt(&config);
+            &generated
+        }
+    };
+    let dtb_size = (dtb.len() + frame_mask) & !frame_mask;
+    if config.kernel_image.len() + dtb_size > config.ram_size {
+        return Err(CreateError::ImageTooLarge);
+    }
+    let mut layout = GuestMemoryLayout::new();
+    layout.add_region(config.ram_base, config.ram_size, RegionKind::Ram)?;
+    let space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc.clone())?;
+    let mut vm = Vm {
+        space,
+        ram_frames: Vec::new(),
+        cpu_count: config.cpu_count,
+        entry: config.ram_base,
+        dtb: None,
+        layout,
+        zero_pool: ZeroedFramePool::new_in(
+            ZERO_POOL_CAPACITY,
+            ZERO_POOL_LOW_WATER,
+            frame_alloc.clone(),
+        ),
+        frame_alloc,
+    };
+    if !config.lazy_ram {
+        for addr in
+            (config.ram_base.0..config.ram_base.0 + config.ram_size).step_by(GUEST_FRAME_SIZE)
+        {
+            vm.map_ram_page(GuestPhysAddr(addr))?;
+        }
+    }
+    vm.load(config.ram_base, config.kernel_image)?;
+    let dtb_gpa = GuestPhysAddr(config.ram_base.0 + config.ram_size - dtb_size);
+    vm.load(dtb_gpa, dtb)?;
+    vm.dtb = Some(dtb_gpa);
+    Ok(vm)
+}
+
+// Maps guest RAM onto one host-physically contiguous run of frames.
+//
+// Devices doing DMA for the guest see host physical addresses, so their guest
+// RAM must be linearly backed. Returns the host page number of the run, e.g. to
+// program an IOMMU; the caller owns the run and frees it with the allocator.
+pub fn map_contiguous_ram<A: FrameAllocator + Clone>(
+    space: &mut PagedAddrSpace<Sv39x4, A>,
+    gpa_base: GuestPhysAddr,
+    size: usize,
+    frame_alloc: A,
+) -> Result<PhysPageNum, CreateError> {
+    let frame_mask = GUEST_FRAME_SIZE - 1;
+    if gpa_base.0 & frame_mask != 0 || size & frame_mask != 0 {
+        return Err(CreateError::MisalignedRam);
+    }
+    let count = size / GUEST_FRAME_SIZE;
+    let base = frame_alloc.allocate_frames(count, 0)?;
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U;
+    let gpa = VirtAddr(gpa_base.0)..VirtAddr(gpa_base.0 + size);
+    if let Err(e) = space.map_range(gpa, base.addr_begin::<Sv39x4>(), flags) {
+        frame_alloc.deallocate_frames(base, count);
+        return Err(e.into());
+    }
+    Ok(base)
+}
+
+impl<A: FrameAllocator + Clone> Vm<LimitedFrameAllocator<A>> {
+    // host frames used by this guest, including G-stage page tables
+    pub fn frames_used(&self) -> usize {
+        self.frame_alloc.used()
+    }
+    // maximum number of host frames for this guest
+    pub fn frame_quota(&self) -> usize {
+        self.frame_alloc.limit()
+    }
+}
+
+impl<A: FrameAllocator + Clone> Vm<A> {
+    // Guest physical address where the guest starts to run
+    pub fn entry(&self) -> GuestPhysAddr {
+        self.entry
+    }
+    // Guest physical address of the device tree
+    pub fn dtb(&self) -> Option<GuestPhysAddr> {
+        self.dtb
+    }
+    pub fn cpu_count(&self) -> usize {
+        self.cpu_count
+    }
+    // value of `hgatp` to run this guest in Sv39x4 mode tagged with `vmid`
+    pub fn hgatp(&self, vmid: usize) -> usize {
+        let root_ppn = self.space.root_page_number().addr_begin::<Sv39x4>().0 >> 12;
+        (HGATP_MODE_SV39X4 << 60) | ((vmid & HGATP_VMID_MASK) << 44) | root_ppn
+    }
+    // G-stage address space of this guest
+    pub fn space(&self) -> &PagedAddrSpace<Sv39x4, A> {
+        &self.space
+    }
+    // Guest physical memory layout, including the RAM region
+    pub fn layout(&self) -> &GuestMemoryLayout {
+        &self.layout
+    }
+    // Adds a non-RAM region, e.g. device registers, to the guest.
+    //
+    // ROM regions must be aligned to guest frames; they are backed by zeroed
+    // host frames right away, mapped read-only and executable. If backing
+    // fails, the region is not added.
+    pub fn add_region(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        kind: RegionKind,
+    ) -> Result<(), CreateError> {
+        let frame_mask = GUEST_FRAME_SIZE - 1;
+        if kind == RegionKind::Rom && (start.0 & frame_mask != 0 || size & frame_mask != 0) {
+            return Err(CreateError::MisalignedRam);
+        }
+        self.layout.add_region(start, size, kind)?;
+        if kind != RegionKind::Rom {
+            return Ok(());
+        }
+        let mapped = self.ram_frames.len();
+        let flags = Sv39Flags::R | Sv39Flags::X | Sv39Flags::U;
+        for addr in (start.0..start.0 + size).step_by(GUEST_FRAME_SIZE) {
+            if let Err(e) = self.map_zeroed_page(GuestPhysAddr(addr), flags) {
+                // unmap pages of this region mapped so far, and forget the region
+                let pages = self.ram_frames.len() - mapped;
+                let vpn = start.page_number::<Sv39x4>();
+                let unmapped = self.space.deallocate_map(vpn, pages);
+                unmapped.expect("unmap ROM pages just mapped");
+                self.ram_frames.truncate(mapped);
+                self.layout.remove_region(start);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    // Hot-adds a guest RAM region to a running guest.
+    //
+    // Pages of the region are mapped lazily on first access, like lazy boot
+    // RAM, within the guest's frame quota. The device tree of the guest is not
+    // updated; the guest learns of the region through its hotplug mechanism.
+    pub fn add_memory(&mut self, gpa: GuestPhysAddr, size: usize) -> Result<(), CreateError> {
+        let frame_mask = GUEST_FRAME_SIZE - 1;
+        if gpa.0 & frame_mask != 0 || size & frame_mask != 0 || size == 0 {
+            return Err(CreateError::MisalignedRam);
+        }
+        self.layout.add_region(gpa, size, RegionKind::Ram)
+    }
+    // Handles a guest page fault by the region it falls in.
+    //
+    // Faults in RAM get host frames, or report out of memory once the guest
+    // has used up its frame quota. Loads and stores in device regions are
+    // decoded for device emulation, from `htinst` or else from the guest
+    // instruction at `sepc` of `ctx`; instruction fetches from devices, and
+    // accesses that cannot be decoded, are access faults. ROM is mapped
+    // read-only when added, so faults in ROM are stores, which are access
+    // faults like any access to holes.
+    pub fn handle_guest_page_fault(
+        &mut self,
+        fault: &GuestPageFault,
+        ctx: &GuestContext,
+    ) -> Result<FaultAction, CreateError> {
+        let region = self.layout.region_at(fault.gpa).copied();
+        match region.map(|r| (r.kind, r.start)) {
+            Some((RegionKind::Ram, _)) => match self.map_ram_page(fault.gpa) {
+                Ok(_) => Ok(FaultAction::MappedRam),
+                Err(CreateError::FrameAllocFailed) => Ok(FaultAction::OutOfMemory),
+                Err(e) => Err(e),
+            },
+            Some((RegionKind::Mmio { device }, start)) => {
+                match self.decode_fault_access(fault, ctx) {
+                    Some(access) => Ok(FaultAction::EmulateMmio {
+                        device,
+                        offset: fault.gpa.0 - start.0,
+                        access,
+                    }),
+                    None => Ok(FaultAction::InjectAccessFault),
+                }
+            }
+            Some((RegionKind::Rom, _)) | Some((RegionKind::Hole, _)) | None => {
+                Ok(FaultAction::InjectAccessFault)
+            }
+        }
+    }
+    // Decodes the load or store of a guest page fault.
+    //
+    // Uses the transformed instruction in `htinst` if the hardware provides
+    // one; if `htinst` is zero or a pseudo-instruction, the trapping
+    // instruction is fetched from guest memory at `sepc` of `ctx` through the
+    // VS-stage page tables. Returns None for instruction fetch faults, if the
+    // instruction cannot be fetched, or if it is not a load or store matching
+    // the fault.
+    pub fn decode_fault_access(
+        &self,
+        fault: &GuestPageFault,
+        ctx: &GuestContext,
+    ) -> Option<DecodedAccess> {
+        let is_store = match fault.kind {
+            GuestFaultKind::Fetch => return None,
+            GuestFaultKind::Load => false,
+            GuestFaultKind::Store => true,
+        };
+        let access = match decode_htinst(fault.htinst) {
+            Some(access) => access,
+            None => {
+                let insn = self.fetch_guest_insn(ctx).ok()?;
+                decode_htinst(transform_fetched_insn(insn)?)?
+            }
+        };
+        Some(access).filter(|a| a.is_store == is_store)
+    }
+    // reads the guest instruction at `sepc`, halfword by halfword as a 32-bit
+    // instruction may cross a page boundary
+    fn fetch_guest_insn(&self, ctx: &GuestContext) -> Result<usize, PageError> {
+        let mut half = [0u8; 2];
+        let gpa = self.translate_guest_va(ctx.vsatp, ctx.sepc)?;
+        self.read_guest(gpa, &mut half)?;
+        let low = u16::from_le_bytes(half) as usize;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        let gpa = self.translate_guest_va(ctx.vsatp, ctx.sepc.wrapping_add(2))?;
+        self.read_guest(gpa, &mut half)?;
+        Ok(low | (u16::from_le_bytes(half) as usize) << 16)
+    }
+    // Translates a guest virtual address by the guest page tables of `vsatp`.
+    //
+    // Walks the page tables in guest memory without checking permissions or
+    // updating A and D bits; only used to fetch instructions the guest has
+    // just executed.
+    fn translate_guest_va(&self, vsatp: usize, gva: usize) -> Result<GuestPhysAddr, PageError> {
+        let levels = match vsatp >> 60 {
+            VSATP_MODE_BARE => return Ok(GuestPhysAddr(gva)),
+            VSATP_MODE_SV39 => 3,
+            VSATP_MODE_SV48 => 4,
+            _ => return Err(PageError::InvalidEntry),
+        };
+        let mut table = (vsatp & ((1 << 44) - 1)) << 12;
+        for level in (0..levels).rev() {
+            let idx = (gva >> (12 + 9 * level)) & 511;
+            let mut bytes = [0u8; 8];
+            self.read_guest(GuestPhysAddr(table + idx * 8), &mut bytes)?;
+            let pte = usize::from_le_bytes(bytes);
+            if pte & VS_PTE_V == 0 {
+                return Err(PageError::InvalidEntry);
+            }
+            let base = ((pte >> 10) & ((1 << 44) - 1)) << 12;
+            if pte & (VS_PTE_R | VS_PTE_X) != 0 {
+                let mask = (1 << (12 + 9 * level)) - 1;
+                return Ok(GuestPhysAddr((base & !mask) | (gva & mask)));
+            }
+            table = base;
+        }
+        Err(PageError::NotLeafInLowestPage)
+    }
+    // Backs a guest RAM page with a zeroed host frame.
+    //
+    // Used on creation, or on the first access to a lazily mapped guest page,
+    // in boot RAM or in hot-added RAM.
+    // Frames come from the pre-zeroed pool if any, or are zeroed inline.
+    pub fn map_ram_page(&mut self, gpa: GuestPhysAddr) -> Result<PhysPageNum, CreateError> {
+        if self.layout.region_kind(gpa) != RegionKind::Ram {
+            return Err(CreateError::OutOfRam);
+        }
+        let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U;
+        self.map_zeroed_page(gpa, flags)
+    }
+    // backs a guest page with a zeroed host frame mapped with `flags`
+    fn map_zeroed_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        flags: Sv39Flags,
+    ) -> Result<PhysPageNum, CreateError> {
+        let frame = match self.zero_pool.take() {
+            Some(frame) => frame,
+            None => FrameBox::try_new_zeroed_in(self.frame_alloc.clone())?,
+        };
+        let ppn = frame.phys_page_num();
+        self.space
+            .allocate_map(gpa.page_number::<Sv39x4>(), ppn, 1, flags)?;
+        self.ram_frames.push(frame);
+        Ok(ppn)
+    }
+    // Refills the pre-zeroed frame pool if it runs low.
+    //
+    // Kept off the guest page fault path; call it on an idle hart or at the end
+    // of the run loop. Returns the number of frames zeroed.
+    pub fn refill_zero_pool(&mut self) -> Result<usize, CreateError> {
+        if !self.zero_pool.needs_refill() {
+            return Ok(0);
+        }
+        Ok(self.zero_pool.refill()?)
+    }
+    // Copies guest physical memory at `gpa` into `buf`.
+    //
+    // The buffer may span pages; all of them must be mapped, lazy RAM pages
+    // included, or nothing is read.
+    pub fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> Result<(), PageError> {
+        let mut copied = 0;
+        for (host, len) in host_runs(&self.space, gpa, buf.len())? {
+            // note(unsafe): guest frames are identically mapped by hypervisor
+            let src = unsafe { core::slice::from_raw_parts(host as *const u8, len) };
+            buf[copied..copied + len].copy_from_slice(src);
+            copied += len;
+        }
+        Ok(())
+    }
+    // Copies `buf` into guest physical memory at `gpa`.
+    //
+    // The buffer may span pages; all of them must be mapped, or nothing is
+    // written. Writes are not limited by G-stage permissions, e.g. for ROM.
+    pub fn write_guest(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> Result<(), PageError> {
+        let mut copied = 0;
+        for (host, len) in host_runs(&self.space, gpa, buf.len())? {
+            // note(unsafe): guest frames are identically mapped by hypervisor
+            let dst = unsafe { core::slice::from_raw_parts_mut(host as *mut u8, len) };
+            dst.copy_from_slice(&buf[copied..copied + len]);
+            copied += len;
+        }
+        Ok(())
+    }
+    // copies bytes into guest RAM, mapping lazy pages on the way
+    fn load(&mut self, gpa: GuestPhysAddr, bytes: &[u8]) -> Result<(), CreateError> {
+        let end = gpa.0 + bytes.len();
+        for addr in (gpa.0 & !(GUEST_FRAME_SIZE - 1)..end).step_by(GUEST_FRAME_SIZE) {
+            let vpn = GuestPhysAddr(addr).page_number::<Sv39x4>();
+            if self.space.find_ppn(vpn).is_err() {
+                self.map_ram_page(GuestPhysAddr(addr))?;
+            }
+        }
+        let copied = Cell::new(0);
+        mm::translate_frame_read(
+            &self.space,
+            VirtAddr(gpa.0),
+            bytes.len(),
+            |ppn, offset, len| {
+                let src = &bytes[copied.get()..copied.get() + len];
+                let dst = (ppn.addr_begin::<Sv39x4>().0 + offset) as *mut u8;
+                // note(unsafe): guest frames are identically mapped by hypervisor
+                unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, len) };
+                copied.set(copied.get() + len);
+            },
+        )?;
+        Ok(())
+    }
+}
+
+// Saved registers of a guest hart
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct GuestContext {
+    /// General purpose registers, indexed by register number; x[0] holds the
+    /// host stack pointer while the guest runs
+    pub x: [usize; 32],
+    /// Guest program counter to resume from
+    pub sepc: usize,
+    /// Host `sstatus` to resume guest with; field SPP is guest privilege, VS or VU
+    pub sstatus: usize,
+    /// Virtual supervisor trap registers of guest
+    pub vs: VsTrapCsrs,
+    /// Interrupts injected into guest, loaded to `hvip` when guest runs
+    pub hvip: usize,
+    /// Guest interrupt enables, loaded to `hie` when guest runs
+    pub hie: usize,
+    /// Guest address translation and protection, loaded to `vsatp` when guest runs
+    pub vsatp: usize,
+    /// Device tree passed to guest on boot
+    pub dtb: Option<GuestPhysAddr>,
+}
+
+// Virtual supervisor trap registers, loaded to hardware when guest runs
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct VsTrapCsrs {
+    pub vsstatus: usize,
+    pub vstvec: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+}
+
+bitflags::bitflags! {
+    // Interrupts of a guest hart, in bit positions of guest `sip` and `sie`
+    pub struct VirtIntSet: usize {
+        const SOFTWARE = 1 << 1;
+        const TIMER = 1 << 5;
+        const EXTERNAL = 1 << 9;
+    }
+}
+
+impl VirtIntSet {
+    // VS-level interrupts in `hvip`, `hip` and `hie` are one bit above their
+    // supervisor-level positions in guest `sip` and `sie`
+    pub fn from_h_bits(bits: usize) -> Self {
+        VirtIntSet::from_bits_truncate(bits >> 1)
+    }
+    pub fn to_h_bits(self) -> usize {
+        self.bits() << 1
+    }
+}
+
+// Interrupts pending for guest, as injected through `hvip`
+pub fn guest_interrupt_pending(ctx: &GuestContext) -> VirtIntSet {
+    VirtIntSet::from_h_bits(ctx.hvip)
+}
+
+// Injects or withdraws pending guest interrupts; other interrupts are left as is
+pub fn set_guest_interrupt_pending(ctx: &mut GuestContext, set: VirtIntSet) {
+    let mask = VirtIntSet::all().to_h_bits();
+    ctx.hvip = (ctx.hvip & !mask) | set.to_h_bits();
+}
+
+// Interrupts enabled for guest, as in `hie`
+pub fn guest_interrupt_enable(ctx: &GuestContext) -> VirtIntSet {
+    VirtIntSet::from_h_bits(ctx.hie)
+}
+
+// Sets which guest interrupts are enabled
+pub fn set_guest_interrupt_enable(ctx: &mut GuestContext, set: VirtIntSet) {
+    let mask = VirtIntSet::all().to_h_bits();
+    ctx.hie = (ctx.hie & !mask) | set.to_h_bits();
+}
+
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+// fields of `hstatus` giving the mode `sret` returns to
+const HSTATUS_SPV: usize = 1 << 7;
+const HSTATUS_SPVP: usize = 1 << 8;
+
+impl GuestContext {
+    // a guest hart context starting from `entry`
+    pub fn new(entry: GuestPhysAddr) -> Self {
+        GuestContext {
+            x: [0; 32],
+            sepc: entry.0,
+            sstatus: SSTATUS_SPP, // guest boots in VS mode
+            vs: VsTrapCsrs::default(),
+            hvip: 0,
+            hie: 0,
+            vsatp: 0, // bare, guest boots without paging
+            dtb: None,
+        }
+    }
+}
+
+// Reflects an exception the hypervisor won't handle back into guest.
+//
+// Works like a hardware trap into VS mode: the faulting guest pc is saved into
+// `vsepc`, previous privilege and interrupt enable are saved into `vsstatus`,
+// and the guest resumes at its trap vector in VS mode. Exceptions always enter
+// the base address of `vstvec`, even in vectored mode.
+pub fn inject_exception(ctx: &mut GuestContext, cause: usize, tval: usize) {
+    let vs = &mut ctx.vs;
+    vs.vsepc = ctx.sepc;
+    vs.vscause = cause;
+    vs.vstval = tval;
+    let mut vsstatus = vs.vsstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+    if ctx.sstatus & SSTATUS_SPP != 0 {
+        vsstatus |= SSTATUS_SPP;
+    }
+    if vs.vsstatus & SSTATUS_SIE != 0 {
+        vsstatus |= SSTATUS_SPIE;
+    }
+    vs.vsstatus = vsstatus;
+    ctx.sepc = vs.vstvec & !0b11;
+    ctx.sstatus |= SSTATUS_SPP; // guest trap handler runs in VS mode
+}
+
+// Sets up boot arguments of a guest hart.
+//
+// Guest kernels expect `a0` to be hart id and `a1` to be the device tree
+// address on entry. The device tree must be mapped in guest address space.
+pub fn setup_boot_args<A: FrameAllocator + Clone>(
+    ctx: &mut GuestContext,
+    space: &PagedAddrSpace<Sv39x4, A>,
+    hartid: usize,
+    dtb_gpa: GuestPhysAddr,
+) -> Result<(), PageError> {
+    space.find_ppn(dtb_gpa.page_number::<Sv39x4>())?;
+    ctx.x[10] = hartid; // a0
+    ctx.x[11] = dtb_gpa.0; // a1
+    ctx.dtb = Some(dtb_gpa);
+    Ok(())
+}
+
+// Image of a guest that only waits for interrupts: `wfi; j .`
+pub const WFI_GUEST_IMAGE: [u8; 8] = [0x73, 0x00, 0x50, 0x10, 0x6f, 0x00, 0x00, 0x00];
+
+// Creates a minimal guest for exercising guest entry and exit.
+//
+// Its first page of RAM holds `WFI_GUEST_IMAGE` at the entry point, mapped
+// executable, and the second its generated device tree; a context from
+// `GuestContext::new(vm.entry())` runs it with `run_guest` in VS mode until
+// an interrupt, e.g. the host timer, causes a guest exit.
+pub fn test_guest_wfi<A: FrameAllocator + Clone>(frame_alloc: A) -> Vm<LimitedFrameAllocator<A>> {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 2 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &WFI_GUEST_IMAGE,
+        dtb: None,
+        lazy_ram: false,
+        frame_quota: usize::MAX,
+    };
+    create_guest(config, frame_alloc).expect("create wfi guest")
+}
+
+// interrupt bit and supervisor timer interrupt code in `scause`
+const SCAUSE_INTERRUPT: usize = 1 << (usize::BITS - 1);
+const SUPERVISOR_TIMER_INTERRUPT: usize = 5;
+// host timer ticks before the wfi guest is interrupted in its test
+const GUEST_TIMER_TEST_TICKS: u64 = 100_000;
+
+// VS-stage translation modes in field MODE of `vsatp`
+const VSATP_MODE_BARE: usize = 0;
+const VSATP_MODE_SV39: usize = 8;
+const VSATP_MODE_SV48: usize = 9;
+
+// Flags of VS-stage page table entries read when walking guest page tables
+const VS_PTE_V: usize = 1 << 0;
+const VS_PTE_R: usize = 1 << 1;
+const VS_PTE_X: usize = 1 << 3;
+
+// Flushes VS-stage translations of current guest on this hart
+pub type FenceFn = fn();
+
+fn hfence_vvma_all() {
+    mm::hfence_vvma(None, None)
+}
+
+// Handles a guest write to its `satp`, trapped while `hstatus.VTVM` is set.
+//
+// With two-stage translation the guest manages its own page tables in guest
+// RAM; the hypervisor only checks that the new root table lies in guest RAM,
+// and backs it with a host frame if it is lazily mapped. The write is rejected
+// with `OutOfRam` otherwise, and with `Page(InvalidEntry)` on unsupported
+// modes, leaving the context untouched. Stale guest translations of the
+// previous tables are flushed on success.
+pub fn on_guest_satp_write<A: FrameAllocator + Clone>(
+    vm: &mut Vm<A>,
+    ctx: &mut GuestContext,
+    new_vsatp: usize,
+) -> Result<(), CreateError> {
+    on_guest_satp_write_with(vm, ctx, new_vsatp, hfence_vvma_all)
+}
+
+// Handles a guest `satp` write, flushing guest translations by `fence`
+pub fn on_guest_satp_write_with<A: FrameAllocator + Clone>(
+    vm: &mut Vm<A>,
+    ctx: &mut GuestContext,
+    new_vsatp: usize,
+    fence: FenceFn,
+) -> Result<(), CreateError> {
+    match new_vsatp >> 60 {
+        VSATP_MODE_BARE => {}
+        VSATP_MODE_SV39 | VSATP_MODE_SV48 => {
+            let root = GuestPhysAddr((new_vsatp & ((1 << 44) - 1)) << 12);
+            if vm.layout.region_kind(root) != RegionKind::Ram {
+                return Err(CreateError::OutOfRam);
+            }
+            if vm.space.find_ppn(root.page_number::<Sv39x4>()).is_err() {
+                vm.map_ram_page(root)?;
+            }
+        }
+        _ => return Err(CreateError::Page(PageError::InvalidEntry)),
+    }
+    ctx.vsatp = new_vsatp;
+    fence();
+    Ok(())
+}
+
+// Reasons for the run loop to return to VM manager
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VmExit {
+    /// Guest requested to shut down or reboot itself through SBI SRST
+    SystemReset { reset_type: ResetType, reason: u32 },
+}
+
+// Reset types of SBI system reset extension
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+// Result of handling a supervisor ecall from guest
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EcallOutcome {
+    /// Return values are written into guest context; resume the guest
+    Resume,
+    /// Leave the run loop
+    Exit(VmExit),
+}
+
+const SBI_ERR_NOT_SUPPORTED: usize = -2_isize as usize;
+const SBI_ERR_INVALID_PARAM: usize = -3_isize as usize;
+
+// Handles a supervisor ecall from guest, i.e. a guest SBI call.
+//
+// SBI calls are never passed through to host SBI implementation as is; for
+// example, a guest system reset must only destroy the guest rather than power
+// off the whole machine. Unsupported calls return `SBI_ERR_NOT_SUPPORTED`.
+pub fn handle_vs_ecall(ctx: &mut GuestContext) -> EcallOutcome {
+    handle_vs_ecall_with(ctx, sbi::forward_call)
+}
+
+// Host SBI call used to forward guest calls, as `sbi::forward_call`
+pub type ForwardFn = fn(usize, usize, [usize; 6]) -> sbi::SbiRet;
+
+// Outcome of a guest SBI call forwarded to host SBI
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Forwarded {
+    /// The call completed, successfully or not; return values go to the guest
+    Complete(sbi::SbiRet),
+    /// The call may be partially done; the guest restarts it
+    Retry,
+}
+
+// Forwards a guest call to host SBI.
+//
+// Calls of the RFENCE extension (EID 0x52464E43) are restartable: remote fences
+// are idempotent, so when host SBI fails a fence with `SBI_ERR_FAILED`, e.g.
+// after fencing only some of the harts, the guest runs the same ecall again.
+// Guest fences apply to VS-stage translation, so SFENCE.VMA functions are
+// forwarded as HFENCE.VVMA; guest hart ids are host hart ids for now.
+// Calls of other extensions are not forwarded.
+fn forward_vs_ecall(ctx: &GuestContext, forward: ForwardFn) -> Forwarded {
+    use sbi::*;
+    let (extension, function) = (ctx.x[17], ctx.x[16]); // a7, a6
+    let args = [
+        ctx.x[10], ctx.x[11], ctx.x[12], ctx.x[13], ctx.x[14], ctx.x[15],
+    ];
+    let host_function = match (extension, function) {
+        (EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_FENCE_I) => FUNCTION_RFENCE_REMOTE_FENCE_I,
+        (EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA) => FUNCTION_RFENCE_REMOTE_HFENCE_VVMA,
+        (EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID) => {
+            FUNCTION_RFENCE_REMOTE_HFENCE_VVMA_ASID
+        }
+        _ => {
+            return Forwarded::Complete(SbiRet {
+                error: SBI_ERR_NOT_SUPPORTED,
+                value: 0,
+            })
+        }
+    };
+    let ret = forward(extension, host_function, args);
+    if ret.error == SBI_ERR_FAILED {
+        Forwarded::Retry
+    } else {
+        Forwarded::Complete(ret)
+    }
+}
+
+// Handles a guest SBI call, forwarding calls to host SBI by `forward`
+pub fn handle_vs_ecall_with(ctx: &mut GuestContext, forward: ForwardFn) -> EcallOutcome {
+    let (extension, function) = (ctx.x[17], ctx.x[16]); // a7, a6
+    let (error, value) = match (extension, function) {
+        (sbi::EXTENSION_SRST, 0) => {
+            let reset_type = match ctx.x[10] as u32 {
+                0 => Some(ResetType::Shutdown),
+                1 => Some(ResetType::ColdReboot),
+                2 => Some(ResetType::WarmReboot),
+                _ => None,
+            };
+            match reset_type {
+                Some(reset_type) => {
+                    let reason = ctx.x[11] as u32;
+                    return EcallOutcome::Exit(VmExit::SystemReset { reset_type, reason });
+                }
+                None => (SBI_ERR_INVALID_PARAM, 0),
+            }
+        }
+        _ => match forward_vs_ecall(ctx, forward) {
+            Forwarded::Complete(ret) => (ret.error, ret.value),
+            // keep `sepc` at the ecall and arguments untouched, so the guest restarts it
+            Forwarded::Retry => return EcallOutcome::Resume,
+        },
+    };
+    ctx.x[10] = error; // a0
+    ctx.x[11] = value; // a1
+    ctx.sepc = ctx.sepc.wrapping_add(4); // skip ecall
+    EcallOutcome::Resume
+}
+
+// Virtual time of a guest hart.
+//
+// Guest time is host time minus an offset, presented to the guest through
+// `htimedelta`. While the guest is descheduled its time is paused: the time
+// spent paused is added to the offset on resume, so the guest sees monotonic
+// time without the gap.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct GuestTimer {
+    // host ticks subtracted from host time
+    offset: u64,
+    // host time when paused, if paused
+    paused_at: Option<u64>,
+}
+
+fn read_host_time() -> u64 {
+    riscv::register::time::read64()
+}
+
+impl GuestTimer {
+    pub const fn new() -> Self {
+        GuestTimer {
+            offset: 0,
+            paused_at: None,
+        }
+    }
+    // pauses guest time when the guest is descheduled
+    pub fn pause(&mut self) {
+        self.pause_at(read_host_time())
+    }
+    // resumes guest time when the guest is scheduled again
+    pub fn resume(&mut self) {
+        self.resume_at(read_host_time())
+    }
+    pub fn pause_at(&mut self, host_time: u64) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(host_time);
+        }
+    }
+    pub fn resume_at(&mut self, host_time: u64) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.offset += host_time.saturating_sub(paused_at);
+        }
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+    // Guest-visible time at given host time; stays where it paused while paused
+    pub fn guest_time(&self, host_time: u64) -> u64 {
+        self.paused_at.unwrap_or(host_time) - self.offset
+    }
+    // Host time when the guest sees `guest_time`, e.g. to program a guest timer deadline
+    pub fn host_time(&self, guest_time: u64) -> u64 {
+        guest_time.saturating_add(self.offset)
+    }
+    // value of `htimedelta` to present guest time when the guest runs
+    pub fn htimedelta(&self) -> usize {
+        (self.offset as usize).wrapping_neg()
+    }
+}
+
+// A guest hart time-sharing this hart
+#[derive(Debug)]
+struct ScheduledGuest {
+    ctx: GuestContext,
+    timer: GuestTimer,
+    hgatp: usize,
+    runnable: bool,
+}
+
+// Hardware state to load when switching to another guest on a timer tick
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GuestSwitch {
+    pub from: Option<usize>,
+    pub to: usize,
+    pub hgatp: usize,
+    pub vsatp: usize,
+    pub htimedelta: usize,
+}
+
+// Round-robin scheduler of guest harts sharing one host hart.
+//
+// On each timer tick the running guest's context is saved and its time is
+// paused, then the next runnable guest after it is selected and its context is
+// restored. Guests are identified by the order they are added.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    guests: Vec<ScheduledGuest>,
+    current: Option<usize>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Scheduler {
+            guests: Vec::new(),
+            current: None,
+        }
+    }
+    // Adds a runnable guest hart with its initial context; returns its identifier
+    pub fn add_guest(&mut self, ctx: GuestContext, hgatp: usize) -> usize {
+        self.guests.push(ScheduledGuest {
+            ctx,
+            timer: GuestTimer::new(),
+            hgatp,
+            runnable: true,
+        });
+        self.guests.len() - 1
+    }
+    // Marks a guest runnable or not, e.g. while it waits in WFI; takes effect on next tick
+    pub fn set_runnable(&mut self, id: usize, runnable: bool) {
+        self.guests[id].runnable = runnable;
+    }
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+    // Saved context of a guest; stale for the running guest until it is switched out
+    pub fn context(&self, id: usize) -> &GuestContext {
+        &self.guests[id].ctx
+    }
+    pub fn timer(&self, id: usize) -> &GuestTimer {
+        &self.guests[id].timer
+    }
+    // Selects the next runnable guest after the running one, wrapping around
+    fn next_runnable(&self) -> Option<usize> {
+        let len = self.guests.len();
+        let start = self.current.map(|id| id + 1).unwrap_or(0);
+        (0..len)
+            .map(|i| (start + i) % len)
+            .find(|&id| self.guests[id].runnable)
+    }
+    // Handles a timer tick on this hart.
+    //
+    // `live` is the context of the running guest, which is saved and replaced
+    // with the context of the next guest. Returns the state to load into
+    // hardware, or `None` if the running guest keeps running or nothing is runnable.
+    pub fn tick_at(&mut self, host_time: u64, live: &mut GuestContext) -> Option<GuestSwitch> {
+        let next = self.next_runnable()?;
+        if Some(next) == self.current {
+            return None;
+        }
+        if let Some(prev) = self.current {
+            let guest = &mut self.guests[prev];
+            guest.ctx = live.clone();
+            guest.timer.pause_at(host_time);
+        }
+        let guest = &mut self.guests[next];
+        guest.timer.resume_at(host_time);
+        *live = guest.ctx.clone();
+        let switch = GuestSwitch {
+            from: self.current,
+            to: next,
+            hgatp: guest.hgatp,
+            vsatp: guest.ctx.vsatp,
+            htimedelta: guest.timer.htimedelta(),
+        };
+        self.current = Some(next);
+        Some(switch)
+    }
+    // Handles a timer tick and loads the next guest's translation and time into hardware
+    #[allow(unused)] // use in the future
+    pub fn tick(&mut self, live: &mut GuestContext) -> Option<GuestSwitch> {
+        let switch = self.tick_at(read_host_time(), live)?;
+        unsafe { load_guest_switch(&switch) };
+        Some(switch)
+    }
+}
+
+// Loads translation and time of the guest switched to.
+//
+// G-stage translations of the previous guest are flushed as a whole, as
+// guests are not yet tagged with distinct VMIDs.
+unsafe fn load_guest_switch(switch: &GuestSwitch) {
+    hcsr::write_hgatp(switch.hgatp);
+    mm::hfence_gvma(None, None);
+    hcsr::write_vsatp(switch.vsatp);
+    hcsr::write_htimedelta(switch.htimedelta);
+}
+
+// Trap CSRs of a guest exit, read right after the guest trapped
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GuestExit {
+    pub scause: usize,
+    pub stval: usize,
+    pub htval: usize,
+    pub htinst: usize,
+}
+
+// Runs a guest hart on current hart until its next trap into the hypervisor.
+//
+// Loads G-stage translation of `vm` tagged with `vmid`, and guest translation
+// and interrupts from `ctx`, then enters the guest at `ctx.sepc` in the mode
+// given by field SPP of `ctx.sstatus`. Traps are not delegated to the guest, so
+// any guest exception exits the guest, as do host interrupts enabled in `sie`,
+// e.g. the host timer. Guest registers are saved back into `ctx` on exit, and
+// the persistent trap handler is back in place when this function returns.
+pub fn run_guest<A: FrameAllocator + Clone>(
+    vm: &Vm<A>,
+    ctx: &mut GuestContext,
+    vmid: usize,
+) -> GuestExit {
+    let spvp = if ctx.sstatus & SSTATUS_SPP != 0 {
+        HSTATUS_SPVP
+    } else {
+        0
+    };
+    let hstatus = (hcsr::read_hstatus().bits() & !HSTATUS_SPVP) | HSTATUS_SPV | spvp;
+    unsafe {
+        hcsr::write_hgatp(vm.hgatp(vmid));
+        mm::hfence_gvma(None, None);
+        hcsr::write_vsatp(ctx.vsatp);
+        hcsr::write_hvip(ctx.hvip);
+        hcsr::write_hie(ctx.hie);
+        hcsr::write_hstatus(hstatus);
+        enter_guest(ctx);
+    }
+    GuestExit {
+        scause: riscv::register::scause::read().bits(),
+        stval: riscv::register::stval::read(),
+        htval: hcsr::read_htval(),
+        htinst: hcsr::read_htinst(),
+    }
+}
+
+// host registers saved by `enter_guest`: ra, gp, tp, s0 to s11, sscratch, stvec and sstatus
+const HOST_FRAME_SIZE: usize = 18 * 8;
+
+// Saves host states and enters the guest of `ctx`.
+//
+// Host callee-saved registers, `sscratch`, `stvec` and `sstatus` are saved on
+// host stack, whose pointer is kept in `ctx.x[0]`. While the guest runs,
+// `sscratch` points to `ctx` and `stvec` to `guest_exit`, which returns to the
+// caller of this function on the next trap from the guest.
+#[naked]
+unsafe extern "C" fn enter_guest(ctx: *mut GuestContext) {
+    asm!(
+        "addi   sp, sp, -{host_frame_size}",
+        "sd     ra, 0*8(sp)",
+        "sd     gp, 1*8(sp)",
+        "sd     tp, 2*8(sp)",
+        "sd     s0, 3*8(sp)",
+        "sd     s1, 4*8(sp)",
+        "sd     s2, 5*8(sp)",
+        "sd     s3, 6*8(sp)",
+        "sd     s4, 7*8(sp)",
+        "sd     s5, 8*8(sp)",
+        "sd     s6, 9*8(sp)",
+        "sd     s7, 10*8(sp)",
+        "sd     s8, 11*8(sp)",
+        "sd     s9, 12*8(sp)",
+        "sd     s10, 13*8(sp)",
+        "sd     s11, 14*8(sp)",
+        "csrr   t0, sscratch",
+        "sd     t0, 15*8(sp)",
+        "csrr   t0, stvec",
+        "sd     t0, 16*8(sp)",
+        "csrr   t0, sstatus",
+        "sd     t0, 17*8(sp)",
+        "sd     sp, 0*8(a0)", // host sp in guest x0
+        "csrw   sscratch, a0",
+        "la     t0, {guest_exit}",
+        "csrw   stvec, t0",
+        "ld     t0, 32*8(a0)",
+        "csrw   sepc, t0",
+        "ld     t0, 33*8(a0)",
+        "csrw   sstatus, t0",
+        "ld     x1, 1*8(a0)",
+        "ld     x2, 2*8(a0)",
+        "ld     x3, 3*8(a0)",
+        "ld     x4, 4*8(a0)",
+        "ld     x5, 5*8(a0)",
+        "ld     x6, 6*8(a0)",
+        "ld     x7, 7*8(a0)",
+        "ld     x8, 8*8(a0)",
+        "ld     x9, 9*8(a0)",
+        "ld     x11, 11*8(a0)",
+        "ld     x12, 12*8(a0)",
+        "ld     x13, 13*8(a0)",
+        "ld     x14, 14*8(a0)",
+        "ld     x15, 15*8(a0)",
+        "ld     x16, 16*8(a0)",
+        "ld     x17, 17*8(a0)",
+        "ld     x18, 18*8(a0)",
+        "ld     x19, 19*8(a0)",
+        "ld     x20, 20*8(a0)",
+        "ld     x21, 21*8(a0)",
+        "ld     x22, 22*8(a0)",
+        "ld     x23, 23*8(a0)",
+        "ld     x24, 24*8(a0)",
+        "ld     x25, 25*8(a0)",
+        "ld     x26, 26*8(a0)",
+        "ld     x27, 27*8(a0)",
+        "ld     x28, 28*8(a0)",
+        "ld     x29, 29*8(a0)",
+        "ld     x30, 30*8(a0)",
+        "ld     x31, 31*8(a0)",
+        "ld     x10, 10*8(a0)",
+        "sret",
+        host_frame_size = const HOST_FRAME_SIZE,
+        guest_exit = sym guest_exit,
+        options(noreturn),
+    )
+}
+
+// Trap vector while a guest runs.
+//
+// Saves guest registers, `sepc` and `sstatus` into the context in `sscratch`,
+// restores host states saved by `enter_guest` and returns to its caller.
+#[naked]
+unsafe extern "C" fn guest_exit() -> ! {
+    asm!(
+        ".p2align 2",
+        "csrrw  a0, sscratch, a0",
+        "sd     x1, 1*8(a0)",
+        "sd     x2, 2*8(a0)",
+        "sd     x3, 3*8(a0)",
+        "sd     x4, 4*8(a0)",
+        "sd     x5, 5*8(a0)",
+        "sd     x6, 6*8(a0)",
+        "sd     x7, 7*8(a0)",
+        "sd     x8, 8*8(a0)",
+        "sd     x9, 9*8(a0)",
+        "sd     x11, 11*8(a0)",
+        "sd     x12, 12*8(a0)",
+        "sd     x13, 13*8(a0)",
+        "sd     x14, 14*8(a0)",
+        "sd     x15, 15*8(a0)",
+        "sd     x16, 16*8(a0)",
+        "sd     x17, 17*8(a0)",
+        "sd     x18, 18*8(a0)",
+        "sd     x19, 19*8(a0)",
+        "sd     x20, 20*8(a0)",
+        "sd     x21, 21*8(a0)",
+        "sd     x22, 22*8(a0)",
+        "sd     x23, 23*8(a0)",
+        "sd     x24, 24*8(a0)",
+        "sd     x25, 25*8(a0)",
+        "sd     x26, 26*8(a0)",
+        "sd     x27, 27*8(a0)",
+        "sd     x28, 28*8(a0)",
+        "sd     x29, 29*8(a0)",
+        "sd     x30, 30*8(a0)",
+        "sd     x31, 31*8(a0)",
+        "csrr   t0, sscratch",
+        "sd     t0, 10*8(a0)", // guest a0
+        "csrr   t0, sepc",
+        "sd     t0, 32*8(a0)",
+        "csrr   t0, sstatus",
+        "sd     t0, 33*8(a0)",
+        "ld     sp, 0*8(a0)",
+        "ld     t0, 15*8(sp)",
+        "csrw   sscratch, t0",
+        "ld     t0, 16*8(sp)",
+        "csrw   stvec, t0",
+        "ld     t0, 17*8(sp)",
+        "csrw   sstatus, t0",
+        "ld     ra, 0*8(sp)",
+        "ld     gp, 1*8(sp)",
+        "ld     tp, 2*8(sp)",
+        "ld     s0, 3*8(sp)",
+        "ld     s1, 4*8(sp)",
+        "ld     s2, 5*8(sp)",
+        "ld     s3, 6*8(sp)",
+        "ld     s4, 7*8(sp)",
+        "ld     s5, 8*8(sp)",
+        "ld     s6, 9*8(sp)",
+        "ld     s7, 10*8(sp)",
+        "ld     s8, 11*8(sp)",
+        "ld     s9, 12*8(sp)",
+        "ld     s10, 13*8(sp)",
+        "ld     s11, 14*8(sp)",
+        "addi   sp, sp, {host_frame_size}",
+        "ret",
+        host_frame_size = const HOST_FRAME_SIZE,
+        options(noreturn),
+    )
+}
+
+// An event in the run loop of a guest hart
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GuestEvent {
+    /// Guest trapped into the hypervisor
+    Exit {
+        scause: usize,
+        sepc: usize,
+        stval: usize,
+        htval: usize,
+        htinst: usize,
+    },
+    /// An exception was reflected into the guest by `inject_exception`
+    InjectedException { cause: usize, tval: usize },
+    /// Interrupts were made pending to the guest
+    InjectedInterrupt(VirtIntSet),
+}
+
+// An event with the cycle count when it was recorded
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LoggedEvent {
+    pub cycle: usize,
+    pub event: GuestEvent,
+}
+
+// Number of recent events kept by an event log
+pub const EVENT_LOG_SIZE: usize = 64;
+
+// Recent events of a guest hart, kept in a fixed-size ring buffer.
+//
+// Each guest hart owns its log and records into it from the run loop without
+// locking; once the ring is full, the oldest event is overwritten. Dump the log
+// when the guest misbehaves to see a timeline of what it was doing.
+#[derive(Clone, Debug)]
+pub struct EventLog {
+    events: [Option<LoggedEvent>; EVENT_LOG_SIZE],
+    // slot to write the next event into
+    next: usize,
+}
+
+fn read_cycle() -> usize {
+    riscv::register::cycle::read()
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        EventLog {
+            events: [None; EVENT_LOG_SIZE],
+            next: 0,
+        }
+    }
+    // records an event at current cycle count
+    pub fn record(&mut self, event: GuestEvent) {
+        self.record_at(read_cycle(), event)
+    }
+    // records an event at given cycle count
+    pub fn record_at(&mut self, cycle: usize, event: GuestEvent) {
+        self.events[self.next] = Some(LoggedEvent { cycle, event });
+        self.next = (self.next + 1) % EVENT_LOG_SIZE;
+    }
+    // recorded events, from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &LoggedEvent> + '_ {
+        let (newer, older) = self.events.split_at(self.next);
+        older.iter().chain(newer).filter_map(|e| e.as_ref())
+    }
+    pub fn clear(&mut self) {
+        *self = EventLog::new();
+    }
+    // prints recorded events, from the oldest to the newest
+    pub fn dump(&self) {
+        let mut ans = alloc::string::String::new();
+        self.dump_to(&mut ans).unwrap();
+        crate::print!("{}", ans);
+    }
+    fn dump_to(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        for LoggedEvent { cycle, event } in self.iter() {
+            write!(w, "[{:>12}] ", cycle)?;
+            match event {
+                GuestEvent::Exit {
+                    scause,
+                    sepc,
+                    stval,
+                    htval,
+                    htinst,
+                } => writeln!(
+                    w,
+                    "exit scause={:#x} sepc={:#x} stval={:#x} htval={:#x} htinst={:#x}",
+                    scause, sepc, stval, htval, htinst
+                )?,
+                GuestEvent::InjectedException { cause, tval } => {
+                    writeln!(w, "inject exception cause={:#x} tval={:#x}", cause, tval)?
+                }
+                GuestEvent::InjectedInterrupt(set) => writeln!(w, "inject interrupt {:?}", set)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// A guest buffer viewed from the hypervisor without copying
+#[derive(Debug)]
+pub enum GuestSlice<'a> {
+    /// Buffer backed by one host-physically contiguous run
+    Contiguous(&'a [u8]),
+    /// Buffer fragmented over host frames, in guest address order
+    Chunked(alloc::vec::IntoIter<&'a [u8]>),
+}
+
+// Views a guest buffer in place.
+//
+// Returns a borrowed slice if the buffer lies in one contiguous host-physical
+// run, which is the common case; otherwise returns its chunks in order.
+// Guest frames must be identically mapped by hypervisor, and the guest must not
+// modify the buffer while it's being viewed.
+pub fn guest_slice<'a, A: FrameAllocator + Clone>(
+    space: &'a PagedAddrSpace<Sv39x4, A>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> Result<GuestSlice<'a>, PageError> {
+    let runs = host_runs(space, gpa, len)?;
+    // note(unsafe): guest frames are identically mapped by hypervisor
+    let frame_slice = |(addr, len)| unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    match runs.as_slice() {
+        [] => Ok(GuestSlice::Contiguous(&[])),
+        &[run] => Ok(GuestSlice::Contiguous(frame_slice(run))),
+        _ => {
+            let chunks: Vec<&'a [u8]> = runs.into_iter().map(frame_slice).collect();
+            Ok(GuestSlice::Chunked(chunks.into_iter()))
+        }
+    }
+}
+
+// Host (address, length) of each host-physically contiguous run of a guest buffer.
+//
+// Fails if any page of the buffer is not mapped, before anything is accessed.
+fn host_runs<A: FrameAllocator + Clone>(
+    space: &PagedAddrSpace<Sv39x4, A>,
+    gpa: GuestPhysAddr,
+    len: usize,
+) -> Result<Vec<(usize, usize)>, PageError> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    let mut cur = gpa.0;
+    let end = gpa.0.checked_add(len).ok_or(PageError::InvalidRange)?;
+    while cur < end {
+        let vpn = GuestPhysAddr(cur).page_number::<Sv39x4>();
+        let (entry, lvl) = space.find_ppn(vpn)?;
+        // a NAPOT entry covers several pages; its ppn is the base of the whole range
+        let range = Sv39x4::entry_leaf_va_range(entry, vpn, lvl);
+        let offset = cur - range.start.addr_begin::<Sv39x4>().0;
+        let host = entry.ppn().addr_begin::<Sv39x4>().0 + offset;
+        let run_len = usize::min(end - cur, range.end.addr_begin::<Sv39x4>().0 - cur);
+        match runs.last_mut() {
+            Some((addr, len)) if *addr + *len == host => *len += run_len,
+            _ => runs.push((host, run_len)),
+        }
+        cur += run_len;
+    }
+    Ok(runs)
+}
+
+// How dirty guest pages are tracked
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DirtyTracking {
+    /// Hardware sets D bits on store (Svadu); clear D bits and scan them later
+    HardwareDirtyBits,
+    /// Write-protect guest pages and record the first store fault, as `DirtyLog` does
+    WriteProtect,
+}
+
+// Chooses dirty tracking strategy by hardware A/D bit update support.
+//
+// With hardware update, dirty logging costs no traps at all; without it (Svade),
+// clearing D bits would make every first store fault anyway, so write protection
+// is used, which also works when D bits are never set by hardware.
+pub fn dirty_tracking_strategy(hw_ad_update: bool) -> DirtyTracking {
+    if hw_ad_update {
+        DirtyTracking::HardwareDirtyBits
+    } else {
+        DirtyTracking::WriteProtect
+    }
+}
+
+// Dirty page log of a guest address space.
+//
+// When logging is enabled, writable guest pages are mapped read-only; the first
+// store to a logged page traps into the hypervisor, which records the page as
+// dirty and grants write permission again.
+#[derive(Debug)]
+pub struct DirtyLog {
+    // write protected leaf mappings sorted by address: (start, size in bytes)
+    logged: Vec<(GuestPhysAddr, usize)>,
+    // first guest physical address covered by the bitmap
+    base: GuestPhysAddr,
+    // one bit for every guest frame from `base`
+    bitmap: Vec<u64>,
+}
+
+// Starts dirty logging on a guest address space.
+//
+// All writable leaf mappings are changed into read-only. The caller must flush
+// the G-stage TLB of this guest after this function returns.
+pub fn enable_dirty_logging<A: FrameAllocator + Clone>(
+    space: &mut PagedAddrSpace<Sv39x4, A>,
+) -> DirtyLog {
+    let mut logged = Vec::new();
+    space.visit_leaves_mut(|vpn, level, entry| {
+        let flags = entry.flags();
+        if flags.contains(Sv39Flags::W) {
+            entry.write_ppn_flags(entry.ppn(), flags - Sv39Flags::W);
+            let start = GuestPhysAddr(vpn.addr_begin::<Sv39x4>().0);
+            let size = Sv39x4::get_layout_for_level(level).page_size::<Sv39x4>();
+            logged.push((start, size));
+        }
+    });
+    let (base, frames) = match (logged.first(), logged.last()) {
+        (Some(&(first, _)), Some(&(last, size))) => {
+            (first, (last.0 + size - first.0) / GUEST_FRAME_SIZE)
+        }
+        _ => (GuestPhysAddr(0), 0),
+    };
+    DirtyLog {
+        logged,
+        base,
+        bitmap: vec![0; frames.div_ceil(64)],
+    }
+}
+
+impl DirtyLog {
+    // Handles a guest store page fault under dirty logging.
+    //
+    // Returns true if the faulting page is logged: the page is recorded as dirty
+    // and its leaf mapping is writable again. Returns false if the page is not
+    // logged, in which case the caller should inject the fault into the guest.
+    // Every entry of a NAPOT leaf is made writable, as they must stay identical.
+    pub fn handle_store_fault<A: FrameAllocator + Clone>(
+        &mut self,
+        space: &mut PagedAddrSpace<Sv39x4, A>,
+        gpa: GuestPhysAddr,
+    ) -> Result<bool, PageError> {
+        if self.find_logged(gpa).is_none() {
+            return Ok(false);
+        }
+        let vpn = gpa.page_number::<Sv39x4>();
+        let (entry, lvl) = space.find_entry_mut(vpn)?;
+        let leaf = Sv39x4::entry_leaf_va_range(entry, vpn, lvl);
+        let mut cur = leaf.start;
+        while cur != leaf.end {
+            let (entry, _lvl) = space.find_entry_mut(cur)?;
+            entry.write_ppn_flags(entry.ppn(), entry.flags() | Sv39Flags::W);
+            cur = cur.next_page_by_level::<Sv39x4>(lvl);
+        }
+        mm::hfence_gvma(Some(gpa.0), None);
+        // the whole leaf is writable now, every frame it covers may become dirty
+        let (start, end) = (
+            leaf.start.addr_begin::<Sv39x4>().0,
+            leaf.end.addr_begin::<Sv39x4>().0,
+        );
+        for addr in (start..end).step_by(GUEST_FRAME_SIZE) {
+            let idx = (addr - self.base.0) / GUEST_FRAME_SIZE;
+            self.bitmap[idx / 64] |= 1 << (idx % 64);
+        }
+        Ok(true)
+    }
+
+    // Takes all dirty pages recorded since the last call.
+    //
+    // Taken pages are write protected again and flushed from the G-stage TLB,
+    // so that the next store to any of them is logged again.
+    pub fn take_dirty_set<A: FrameAllocator + Clone>(
+        &mut self,
+        space: &mut PagedAddrSpace<Sv39x4, A>,
+    ) -> Result<Vec<GuestPhysAddr>, PageError> {
+        let mut ans = Vec::new();
+        for (i, word) in self.bitmap.iter_mut().enumerate() {
+            while *word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                *word &= *word - 1;
+                ans.push(GuestPhysAddr(
+                    self.base.0 + (i * 64 + bit) * GUEST_FRAME_SIZE,
+                ));
+            }
+        }
+        for gpa in &ans {
+            let (entry, _lvl) = space.find_entry_mut(gpa.page_number::<Sv39x4>())?;
+            entry.write_ppn_flags(entry.ppn(), entry.flags() - Sv39Flags::W);
+            mm::hfence_gvma(Some(gpa.0), None);
+        }
+        Ok(ans)
+    }
+
+    // finds the logged leaf mapping that contains `gpa`
+    fn find_logged(&self, gpa: GuestPhysAddr) -> Option<(GuestPhysAddr, usize)> {
+        let idx = match self.logged.binary_search_by(|(start, _)| start.cmp(&gpa)) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (start, size) = self.logged[idx];
+        if gpa.0 < start.0 + size {
+            Some((start, size))
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn test_guest_timer() {
+    let mut timer = GuestTimer::new();
+    assert_eq!(timer.guest_time(1000), 1000, "no offset before pausing");
+    timer.pause_at(1000);
+    assert!(timer.is_paused());
+    assert_eq!(timer.guest_time(1500), 1000, "time stops while paused");
+    timer.pause_at(1200); // already paused
+    timer.resume_at(1600);
+    assert!(!timer.is_paused());
+    assert_eq!(timer.guest_time(1600), 1000, "paused interval skipped");
+    assert_eq!(timer.guest_time(1700), 1100, "time runs after resuming");
+    timer.resume_at(1800); // not paused
+    assert_eq!(timer.guest_time(1800), 1200, "resume without pause ignored");
+    timer.pause_at(2000);
+    timer.resume_at(2300);
+    assert_eq!(timer.guest_time(2300), 1400, "gaps accumulated");
+    assert_eq!(timer.host_time(1500), 2400, "guest deadline in host time");
+    let mut now = GuestTimer::new();
+    now.pause();
+    assert!(now.is_paused(), "paused at current host time");
+    now.resume();
+    assert!(!now.is_paused(), "resumed at current host time");
+    let host_time = 5000_usize;
+    let delta = timer.htimedelta();
+    assert_eq!(
+        host_time.wrapping_add(delta),
+        4100,
+        "time presented by htimedelta"
+    );
+    println!("zihai > guest timer test passed");
+}
+
+pub(crate) fn test_scheduler() {
+    let mut sched = Scheduler::new();
+    let a = sched.add_guest(GuestContext::new(GuestPhysAddr(0x8000_0000)), 0xa);
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x9000_0000));
+    ctx.vsatp = 8 << 60;
+    let b = sched.add_guest(ctx, 0xb);
+    let mut live = GuestContext::new(GuestPhysAddr(0));
+    let switch = sched
+        .tick_at(100, &mut live)
+        .expect("first guest scheduled");
+    assert_eq!((switch.from, switch.to, switch.hgatp), (None, a, 0xa));
+    assert_eq!(live.sepc, 0x8000_0000, "context of first guest restored");
+    live.sepc = 0x8000_0010; // guest a runs
+    let switch = sched
+        .tick_at(200, &mut live)
+        .expect("switch to second guest");
+    assert_eq!((switch.from, switch.to, switch.hgatp), (Some(a), b, 0xb));
+    assert_eq!(switch.vsatp, 8 << 60, "vsatp of second guest");
+    assert_eq!(live.sepc, 0x9000_0000, "context of second guest restored");
+    assert_eq!(
+        sched.context(a).sepc,
+        0x8000_0010,
+        "context of first guest saved"
+    );
+    assert!(sched.timer(a).is_paused(), "descheduled guest time paused");
+    live.x[10] = 42; // guest b runs
+    let switch = sched.tick_at(500, &mut live).expect("round robin back");
+    assert_eq!((switch.from, switch.to), (Some(b), a));
+    assert_eq!(live.sepc, 0x8000_0010, "first guest resumes where it left");
+    assert_eq!(
+        sched.timer(a).guest_time(500),
+        200,
+        "time paused while away"
+    );
+    assert_eq!(switch.htimedelta, 300_usize.wrapping_neg());
+    assert_eq!(sched.context(b).x[10], 42, "context of second guest saved");
+    sched.set_runnable(b, false);
+    assert_eq!(
+        sched.tick_at(600, &mut live),
+        None,
+        "only runnable guest keeps running"
+    );
+    assert_eq!(sched.current(), Some(a));
+    sched.set_runnable(b, true);
+    let switch = sched.tick_at(700, &mut live).unwrap();
+    assert_eq!((switch.from, switch.to), (Some(a), b));
+    assert_eq!(live.x[10], 42, "second guest resumes where it left");
+    println!("zihai > guest scheduler test passed");
+}
+
+pub(crate) fn test_event_log() {
+    let exit = |sepc| GuestEvent::Exit {
+        scause: 10, // supervisor ecall from VS mode
+        sepc,
+        stval: 0,
+        htval: 0,
+        htinst: 0,
+    };
+    let mut log = EventLog::new();
+    assert_eq!(log.iter().count(), 0, "empty log");
+    log.record_at(100, exit(0x80000000));
+    let exception = GuestEvent::InjectedException { cause: 2, tval: 0 };
+    log.record_at(200, exception);
+    log.record_at(300, GuestEvent::InjectedInterrupt(VirtIntSet::TIMER));
+    let mut ans = alloc::string::String::new();
+    log.dump_to(&mut ans).unwrap();
+    let expected = concat!(
+        "[         100] exit scause=0xa sepc=0x80000000 stval=0x0 htval=0x0 htinst=0x0\n",
+        "[         200] inject exception cause=0x2 tval=0x0\n",
+        "[         300] inject interrupt TIMER\n",
+    );
+    assert_eq!(ans, expected, "events dumped in order");
+    for i in 0..EVENT_LOG_SIZE {
+        log.record_at(1000 + i, exit(0x80001000 + i * 4));
+    }
+    assert_eq!(log.iter().count(), EVENT_LOG_SIZE, "fixed size");
+    let oldest = log.iter().next().unwrap();
+    assert_eq!(oldest.cycle, 1000, "oldest events evicted");
+    let newest = log.iter().last().unwrap();
+    assert_eq!(newest.cycle, 1000 + EVENT_LOG_SIZE - 1, "newest event last");
+    let in_order = log
+        .iter()
+        .zip(log.iter().skip(1))
+        .all(|(a, b)| a.cycle < b.cycle);
+    assert!(in_order, "events kept in order after wrapping");
+    log.record(exit(0x80002000));
+    let newest = log.iter().last().unwrap();
+    assert_eq!(newest.event, exit(0x80002000), "recorded at current cycle");
+    log.clear();
+    assert_eq!(log.iter().count(), 0, "log cleared");
+    log.dump(); // prints nothing
+    println!("zihai > event log test passed");
+}
+
+pub(crate) fn test_decode_htinst() {
+    // lw a0, 8(a1) with rs1 and offset zeroed
+    let lw = DecodedAccess {
+        is_store: false,
+        width: 4,
+        signed: true,
+        reg: 10,
+        insn_len: 4,
+    };
+    assert_eq!(decode_htinst(0x0000_2503), Some(lw), "32-bit load");
+    // c.lw a0, 8(a1) transformed into lw form with bit 1 cleared
+    let c_lw = DecodedAccess { insn_len: 2, ..lw };
+    assert_eq!(decode_htinst(0x0000_2501), Some(c_lw), "compressed load");
+    // lhu s1, 0(a0)
+    let lhu = decode_htinst(0x0000_5483).unwrap();
+    assert_eq!(
+        (lhu.width, lhu.signed, lhu.reg),
+        (2, false, 9),
+        "unsigned load"
+    );
+    // sd a2, 0(a3)
+    let sd = DecodedAccess {
+        is_store: true,
+        width: 8,
+        signed: false,
+        reg: 12,
+        insn_len: 4,
+    };
+    assert_eq!(decode_htinst(0x00c0_3023), Some(sd), "store");
+    assert_eq!(decode_htinst(0), None, "htinst not provided");
+    assert_eq!(
+        decode_htinst(HTINST_PSEUDO_READ_64),
+        None,
+        "page table walk"
+    );
+    assert_eq!(decode_htinst(0x0000_0073), None, "not a load or store");
+    // instructions fetched from guest memory are transformed first
+    assert_eq!(transform_fetched_insn(0x0085_a503), Some(0x0085_a503));
+    assert_eq!(
+        transform_fetched_insn(0x4188),
+        Some(0x0000_2501),
+        "c.lw a0, 0(a1) expanded"
+    );
+    let c_sw = decode_htinst(transform_fetched_insn(0xc188).unwrap());
+    let c_sw_expected = DecodedAccess {
+        is_store: true,
+        insn_len: 2,
+        signed: false,
+        ..c_lw
+    };
+    assert_eq!(c_sw, Some(c_sw_expected), "c.sw a0, 0(a1) expanded");
+    // c.sdsp ra, 8(sp)
+    let c_sdsp = decode_htinst(transform_fetched_insn(0xe406).unwrap()).unwrap();
+    assert_eq!(
+        (c_sdsp.is_store, c_sdsp.width, c_sdsp.reg),
+        (true, 8, 1),
+        "c.sdsp expanded"
+    );
+    assert_eq!(transform_fetched_insn(0x0001), None, "c.nop");
+    println!("zihai > htinst decode test passed");
+}
+
+pub(crate) fn test_decode_guest_page_fault() {
+    let with_gva = HStatus::from_bits((2 << 32) | (1 << 7) | (1 << 6)); // SPV and GVA
+    let decode = |code, htval, stval| decode_guest_page_fault(code, with_gva, htval, stval, 0);
+    let fault = decode(23, 0x8000_1234 >> 2, 0xffff_ffc0_0000_1237).unwrap();
+    assert_eq!(fault.kind, GuestFaultKind::Store, "store guest page fault");
+    assert_eq!(fault.gpa, GuestPhysAddr(0x8000_1237), "low bits from stval");
+    // highest guest physical address under Sv39x4, wider than 32 and 39 bits
+    let gpa = 0x1ff_ffff_f123;
+    let fault = decode(21, gpa >> 2, 0x1123).unwrap();
+    assert_eq!(fault.kind, GuestFaultKind::Load, "load guest page fault");
+    assert_eq!(
+        fault.gpa,
+        GuestPhysAddr(gpa),
+        "full width guest physical address"
+    );
+    assert_eq!(fault.gva, Some(0x1123), "guest virtual address");
+    let fault = decode(20, (1 << 62) | (gpa >> 2), 0x123).unwrap();
+    assert_eq!(
+        fault.gpa,
+        GuestPhysAddr(gpa),
+        "masked to guest physical width"
+    );
+    assert_eq!(decode(13, 0, 0), None, "not a guest page fault");
+    // stval does not hold a guest virtual address without GVA
+    let without_gva = HStatus::from_bits((2 << 32) | (1 << 7));
+    let fault = decode_guest_page_fault(21, without_gva, 0x8000_1234 >> 2, 0x3, 0x2503).unwrap();
+    assert_eq!(fault.gva, None, "no guest virtual address");
+    assert_eq!(fault.htinst, 0x2503, "transformed instruction kept");
+    assert_eq!(
+        fault.gpa,
+        GuestPhysAddr(0x8000_1234),
+        "low bits not taken from stval"
+    );
+    println!("zihai > guest page fault decode test passed");
+}
+
+pub(crate) fn test_guest_slice(frame_alloc: &mm::DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc).unwrap();
+    let frame1 = FrameBox::try_new_in(frame_alloc).unwrap();
+    let frame2 = FrameBox::try_new_in(frame_alloc).unwrap();
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::U;
+    // guest pages mapped in reverse order of host frames, never contiguous
+    let (ppn1, ppn2) = (frame1.phys_page_num(), frame2.phys_page_num());
+    let gpa = GuestPhysAddr(0x8000_0000);
+    space
+        .allocate_map(gpa.page_number::<Sv39x4>(), ppn2, 1, flags)
+        .unwrap();
+    let gpa_next = GuestPhysAddr(gpa.0 + GUEST_FRAME_SIZE);
+    space
+        .allocate_map(gpa_next.page_number::<Sv39x4>(), ppn1, 1, flags)
+        .unwrap();
+    let host1 = ppn1.addr_begin::<Sv39x4>().0 as *mut u8;
+    let host2 = ppn2.addr_begin::<Sv39x4>().0 as *mut u8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(b"hello".as_ptr(), host2.add(0x100), 5);
+        core::ptr::copy_nonoverlapping(b"zihai".as_ptr(), host2.add(0xffd), 3);
+        core::ptr::copy_nonoverlapping(b"hai".as_ptr(), host1, 3);
+    }
+    match guest_slice(&space, GuestPhysAddr(gpa.0 + 0x100), 5) {
+        Ok(GuestSlice::Contiguous(bytes)) => {
+            assert_eq!(bytes, b"hello", "single frame buffer");
+            assert_eq!(bytes.as_ptr(), unsafe { host2.add(0x100) }, "zero copy");
+        }
+        _ => panic!("single frame buffer should be contiguous"),
+    }
+    match guest_slice(&space, GuestPhysAddr(gpa.0 + 0xffd), 6) {
+        Ok(GuestSlice::Chunked(mut chunks)) => {
+            assert_eq!(chunks.next(), Some(&b"zih"[..]), "first chunk");
+            assert_eq!(chunks.next(), Some(&b"hai"[..]), "second chunk");
+            assert_eq!(chunks.next(), None, "two chunks");
+        }
+        _ => panic!("cross frame buffer should be chunked"),
+    }
+    assert_eq!(
+        guest_slice(&space, GuestPhysAddr(gpa.0 + 0x1ffe), 4).map(|_| ()),
+        Err(PageError::InvalidEntry),
+        "buffer crosses unmapped page"
+    );
+    drop(space);
+    drop((frame1, frame2));
+    println!("zihai > guest slice test passed");
+}
+
+pub(crate) fn test_map_contiguous_ram(frame_alloc: &mm::DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc).unwrap();
+    let gpa_base = GuestPhysAddr(0x8000_0000);
+    let base = map_contiguous_ram(&mut space, gpa_base, 8 * GUEST_FRAME_SIZE, frame_alloc)
+        .expect("map contiguous guest RAM");
+    for i in 0..8 {
+        let gpa = GuestPhysAddr(gpa_base.0 + i * GUEST_FRAME_SIZE);
+        let (entry, _) = space.find_ppn(gpa.page_number::<Sv39x4>()).unwrap();
+        assert_eq!(
+            entry.ppn().addr_begin::<Sv39x4>().0,
+            base.addr_begin::<Sv39x4>().0 + i * GUEST_FRAME_SIZE,
+            "consecutive host physical pages"
+        );
+    }
+    assert_eq!(
+        map_contiguous_ram(&mut space, GuestPhysAddr(0x9000_0800), 0x1000, frame_alloc),
+        Err(CreateError::MisalignedRam),
+        "misaligned guest RAM"
+    );
+    frame_alloc.deallocate_frames(base, 8);
+    println!("zihai > contiguous guest RAM test passed");
+}
+
+pub(crate) fn test_guest_memory_layout(frame_alloc: &mm::DefaultFrameAllocator) {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 16 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let mut vm = create_guest(config, frame_alloc).unwrap();
+    vm.add_region(GuestPhysAddr(0x1000), 0x1000, RegionKind::Rom)
+        .unwrap();
+    let rom_vpn = GuestPhysAddr(0x1000).page_number::<Sv39x4>();
+    let (entry, _) = vm.space().find_ppn(rom_vpn).unwrap();
+    let rom_flags = Sv39Flags::V | Sv39Flags::R | Sv39Flags::X | Sv39Flags::U;
+    assert_eq!(entry.flags(), rom_flags, "ROM mapped read-only when added");
+    vm.write_guest(GuestPhysAddr(0x1010), b"boot").unwrap();
+    let mut rom = [0; 4];
+    vm.read_guest(GuestPhysAddr(0x1010), &mut rom).unwrap();
+    assert_eq!(&rom, b"boot", "ROM contents written by hypervisor");
+    assert_eq!(
+        vm.add_region(GuestPhysAddr(0x3800), 0x1000, RegionKind::Rom),
+        Err(CreateError::MisalignedRam),
+        "misaligned ROM"
+    );
+    let uart = RegionKind::Mmio { device: 3 };
+    vm.add_region(GuestPhysAddr(0x10000000), 0x100, uart)
+        .unwrap();
+    assert_eq!(
+        vm.add_region(GuestPhysAddr(0x80008000), 0x1000, RegionKind::Rom),
+        Err(CreateError::OverlappingRegion),
+        "region inside guest RAM"
+    );
+    assert_eq!(
+        vm.add_region(GuestPhysAddr(0xfff), 2, RegionKind::Hole),
+        Err(CreateError::OverlappingRegion),
+        "region over start of ROM"
+    );
+    assert_eq!(
+        vm.layout().region_kind(GuestPhysAddr(0x10000100)),
+        RegionKind::Hole
+    );
+    // faults in lazily mapped RAM take frames from the pre-zeroed pool
+    assert_eq!(vm.refill_zero_pool(), Ok(ZERO_POOL_CAPACITY), "pool filled");
+    let fault = GuestPageFault {
+        kind: GuestFaultKind::Store,
+        gpa: GuestPhysAddr(0x80004000),
+        gva: None,
+        htinst: 0,
+    };
+    let ctx = GuestContext::new(vm.entry());
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault, &ctx),
+        Ok(FaultAction::MappedRam)
+    );
+    assert_eq!(
+        vm.zero_pool.len(),
+        ZERO_POOL_CAPACITY - 1,
+        "frame from pool"
+    );
+    assert_eq!(vm.refill_zero_pool(), Ok(0), "above low water mark");
+    let mut fault = |kind, gpa| {
+        let fault = GuestPageFault {
+            kind,
+            gpa: GuestPhysAddr(gpa),
+            gva: Some(gpa),
+            htinst: 0x00a0_2023, // sw a0 with address fields zeroed
+        };
+        vm.handle_guest_page_fault(&fault, &ctx).unwrap()
+    };
+    use GuestFaultKind::*;
+    assert_eq!(
+        fault(Load, 0x80003008),
+        FaultAction::MappedRam,
+        "fault in RAM"
+    );
+    let action = FaultAction::EmulateMmio {
+        device: 3,
+        offset: 5,
+        access: DecodedAccess {
+            is_store: true,
+            width: 4,
+            signed: false,
+            reg: 10,
+            insn_len: 4,
+        },
+    };
+    assert_eq!(fault(Store, 0x10000005), action, "fault in device");
+    assert_eq!(
+        fault(Store, 0x1008),
+        FaultAction::InjectAccessFault,
+        "store to ROM"
+    );
+    assert_eq!(
+        fault(Load, 0x2000),
+        FaultAction::InjectAccessFault,
+        "fault in hole"
+    );
+    assert_eq!(
+        fault(Fetch, 0x90000000),
+        FaultAction::InjectAccessFault,
+        "after RAM"
+    );
+    let mapped = GuestPhysAddr(0x80003000).page_number::<Sv39x4>();
+    assert!(
+        vm.space().find_ppn(mapped).is_ok(),
+        "RAM page mapped on fault"
+    );
+    println!("zihai > guest memory layout test passed");
+}
+
+pub(crate) fn test_decode_fault_access(frame_alloc: &mm::DefaultFrameAllocator) {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 16 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let mut vm = create_guest(config, frame_alloc).unwrap();
+    let uart = RegionKind::Mmio { device: 3 };
+    vm.add_region(GuestPhysAddr(0x10000000), 0x100, uart)
+        .unwrap();
+    // lw a0, 8(a1); c.sw a0, 0(a1)
+    vm.load(
+        GuestPhysAddr(0x80006000),
+        &[0x03, 0xa5, 0x85, 0x00, 0x88, 0xc1],
+    )
+    .unwrap();
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x80006000));
+    let fault = |kind, htinst| GuestPageFault {
+        kind,
+        gpa: GuestPhysAddr(0x10000008),
+        gva: None,
+        htinst,
+    };
+    let lw = DecodedAccess {
+        is_store: false,
+        width: 4,
+        signed: true,
+        reg: 10,
+        insn_len: 4,
+    };
+    use GuestFaultKind::*;
+    assert_eq!(
+        vm.decode_fault_access(&fault(Load, 0), &ctx),
+        Some(lw),
+        "fetched when htinst not provided"
+    );
+    assert_eq!(
+        vm.decode_fault_access(&fault(Load, HTINST_PSEUDO_READ_64), &ctx),
+        Some(lw),
+        "fetched on pseudo-instruction"
+    );
+    assert_eq!(
+        vm.decode_fault_access(&fault(Store, 0), &ctx),
+        None,
+        "load does not match store fault"
+    );
+    assert_eq!(
+        vm.decode_fault_access(&fault(Fetch, 0), &ctx),
+        None,
+        "instruction fetch"
+    );
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault(Fetch, 0), &ctx),
+        Ok(FaultAction::InjectAccessFault),
+        "fetch from device"
+    );
+    // guest paging on: gigapage at virtual 0 maps guest RAM base
+    let root = 0x80007000;
+    vm.load(GuestPhysAddr(root), &0x2000_000busize.to_le_bytes())
+        .unwrap();
+    ctx.vsatp = (VSATP_MODE_SV39 << 60) | (root >> 12);
+    ctx.sepc = 0x6004;
+    let c_sw = DecodedAccess {
+        is_store: true,
+        signed: false,
+        insn_len: 2,
+        ..lw
+    };
+    let action = FaultAction::EmulateMmio {
+        device: 3,
+        offset: 8,
+        access: c_sw,
+    };
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault(Store, 0), &ctx),
+        Ok(action),
+        "compressed store fetched through guest page table"
+    );
+    ctx.sepc = 0x4000_0000;
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault(Store, 0), &ctx),
+        Ok(FaultAction::InjectAccessFault),
+        "guest instruction not mapped"
+    );
+    println!("zihai > guest fault access decode test passed");
+}
+
+pub(crate) fn test_add_memory(frame_alloc: &mm::DefaultFrameAllocator) {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 4 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let mut vm = create_guest(config, frame_alloc).unwrap();
+    let fault = GuestPageFault {
+        kind: GuestFaultKind::Store,
+        gpa: GuestPhysAddr(0x1_0000_2008),
+        gva: None,
+        htinst: 0,
+    };
+    let ctx = GuestContext::new(vm.entry());
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault, &ctx),
+        Ok(FaultAction::InjectAccessFault),
+        "hole before hot-adding"
+    );
+    let hot_base = GuestPhysAddr(0x1_0000_0000);
+    assert_eq!(
+        vm.add_memory(GuestPhysAddr(0x1_0000_0800), GUEST_FRAME_SIZE),
+        Err(CreateError::MisalignedRam)
+    );
+    assert_eq!(
+        vm.add_memory(GuestPhysAddr(0x80002000), 4 * GUEST_FRAME_SIZE),
+        Err(CreateError::OverlappingRegion),
+        "region over boot RAM"
+    );
+    vm.add_memory(hot_base, 8 * GUEST_FRAME_SIZE).unwrap();
+    assert_eq!(vm.layout().region_kind(fault.gpa), RegionKind::Ram);
+    assert_eq!(
+        vm.handle_guest_page_fault(&fault, &ctx),
+        Ok(FaultAction::MappedRam),
+        "fault in hot-added RAM served"
+    );
+    let mapped = fault.gpa.page_number::<Sv39x4>();
+    assert!(vm.space().find_ppn(mapped).is_ok(), "frame mapped");
+    vm.write_guest(fault.gpa, b"hot").unwrap();
+    let mut buf = [0; 3];
+    vm.read_guest(fault.gpa, &mut buf).unwrap();
+    assert_eq!(&buf, b"hot", "hot-added RAM usable");
+    let after = GuestPhysAddr(hot_base.0 + 8 * GUEST_FRAME_SIZE);
+    assert_eq!(vm.map_ram_page(after), Err(CreateError::OutOfRam));
+    println!("zihai > add memory test passed");
+}
+
+pub(crate) fn test_setup_boot_args(frame_alloc: &mm::DefaultFrameAllocator) {
+    let dtb = [0xd0, 0x0d, 0xfe, 0xed];
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 4 * GUEST_FRAME_SIZE,
+        cpu_count: 2,
+        kernel_image: &[],
+        dtb: Some(&dtb),
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let vm = create_guest(config, frame_alloc).unwrap();
+    let mut ctx = GuestContext::new(vm.entry());
+    let dtb_gpa = vm.dtb().unwrap();
+    setup_boot_args(&mut ctx, vm.space(), 1, dtb_gpa).expect("setup boot arguments");
+    assert_eq!(ctx.x[10], 1, "a0 is hart id");
+    assert_eq!(ctx.x[11], dtb_gpa.0, "a1 is device tree address");
+    assert_eq!(ctx.dtb, Some(dtb_gpa), "device tree recorded");
+    assert_eq!(ctx.sepc, 0x80000000, "guest starts from entry");
+    let mut ctx = GuestContext::new(vm.entry());
+    assert_eq!(
+        setup_boot_args(&mut ctx, vm.space(), 0, GuestPhysAddr(0x80001000)),
+        Err(PageError::InvalidEntry),
+        "device tree not mapped"
+    );
+    assert_eq!(
+        (ctx.x[10], ctx.x[11], ctx.dtb),
+        (0, 0, None),
+        "context unchanged"
+    );
+    println!("zihai > guest boot arguments test passed");
+}
+
+pub(crate) fn test_guest_interrupts() {
+    // VSSIP, VSTIP and VSEIP in `hvip`, `hip` and `hie`
+    let (vssip, vstip, vseip) = (1 << 2, 1 << 6, 1 << 10);
+    assert_eq!(
+        VirtIntSet::SOFTWARE.to_h_bits(),
+        vssip,
+        "software interrupt"
+    );
+    assert_eq!(VirtIntSet::TIMER.to_h_bits(), vstip, "timer interrupt");
+    assert_eq!(
+        VirtIntSet::EXTERNAL.to_h_bits(),
+        vseip,
+        "external interrupt"
+    );
+    assert_eq!(
+        VirtIntSet::from_h_bits(vssip | vseip | (1 << 12)),
+        VirtIntSet::SOFTWARE | VirtIntSet::EXTERNAL,
+        "bits other than VS-level interrupts ignored"
+    );
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x80000000));
+    ctx.hie = 1 << 12; // SGEIE, not managed by VirtIntSet
+    set_guest_interrupt_enable(&mut ctx, VirtIntSet::TIMER | VirtIntSet::EXTERNAL);
+    assert_eq!(ctx.hie, (1 << 12) | vstip | vseip, "hie written");
+    assert_eq!(
+        guest_interrupt_enable(&ctx),
+        VirtIntSet::TIMER | VirtIntSet::EXTERNAL
+    );
+    set_guest_interrupt_pending(&mut ctx, VirtIntSet::TIMER);
+    assert_eq!(ctx.hvip, vstip, "hvip written");
+    assert_eq!(guest_interrupt_pending(&ctx), VirtIntSet::TIMER);
+    set_guest_interrupt_pending(&mut ctx, VirtIntSet::empty());
+    assert!(
+        guest_interrupt_pending(&ctx).is_empty(),
+        "interrupt withdrawn"
+    );
+    println!("zihai > guest interrupt state test passed");
+}
+
+pub(crate) fn test_guest_satp_write(frame_alloc: &mm::DefaultFrameAllocator) {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static FENCES: AtomicUsize = AtomicUsize::new(0);
+    fn count_fence() {
+        FENCES.fetch_add(1, Ordering::Relaxed);
+    }
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 16 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let mut vm = create_guest(config, frame_alloc).unwrap();
+    let mut ctx = GuestContext::new(vm.entry());
+    let sv39 = |root: usize| (VSATP_MODE_SV39 << 60) | (root >> 12);
+    let ans = on_guest_satp_write_with(&mut vm, &mut ctx, sv39(0x90000000), count_fence);
+    assert_eq!(ans, Err(CreateError::OutOfRam), "root outside guest RAM");
+    assert_eq!(ctx.vsatp, 0, "rejected write not applied");
+    assert_eq!(FENCES.load(Ordering::Relaxed), 0, "no fence on rejection");
+    let ans = on_guest_satp_write_with(&mut vm, &mut ctx, 5 << 60, count_fence);
+    assert_eq!(
+        ans,
+        Err(CreateError::Page(PageError::InvalidEntry)),
+        "unsupported mode"
+    );
+    let root = GuestPhysAddr(0x80002000);
+    let ans = on_guest_satp_write_with(&mut vm, &mut ctx, sv39(root.0), count_fence);
+    assert_eq!(ans, Ok(()), "root in guest RAM");
+    assert_eq!(ctx.vsatp, sv39(root.0), "vsatp updated");
+    assert_eq!(
+        FENCES.load(Ordering::Relaxed),
+        1,
+        "guest translations flushed"
+    );
+    assert!(
+        vm.space().find_ppn(root.page_number::<Sv39x4>()).is_ok(),
+        "lazy root table mapped"
+    );
+    let ans = on_guest_satp_write_with(&mut vm, &mut ctx, 0, count_fence);
+    assert_eq!(ans, Ok(()), "paging turned off");
+    assert_eq!(FENCES.load(Ordering::Relaxed), 2, "flushed again");
+    let ans = on_guest_satp_write(&mut vm, &mut ctx, sv39(root.0));
+    assert_eq!(ans, Ok(()), "flushed on current hart");
+    assert_eq!(ctx.vsatp, sv39(root.0), "paging turned on again");
+    println!("zihai > guest satp write test passed");
+}
+
+pub(crate) fn test_inject_exception() {
+    const LOAD_PAGE_FAULT: usize = 13;
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x80000000));
+    ctx.sepc = 0x10234; // faulting guest user pc
+    ctx.sstatus &= !SSTATUS_SPP; // trapped from VU mode
+    ctx.vs.vstvec = 0x80200001; // vectored mode
+    ctx.vs.vsstatus = SSTATUS_SIE;
+    inject_exception(&mut ctx, LOAD_PAGE_FAULT, 0x3fff_f000);
+    assert_eq!(ctx.vs.vsepc, 0x10234, "faulting pc");
+    assert_eq!(ctx.vs.vscause, LOAD_PAGE_FAULT, "cause");
+    assert_eq!(ctx.vs.vstval, 0x3fff_f000, "faulting address");
+    assert_eq!(ctx.vs.vsstatus & SSTATUS_SPP, 0, "previous privilege VU");
+    assert_ne!(
+        ctx.vs.vsstatus & SSTATUS_SPIE,
+        0,
+        "previous interrupt enabled"
+    );
+    assert_eq!(ctx.vs.vsstatus & SSTATUS_SIE, 0, "interrupts disabled");
+    assert_eq!(ctx.sepc, 0x80200000, "resume at vector base");
+    assert_ne!(ctx.sstatus & SSTATUS_SPP, 0, "resume in VS mode");
+    // nested fault in guest kernel with interrupts disabled
+    ctx.sepc = 0x80200010;
+    inject_exception(&mut ctx, LOAD_PAGE_FAULT, 0);
+    assert_ne!(ctx.vs.vsstatus & SSTATUS_SPP, 0, "previous privilege VS");
+    assert_eq!(
+        ctx.vs.vsstatus & SSTATUS_SPIE,
+        0,
+        "previous interrupt disabled"
+    );
+    assert_eq!(ctx.vs.vsepc, 0x80200010, "nested faulting pc");
+    println!("zihai > guest exception injection test passed");
+}
+
+pub(crate) fn test_vs_ecall_reset() {
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x80000000));
+    ctx.sepc = 0x80001000;
+    ctx.x[17] = sbi::EXTENSION_SRST;
+    ctx.x[16] = 0; // system reset
+    ctx.x[10] = 0; // shutdown
+    ctx.x[11] = 1; // system failure
+    let exit = VmExit::SystemReset {
+        reset_type: ResetType::Shutdown,
+        reason: 1,
+    };
+    assert_eq!(
+        handle_vs_ecall(&mut ctx),
+        EcallOutcome::Exit(exit),
+        "guest shutdown"
+    );
+    assert_eq!(ctx.sepc, 0x80001000, "guest not resumed");
+    ctx.x[10] = 2; // warm reboot
+    ctx.x[11] = 0; // no reason
+    let exit = VmExit::SystemReset {
+        reset_type: ResetType::WarmReboot,
+        reason: 0,
+    };
+    assert_eq!(
+        handle_vs_ecall(&mut ctx),
+        EcallOutcome::Exit(exit),
+        "guest reboot"
+    );
+    ctx.x[10] = 0x1000_0000; // reserved reset type
+    assert_eq!(
+        handle_vs_ecall(&mut ctx),
+        EcallOutcome::Resume,
+        "invalid reset type"
+    );
+    assert_eq!(ctx.x[10], SBI_ERR_INVALID_PARAM, "invalid parameter error");
+    assert_eq!(ctx.sepc, 0x80001004, "ecall skipped");
+    ctx.x[17] = 0x0A000000; // unsupported extension
+    assert_eq!(
+        handle_vs_ecall(&mut ctx),
+        EcallOutcome::Resume,
+        "other ecall"
+    );
+    assert_eq!(ctx.x[10], SBI_ERR_NOT_SUPPORTED, "not supported error");
+    println!("zihai > guest system reset ecall test passed");
+}
+
+pub(crate) fn test_forwarded_ecall() {
+    use sbi::SbiRet;
+    fn host_fence_failed(_: usize, _: usize, _: [usize; 6]) -> SbiRet {
+        SbiRet {
+            error: sbi::SBI_ERR_FAILED,
+            value: 0,
+        }
+    }
+    fn host_fence_done(extension: usize, function: usize, args: [usize; 6]) -> SbiRet {
+        assert_eq!(extension, sbi::EXTENSION_RFENCE, "rfence extension");
+        assert_eq!(
+            function,
+            sbi::FUNCTION_RFENCE_REMOTE_HFENCE_VVMA,
+            "guest fence on VS-stage"
+        );
+        assert_eq!(args[..4], [0b11, 0, 0x1000, 0x2000], "guest arguments");
+        SbiRet {
+            error: sbi::SBI_SUCCESS,
+            value: 0,
+        }
+    }
+    let mut ctx = GuestContext::new(GuestPhysAddr(0x80000000));
+    ctx.sepc = 0x80001000;
+    ctx.x[17] = sbi::EXTENSION_RFENCE;
+    ctx.x[16] = sbi::FUNCTION_RFENCE_REMOTE_SFENCE_VMA;
+    ctx.x[10..16].copy_from_slice(&[0b11, 0, 0x1000, 0x2000, 0x5, 0x6]); // harts 0 and 1, one range
+    let outcome = handle_vs_ecall_with(&mut ctx, host_fence_failed);
+    assert_eq!(outcome, EcallOutcome::Resume, "retried in guest");
+    assert_eq!(ctx.sepc, 0x80001000, "sepc not advanced on retry");
+    assert_eq!(
+        ctx.x[10..16],
+        [0b11, 0, 0x1000, 0x2000, 0x5, 0x6],
+        "a0 to a5 kept for retry"
+    );
+    let outcome = handle_vs_ecall_with(&mut ctx, host_fence_done);
+    assert_eq!(outcome, EcallOutcome::Resume, "fence done");
+    assert_eq!(ctx.sepc, 0x80001004, "sepc advanced on completion");
+    assert_eq!(ctx.x[10], sbi::SBI_SUCCESS, "success returned");
+    ctx.x[17] = sbi::EXTENSION_HSM; // not forwarded
+    let outcome = handle_vs_ecall_with(&mut ctx, host_fence_failed);
+    assert_eq!(outcome, EcallOutcome::Resume, "not forwarded");
+    assert_eq!(ctx.x[10], SBI_ERR_NOT_SUPPORTED, "not supported error");
+    assert_eq!(ctx.sepc, 0x80001008, "unsupported call skipped");
+    println!("zihai > forwarded ecall test passed");
+}
+
+pub(crate) fn test_create_guest(frame_alloc: &mm::DefaultFrameAllocator) {
+    let image = [0x73, 0x00, 0x50, 0x10, 0x6f, 0x00, 0x00, 0x00]; // wfi; j .
+    let dtb = [0xd0, 0x0d, 0xfe, 0xed]; // device tree magic
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 16 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &image,
+        dtb: Some(&dtb),
+        lazy_ram: false,
+        frame_quota: usize::MAX,
+    };
+    let host_bytes = |vm: &Vm<_>, gpa: GuestPhysAddr, len| {
+        let (entry, _) = vm.space().find_ppn(gpa.page_number::<Sv39x4>()).unwrap();
+        let addr = entry.ppn().addr_begin::<Sv39x4>().0 + (gpa.0 & (GUEST_FRAME_SIZE - 1));
+        unsafe { core::slice::from_raw_parts(addr as *const u8, len) }.to_vec()
+    };
+    let vm = create_guest(config, frame_alloc).expect("create guest");
+    assert_eq!(vm.entry(), GuestPhysAddr(0x80000000), "guest entry point");
+    assert_eq!(vm.cpu_count(), config.cpu_count, "number of guest harts");
+    for i in 0..16 {
+        let gpa = GuestPhysAddr(0x80000000 + i * GUEST_FRAME_SIZE);
+        assert!(
+            vm.space().find_ppn(gpa.page_number::<Sv39x4>()).is_ok(),
+            "guest RAM mapped"
+        );
+    }
+    let after_ram = GuestPhysAddr(0x80000000 + 16 * GUEST_FRAME_SIZE);
+    assert!(
+        vm.space()
+            .find_ppn(after_ram.page_number::<Sv39x4>())
+            .is_err(),
+        "outside guest RAM"
+    );
+    assert_eq!(
+        host_bytes(&vm, vm.entry(), image.len()),
+        image,
+        "kernel loaded"
+    );
+    assert_eq!(vm.dtb(), Some(GuestPhysAddr(0x8000f000)), "dtb address");
+    assert_eq!(
+        host_bytes(&vm, vm.dtb().unwrap(), dtb.len()),
+        dtb,
+        "dtb loaded"
+    );
+    drop(vm);
+    let mut vm = create_guest(
+        GuestConfig {
+            lazy_ram: true,
+            dtb: None,
+            ..config
+        },
+        frame_alloc,
+    )
+    .expect("create lazy guest");
+    let gpa = GuestPhysAddr(0x80001000);
+    let vpn = gpa.page_number::<Sv39x4>();
+    assert!(vm.space().find_ppn(vpn).is_err(), "lazy RAM not mapped");
+    vm.map_ram_page(gpa).expect("map lazy RAM page");
+    assert!(
+        vm.space().find_ppn(vpn).is_ok(),
+        "lazy RAM mapped on access"
+    );
+    assert_eq!(
+        vm.map_ram_page(after_ram),
+        Err(CreateError::OutOfRam),
+        "map outside guest RAM"
+    );
+    assert_eq!(
+        host_bytes(&vm, vm.entry(), image.len()),
+        image,
+        "lazy kernel loaded"
+    );
+    let dtb_gpa = vm.dtb().expect("device tree generated");
+    assert_eq!(dtb_gpa, GuestPhysAddr(0x8000f000), "generated dtb address");
+    let mut header = [0u8; 8];
+    vm.read_guest(dtb_gpa, &mut header).unwrap();
+    assert_eq!(header[..4], FDT_MAGIC.to_be_bytes(), "device tree magic");
+    let total = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    assert!(total <= GUEST_FRAME_SIZE, "fits in last page of RAM");
+    println!("zihai > create guest test passed");
+}
+
+pub(crate) fn test_minimal_fdt() {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 0x800_0000,
+        cpu_count: 2,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: usize::MAX,
+    };
+    let fdt = minimal_fdt(&config);
+    let field = |idx: usize| {
+        let bytes = [
+            fdt[idx * 4],
+            fdt[idx * 4 + 1],
+            fdt[idx * 4 + 2],
+            fdt[idx * 4 + 3],
+        ];
+        u32::from_be_bytes(bytes) as usize
+    };
+    assert_eq!(field(0), FDT_MAGIC as usize, "magic");
+    assert_eq!(field(1), fdt.len(), "total size");
+    let (off_struct, off_strings) = (field(2), field(3));
+    assert_eq!(field(4), FDT_HEADER_SIZE, "memory reservation block");
+    assert_eq!(field(5), FDT_VERSION as usize, "version");
+    assert_eq!(off_strings + field(8), fdt.len(), "strings at the end");
+    assert_eq!(
+        off_struct + field(9),
+        off_strings,
+        "structure before strings"
+    );
+    let structure = &fdt[off_struct..off_strings];
+    let contains = |needle: &[u8]| structure.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"cpu@0\0"), "first hart");
+    assert!(contains(b"cpu@1\0"), "second hart");
+    assert!(!contains(b"cpu@2\0"), "no more harts");
+    assert!(contains(b"memory@80000000\0"), "memory node");
+    let reg = [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0];
+    assert!(contains(&reg), "memory base and size");
+    assert_eq!(
+        structure[structure.len() - 4..],
+        FDT_END.to_be_bytes(),
+        "end token"
+    );
+    println!("zihai > minimal device tree test passed");
+}
+
+pub(crate) fn test_wfi_guest(frame_alloc: &mm::DefaultFrameAllocator) {
+    let vm = test_guest_wfi(frame_alloc);
+    let ctx = GuestContext::new(vm.entry());
+    assert_eq!(ctx.sepc, 0x80000000, "context starts at guest entry");
+    assert_ne!(ctx.sstatus & SSTATUS_SPP, 0, "guest runs in VS mode");
+    let (entry, _) = vm
+        .space()
+        .find_ppn(vm.entry().page_number::<Sv39x4>())
+        .expect("entry page mapped");
+    assert!(
+        entry.flags().contains(Sv39Flags::X | Sv39Flags::U),
+        "entry page executable by guest"
+    );
+    let addr = entry.ppn().addr_begin::<Sv39x4>().0;
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, 8) };
+    assert_eq!(bytes, WFI_GUEST_IMAGE, "spin loop loaded");
+    // the guest waits until the host timer fires and exits to the hypervisor
+    let mut ctx = ctx;
+    sbi::set_timer((read_host_time() + GUEST_TIMER_TEST_TICKS) as usize);
+    unsafe { riscv::register::sie::set_stimer() };
+    let exit = run_guest(&vm, &mut ctx, 0);
+    sbi::set_timer(usize::MAX);
+    unsafe { riscv::register::sie::clear_stimer() };
+    assert_eq!(
+        exit.scause,
+        SCAUSE_INTERRUPT | SUPERVISOR_TIMER_INTERRUPT,
+        "timer interrupt exit"
+    );
+    assert!(
+        (0x80000000..0x80000008).contains(&ctx.sepc),
+        "exited from spin loop"
+    );
+    assert_ne!(ctx.sstatus & SSTATUS_SPP, 0, "exited from VS mode");
+    assert!(hcsr::read_hstatus().spv(), "trapped from guest");
+    println!("zihai > wfi guest run test passed");
+}
+
+pub(crate) fn test_read_write_guest(frame_alloc: &mm::DefaultFrameAllocator) {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 4 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: false,
+        frame_quota: usize::MAX,
+    };
+    let mut vm = create_guest(config, frame_alloc).expect("create guest");
+    let pattern: [u8; 16] = core::array::from_fn(|i| 0xa0 + i as u8);
+    let gpa = GuestPhysAddr(0x80000ff8); // crosses into the next page
+    vm.write_guest(gpa, &pattern)
+        .expect("write across page boundary");
+    let mut buf = [0u8; 16];
+    vm.read_guest(gpa, &mut buf)
+        .expect("read across page boundary");
+    assert_eq!(buf, pattern, "pattern read back");
+    let mut tail = [0u8; 8];
+    vm.read_guest(GuestPhysAddr(0x80001000), &mut tail).unwrap();
+    assert_eq!(tail, pattern[8..], "second half on next page");
+    let end = GuestPhysAddr(0x80000000 + 4 * GUEST_FRAME_SIZE - 4);
+    assert_eq!(
+        vm.write_guest(end, &pattern),
+        Err(PageError::InvalidEntry),
+        "write past guest RAM"
+    );
+    let mut last = [0u8; 4];
+    vm.read_guest(end, &mut last).unwrap();
+    assert_eq!(last, [0; 4], "nothing written on failure");
+    assert_eq!(
+        vm.read_guest(end, &mut buf),
+        Err(PageError::InvalidEntry),
+        "read past guest RAM"
+    );
+    println!("zihai > guest memory read write test passed");
+}
+
+pub(crate) fn test_guest_frame_quota(frame_alloc: &mm::DefaultFrameAllocator) {
+    let config = GuestConfig {
+        ram_base: GuestPhysAddr(0x80000000),
+        ram_size: 16 * GUEST_FRAME_SIZE,
+        cpu_count: 1,
+        kernel_image: &[],
+        dtb: None,
+        lazy_ram: true,
+        frame_quota: 0,
+    };
+    let empty_vm = create_guest(config, frame_alloc).map(|_| ());
+    assert_eq!(
+        empty_vm,
+        Err(CreateError::FrameAllocFailed),
+        "no frame for page table"
+    );
+    let mut small = create_guest(
+        GuestConfig {
+            frame_quota: 8,
+            ..config
+        },
+        frame_alloc,
+    )
+    .unwrap();
+    let mut other = create_guest(
+        GuestConfig {
+            frame_quota: 8,
+            ..config
+        },
+        frame_alloc,
+    )
+    .unwrap();
+    let fault = |vm: &mut Vm<_>, i| {
+        let fault = GuestPageFault {
+            kind: GuestFaultKind::Store,
+            gpa: GuestPhysAddr(0x80000000 + i * GUEST_FRAME_SIZE),
+            gva: None,
+            htinst: 0,
+        };
+        let ctx = GuestContext::new(vm.entry());
+        vm.handle_guest_page_fault(&fault, &ctx).unwrap()
+    };
+    let mut mapped = 0;
+    while fault(&mut small, mapped) == FaultAction::MappedRam {
+        mapped += 1;
+    }
+    assert!(mapped > 0, "pages faulted in within quota");
+    assert_eq!(small.frames_used(), small.frame_quota(), "quota used up");
+    assert_eq!(
+        fault(&mut small, mapped + 1),
+        FaultAction::OutOfMemory,
+        "later faults fail cleanly"
+    );
+    assert_eq!(small.frames_used(), 8, "no frame leaked on failure");
+    let other_used = other.frames_used();
+    assert_eq!(fault(&mut other, 0), FaultAction::MappedRam, "other guest");
+    assert_eq!(other.frames_used(), other_used + 1, "quota of its own");
+    drop(small);
+    drop(other);
+    println!("zihai > guest frame quota test passed");
+}
+
+pub(crate) fn test_dirty_tracking_strategy(hw_ad_update: bool) {
+    let strategy = dirty_tracking_strategy(true);
+    assert_eq!(strategy, DirtyTracking::HardwareDirtyBits, "Svadu");
+    let strategy = dirty_tracking_strategy(false);
+    assert_eq!(strategy, DirtyTracking::WriteProtect, "Svade");
+    println!(
+        "zihai > dirty tracking strategy test passed, current: {:?}",
+        dirty_tracking_strategy(hw_ad_update)
+    );
+}
+
+pub(crate) fn test_dirty_logging(frame_alloc: &mm::DefaultFrameAllocator) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc)
+        .expect("allocate page to create guest address space");
+    space
+        .allocate_map(
+            GuestPhysAddr(0x80000000).page_number::<Sv39x4>(),
+            mm::PhysAddr(0x80600000).page_number::<Sv39x4>(),
+            4,
+            Sv39Flags::R | Sv39Flags::W | Sv39Flags::U,
+        )
+        .expect("allocate writable guest pages");
+    space
+        .allocate_map(
+            GuestPhysAddr(0x80004000).page_number::<Sv39x4>(),
+            mm::PhysAddr(0x80604000).page_number::<Sv39x4>(),
+            1,
+            Sv39Flags::R | Sv39Flags::U,
+        )
+        .expect("allocate read-only guest page");
+    let mut log = enable_dirty_logging(&mut space);
+    let vpn = GuestPhysAddr(0x80001000).page_number::<Sv39x4>();
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert!(
+        !entry.flags().contains(Sv39Flags::W),
+        "writable page becomes write protected"
+    );
+    let ans = log.handle_store_fault(&mut space, GuestPhysAddr(0x80001008));
+    assert!(matches!(ans, Ok(true)), "store fault on logged page");
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert!(
+        entry.flags().contains(Sv39Flags::W),
+        "dirty page becomes writable"
+    );
+    let ans = log.handle_store_fault(&mut space, GuestPhysAddr(0x80004000));
+    assert!(matches!(ans, Ok(false)), "read-only page is not logged");
+    assert_eq!(
+        log.take_dirty_set(&mut space),
+        Ok(vec![GuestPhysAddr(0x80001000)]),
+        "dirty set"
+    );
+    let (entry, _) = space.find_ppn(vpn).unwrap();
+    assert!(
+        !entry.flags().contains(Sv39Flags::W),
+        "taken page write protected again"
+    );
+    let ans = log.handle_store_fault(&mut space, GuestPhysAddr(0x80001010));
+    assert!(matches!(ans, Ok(true)), "store after taken is logged again");
+    assert_eq!(
+        log.take_dirty_set(&mut space),
+        Ok(vec![GuestPhysAddr(0x80001000)]),
+        "dirty again after taken"
+    );
+    assert_eq!(
+        log.take_dirty_set(&mut space),
+        Ok(vec![]),
+        "dirty set after taken"
+    );
+    let mut space = PagedAddrSpace::try_new_in(Sv39x4, frame_alloc)
+        .expect("allocate page to create guest address space");
+    let napot_base = GuestPhysAddr(0x80010000).page_number::<Sv39x4>();
+    space
+        .allocate_map_napot(
+            napot_base,
+            mm::PhysAddr(0x80610000).page_number::<Sv39x4>(),
+            Sv39Flags::R | Sv39Flags::W | Sv39Flags::U,
+        )
+        .expect("allocate writable NAPOT guest pages");
+    let mut log = enable_dirty_logging(&mut space);
+    let ans = log.handle_store_fault(&mut space, GuestPhysAddr(0x80013008));
+    assert!(matches!(ans, Ok(true)), "store fault on logged NAPOT page");
+    for i in 0..mm::NAPOT_FRAMES {
+        let (entry, _) = space.find_ppn(napot_base.offset(i as isize)).unwrap();
+        assert!(
+            entry.is_napot() && entry.flags().contains(Sv39Flags::W),
+            "every entry of NAPOT leaf becomes writable"
+        );
+    }
+    let dirty = log.take_dirty_set(&mut space).unwrap();
+    assert_eq!(dirty.len(), mm::NAPOT_FRAMES, "whole NAPOT leaf dirty");
+    println!("zihai > guest dirty logging test passed");
+}